@@ -0,0 +1,49 @@
+//
+// Copyright 2019 Tamas Blummer
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+// http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+//
+//!
+//! # Fuzz message decoding
+//!
+//! Feed arbitrary bytes to the wire format decoder in chunks, as they would arrive from a peer.
+//! Decoding must end with an error or a need for more data, never panic.
+//!
+//! Run with: cargo fuzz run message_decode
+//!
+
+#![no_main]
+#[macro_use] extern crate libfuzzer_sys;
+extern crate murmel;
+
+use murmel::p2p::{Buffer, decode_message};
+use std::io::Write;
+
+fuzz_target!(|data: &[u8]| {
+    if data.is_empty() {
+        return;
+    }
+    // the first byte tells the size of chunks the rest arrives in
+    let chunk = data[0] as usize + 1;
+    let mut buffer = Buffer::new();
+    for piece in data[1..].chunks(chunk) {
+        buffer.write_all(piece).unwrap();
+        loop {
+            match decode_message(&mut buffer) {
+                Ok(Some(_)) => continue,
+                Ok(None) => break,
+                Err(_) => return
+            }
+        }
+    }
+});
@@ -0,0 +1,369 @@
+//
+// Copyright 2019 Tamas Blummer
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+// http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+//
+//!
+//! # Address book
+//!
+//! Addresses of peers learned from addr messages and connections, persisted in the config DB
+//! to find peers in later runs.
+//!
+//! A node serving others answers getaddr of incoming peers with a sample of known good addresses.
+//! The sample is cached for about a day, so repeated requests do not reveal the whole book,
+//! which would allow to fingerprint the node. Short addr messages with fresh addresses are
+//! relayed to a few peers and the node's own address is announced daily, once known.
+//!
+//! Addresses received are accepted by an AddrPolicy of required services, maximum age and
+//! whether Tor addresses are kept. Entries of an addr message beyond what the policy allows are
+//! ignored, a peer sending more than the protocol allows is penalized.
+//!
+//! Outgoing peers are asked for addresses at connect and again periodically, the one asked
+//! longest ago first. Addresses not heard of within the horizon are dropped from the book.
+//!
+
+use bitcoin::{
+    consensus::{Decodable, Encodable, encode},
+    network::{
+        address::Address,
+        message::NetworkMessage
+    }
+};
+use clock::SharedClock;
+use configdb::SharedConfigDB;
+use error::Error;
+use lock::Recover;
+use p2p::{P2PControlSender, PeerId, PeerMessage, PeerMessageReceiver, PeerMessageSender, SharedLocalAddress};
+use rand::{Rng, seq::SliceRandom, thread_rng};
+use std::{
+    collections::{HashMap, HashSet},
+    io,
+    net::{IpAddr, SocketAddr},
+    sync::{Arc, Mutex, mpsc},
+    thread,
+    time::{Duration, Instant}
+};
+use tracing::{Level, field::display};
+
+// most addresses in an addr message
+const MAX_ADDR: usize = 1000;
+// most addresses kept
+const MAX_ADDRESSES: usize = 10000;
+// addresses not seen since this many seconds are not shared
+const ADDRESS_HORIZON: u32 = 30 * 24 * 3600;
+// an addr message up to this size with addresses seen this recently is relayed
+const RELAY_ADDR: usize = 10;
+const RELAY_FRESH: u32 = 10 * 60;
+// number of peers an addr message is relayed to
+const RELAY_PEERS: usize = 2;
+// percent of known addresses in an answer to getaddr
+const GETADDR_PERCENT: usize = 23;
+// seconds an answer to getaddr is reused, randomized by +- an eighth
+const GETADDR_CACHE: u64 = 24 * 3600;
+// seconds between announcing our own address
+const ANNOUNCE_INTERVAL: u64 = 24 * 3600;
+// seconds between storing changed addresses
+const STORE_INTERVAL: u64 = 60;
+// seconds between asking an outgoing peer for addresses again
+const GETADDR_REFRESH: u64 = 3600;
+
+pub type SharedAddrPolicy = Arc<Mutex<AddrPolicy>>;
+
+/// Which addresses received in addr messages are kept
+#[derive(Clone, Debug)]
+pub struct AddrPolicy {
+    /// keep addresses announced with all of these services only
+    pub required_services: u64,
+    /// keep addresses heard of within this many seconds only
+    pub max_age: u32,
+    /// entries of an addr message considered, further ones are ignored
+    pub max_entries: usize,
+    /// keep Tor addresses, they are only of use if connecting through Tor
+    pub accept_tor: bool
+}
+
+impl Default for AddrPolicy {
+    fn default() -> AddrPolicy {
+        AddrPolicy { required_services: 0, max_age: ADDRESS_HORIZON, max_entries: MAX_ADDR, accept_tor: false }
+    }
+}
+
+/// An address of a peer and when it was last heard of
+#[derive(Clone, Debug)]
+pub struct KnownAddress {
+    /// unix time the address was last seen connected or announced
+    pub last_seen: u32,
+    /// address and services
+    pub address: Address
+}
+
+impl Encodable for KnownAddress {
+    fn consensus_encode<W: io::Write>(&self, mut w: W) -> Result<usize, encode::Error> {
+        Ok(self.last_seen.consensus_encode(&mut w)? + self.address.consensus_encode(&mut w)?)
+    }
+}
+
+impl Decodable for KnownAddress {
+    fn consensus_decode<D: io::Read>(mut d: D) -> Result<KnownAddress, encode::Error> {
+        Ok(KnownAddress { last_seen: Decodable::consensus_decode(&mut d)?, address: Decodable::consensus_decode(&mut d)? })
+    }
+}
+
+pub struct AddressBook {
+    p2p: P2PControlSender<NetworkMessage>,
+    configdb: SharedConfigDB,
+    // our address and services
+    local: SharedLocalAddress,
+    // which received addresses are kept
+    policy: SharedAddrPolicy,
+    clock: SharedClock,
+    addresses: HashMap<SocketAddr, KnownAddress>,
+    // addresses changed since last store
+    dirty: bool,
+    last_store: Instant,
+    // answer to getaddr and until when it is used
+    cached: Option<(Instant, Vec<(u32, Address)>)>,
+    // peers already answered a getaddr
+    answered: HashSet<PeerId>,
+    // outgoing peers with the time they were last asked for addresses
+    asked: HashMap<PeerId, Instant>,
+    next_announce: Instant,
+    next_refresh: Instant
+}
+
+impl AddressBook {
+    pub fn new(configdb: SharedConfigDB, p2p: P2PControlSender<NetworkMessage>, local: SharedLocalAddress, policy: SharedAddrPolicy, clock: SharedClock) -> Result<PeerMessageSender<NetworkMessage>, Error> {
+        let (sender, receiver) = mpsc::sync_channel(p2p.back_pressure);
+
+        let addresses = configdb.read().recover().fetch_addresses()?.into_iter()
+            .filter_map(|a| a.address.socket_addr().ok().map(|s| (s, a))).collect::<HashMap<_, _>>();
+        info!("{} known peer addresses", addresses.len());
+        let now = clock.now();
+        let mut addressbook = AddressBook { p2p, configdb, local, policy, clock, addresses, dirty: false, last_store: now,
+            cached: None, answered: HashSet::new(), asked: HashMap::new(), next_announce: now,
+            next_refresh: now + Duration::from_secs(GETADDR_REFRESH) };
+
+        thread::Builder::new().name("address book".to_string()).spawn(move || { addressbook.run(receiver) }).unwrap();
+
+        Ok(PeerMessageSender::new(sender))
+    }
+
+    fn run(&mut self, receiver: PeerMessageReceiver<NetworkMessage>) {
+        let span = span!(Level::INFO, "address book");
+        let _enter = span.enter();
+        loop {
+            while let Ok(msg) = receiver.recv_timeout(Duration::from_millis(1000)) {
+                match msg {
+                    PeerMessage::Connected(pid, address) => self.connected(pid, address),
+                    PeerMessage::Disconnected(pid, _) => {
+                        self.answered.remove(&pid);
+                        self.asked.remove(&pid);
+                    },
+                    PeerMessage::Incoming(pid, msg) => {
+                        let span = span!(Level::DEBUG, "peer", peer = display(pid));
+                        let _enter = span.enter();
+                        match msg {
+                            NetworkMessage::Addr(ref addresses) => self.addr(addresses, pid),
+                            NetworkMessage::GetAddr => self.get_addr(pid),
+                            _ => {}
+                        }
+                    },
+                    _ => {}
+                }
+            }
+            self.announce();
+            self.refresh();
+            if let Err(e) = self.store() {
+                error!("Error storing peer addresses: {}", e);
+            }
+        }
+    }
+
+    fn connected(&mut self, pid: PeerId, address: Option<SocketAddr>) {
+        if self.p2p.is_outgoing(pid) == Some(true) {
+            // a peer we could connect is good
+            if let Some(address) = address {
+                let services = self.p2p.peer_version(pid).map(|v| v.services).unwrap_or(0);
+                self.addresses.insert(address, KnownAddress { last_seen: self.unix_time(), address: Address::new(&address, services) });
+                self.dirty = true;
+            }
+            self.p2p.send_network(pid, NetworkMessage::GetAddr);
+            self.asked.insert(pid, self.clock.now());
+        }
+        if let Some(own) = self.own_address() {
+            self.p2p.send_network(pid, NetworkMessage::Addr(own));
+        }
+    }
+
+    fn addr(&mut self, addresses: &Vec<(u32, Address)>, peer: PeerId) {
+        let policy = self.policy.lock().recover().clone();
+        if addresses.len() > MAX_ADDR {
+            debug!("too many addresses in addr message peer={}", peer);
+            self.p2p.ban(peer, 20);
+            return;
+        }
+        if addresses.len() > policy.max_entries {
+            debug!("considering {} of {} addresses in addr message peer={}", policy.max_entries, addresses.len(), peer);
+        }
+        let now = self.unix_time();
+        let mut fresh = 0;
+        for (time, address) in addresses.iter().take(policy.max_entries) {
+            if let Ok(socket) = address.socket_addr() {
+                if is_onion(address) {
+                    if !policy.accept_tor {
+                        continue;
+                    }
+                } else if !is_routable(&socket.ip()) {
+                    continue;
+                }
+                // do not believe times in the future, handle as if old
+                let time = if *time > now.saturating_add(10 * 60) { now.saturating_sub(5 * 24 * 3600) } else { *time };
+                if time.saturating_add(RELAY_FRESH) > now {
+                    fresh += 1;
+                }
+                if address.services & policy.required_services != policy.required_services || time.saturating_add(policy.max_age) <= now {
+                    continue;
+                }
+                let known = self.addresses.entry(socket).or_insert(KnownAddress { last_seen: 0, address: address.clone() });
+                if time > known.last_seen {
+                    known.last_seen = time;
+                    known.address = address.clone();
+                    self.dirty = true;
+                }
+            }
+        }
+        if addresses.len() <= RELAY_ADDR && fresh == addresses.len() && fresh > 0 {
+            let mut peers = self.p2p.peers().into_iter().filter(|p| *p != peer).collect::<Vec<_>>();
+            peers.shuffle(&mut thread_rng());
+            for relay in peers.into_iter().take(RELAY_PEERS) {
+                trace!("relay {} addresses to peer={}", addresses.len(), relay);
+                self.p2p.send_network(relay, NetworkMessage::Addr(addresses.clone()));
+            }
+        }
+    }
+
+    // answer getaddr of incoming peers once per connection
+    fn get_addr(&mut self, peer: PeerId) {
+        if !self.local.is_server() || self.p2p.is_outgoing(peer) != Some(false) || !self.answered.insert(peer) {
+            return;
+        }
+        let renew = match self.cached {
+            Some((until, _)) => until <= self.clock.now(),
+            None => true
+        };
+        if renew {
+            let now = self.unix_time();
+            let good = self.addresses.values().filter(|a| a.last_seen + ADDRESS_HORIZON > now).collect::<Vec<_>>();
+            let n = (good.len() * GETADDR_PERCENT / 100).min(MAX_ADDR);
+            let sample = good.choose_multiple(&mut thread_rng(), n).map(|a| (a.last_seen, a.address.clone())).collect::<Vec<_>>();
+            let secs = thread_rng().gen_range(GETADDR_CACHE * 7 / 8, GETADDR_CACHE * 9 / 8);
+            self.cached = Some((self.clock.now() + Duration::from_secs(secs), sample));
+        }
+        if let Some((_, ref sample)) = self.cached {
+            debug!("answer getaddr with {} addresses peer={}", sample.len(), peer);
+            self.p2p.send_network(peer, NetworkMessage::Addr(sample.clone()));
+        }
+    }
+
+    // announce our own address to all peers daily
+    fn announce(&mut self) {
+        if self.next_announce > self.clock.now() {
+            return;
+        }
+        let own = match self.own_address() {
+            Some(own) => own,
+            None => return
+        };
+        self.next_announce = self.clock.now() + Duration::from_secs(ANNOUNCE_INTERVAL);
+        for peer in self.p2p.peers() {
+            self.p2p.send_network(peer, NetworkMessage::Addr(own.clone()));
+        }
+    }
+
+    // ask the outgoing peer asked longest ago for addresses and drop those beyond the horizon
+    fn refresh(&mut self) {
+        if self.next_refresh > self.clock.now() {
+            return;
+        }
+        let instant = self.clock.now();
+        self.next_refresh = instant + Duration::from_secs(GETADDR_REFRESH);
+        if let Some((peer, _)) = self.asked.iter().min_by_key(|(_, at)| **at).map(|(p, a)| (*p, *a))
+            .filter(|(_, at)| instant.duration_since(*at) >= Duration::from_secs(GETADDR_REFRESH)) {
+            debug!("ask for addresses again peer={}", peer);
+            self.p2p.send_network(peer, NetworkMessage::GetAddr);
+            self.asked.insert(peer, instant);
+        }
+        let now = self.unix_time();
+        let before = self.addresses.len();
+        self.addresses.retain(|_, a| a.last_seen + ADDRESS_HORIZON > now);
+        if self.addresses.len() < before {
+            debug!("dropped {} addresses not heard of within {} days", before - self.addresses.len(), ADDRESS_HORIZON / (24 * 3600));
+            self.dirty = true;
+        }
+    }
+
+    // our address as addr message content, if serving and known
+    fn own_address(&self) -> Option<Vec<(u32, Address)>> {
+        if self.local.is_server() && self.local.services() != 0 && self.local.get().is_some() {
+            Some(vec!((self.unix_time(), self.local.address())))
+        } else {
+            None
+        }
+    }
+
+    // unix time of the clock as in addr messages
+    fn unix_time(&self) -> u32 {
+        self.clock.unix_time() as u32
+    }
+
+    // store changed addresses, only the most recently seen are kept
+    fn store(&mut self) -> Result<(), Error> {
+        if !self.dirty || self.clock.now().duration_since(self.last_store) < Duration::from_secs(STORE_INTERVAL) {
+            return Ok(());
+        }
+        let mut addresses = self.addresses.values().cloned().collect::<Vec<_>>();
+        if addresses.len() > MAX_ADDRESSES {
+            addresses.sort_by(|a, b| b.last_seen.cmp(&a.last_seen));
+            addresses.truncate(MAX_ADDRESSES);
+            let keep = addresses.iter().filter_map(|a| a.address.socket_addr().ok()).collect::<HashSet<_>>();
+            self.addresses.retain(|s, _| keep.contains(s));
+        }
+        let mut configdb = self.configdb.write().recover();
+        configdb.store_addresses(addresses)?;
+        configdb.batch()?;
+        self.dirty = false;
+        self.last_store = self.clock.now();
+        Ok(())
+    }
+}
+
+/// an address other peers could connect to
+pub fn is_routable(ip: &IpAddr) -> bool {
+    match ip {
+        IpAddr::V4(ip) => !(ip.is_unspecified() || ip.is_loopback() || ip.is_private() || ip.is_link_local() ||
+            ip.is_broadcast() || ip.is_documentation()),
+        IpAddr::V6(ip) => {
+            let first = ip.segments()[0];
+            !(ip.is_unspecified() || ip.is_loopback() ||
+                // unique local fc00::/7 and link local fe80::/10
+                first & 0xfe00 == 0xfc00 || first & 0xffc0 == 0xfe80 ||
+                // documentation 2001:db8::/32
+                (first == 0x2001 && ip.segments()[1] == 0x0db8))
+        }
+    }
+}
+
+/// a Tor address in OnionCat encoding, fd87:d87e:eb43::/48
+pub fn is_onion(address: &Address) -> bool {
+    address.address[0] == 0xfd87 && address.address[1] == 0xd87e && address.address[2] == 0xeb43
+}
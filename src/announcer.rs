@@ -0,0 +1,186 @@
+//
+// Copyright 2019 Tamas Blummer
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+// http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+//
+//!
+//! # Announce inventory
+//!
+//! A serving node announces inventory to peers that do not yet know it. What a peer knows is
+//! learned from its own announcements, requests and data it sent, and what was announced to it.
+//! Blocks are announced at once, transactions are batched on a trickle timer with random
+//! intervals like Bitcoin Core does, so that the origin of a transaction is harder to tell.
+//!
+
+use bitcoin::{
+    BitcoinHash,
+    network::{
+        message::NetworkMessage,
+        message_blockdata::{Inventory, InvType}
+    }
+};
+use bitcoin_hashes::sha256d::Hash as Sha256dHash;
+use clock::SharedClock;
+use lru_cache::LruCache;
+use p2p::{P2PControlSender, PeerId, PeerMessage, PeerMessageReceiver, PeerMessageSender, SharedLocalAddress};
+use rand::{Rng, thread_rng};
+use std::{
+    collections::HashMap,
+    sync::mpsc,
+    thread,
+    time::{Duration, Instant}
+};
+use tracing::{Level, field::display};
+
+// number of inventory items remembered as known by a peer
+const KNOWN_INVENTORY: usize = 50000;
+// average milliseconds between transaction announcements to a peer
+const TRICKLE_MILLIS: f64 = 5000.0;
+// most transactions announced at once
+const INVENTORY_BROADCAST_MAX: usize = 35;
+
+pub struct Announcer {
+    p2p: P2PControlSender<NetworkMessage>,
+    local: SharedLocalAddress,
+    clock: SharedClock,
+    // inventory known by peer
+    known: HashMap<PeerId, LruCache<Sha256dHash, ()>>,
+    // transactions waiting for the trickle timer of a peer
+    queued: HashMap<PeerId, Vec<Inventory>>,
+    next_trickle: HashMap<PeerId, Instant>
+}
+
+impl Announcer {
+    /// Inventory sent as PeerMessage::Outgoing(NetworkMessage::Inv) to the returned sender is announced
+    /// to peers that do not know it yet
+    pub fn new(p2p: P2PControlSender<NetworkMessage>, local: SharedLocalAddress, clock: SharedClock) -> PeerMessageSender<NetworkMessage> {
+        let (sender, receiver) = mpsc::sync_channel(p2p.back_pressure);
+
+        let mut announcer = Announcer { p2p, local, clock, known: HashMap::new(), queued: HashMap::new(), next_trickle: HashMap::new() };
+
+        thread::Builder::new().name("announcer".to_string()).spawn(move || { announcer.run(receiver) }).unwrap();
+
+        PeerMessageSender::new(sender)
+    }
+
+    fn run(&mut self, receiver: PeerMessageReceiver<NetworkMessage>) {
+        let span = span!(Level::INFO, "announcer");
+        let _enter = span.enter();
+        loop {
+            while let Ok(msg) = receiver.recv_timeout(Duration::from_millis(1000)) {
+                match msg {
+                    PeerMessage::Outgoing(NetworkMessage::Inv(inv)) => self.announce(inv),
+                    PeerMessage::Connected(pid, _) => {
+                        self.known.insert(pid, LruCache::new(KNOWN_INVENTORY));
+                        self.next_trickle.insert(pid, next_trickle(self.clock.now()));
+                    },
+                    PeerMessage::Disconnected(pid, _) => {
+                        self.known.remove(&pid);
+                        self.queued.remove(&pid);
+                        self.next_trickle.remove(&pid);
+                    },
+                    PeerMessage::Incoming(pid, msg) => {
+                        let span = span!(Level::TRACE, "peer", peer = display(pid));
+                        let _enter = span.enter();
+                        match msg {
+                            NetworkMessage::Inv(ref inv) | NetworkMessage::GetData(ref inv) => {
+                                for item in inv {
+                                    self.add_known(pid, &item.hash);
+                                }
+                            },
+                            NetworkMessage::Tx(ref tx) => self.add_known(pid, &tx.txid()),
+                            NetworkMessage::Block(ref block) => self.add_known(pid, &block.bitcoin_hash()),
+                            NetworkMessage::Headers(ref headers) => {
+                                for header in headers {
+                                    self.add_known(pid, &header.bitcoin_hash());
+                                }
+                            },
+                            _ => {}
+                        }
+                    },
+                    _ => {}
+                }
+            }
+            self.trickle();
+        }
+    }
+
+    fn add_known(&mut self, peer: PeerId, hash: &Sha256dHash) {
+        if let Some(known) = self.known.get_mut(&peer) {
+            known.insert(*hash, ());
+        }
+    }
+
+    // send blocks at once, queue transactions for the trickle timer
+    fn announce(&mut self, inv: Vec<Inventory>) {
+        if !self.local.is_server() {
+            return;
+        }
+        for (peer, known) in self.known.iter_mut() {
+            let mut blocks = Vec::new();
+            for item in &inv {
+                if known.contains_key(&item.hash) {
+                    continue;
+                }
+                match item.inv_type {
+                    InvType::Block | InvType::WitnessBlock => {
+                        known.insert(item.hash, ());
+                        blocks.push(Inventory { inv_type: InvType::Block, hash: item.hash });
+                    },
+                    InvType::Transaction | InvType::WitnessTransaction => {
+                        let queue = self.queued.entry(*peer).or_insert(Vec::new());
+                        if !queue.iter().any(|q| q.hash == item.hash) {
+                            queue.push(Inventory { inv_type: InvType::Transaction, hash: item.hash });
+                        }
+                    },
+                    _ => {}
+                }
+            }
+            if !blocks.is_empty() {
+                debug!("announce {} blocks peer={}", blocks.len(), peer);
+                self.p2p.send_network(*peer, NetworkMessage::Inv(blocks));
+            }
+        }
+    }
+
+    // announce queued transactions to peers whose timer expired
+    fn trickle(&mut self) {
+        let now = self.clock.now();
+        let due = self.next_trickle.iter().filter(|(_, t)| **t <= now).map(|(p, _)| *p).collect::<Vec<_>>();
+        for peer in due {
+            self.next_trickle.insert(peer, next_trickle(now));
+            let mut batch = Vec::new();
+            if let Some(queue) = self.queued.get_mut(&peer) {
+                let n = queue.len().min(INVENTORY_BROADCAST_MAX);
+                batch = queue.drain(..n).collect::<Vec<_>>();
+            }
+            if let Some(known) = self.known.get_mut(&peer) {
+                // the peer might have learned some while waiting
+                batch.retain(|item| !known.contains_key(&item.hash));
+                for item in &batch {
+                    known.insert(item.hash, ());
+                }
+            }
+            if !batch.is_empty() {
+                trace!("announce {} transactions peer={}", batch.len(), peer);
+                self.p2p.send_network(peer, NetworkMessage::Inv(batch));
+            }
+        }
+    }
+}
+
+// random time of the next trickle, exponentially distributed
+fn next_trickle(now: Instant) -> Instant {
+    let u: f64 = thread_rng().gen_range(f64::EPSILON, 1.0);
+    now + Duration::from_millis((-u.ln() * TRICKLE_MILLIS) as u64)
+}
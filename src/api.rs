@@ -0,0 +1,210 @@
+//
+// Copyright 2019 Tamas Blummer
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+// http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+//
+//!
+//! # Client API
+//!
+//! A line based protocol on a loopback TCP port, so that a separate process, e.g. a GUI, can use
+//! the node without linking this crate. A client first authenticates with the configured token:
+//!
+//! ```text
+//! auth <token>           ok | error unauthorized, the connection is closed
+//! broadcast <hex tx>     ok <txid>
+//! watch <hex script>     ok, outputs paying to the script are added to the wallet
+//! subscribe              ok, followed by a line "event <event>" for every future event
+//! stats                  a line "stats <day> <uptime> <blocks served> <filters served> <bytes>"
+//!                        for every day of service statistics, oldest first, then ok
+//! quit                   the connection is closed
+//! ```
+//!
+//! Failed commands are answered with "error <reason>". Lines are at most a few megabytes, the
+//! line with the token at most a kilobyte.
+//!
+//! Events are written as their name followed by their fields separated by space, hashes as hex
+//! in the usual byte order, - for a missing value:
+//!
+//! ```text
+//! tip_divergence <oracle> <our height> <our hash> <their height> <their hash>
+//! filter_match <height> <block>
+//! possible_double_spend <txid>:<vout> <expected txid> <conflicting txid> <block | ->
+//! version_bits_signal <bit> <height> <block>
+//! recovered_from_corruption <height | -> <block | -> <number of problems>
+//! address_reuse <hex script> <txid>
+//! dust_received <txid>:<vout> <value>
+//! transaction_replaced <replaced txid> <replacement txid> <block>
+//! script_hash_status <script hash> <status | ->
+//! payment_received <uri> <txid> <amount> <confirmations>
+//! peer_disconnected <address> <reason>
+//! ```
+//!
+
+use bitcoin::{
+    Script, Transaction,
+    consensus::deserialize,
+    network::message::NetworkMessage
+};
+use bitcoin_hashes::hex::{FromHex, ToHex};
+use configdb::SharedConfigDB;
+use downstream::Subscribers;
+use error::Error;
+use event::Event;
+use futures::executor::block_on_stream;
+use listener::{self, read_line};
+use lock::Recover;
+use p2p::{PeerMessage, PeerMessageSender};
+use std::{
+    io::{self, BufReader, Write},
+    net::{SocketAddr, TcpStream},
+    sync::{Arc, Mutex},
+    thread
+};
+use wallet::SharedWallet;
+
+// longest line with the token
+const MAX_AUTH_LINE: u64 = 1024;
+// longest line of an authenticated client, a transaction in hex
+const MAX_LINE: u64 = 8 * 1024 * 1024 + 1024;
+
+/// Serves clients of the API
+pub struct ApiServer {
+    token: String,
+    events: Subscribers<Event>,
+    broadcaster: PeerMessageSender<NetworkMessage>,
+    wallet: SharedWallet,
+    configdb: SharedConfigDB
+}
+
+impl ApiServer {
+    pub fn new(token: &str, events: Subscribers<Event>, broadcaster: PeerMessageSender<NetworkMessage>, wallet: SharedWallet, configdb: SharedConfigDB) -> ApiServer {
+        ApiServer { token: token.to_string(), events, broadcaster, wallet, configdb }
+    }
+
+    /// Accept clients at the loopback address in a thread of its own, returns the address bound
+    pub fn listen(self, addr: SocketAddr) -> Result<SocketAddr, Error> {
+        if !addr.ip().is_loopback() {
+            return Err(Error::IO(io::Error::new(io::ErrorKind::InvalidInput, "the client API only listens on a loopback address")));
+        }
+        if self.token.is_empty() {
+            return Err(Error::IO(io::Error::new(io::ErrorKind::InvalidInput, "the client API needs a token")));
+        }
+        let server = Arc::new(self);
+        let bound = listener::listen("api", addr, move |stream| server.serve(stream))?;
+        info!("client API at {}", bound);
+        Ok(bound)
+    }
+
+    fn serve(&self, stream: TcpStream) -> Result<(), io::Error> {
+        let writer = Arc::new(Mutex::new(stream.try_clone()?));
+        let mut reader = BufReader::new(stream);
+        let authenticated = match read_line(&mut reader, MAX_AUTH_LINE)? {
+            Some(line) => self.authenticates(line.as_str()),
+            None => return Ok(())
+        };
+        if !authenticated {
+            writeln!(writer.lock().recover(), "error unauthorized")?;
+            return Ok(());
+        }
+        writeln!(writer.lock().recover(), "ok")?;
+        while let Some(line) = read_line(&mut reader, MAX_LINE)? {
+            let mut words = line.trim().splitn(2, ' ');
+            let reply = match (words.next().unwrap_or(""), words.next()) {
+                ("broadcast", Some(hex)) => self.broadcast(hex),
+                ("watch", Some(hex)) => self.watch(hex),
+                ("subscribe", None) => self.subscribe(writer.clone()).map(|_| "ok".to_string()),
+                ("stats", None) => self.stats(),
+                ("quit", None) => return Ok(()),
+                _ => Err("unknown command".to_string())
+            };
+            let mut writer = writer.lock().recover();
+            match reply {
+                Ok(reply) => writeln!(writer, "{}", reply)?,
+                Err(e) => writeln!(writer, "error {}", e)?
+            }
+        }
+        Ok(())
+    }
+
+    // compare without revealing the length of a matching prefix through timing
+    fn authenticates(&self, line: &str) -> bool {
+        let mut words = line.trim().splitn(2, ' ');
+        if words.next() != Some("auth") {
+            return false;
+        }
+        let token = words.next().unwrap_or("").as_bytes();
+        let expected = self.token.as_bytes();
+        token.len() == expected.len() && token.iter().zip(expected.iter()).fold(0u8, |acc, (a, b)| acc | (a ^ b)) == 0
+    }
+
+    fn broadcast(&self, hex: &str) -> Result<String, String> {
+        let data = Vec::<u8>::from_hex(hex).map_err(|e| e.to_string())?;
+        let tx: Transaction = deserialize(data.as_slice()).map_err(|e| e.to_string())?;
+        let txid = tx.txid();
+        self.broadcaster.send(PeerMessage::Outgoing(NetworkMessage::Tx(tx)));
+        Ok(format!("ok {}", txid))
+    }
+
+    fn watch(&self, hex: &str) -> Result<String, String> {
+        let script = Script::from(Vec::<u8>::from_hex(hex).map_err(|e| e.to_string())?);
+        self.wallet.lock().recover().add_script(script).map_err(|e| e.to_string())?;
+        Ok("ok".to_string())
+    }
+
+    fn stats(&self) -> Result<String, String> {
+        let history = self.configdb.read().recover().fetch_statistics().map_err(|e| e.to_string())?;
+        Ok(history.iter().map(|day| format!("stats {} {} {} {} {}\n", day.day, day.uptime, day.blocks_served, day.filters_served, day.bytes))
+            .collect::<String>() + "ok")
+    }
+
+    // forward events to the client until it goes away
+    fn subscribe(&self, writer: Arc<Mutex<TcpStream>>) -> Result<(), String> {
+        let events = self.events.subscribe();
+        thread::Builder::new().name("apievents".to_string()).spawn(move || {
+            for event in block_on_stream(events) {
+                if writeln!(writer.lock().recover(), "event {}", event_line(&event)).is_err() {
+                    break;
+                }
+            }
+        }).map(|_| ()).map_err(|e| e.to_string())
+    }
+}
+
+// the event in the format of the API
+fn event_line(event: &Event) -> String {
+    let or_dash = |v: Option<String>| v.unwrap_or_else(|| "-".to_string());
+    match event {
+        Event::TipDivergence { oracle, ours, theirs } =>
+            format!("tip_divergence {} {} {} {} {}", oracle, ours.0, ours.1, theirs.0, theirs.1),
+        Event::FilterMatch { block } =>
+            format!("filter_match {} {}", block.0, block.1),
+        Event::PossibleDoubleSpend { outpoint, expected, conflicting, block } =>
+            format!("possible_double_spend {}:{} {} {} {}", outpoint.txid, outpoint.vout, expected.txid(), conflicting.txid(), or_dash(block.map(|b| b.to_string()))),
+        Event::VersionBitsSignal { bit, block } =>
+            format!("version_bits_signal {} {} {}", bit, block.0, block.1),
+        Event::RecoveredFromCorruption { tip, problems } =>
+            format!("recovered_from_corruption {} {} {}", or_dash(tip.map(|t| t.0.to_string())), or_dash(tip.map(|t| t.1.to_string())), problems.len()),
+        Event::AddressReuse { script, txid } =>
+            format!("address_reuse {} {}", script.as_bytes().to_hex(), txid),
+        Event::DustReceived { outpoint, value } =>
+            format!("dust_received {}:{} {}", outpoint.txid, outpoint.vout, value),
+        Event::TransactionReplaced { replaced, replacement, block } =>
+            format!("transaction_replaced {} {} {}", replaced, replacement.txid(), block),
+        Event::ScriptHashStatus { hash, status } =>
+            format!("script_hash_status {} {}", hash.to_hex(), or_dash(status.map(|s| s.to_hex()))),
+        Event::PaymentReceived { uri, txid, amount, confirmations } =>
+            format!("payment_received {} {} {} {}", uri, txid, amount, confirmations),
+        Event::PeerDisconnected { address, reason } =>
+            format!("peer_disconnected {} {}", address, reason.to_string().replace(' ', "_"))
+    }
+}
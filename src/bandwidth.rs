@@ -0,0 +1,122 @@
+//
+// Copyright 2019 Tamas Blummer
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+// http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+//
+//!
+//! # Bandwidth budget
+//!
+//! Accounts bytes exchanged with peers and tells if the node should restrict itself
+//! to syncing headers, because the application signalled a metered connection or the
+//! bandwidth cap of the current period is exhausted.
+//!
+
+use clock::SharedClock;
+use std::sync::{Arc, atomic::{AtomicBool, AtomicU64, Ordering}};
+
+/// Shared bandwidth budget
+pub type SharedBandwidth = Arc<Bandwidth>;
+
+/// number of connections kept while restricted
+pub const RESTRICTED_CONNECTIONS: usize = 1;
+
+// length of an accounting period in seconds
+const PERIOD: u64 = 24 * 3600;
+
+/// Bandwidth budget of the node
+pub struct Bandwidth {
+    // application signalled a metered connection
+    metered: AtomicBool,
+    // bytes allowed per period, 0 is unlimited
+    cap: AtomicU64,
+    // bytes sent and received in current period
+    used: AtomicU64,
+    // bytes sent and received since start
+    total: AtomicU64,
+    // start of the current period in unix time
+    period_start: AtomicU64,
+    clock: SharedClock
+}
+
+impl Bandwidth {
+    /// create an unmetered budget without cap, periods measured by the clock
+    pub fn new(clock: SharedClock) -> Bandwidth {
+        Bandwidth { metered: AtomicBool::new(false), cap: AtomicU64::new(0), used: AtomicU64::new(0), total: AtomicU64::new(0), period_start: AtomicU64::new(clock.unix_time()), clock }
+    }
+
+    /// signal if the node is on a metered connection
+    pub fn set_metered(&self, metered: bool) {
+        if self.metered.swap(metered, Ordering::Relaxed) != metered {
+            info!("switched to {} connection", if metered { "metered" } else { "unmetered" });
+        }
+    }
+
+    /// is the node on a metered connection
+    pub fn is_metered(&self) -> bool {
+        self.metered.load(Ordering::Relaxed)
+    }
+
+    /// set the number of bytes that might be sent and received within 24 hours, None is unlimited
+    pub fn set_cap(&self, cap: Option<u64>) {
+        self.cap.store(cap.unwrap_or(0), Ordering::Relaxed);
+    }
+
+    /// bytes allowed within 24 hours if capped
+    pub fn cap(&self) -> Option<u64> {
+        match self.cap.load(Ordering::Relaxed) {
+            0 => None,
+            n => Some(n)
+        }
+    }
+
+    /// account bytes sent to or received from a peer
+    pub fn account(&self, bytes: usize) {
+        self.roll_period();
+        self.used.fetch_add(bytes as u64, Ordering::Relaxed);
+        self.total.fetch_add(bytes as u64, Ordering::Relaxed);
+    }
+
+    /// bytes sent and received in the current period
+    pub fn used(&self) -> u64 {
+        self.roll_period();
+        self.used.load(Ordering::Relaxed)
+    }
+
+    /// bytes sent and received since start
+    pub fn total(&self) -> u64 {
+        self.total.load(Ordering::Relaxed)
+    }
+
+    /// true if the node should not download blocks, should reduce its connections
+    /// and should not serve other peers
+    pub fn is_restricted(&self) -> bool {
+        if self.is_metered() {
+            return true;
+        }
+        if let Some(cap) = self.cap() {
+            return self.used() >= cap;
+        }
+        false
+    }
+
+    // start a new accounting period if the current one is over
+    fn roll_period(&self) {
+        let now = self.clock.unix_time();
+        let start = self.period_start.load(Ordering::Relaxed);
+        if now >= start + PERIOD {
+            if self.period_start.compare_exchange(start, now, Ordering::Relaxed, Ordering::Relaxed).is_ok() {
+                self.used.store(0, Ordering::Relaxed);
+            }
+        }
+    }
+}
@@ -22,7 +22,9 @@ extern crate simple_logger;
 use bitcoin::network::constants::Network;
 use log::Level;
 use murmel::{
-    constructor::Constructor
+    constructor::Constructor,
+    datadir::DataDir,
+    syncconfig::SyncConfig
 };
 
 use std::{
@@ -36,11 +38,12 @@ use std::{
 pub fn main() {
     if find_opt("help") {
         println!("Murmel Client");
-        println!("{} [--help] [--log trace|debug|info|warn|error] [--connections n] [--peer ip_address:port] [--db database_file] [--network main|test]", args().next().unwrap());
+        println!("{} [--help] [--log trace|debug|info|warn|error] [--connections n] [--peer ip_address:port] [--db database_file] [--datadir directory] [--network main|test]", args().next().unwrap());
         println!("--log level: level is one of trace|debug|info|warn|error");
         println!("--connections n: maintain at least n connections");
         println!("--peer ip_address: connect to the given peer at start. You may use more than one --peer option.");
         println!("--db file: store data in the given sqlite database file. Created if does not exist.");
+        println!("--datadir dir: keep data of the network in a locked subdirectory of dir, instead of --db");
         println!("--network net: net is one of main|test for corresponding Bitcoin networks");
         println!("--nodns : do not use dns seed");
         println!("--birth unixtime : blocks will be downloaded if matching filters after this time stamp");
@@ -91,13 +94,17 @@ pub fn main() {
         SystemTime::now().duration_since(SystemTime::UNIX_EPOCH).unwrap().as_secs()
     };
 
-    let chaindb =
-        if let Some(path) = find_arg("db") {
-            Constructor::open_db(Some(&Path::new(path.as_str())), network,  birth).unwrap()
-        } else {
-            Constructor::open_db(Some(&Path::new("client.db")), network, birth).unwrap()
-        };
-    let mut spv = Constructor::new(network, listen, chaindb).unwrap();
+    // keeps the data directory locked while running
+    let datadir = find_arg("datadir").map(|dir| DataDir::open(Path::new(dir.as_str()), network).expect("can not open data directory"));
+    let (chaindb, configdb) = if let Some(ref datadir) = datadir {
+        (Constructor::open_db(Some(datadir.chain_db().as_path()), network, birth).unwrap(),
+         Constructor::open_config_db(Some(datadir.config_db().as_path())).unwrap())
+    } else {
+        let path = find_arg("db").unwrap_or("client.db".to_string());
+        (Constructor::open_db(Some(&Path::new(path.as_str())), network, birth).unwrap(),
+         Constructor::open_config_db(Some(&Path::new(format!("{}.cfg", path).as_str()))).unwrap())
+    };
+    let spv = Constructor::new(network, listen, chaindb, configdb, SyncConfig::default()).unwrap();
     spv.run(network, peers, connections).expect("can not start node");
 }
 
@@ -0,0 +1,267 @@
+//
+// Copyright 2019 Tamas Blummer
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+// http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+//
+//!
+//! # Murmel node
+//!
+//! Runs a node configured by a TOML file and command line options, options override the file.
+//!
+
+extern crate bitcoin;
+extern crate log;
+extern crate murmel;
+#[macro_use] extern crate serde_derive;
+extern crate simple_logger;
+extern crate toml;
+
+use bitcoin::network::constants::Network;
+use log::Level;
+use murmel::{
+    chaindb::{FilterRetention, VerifyLevel},
+    constructor::Constructor,
+    datadir::DataDir,
+    localnode::LocalDiscovery,
+    syncconfig::SyncConfig
+};
+use std::{
+    env::{self, args},
+    fs,
+    net::SocketAddr,
+    path::Path,
+    process,
+    str::FromStr,
+    time::SystemTime
+};
+
+/// Content of the config file, all optional
+#[derive(Deserialize, Default)]
+struct Config {
+    /// main, test or regtest
+    network: Option<String>,
+    /// root of the data directory
+    datadir: Option<String>,
+    /// peers to connect at start
+    #[serde(default)]
+    connect: Vec<String>,
+    /// addresses to listen on
+    #[serde(default)]
+    listen: Vec<String>,
+    /// keep filters of this many recent blocks only
+    prune: Option<u32>,
+    /// serve other peers
+    server: Option<bool>,
+    /// address other peers reach this node at, if behind NAT
+    external: Option<String>,
+    /// connect through a SOCKS5 proxy
+    proxy: Option<String>,
+    /// keep at least this many connections
+    connections: Option<usize>,
+    /// log level
+    log: Option<String>,
+    /// look for a full node on localhost or the local subnet
+    discover: Option<String>,
+    /// loopback address of the client API
+    api: Option<String>,
+    /// token clients of the API authenticate with
+    api_token: Option<String>
+}
+
+pub fn main() {
+    if find_opt("help") {
+        println!("Murmel Node");
+        println!("{} [--help] [--config file] [--network main|test|regtest] [--datadir directory] [--connect ip_address:port] [--listen ip_address:port] [--prune n] [--server] [--external ip_address:port] [--proxy ip_address:port] [--connections n] [--log trace|debug|info|warn|error] [--verify links|pow|blocks] [--record file] [--discover localhost|subnet] [--api ip_address:port]", args().next().unwrap());
+        println!("--config file: read options from the TOML file, command line options take precedence");
+        println!("--network net: net is one of main|test|regtest");
+        println!("--datadir dir: store data in a subdirectory of dir for the network");
+        println!("--connect address: connect to the given peer at start. You may use more than one --connect option.");
+        println!("--listen address: accept connections at the address. You may use more than one --listen option.");
+        println!("--prune n: keep filters of the last n blocks only");
+        println!("--server: serve other peers, listens on the default port of the network unless --listen is given");
+        println!("--external address: advertise this address to other peers, e.g. of a router forwarding the listen port");
+        println!("--proxy address: connect through a SOCKS5 proxy");
+        println!("--connections n: maintain at least n connections");
+        println!("--log level: level is one of trace|debug|info|warn|error");
+        println!("--verify level: check the stored chain, truncate it at the first corruption and exit. level is one of links|pow|blocks");
+        println!("--record file: append traffic with peers to the file, to be replayed with Constructor::replay");
+        println!("--discover scope: connect a full node found on localhost or the local subnet first. scope is one of localhost|subnet");
+        println!("--api address: serve the client API at the loopback address, clients authenticate with the token in MURMEL_API_TOKEN or api_token of the config file");
+        println!("defaults:");
+        println!("--network main");
+        println!("--datadir .murmel");
+        println!("--connections 3");
+        println!("--log info");
+        return;
+    }
+
+    let mut config = if let Some(file) = find_arg("config") {
+        let content = fs::read_to_string(file.as_str()).unwrap_or_else(|e| exit(format!("can not read config file {}: {}", file, e)));
+        toml::from_str::<Config>(content.as_str()).unwrap_or_else(|e| exit(format!("invalid config file {}: {}", file, e)))
+    } else {
+        Config::default()
+    };
+    override_config(&mut config);
+
+    let level = match config.log.as_ref().map(|s| s.as_str()).unwrap_or("info") {
+        "error" => Level::Error,
+        "warn" => Level::Warn,
+        "debug" => Level::Debug,
+        "trace" => Level::Trace,
+        _ => Level::Info
+    };
+    simple_logger::init_with_level(level).unwrap();
+
+    let network = match config.network.as_ref().map(|s| s.as_str()).unwrap_or("main") {
+        "main" => Network::Bitcoin,
+        "test" => Network::Testnet,
+        "regtest" => Network::Regtest,
+        other => exit(format!("unknown network {}", other))
+    };
+
+    let peers = config.connect.iter().map(|s| parse_address(s)).collect::<Vec<_>>();
+    let mut listen = config.listen.iter().map(|s| parse_address(s)).collect::<Vec<_>>();
+    if config.server.unwrap_or(false) && listen.is_empty() {
+        let port = match network {
+            Network::Bitcoin => 8333,
+            Network::Testnet => 18333,
+            Network::Regtest => 18444
+        };
+        listen.push(SocketAddr::from(([0, 0, 0, 0], port)));
+    }
+
+    let root = config.datadir.unwrap_or(".murmel".to_string());
+    let datadir = DataDir::open(Path::new(root.as_str()), network).unwrap_or_else(|e| exit(format!("{}", e)));
+    let birth = SystemTime::now().duration_since(SystemTime::UNIX_EPOCH).unwrap().as_secs();
+    let chaindb = Constructor::open_db(Some(datadir.chain_db().as_path()), network, birth).unwrap_or_else(|e| exit(format!("{}", e)));
+    if let Some(level) = find_arg("verify") {
+        let level = match level.as_str() {
+            "links" => VerifyLevel::Links,
+            "pow" => VerifyLevel::ProofOfWork,
+            "blocks" => VerifyLevel::Blocks,
+            other => exit(format!("unknown verify level {}", other))
+        };
+        let report = chaindb.write().unwrap().verify(level, &mut |checked, total| println!("verified {} of {} headers", checked, total))
+            .unwrap_or_else(|e| exit(format!("{}", e)));
+        for problem in &report.problems {
+            println!("corrupted at {}", problem);
+        }
+        if let Some(height) = report.truncated_at {
+            println!("truncated the chain to {} headers, they will be synced again", height);
+        }
+        if let Some(height) = report.blocks_from {
+            println!("blocks will be downloaded again from height {}", height);
+        }
+        return;
+    }
+    let discovery = match config.discover.as_ref().map(|s| s.as_str()) {
+        None => LocalDiscovery::Off,
+        Some("localhost") => LocalDiscovery::Localhost,
+        Some("subnet") => LocalDiscovery::Subnet,
+        Some(other) => exit(format!("unknown discovery scope {}", other))
+    };
+    let configdb = Constructor::open_config_db(Some(datadir.config_db().as_path())).unwrap_or_else(|e| exit(format!("{}", e)));
+    let node = Constructor::new(network, listen, chaindb, configdb, SyncConfig::default()).unwrap_or_else(|e| exit(format!("{}", e)))
+        .with_local_discovery(discovery);
+    if let Some(ref proxy) = config.proxy {
+        node.set_proxy(parse_address(proxy));
+    }
+    if let Some(ref external) = config.external {
+        node.set_external_address(Some(parse_address(external)));
+    }
+    if let Some(keep) = config.prune {
+        node.set_filter_retention(FilterRetention::Recent(keep));
+    }
+    if let Some(ref api) = config.api {
+        let token = config.api_token.as_ref().unwrap_or_else(|| exit("the client API needs a token".to_string()));
+        node.serve_api(parse_address(api), token.as_str()).unwrap_or_else(|e| exit(format!("{}", e)));
+    }
+    if let Some(file) = find_arg("record") {
+        node.record_traffic(Some(Path::new(file.as_str()))).unwrap_or_else(|e| exit(format!("{}", e)));
+    }
+    node.run(network, peers, config.connections.unwrap_or(3)).unwrap_or_else(|e| exit(format!("{}", e)));
+}
+
+// command line options take precedence over the config file
+fn override_config(config: &mut Config) {
+    if let Some(network) = find_arg("network") {
+        config.network = Some(network);
+    }
+    if let Some(datadir) = find_arg("datadir") {
+        config.datadir = Some(datadir);
+    }
+    let connect = find_args("connect");
+    if !connect.is_empty() {
+        config.connect = connect;
+    }
+    let listen = find_args("listen");
+    if !listen.is_empty() {
+        config.listen = listen;
+    }
+    if let Some(prune) = find_arg("prune") {
+        config.prune = Some(prune.parse().unwrap_or_else(|_| exit(format!("invalid --prune {}", prune))));
+    }
+    if find_opt("server") {
+        config.server = Some(true);
+    }
+    if let Some(external) = find_arg("external") {
+        config.external = Some(external);
+    }
+    if let Some(proxy) = find_arg("proxy") {
+        config.proxy = Some(proxy);
+    }
+    if let Some(connections) = find_arg("connections") {
+        config.connections = Some(connections.parse().unwrap_or_else(|_| exit(format!("invalid --connections {}", connections))));
+    }
+    if let Some(log) = find_arg("log") {
+        config.log = Some(log);
+    }
+    if let Some(discover) = find_arg("discover") {
+        config.discover = Some(discover);
+    }
+    if let Some(api) = find_arg("api") {
+        config.api = Some(api);
+    }
+    if let Ok(token) = env::var("MURMEL_API_TOKEN") {
+        config.api_token = Some(token);
+    }
+}
+
+fn parse_address(s: &str) -> SocketAddr {
+    SocketAddr::from_str(s).unwrap_or_else(|_| exit(format!("invalid address {}", s)))
+}
+
+fn exit(message: String) -> ! {
+    eprintln!("{}", message);
+    process::exit(1)
+}
+
+// Returns key-value pairs of options followed by a value.
+fn zipped_args() -> Vec<(String, String)> {
+    let args = args().skip(1).collect::<Vec<_>>();
+    args.iter().zip(args.iter().skip(1))
+        .filter(|(k, v)| k.starts_with("--") && !v.starts_with("--"))
+        .map(|(k, v)| (k[2..].to_string(), v.clone())).collect()
+}
+
+fn find_opt(key: &str) -> bool {
+    args().any(|arg| arg.starts_with("--") && &arg[2..] == key)
+}
+
+fn find_arg(key: &str) -> Option<String> {
+    zipped_args().into_iter().find(|(k, _)| k.as_str() == key).map(|(_, v)| v)
+}
+
+fn find_args(key: &str) -> Vec<String> {
+    zipped_args().into_iter().filter(|(k, _)| k.as_str() == key).map(|(_, v)| v).collect()
+}
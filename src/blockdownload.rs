@@ -0,0 +1,622 @@
+//
+// Copyright 2019 Tamas Blummer
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+// http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+//
+//!
+//! # Download blocks
+//!
+//! Blocks whose filter matched watched scripts are downloaded from peers serving blocks
+//! and passed downstream in the order of height.
+//!
+//! A block is preferably asked from a peer that announced it. A block a peer answered with notfound
+//! is asked from an other peer at once, instead of waiting for the timeout.
+//!
+//! Peers get requests in proportion to their measured throughput, the fastest up to
+//! blocks_per_peer at once. A peer not yet measured is assumed fast, so a new fast peer takes over
+//! requests from slower ones.
+//!
+//! Blocks close to the header tip are asked first, then other blocks with matching filters, last
+//! those requested in bulk e.g. for a wallet rescan. Blocks requested in bulk are passed downstream
+//! as they arrive and do not move the block tip. A block is only queued once, a block requested
+//! again with a higher priority moves up.
+//!
+//! If configured, random decoy blocks are mixed into the requests for matching blocks and
+//! discarded when they arrive, so the blocks asked do not point a peer at the wallet's scripts.
+//!
+//! Blocks are downloaded whole. Compact blocks (BIP152) are not used, as murmel keeps no memory
+//! pool to reconstruct them from, so there are no reconstruction hit rates or getblocktxn round
+//! trips to measure.
+//!
+//! A block is checked against its header by the merkle root of its transactions. Murmel follows
+//! bitcoin, testnet and regtest only: the networks known to rust-bitcoin here have no signet, so
+//! there is no block signature challenge (BIP325) to validate.
+//!
+
+use bandwidth::SharedBandwidth;
+use bitcoin::{
+    BitcoinHash,
+    blockdata::block::Block,
+    consensus::serialize,
+    network::{
+        message::NetworkMessage,
+        message_blockdata::{Inventory, InvType}
+    }
+};
+use bitcoin_hashes::sha256d::Hash as Sha256dHash;
+use chaindb::{ChainDB, SharedChainDB};
+use clock::{SharedClock, SharedRandom};
+use downstream::SharedDownstream;
+use error::Error;
+use lock::Recover;
+use lru_cache::LruCache;
+use p2p::{P2PControlSender, PeerId, PeerMessage, PeerMessageReceiver, PeerMessageSender, SERVICE_BLOCKS};
+use rand::Rng;
+use std::{
+    collections::{BTreeMap, HashMap, HashSet, VecDeque},
+    mem,
+    sync::mpsc,
+    thread,
+    time::{Duration, Instant}
+};
+use syncconfig::SyncConfig;
+use timeout::{ExpectedReply, SharedTimeout};
+use tracing::{Level, field::display};
+
+// number of announced blocks remembered with the peers announcing them
+const ANNOUNCED_BLOCKS: usize = 1000;
+
+// weight of a new throughput sample in the moving average
+const THROUGHPUT_WEIGHT: f64 = 0.3;
+
+// matching blocks this close to the header tip are asked first
+const TIP_DISTANCE: u32 = 6;
+
+// decoys are drawn within this many blocks of the matched block they hide
+const DECOY_DISTANCE: u32 = 144;
+
+// seconds to wait before asking again for a block out of retries, doubled with each round
+const RETRY_BACKOFF: u64 = 30;
+// most doublings of the backoff
+const MAX_BACKOFF_ROUNDS: usize = 5;
+
+// download order of blocks, lower is asked first
+#[derive(Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Debug)]
+enum Priority {
+    // matching block close to the header tip
+    Tip,
+    // matching block of the history
+    Matched,
+    // block requested in bulk
+    Bulk
+}
+
+// download speed of a peer
+struct Throughput {
+    // moving average of bytes per second, None until measured
+    bytes_per_sec: Option<f64>,
+    // start of the current measurement
+    since: Instant
+}
+
+// a block to download
+#[derive(Clone)]
+struct Wanted {
+    height: u32,
+    id: Sha256dHash,
+    // number of times the block was asked before
+    attempts: usize,
+    // peers not to ask, those that answered notfound or were too slow
+    avoid: HashSet<PeerId>,
+    priority: Priority,
+    // asked only to hide the wanted ones, discarded once received
+    decoy: bool
+}
+
+pub struct BlockDownload {
+    p2p: P2PControlSender<NetworkMessage>,
+    chaindb: SharedChainDB,
+    timeout: SharedTimeout<NetworkMessage, ExpectedReply>,
+    downstream: SharedDownstream,
+    bandwidth: SharedBandwidth,
+    config: SyncConfig,
+    // blocks not yet asked for by priority
+    download_queue: BTreeMap<Priority, VecDeque<Wanted>>,
+    // ids of blocks queued or asked
+    wanted: HashSet<Sha256dHash>,
+    // blocks out of retries, asked again after the time
+    backoff: Vec<(Instant, Wanted)>,
+    // blocks asked by peer
+    in_flight: HashMap<PeerId, Vec<Wanted>>,
+    // blocks queued or asked by height, with the block once received
+    pending: BTreeMap<u32, Option<Block>>,
+    // last height checked for matching filters
+    scanned: Option<u32>,
+    // peers that announced a block
+    announced: LruCache<Sha256dHash, HashSet<PeerId>>,
+    // download speed by peer
+    throughput: HashMap<PeerId, Throughput>,
+    // measures throughput
+    clock: SharedClock,
+    // chooses decoys
+    random: SharedRandom
+}
+
+impl BlockDownload {
+    /// Block inventory sent as PeerMessage::Outgoing(NetworkMessage::GetData) to the returned sender
+    /// is downloaded in bulk
+    pub fn new(chaindb: SharedChainDB, p2p: P2PControlSender<NetworkMessage>, timeout: SharedTimeout<NetworkMessage, ExpectedReply>,
+               downstream: SharedDownstream, bandwidth: SharedBandwidth, config: SyncConfig, clock: SharedClock, random: SharedRandom) -> PeerMessageSender<NetworkMessage> {
+        let (sender, receiver) = mpsc::sync_channel(p2p.back_pressure);
+
+        let mut blockdownload = BlockDownload { chaindb, p2p, timeout, downstream, bandwidth, config,
+            download_queue: BTreeMap::new(), wanted: HashSet::new(), backoff: Vec::new(), in_flight: HashMap::new(), pending: BTreeMap::new(), scanned: None,
+            announced: LruCache::new(ANNOUNCED_BLOCKS), throughput: HashMap::new(), clock, random };
+
+        thread::Builder::new().name("block download".to_string()).spawn(move || { blockdownload.run(receiver) }).unwrap();
+
+        PeerMessageSender::new(sender)
+    }
+
+    fn run(&mut self, receiver: PeerMessageReceiver<NetworkMessage>) {
+        let span = span!(Level::INFO, "block download");
+        let _enter = span.enter();
+        loop {
+            while let Ok(msg) = receiver.recv_timeout(Duration::from_millis(1000)) {
+                if let Err(e) = match msg {
+                    PeerMessage::Disconnected(pid, _) => {
+                        self.disconnected(pid);
+                        Ok(())
+                    }
+                    PeerMessage::Outgoing(NetworkMessage::GetData(ref inv)) => self.request(inv),
+                    PeerMessage::Incoming(pid, msg) => {
+                        let span = span!(Level::DEBUG, "peer", peer = display(pid));
+                        let _enter = span.enter();
+                        match msg {
+                            NetworkMessage::Block(ref block) => self.block(block, pid),
+                            NetworkMessage::NotFound(ref inv) => { self.not_found(inv, pid); Ok(()) },
+                            NetworkMessage::Inv(ref inv) => { self.inv(inv, pid); Ok(()) },
+                            _ => { Ok(()) }
+                        }
+                    },
+                    _ => { Ok(()) }
+                } {
+                    error!("Error processing blocks: {}", e);
+                }
+            }
+            self.timeout.lock().recover().check(vec!(ExpectedReply::Block));
+            if let Err(e) = self.scan() {
+                error!("Error finding blocks to download: {}", e);
+            }
+            self.resume();
+            self.ask();
+        }
+    }
+
+    fn is_serving_blocks(&self, peer: PeerId) -> bool {
+        if let Some(peer_version) = self.p2p.peer_version(peer) {
+            return peer_version.services & SERVICE_BLOCKS != 0;
+        }
+        false
+    }
+
+    fn disconnected(&mut self, peer: PeerId) {
+        self.throughput.remove(&peer);
+        if let Some(asked) = self.in_flight.remove(&peer) {
+            for wanted in asked {
+                self.ask_again(wanted);
+            }
+        }
+    }
+
+    // queue a block to be asked from an other peer. A block requested in bulk is given up once out
+    // of retries, a matching one keeps its place before the block tip and is asked again after a
+    // backoff, as the wallet would miss its outputs otherwise.
+    fn ask_again(&mut self, mut wanted: Wanted) {
+        if wanted.decoy {
+            // any other decoy serves as well
+            self.wanted.remove(&wanted.id);
+            return;
+        }
+        if wanted.attempts >= self.config.retries {
+            if wanted.priority == Priority::Bulk {
+                warn!("giving up on block {} after {} attempts", wanted.id, wanted.attempts + 1);
+                self.wanted.remove(&wanted.id);
+                return;
+            }
+            let rounds = (wanted.attempts - self.config.retries).min(MAX_BACKOFF_ROUNDS);
+            let delay = Duration::from_secs(RETRY_BACKOFF << rounds);
+            warn!("block {} not received after {} attempts, asking again in {} seconds", wanted.id, wanted.attempts + 1, delay.as_secs());
+            wanted.attempts += 1;
+            // peers might have it meanwhile
+            wanted.avoid.clear();
+            self.backoff.push((self.clock.now() + delay, wanted));
+            return;
+        }
+        wanted.attempts += 1;
+        self.download_queue.entry(wanted.priority).or_insert(VecDeque::new()).push_front(wanted);
+    }
+
+    // queue blocks again whose backoff expired
+    fn resume(&mut self) {
+        let now = self.clock.now();
+        let (due, waiting) = mem::replace(&mut self.backoff, Vec::new()).into_iter().partition::<Vec<_>, _>(|(at, _)| *at <= now);
+        self.backoff = waiting;
+        for (_, wanted) in due {
+            self.download_queue.entry(wanted.priority).or_insert(VecDeque::new()).push_back(wanted);
+        }
+    }
+
+    // queue a block unless queued or asked already, then raise its priority if lower
+    fn want(&mut self, height: u32, id: Sha256dHash, priority: Priority) {
+        if priority != Priority::Bulk {
+            self.pending.entry(height).or_insert(None);
+        }
+        if self.wanted.insert(id) {
+            self.download_queue.entry(priority).or_insert(VecDeque::new())
+                .push_back(Wanted { height, id, attempts: 0, avoid: HashSet::new(), priority, decoy: false });
+            return;
+        }
+        // a decoy might turn out to be wanted
+        for wanted in self.download_queue.values_mut().flat_map(|q| q.iter_mut()).chain(self.in_flight.values_mut().flat_map(|a| a.iter_mut())) {
+            if wanted.id == id {
+                wanted.decoy = false;
+            }
+        }
+        let mut moved = None;
+        for queue in self.download_queue.values_mut() {
+            if let Some(pos) = queue.iter().position(|w| w.id == id && w.priority > priority) {
+                moved = queue.remove(pos);
+                break;
+            }
+        }
+        if let Some(mut wanted) = moved {
+            wanted.priority = priority;
+            self.download_queue.entry(priority).or_insert(VecDeque::new()).push_back(wanted);
+        }
+        for asked in self.in_flight.values_mut() {
+            for wanted in asked.iter_mut().filter(|w| w.id == id) {
+                wanted.priority = wanted.priority.min(priority);
+            }
+        }
+    }
+
+    // queue blocks requested in bulk, those already stored are passed downstream at once
+    fn request(&mut self, inv: &Vec<Inventory>) -> Result<(), Error> {
+        let mut stored = Vec::new();
+        let mut missing = Vec::new();
+        {
+            let chaindb = self.chaindb.read().recover();
+            for item in inv {
+                match chaindb.pos_on_trunk(&item.hash) {
+                    Some(height) => match chaindb.fetch_block(&item.hash)? {
+                        Some(block) => stored.push((block, height)),
+                        None => missing.push((height, item.hash))
+                    },
+                    None => debug!("requested block {} is not on trunk", item.hash)
+                }
+            }
+        }
+        debug!("{} blocks requested, {} to download", inv.len(), missing.len());
+        for (height, id) in missing {
+            self.want(height, id, Priority::Bulk);
+        }
+        let mut downstream = self.downstream.lock().recover();
+        for (block, height) in &stored {
+            downstream.block_connected(block, *height);
+        }
+        Ok(())
+    }
+
+    // queue blocks with matching filter
+    fn scan(&mut self) -> Result<(), Error> {
+        let chaindb = self.chaindb.read().recover();
+        let filter_tip = match chaindb.fetch_filter_tip()?.and_then(|tip| chaindb.trunk_height(&tip)) {
+            Some(height) => height,
+            None => return Ok(())
+        };
+        let header_tip = chaindb.header_tip().map(|tip| tip.stored.height).unwrap_or(0);
+        let from = match self.scanned {
+            Some(scanned) if scanned <= filter_tip => scanned + 1,
+            _ => first_missing(&chaindb)?
+        };
+        let mut matched = Vec::new();
+        for header in chaindb.iter_trunk(from).take_while(|h| h.stored.height <= filter_tip) {
+            let id = header.bitcoin_hash();
+            if let Some(filter) = chaindb.fetch_filter(&id)? {
+                if filter.matched && !self.pending.contains_key(&header.stored.height) {
+                    match chaindb.fetch_block(&id)? {
+                        // pushed by a peer before the filter matched, only pass downstream
+                        Some(block) => { self.pending.insert(header.stored.height, Some(block)); },
+                        None => matched.push((header.stored.height, id))
+                    }
+                }
+            }
+        }
+        drop(chaindb);
+        let decoys = self.choose_decoys(&matched, filter_tip)?;
+        for (height, id) in matched {
+            let priority = if height + TIP_DISTANCE > header_tip { Priority::Tip } else { Priority::Matched };
+            self.want(height, id, priority);
+        }
+        for (height, id) in decoys {
+            let priority = if height + TIP_DISTANCE > header_tip { Priority::Tip } else { Priority::Matched };
+            self.want_decoy(height, id, priority);
+        }
+        self.scanned = Some(filter_tip);
+        self.deliver()
+    }
+
+    // random blocks of the trunk not stored, queued or asked, near each matched block and not
+    // beyond the filters scanned, so that they look alike to the peer
+    fn choose_decoys(&self, matched: &[(u32, Sha256dHash)], filter_tip: u32) -> Result<Vec<(u32, Sha256dHash)>, Error> {
+        let mut decoys = Vec::new();
+        let n = self.config.decoy_blocks;
+        if n == 0 {
+            return Ok(decoys);
+        }
+        let chaindb = self.chaindb.read().recover();
+        let mut rng = self.random.rng();
+        for (matched_height, _) in matched {
+            let low = matched_height.saturating_sub(DECOY_DISTANCE).max(1);
+            let high = filter_tip.min(matched_height.saturating_add(DECOY_DISTANCE));
+            if low > high {
+                continue;
+            }
+            let mut chosen = 0;
+            // give up on a crowded neighbourhood rather than looping
+            for _ in 0..n * 4 {
+                if chosen == n {
+                    break;
+                }
+                let height = rng.gen_range(low, high + 1);
+                if let Some(header) = chaindb.get_header_for_height(height) {
+                    let id = header.bitcoin_hash();
+                    if !self.wanted.contains(&id) && !matched.iter().any(|(_, m)| *m == id) && !decoys.iter().any(|(_, d)| *d == id)
+                        && chaindb.fetch_block(&id)?.is_none() {
+                        decoys.push((height, id));
+                        chosen += 1;
+                    }
+                }
+            }
+        }
+        Ok(decoys)
+    }
+
+    // queue a decoy at a random position among the blocks of its priority
+    fn want_decoy(&mut self, height: u32, id: Sha256dHash, priority: Priority) {
+        if self.wanted.insert(id) {
+            let queue = self.download_queue.entry(priority).or_insert(VecDeque::new());
+            let pos = self.random.rng().gen_range(0, queue.len() + 1);
+            queue.insert(pos, Wanted { height, id, attempts: 0, avoid: HashSet::new(), priority, decoy: true });
+        }
+    }
+
+    // number of blocks a peer should have in flight, in proportion to its speed relative to the fastest
+    fn capacity(&self, peer: &PeerId, fastest: Option<f64>) -> usize {
+        match (self.throughput.get(peer).and_then(|t| t.bytes_per_sec), fastest) {
+            (Some(speed), Some(fastest)) if fastest > 0.0 =>
+                ((self.config.blocks_per_peer as f64 * speed / fastest).round() as usize).max(1).min(self.config.blocks_per_peer),
+            _ => self.config.blocks_per_peer
+        }
+    }
+
+    // ask peers serving blocks for queued blocks in order of priority, in proportion of their throughput.
+    // A block goes to a peer that announced it if possible, never to one that did not find it.
+    fn ask(&mut self) {
+        if self.bandwidth.is_restricted() {
+            return;
+        }
+        let peers = self.p2p.peers().into_iter().filter(|p| self.is_serving_blocks(*p)).collect::<Vec<_>>();
+        let fastest = self.throughput.values().filter_map(|t| t.bytes_per_sec).fold(None, |m: Option<f64>, s| Some(m.map_or(s, |m| m.max(s))));
+        let limit = peers.iter().map(|p| (*p, self.capacity(p, fastest))).collect::<HashMap<_, _>>();
+        let mut capacity = peers.iter()
+            .map(|p| (*p, limit[p].saturating_sub(self.in_flight.get(p).map(|v| v.len()).unwrap_or(0))))
+            .collect::<HashMap<_, _>>();
+        if self.download_queue.values().all(|q| q.is_empty()) {
+            // rebalance: take back requests a peer has beyond its share if an other could serve them
+            if capacity.values().all(|c| *c == 0) {
+                return;
+            }
+            for peer in &peers {
+                if let Some(asked) = self.in_flight.get_mut(peer) {
+                    if asked.len() > limit[peer] {
+                        let surplus = asked.split_off(limit[peer]);
+                        debug!("take back {} blocks from slow peer={}", surplus.len(), peer);
+                        self.timeout.lock().recover().received(*peer, surplus.len(), ExpectedReply::Block);
+                        for mut wanted in surplus {
+                            wanted.avoid.insert(*peer);
+                            self.download_queue.entry(wanted.priority).or_insert(VecDeque::new()).push_back(wanted);
+                        }
+                    }
+                }
+            }
+            if self.download_queue.values().all(|q| q.is_empty()) {
+                return;
+            }
+        }
+        let mut asked: HashMap<PeerId, Vec<Wanted>> = HashMap::new();
+        let mut waiting = BTreeMap::new();
+        for (priority, queue) in mem::replace(&mut self.download_queue, BTreeMap::new()) {
+            for wanted in queue {
+                let announcing = self.announced.get_mut(&wanted.id).cloned().unwrap_or_default();
+                let available = peers.iter().filter(|p| !wanted.avoid.contains(*p) && capacity[*p] > 0).cloned().collect::<Vec<_>>();
+                let choice = available.iter().find(|p| announcing.contains(*p))
+                    .or_else(|| available.iter().max_by_key(|p| capacity[*p])).cloned();
+                match choice {
+                    Some(peer) => {
+                        *capacity.get_mut(&peer).unwrap() -= 1;
+                        asked.entry(peer).or_insert(Vec::new()).push(wanted);
+                    },
+                    None => waiting.entry(priority).or_insert(VecDeque::new()).push_back(wanted)
+                }
+            }
+        }
+        self.download_queue = waiting;
+        for (peer, asked) in asked {
+            debug!("ask for {} blocks from height {} peer={}", asked.len(), asked[0].height, peer);
+            self.timeout.lock().recover().expect(peer, asked.len(), ExpectedReply::Block);
+            self.p2p.send_network(peer, NetworkMessage::GetData(
+                asked.iter().map(|w| Inventory { inv_type: InvType::WitnessBlock, hash: w.id }).collect()));
+            let in_flight = self.in_flight.entry(peer).or_insert(Vec::new());
+            if in_flight.is_empty() {
+                // measure from now as the peer was idle
+                let throughput = self.throughput.entry(peer).or_insert(Throughput { bytes_per_sec: None, since: self.clock.now() });
+                throughput.since = self.clock.now();
+            }
+            in_flight.extend(asked);
+        }
+    }
+
+    // update the moving average of the peer's speed
+    fn measure(&mut self, peer: PeerId, bytes: usize) {
+        let now = self.clock.now();
+        let throughput = self.throughput.entry(peer).or_insert(Throughput { bytes_per_sec: None, since: now });
+        let elapsed = now.duration_since(throughput.since).as_millis().max(1) as f64 / 1000.0;
+        let sample = bytes as f64 / elapsed;
+        throughput.bytes_per_sec = Some(match throughput.bytes_per_sec {
+            Some(average) => average * (1.0 - THROUGHPUT_WEIGHT) + sample * THROUGHPUT_WEIGHT,
+            None => sample
+        });
+        throughput.since = now;
+    }
+
+    // remember which peer has which block
+    fn inv(&mut self, inv: &Vec<Inventory>, peer: PeerId) {
+        for item in inv {
+            if item.inv_type == InvType::Block || item.inv_type == InvType::WitnessBlock {
+                if let Some(peers) = self.announced.get_mut(&item.hash) {
+                    peers.insert(peer);
+                    continue;
+                }
+                let mut peers = HashSet::new();
+                peers.insert(peer);
+                self.announced.insert(item.hash, peers);
+            }
+        }
+    }
+
+    // ask an other peer for blocks the peer did not find
+    fn not_found(&mut self, inv: &Vec<Inventory>, peer: PeerId) {
+        let mut refused = Vec::new();
+        if let Some(asked) = self.in_flight.get_mut(&peer) {
+            for item in inv {
+                if let Some(pos) = asked.iter().position(|w| w.id == item.hash) {
+                    refused.push(asked.remove(pos));
+                }
+            }
+        }
+        if refused.is_empty() {
+            return;
+        }
+        debug!("{} blocks not found peer={}", refused.len(), peer);
+        self.timeout.lock().recover().received(peer, refused.len(), ExpectedReply::Block);
+        for mut wanted in refused {
+            if let Some(peers) = self.announced.get_mut(&wanted.id) {
+                peers.remove(&peer);
+            }
+            wanted.avoid.insert(peer);
+            self.ask_again(wanted);
+        }
+        self.ask();
+    }
+
+    fn block(&mut self, block: &Block, peer: PeerId) -> Result<(), Error> {
+        let id = block.bitcoin_hash();
+        let wanted = if let Some(asked) = self.in_flight.get_mut(&peer) {
+            match asked.iter().position(|w| w.id == id) {
+                Some(pos) => asked.remove(pos),
+                None => return Ok(())
+            }
+        } else {
+            return Ok(());
+        };
+        self.timeout.lock().recover().received(peer, 1, ExpectedReply::Block);
+        self.measure(peer, serialize(block).len());
+        if block.header.merkle_root != block.merkle_root() {
+            info!("merkle root of block {} does not match, banning peer={}", id, peer);
+            self.ask_again(wanted);
+            self.p2p.ban(peer, 100);
+            return Ok(());
+        }
+        self.wanted.remove(&id);
+        if wanted.decoy {
+            trace!("discard decoy block {}", id);
+            return Ok(());
+        }
+        if wanted.priority == Priority::Bulk {
+            return self.deliver_bulk(block, wanted.height);
+        }
+        if let Some(slot) = self.pending.get_mut(&wanted.height) {
+            *slot = Some(block.clone());
+        }
+        self.deliver()
+    }
+
+    // store and pass downstream a block requested in bulk, the block tip stays
+    fn deliver_bulk(&mut self, block: &Block, height: u32) -> Result<(), Error> {
+        {
+            let mut chaindb = self.chaindb.write().recover();
+            chaindb.store_block(block)?;
+            chaindb.batch()?;
+        }
+        debug!("received requested block {} at height {}", block.bitcoin_hash(), height);
+        self.downstream.lock().recover().block_connected(block, height);
+        Ok(())
+    }
+
+    // store and pass downstream received blocks in order of height
+    fn deliver(&mut self) -> Result<(), Error> {
+        let mut ready = Vec::new();
+        loop {
+            let height = match self.pending.iter().next() {
+                Some((height, Some(_))) => *height,
+                _ => break
+            };
+            if let Some(Some(block)) = self.pending.remove(&height) {
+                ready.push((block, height));
+            }
+        }
+        if ready.is_empty() {
+            return Ok(());
+        }
+        {
+            let mut chaindb = self.chaindb.write().recover();
+            for (block, _) in &ready {
+                chaindb.store_block(block)?;
+            }
+            // all matching blocks below the next pending one are downloaded
+            let tip = match self.pending.keys().next() {
+                Some(height) if *height > 0 => chaindb.get_header_for_height(*height - 1).map(|h| h.bitcoin_hash()),
+                Some(_) => None,
+                None => self.scanned.and_then(|h| chaindb.get_header_for_height(h)).map(|h| h.bitcoin_hash())
+            };
+            if let Some(tip) = tip {
+                chaindb.store_block_tip(&tip)?;
+            }
+            chaindb.batch()?;
+        }
+        // must call downstream outside of chaindb lock as it might also lock chaindb
+        let mut downstream = self.downstream.lock().recover();
+        for (block, height) in &ready {
+            debug!("connected block {} at height {}", block.bitcoin_hash(), height);
+            downstream.block_connected(block, *height);
+        }
+        Ok(())
+    }
+}
+
+// first height not yet checked for a matching block, a block tip reorged out continues at the fork
+fn first_missing(chaindb: &ChainDB) -> Result<u32, Error> {
+    Ok(chaindb.fetch_block_tip()?.and_then(|tip| chaindb.trunk_height(&tip)).map(|h| h + 1).unwrap_or(0))
+}
@@ -0,0 +1,422 @@
+//
+// Copyright 2019 Tamas Blummer
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+// http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+//
+//!
+//! # Broadcast transactions
+//!
+//! A new transaction is sent to a random subset of peers only. It is considered
+//! propagated once one of the remaining peers announces it back to us, otherwise it is
+//! sent again through different peers.
+//!
+//! A BroadcastPolicy may delay sending by a random time, prefer peers not used for other
+//! recent transactions and restrict broadcast to peers of certain addresses, to make it
+//! harder for spy nodes to link transactions to this node.
+//!
+//! A package of dependent transactions, e.g. a parent with a child paying for it, is sent as a
+//! unit: parents first, all to the same peers, retried together. The package propagated once all
+//! its transactions were announced back, it failed if any of them was given up. Finished packages
+//! are forgotten after an hour.
+//!
+
+use bitcoin::{
+    blockdata::transaction::Transaction,
+    network::{
+        message::NetworkMessage,
+        message_blockdata::{Inventory, InvType}
+    }
+};
+use bitcoin_hashes::{Hash, sha256d::Hash as Sha256dHash};
+use clock::{SharedClock, SharedRandom};
+use lock::Recover;
+use p2p::{P2PControlSender, PeerId, PeerMessage, PeerMessageReceiver, PeerMessageSender};
+use rand::{Rng, seq::SliceRandom};
+use std::{
+    collections::{HashMap, HashSet, VecDeque},
+    net::SocketAddr,
+    sync::{Arc, mpsc, Mutex},
+    thread,
+    time::{Duration, Instant}
+};
+
+// number of peers a transaction is sent to at once
+const SUBSET: usize = 2;
+// seconds to wait for announcements from other peers
+const PROPAGATION_SECS: u64 = 30;
+// give up after this many attempts
+const MAX_ATTEMPTS: usize = 5;
+// number of peers remembered as recently used
+const RECENT_PEERS: usize = 8;
+// milliseconds between checks for submitted packages and transactions due
+const CHECK_INTERVAL_MS: u64 = 1000;
+// seconds a finished package is kept for its status to be asked for
+const FINISHED_PACKAGE_SECS: u64 = 3600;
+
+pub type SharedBroadcastPolicy = Arc<Mutex<BroadcastPolicy>>;
+pub type SharedPackages = Arc<Mutex<Packages>>;
+
+/// Privacy options of transaction broadcast
+#[derive(Clone)]
+pub struct BroadcastPolicy {
+    /// delay sending a new transaction by a random time up to this
+    pub max_delay: Duration,
+    /// prefer peers not used to send other recent transactions
+    pub diversify: bool,
+    /// only send through peers whose address passes this filter, e.g. those reached through Tor
+    pub peer_filter: Option<fn(&SocketAddr) -> bool>
+}
+
+impl Default for BroadcastPolicy {
+    fn default() -> BroadcastPolicy {
+        BroadcastPolicy { max_delay: Duration::from_secs(0), diversify: false, peer_filter: None }
+    }
+}
+
+/// State of a package of transactions
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum PackageStatus {
+    /// not all transactions were seen propagated yet
+    Pending,
+    /// all transactions were seen propagated
+    Propagated,
+    /// broadcast of a transaction was given up
+    Failed
+}
+
+/// A package of transactions broadcast as a unit
+#[derive(Clone, Debug)]
+pub struct Package {
+    /// transactions, parents before children
+    pub txids: Vec<Sha256dHash>,
+    /// transactions seen propagated
+    pub propagated: HashSet<Sha256dHash>,
+    /// state of the package
+    pub status: PackageStatus
+}
+
+/// Packages submitted for broadcast and their state
+#[derive(Default)]
+pub struct Packages {
+    // submitted, not yet taken by the broadcaster
+    submitted: Vec<(Sha256dHash, Vec<Transaction>)>,
+    packages: HashMap<Sha256dHash, Package>,
+    // packages no longer pending by the time they finished
+    finished: VecDeque<(Instant, Sha256dHash)>
+}
+
+impl Packages {
+    /// Submit transactions for broadcast as a package, they are sorted parents first.
+    /// Returns the id of the package, the hash of its transaction ids in that order.
+    pub fn submit(&mut self, txs: Vec<Transaction>) -> Sha256dHash {
+        let txs = sort_package(txs);
+        let txids = txs.iter().map(|tx| tx.txid()).collect::<Vec<_>>();
+        let id = Sha256dHash::hash(txids.iter().flat_map(|txid| txid[..].to_vec()).collect::<Vec<_>>().as_slice());
+        if !self.packages.contains_key(&id) {
+            self.packages.insert(id, Package { txids, propagated: HashSet::new(), status: PackageStatus::Pending });
+            self.submitted.push((id, txs));
+        }
+        id
+    }
+
+    /// the package of the id, None if unknown or finished more than an hour ago
+    pub fn get(&self, id: &Sha256dHash) -> Option<Package> {
+        self.packages.get(id).cloned()
+    }
+
+    // set the final status of a pending package
+    fn finish(&mut self, id: &Sha256dHash, status: PackageStatus, now: Instant) {
+        if let Some(package) = self.packages.get_mut(id) {
+            if package.status == PackageStatus::Pending {
+                package.status = status;
+                self.finished.push_back((now, *id));
+            }
+        }
+    }
+
+    // forget packages finished long enough ago
+    fn prune(&mut self, now: Instant) {
+        while let Some((finished, id)) = self.finished.front().cloned() {
+            if now.duration_since(finished) < Duration::from_secs(FINISHED_PACKAGE_SECS) {
+                break;
+            }
+            self.finished.pop_front();
+            self.packages.remove(&id);
+        }
+    }
+}
+
+// order transactions so that parents within the package come before their children
+fn sort_package(mut txs: Vec<Transaction>) -> Vec<Transaction> {
+    let mut txids = HashSet::new();
+    txs.retain(|tx| txids.insert(tx.txid()));
+    let mut sorted: Vec<Transaction> = Vec::new();
+    while !txs.is_empty() {
+        // a transaction whose parents in the package are placed, as txids commit to the inputs there is no cycle
+        let next = txs.iter().position(|tx| tx.input.iter().all(|input|
+            !txids.contains(&input.previous_output.txid) || sorted.iter().any(|s| s.txid() == input.previous_output.txid)))
+            .expect("transactions can not spend each other in a cycle");
+        sorted.push(txs.remove(next));
+    }
+    sorted
+}
+
+// a transaction not yet seen propagated
+struct Pending {
+    tx: Transaction,
+    // the package the transaction belongs to
+    package: Option<Sha256dHash>,
+    // peers the transaction was sent to
+    sent_to: HashSet<PeerId>,
+    // time of last attempt, None if not yet sent
+    sent_at: Option<Instant>,
+    // do not send before
+    not_before: Instant,
+    attempts: usize
+}
+
+pub struct Broadcaster {
+    p2p: P2PControlSender<NetworkMessage>,
+    policy: SharedBroadcastPolicy,
+    packages: SharedPackages,
+    pending: HashMap<Sha256dHash, Pending>,
+    // peers used for recent transactions
+    recent: VecDeque<PeerId>,
+    clock: SharedClock,
+    random: SharedRandom
+}
+
+impl Broadcaster {
+    /// Transactions sent as PeerMessage::Outgoing(NetworkMessage::Tx) to the returned sender are broadcast,
+    /// as are packages submitted
+    pub fn new(p2p: P2PControlSender<NetworkMessage>, policy: SharedBroadcastPolicy, packages: SharedPackages, clock: SharedClock, random: SharedRandom) -> PeerMessageSender<NetworkMessage> {
+        let (sender, receiver) = mpsc::sync_channel(p2p.back_pressure);
+
+        let mut broadcaster = Broadcaster { p2p, policy, packages, pending: HashMap::new(), recent: VecDeque::new(), clock, random };
+
+        thread::Builder::new().name("broadcaster".to_string()).spawn(move || { broadcaster.run(receiver) }).unwrap();
+
+        PeerMessageSender::new(sender)
+    }
+
+    fn run(&mut self, receiver: PeerMessageReceiver<NetworkMessage>) {
+        let interval = Duration::from_millis(CHECK_INTERVAL_MS);
+        // checks are timed independent of the traffic, which might never pause
+        let mut next_check = Instant::now() + interval;
+        loop {
+            let now = Instant::now();
+            let timeout = if next_check > now { next_check - now } else { Duration::from_millis(0) };
+            match receiver.recv_timeout(timeout) {
+                Ok(PeerMessage::Outgoing(NetworkMessage::Tx(tx))) => {
+                    self.broadcast(tx);
+                },
+                Ok(PeerMessage::Incoming(pid, msg)) => {
+                    match msg {
+                        NetworkMessage::Inv(ref inv) => self.inv(inv, pid),
+                        NetworkMessage::GetData(ref inv) => self.get_data(inv, pid),
+                        _ => {}
+                    }
+                },
+                Ok(_) | Err(mpsc::RecvTimeoutError::Timeout) => {},
+                Err(mpsc::RecvTimeoutError::Disconnected) => break
+            }
+            if Instant::now() >= next_check {
+                next_check = Instant::now() + interval;
+                let submitted = {
+                    let mut packages = self.packages.lock().recover();
+                    packages.prune(self.clock.now());
+                    packages.submitted.drain(..).collect::<Vec<_>>()
+                };
+                for (id, txs) in submitted {
+                    self.broadcast_package(id, txs);
+                }
+                self.check();
+            }
+        }
+    }
+
+    fn broadcast(&mut self, tx: Transaction) {
+        let txid = tx.txid();
+        if !self.pending.contains_key(&txid) {
+            let delay = self.delay();
+            info!("broadcast transaction {} in {} ms", txid, delay.as_millis());
+            self.pending.insert(txid, Pending { tx, package: None, sent_to: HashSet::new(), sent_at: None, not_before: self.clock.now() + delay, attempts: 0 });
+            if delay == Duration::from_millis(0) {
+                self.send(&txid);
+            }
+        }
+    }
+
+    // the transactions of a package share the delay
+    fn broadcast_package(&mut self, id: Sha256dHash, txs: Vec<Transaction>) {
+        let delay = self.delay();
+        info!("broadcast package {} of {} transactions in {} ms", id, txs.len(), delay.as_millis());
+        let not_before = self.clock.now() + delay;
+        let first = txs.first().map(|tx| tx.txid());
+        for tx in txs {
+            self.pending.insert(tx.txid(), Pending { tx, package: Some(id), sent_to: HashSet::new(), sent_at: None, not_before, attempts: 0 });
+        }
+        if let Some(first) = first {
+            if delay == Duration::from_millis(0) {
+                self.send(&first);
+            }
+        }
+    }
+
+    // random delay up to the maximum of the policy
+    fn delay(&self) -> Duration {
+        let max_delay = self.policy.lock().recover().max_delay;
+        if max_delay > Duration::from_millis(0) {
+            Duration::from_millis(self.random.rng().gen_range(0, max_delay.as_millis() as u64))
+        } else {
+            max_delay
+        }
+    }
+
+    // the transaction, or all pending transactions of its package parents first
+    fn unit(&self, txid: &Sha256dHash) -> Vec<Sha256dHash> {
+        match self.pending.get(txid).and_then(|p| p.package) {
+            Some(package) => self.packages.lock().recover().packages.get(&package)
+                .map(|p| p.txids.iter().filter(|t| self.pending.contains_key(*t)).cloned().collect())
+                .unwrap_or_default(),
+            None => vec!(*txid)
+        }
+    }
+
+    // send a pending transaction to a random subset of peers not yet tried, with its package
+    fn send(&mut self, txid: &Sha256dHash) {
+        let policy = self.policy.lock().recover().clone();
+        let mut peers = self.p2p.peers();
+        if let Some(ref filter) = policy.peer_filter {
+            let p2p = &self.p2p;
+            peers.retain(|p| if let Some(addr) = p2p.peer_address(*p) { filter(&addr) } else { false });
+        }
+        peers.shuffle(&mut self.random.rng());
+        if policy.diversify {
+            // peers not used for other recent transactions first
+            let recent = &self.recent;
+            peers.sort_by_key(|p| recent.contains(p));
+        }
+        let mut selected = match self.pending.get(txid) {
+            Some(pending) => peers.iter().filter(|p| !pending.sent_to.contains(*p)).take(SUBSET).cloned().collect::<Vec<_>>(),
+            None => return
+        };
+        if selected.is_empty() {
+            // tried all peers, try again with any of them
+            selected = peers.iter().take(SUBSET).cloned().collect();
+        }
+        if selected.is_empty() {
+            debug!("no peers to broadcast transaction {}", txid);
+            return;
+        }
+        let now = self.clock.now();
+        for txid in self.unit(txid) {
+            if let Some(pending) = self.pending.get_mut(&txid) {
+                pending.attempts += 1;
+                pending.sent_at = Some(now);
+                for peer in &selected {
+                    debug!("send transaction {} attempt {} peer={}", txid, pending.attempts, peer);
+                    pending.sent_to.insert(*peer);
+                    self.p2p.send_network(*peer, NetworkMessage::Tx(pending.tx.clone()));
+                }
+            }
+        }
+        for peer in selected {
+            self.recent.push_back(peer);
+            if self.recent.len() > RECENT_PEERS {
+                self.recent.pop_front();
+            }
+        }
+    }
+
+    // propagated once announced by a peer the transaction was not sent to
+    fn inv(&mut self, v: &Vec<Inventory>, peer: PeerId) {
+        for inventory in v {
+            if inventory.inv_type == InvType::Transaction || inventory.inv_type == InvType::WitnessTransaction {
+                let propagated = if let Some(pending) = self.pending.get(&inventory.hash) {
+                    !pending.sent_to.contains(&peer)
+                } else {
+                    false
+                };
+                if propagated {
+                    info!("transaction {} propagated, announced by peer={}", inventory.hash, peer);
+                    if let Some(id) = self.pending.remove(&inventory.hash).and_then(|p| p.package) {
+                        let mut packages = self.packages.lock().recover();
+                        let complete = match packages.packages.get_mut(&id) {
+                            Some(package) => {
+                                package.propagated.insert(inventory.hash);
+                                package.propagated.len() == package.txids.len()
+                            },
+                            None => false
+                        };
+                        if complete {
+                            info!("package {} propagated", id);
+                            packages.finish(&id, PackageStatus::Propagated, self.clock.now());
+                        }
+                    }
+                }
+            }
+        }
+    }
+
+    // serve pending transactions to peers asking for them
+    fn get_data(&mut self, v: &Vec<Inventory>, peer: PeerId) {
+        for inventory in v {
+            if inventory.inv_type == InvType::Transaction || inventory.inv_type == InvType::WitnessTransaction {
+                if let Some(pending) = self.pending.get(&inventory.hash) {
+                    self.p2p.send_network(peer, NetworkMessage::Tx(pending.tx.clone()));
+                }
+            }
+        }
+    }
+
+    // retry transactions not seen propagated in time
+    fn check(&mut self) {
+        let mut retry = Vec::new();
+        let mut failed = Vec::new();
+        let now = self.clock.now();
+        for (txid, pending) in &self.pending {
+            match pending.sent_at {
+                None => if pending.not_before <= now {
+                    retry.push(*txid)
+                },
+                Some(sent_at) => if now.duration_since(sent_at) > Duration::from_secs(PROPAGATION_SECS) {
+                    if pending.attempts >= MAX_ATTEMPTS {
+                        failed.push(*txid);
+                    } else {
+                        retry.push(*txid);
+                    }
+                }
+            }
+        }
+        for txid in failed {
+            warn!("giving up broadcast of transaction {} not propagated after {} attempts", txid, MAX_ATTEMPTS);
+            if let Some(package) = self.pending.remove(&txid).and_then(|p| p.package) {
+                // the rest of the package is given up with it
+                self.pending.retain(|_, p| p.package != Some(package));
+                self.packages.lock().recover().finish(&package, PackageStatus::Failed, now);
+            }
+        }
+        // a package is sent once for all its transactions due
+        let mut sent = HashSet::new();
+        for txid in retry {
+            match self.pending.get(&txid).map(|p| p.package) {
+                Some(Some(package)) => if sent.insert(package) {
+                    self.send(&txid);
+                },
+                Some(None) => self.send(&txid),
+                None => {}
+            }
+        }
+    }
+}
@@ -0,0 +1,66 @@
+//
+// Copyright 2019 Tamas Blummer
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+// http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+//
+//!
+//! # Peer census
+//!
+//! Distribution of user agents, protocol versions and service bits among connected peers,
+//! and of service bits among addresses discovered through gossip.
+//!
+
+use addressbook::KnownAddress;
+use p2p::PeerInfo;
+use std::collections::BTreeMap;
+
+/// Statistics of connected and discovered peers
+#[derive(Clone, Debug, Default)]
+pub struct Census {
+    /// number of connected peers
+    pub connected: usize,
+    /// number of discovered addresses
+    pub discovered: usize,
+    /// connected peers by user agent
+    pub user_agents: BTreeMap<String, usize>,
+    /// connected peers by protocol version
+    pub versions: BTreeMap<u32, usize>,
+    /// connected peers by service bit they announced
+    pub services: BTreeMap<u8, usize>,
+    /// discovered addresses by service bit they were announced with
+    pub discovered_services: BTreeMap<u8, usize>
+}
+
+impl Census {
+    /// Count connected peers and discovered addresses
+    pub fn new(peers: &[PeerInfo], discovered: &[KnownAddress]) -> Census {
+        let mut census = Census { connected: peers.len(), discovered: discovered.len(), ..Census::default() };
+        for peer in peers {
+            *census.user_agents.entry(peer.user_agent.clone()).or_insert(0) += 1;
+            *census.versions.entry(peer.version).or_insert(0) += 1;
+            count_bits(peer.services, &mut census.services);
+        }
+        for known in discovered {
+            count_bits(known.address.services, &mut census.discovered_services);
+        }
+        census
+    }
+}
+
+fn count_bits(services: u64, counts: &mut BTreeMap<u8, usize>) {
+    for bit in 0..64u8 {
+        if services & (1 << bit) != 0 {
+            *counts.entry(bit).or_insert(0) += 1;
+        }
+    }
+}
@@ -16,29 +16,51 @@
 //!
 //! # Blockchain DB for a node
 //!
+//! Headers of the trunk buried deep enough not to change are stored in chunks of a retarget
+//! period, as fixed-size records without the previous hash, with times as differences and
+//! difficulty bits as runs. Other headers, those of forks and of the chunk not yet complete, are
+//! stored one by one.
+//!
 
 use bitcoin::{
     BitcoinHash,
     blockdata::{
-        block::{BlockHeader},
-        constants::genesis_block
+        block::{Block, BlockHeader},
+        constants::genesis_block,
+        transaction::{OutPoint, Transaction}
     },
+    consensus::{Decodable, Encodable, encode::{self, VarInt}},
     network::constants::Network
 };
 
-use bitcoin_hashes::{sha256d};
+use bitcoin_hashes::{Hash, HashEngine, sha256d};
+use configdb::{backup_db, export_db, import_db};
 use error::Error;
 use hammersbald::{
     BitcoinAdaptor, HammersbaldAPI, persistent,
     transient,
 };
 use headercache::{CachedHeader, HeaderCache};
+use lock::Recover;
 use std::{
-    sync::{Arc, RwLock}
+    cmp::{max, min},
+    collections::HashMap,
+    io,
+    mem,
+    sync::{Arc, Mutex, RwLock}
 };
 use std::{
     path::Path
 };
+use timelock::{self, LockStatus};
+use versionbits::{self, Deployment, DeploymentStatus, SignalCounts, ThresholdState, VERSION_BITS};
+
+// number of blocks statistics are computed over, about a day
+const STATS_WINDOW: u32 = 144;
+// number of headers stored in a chunk
+const HEADER_CHUNK: u32 = 2016;
+// headers this deep below the tip are not expected to be reorganized
+const BURIED: u32 = 100;
 
 /// Shared handle to a database storing the block chain
 /// protected by an RwLock
@@ -48,7 +70,28 @@ pub type SharedChainDB = Arc<RwLock<ChainDB>>;
 pub struct ChainDB {
     db: BitcoinAdaptor,
     headercache: HeaderCache,
-    network: Network
+    network: Network,
+    filter_retention: FilterRetention,
+    // signal counts of complete periods by the id of their last header
+    signals: Mutex<HashMap<sha256d::Hash, SignalCounts>>,
+    // number of header chunks stored
+    compacted: u32,
+    // headers added since the last batch
+    unsaved: Vec<sha256d::Hash>,
+    // problems repaired while opening
+    recovered: Vec<String>
+}
+
+/// Which downloaded filters are kept in the DB. Filters outside of retention are pruned
+/// to a record of whether they matched, so a rescan for new scripts has to download them again.
+#[derive(Clone, Copy, Debug)]
+pub enum FilterRetention {
+    /// keep all filters
+    All,
+    /// keep filters of this many blocks below the tip
+    Recent(u32),
+    /// keep no filter content
+    None
 }
 
 impl ChainDB {
@@ -57,7 +100,18 @@ impl ChainDB {
         info!("working with in memory chain db");
         let db = BitcoinAdaptor::new(transient(2)?);
         let headercache = HeaderCache::new(network);
-        Ok(ChainDB { db, network, headercache })
+        Ok(ChainDB { db, network, headercache, filter_retention: FilterRetention::All, signals: Mutex::new(HashMap::new()),
+            compacted: 0, unsaved: Vec::new(), recovered: Vec::new() })
+    }
+
+    /// Create an in-memory database instance with the content of a blob created by export
+    pub fn mem_from(network: Network, blob: &[u8]) -> Result<ChainDB, Error> {
+        info!("working with in memory chain db from an exported state");
+        let mut db = BitcoinAdaptor::new(transient(2)?);
+        import_db(&mut db, blob)?;
+        let headercache = HeaderCache::new(network);
+        Ok(ChainDB { db, network, headercache, filter_retention: FilterRetention::All, signals: Mutex::new(HashMap::new()),
+            compacted: 0, unsaved: Vec::new(), recovered: Vec::new() })
     }
 
     /// Create or open a persistent database instance identified by the path
@@ -65,37 +119,203 @@ impl ChainDB {
         let basename = path.to_str().unwrap().to_string();
         let db = BitcoinAdaptor::new(persistent((basename.clone()).as_str(), 100, 2)?);
         let headercache = HeaderCache::new(network);
-        Ok(ChainDB { db, network, headercache})
+        Ok(ChainDB { db, network, headercache, filter_retention: FilterRetention::All, signals: Mutex::new(HashMap::new()),
+            compacted: 0, unsaved: Vec::new(), recovered: Vec::new() })
+    }
+
+    /// the network of the chain
+    pub fn network(&self) -> Network {
+        self.network
     }
 
-    /// Initialize caches
+    /// Trust the difficulty claimed by headers up to the (height, id) of a header known to be on the
+    /// valid chain, None checks every retarget
+    pub fn set_assume_valid(&mut self, anchor: Option<(u32, sha256d::Hash)>) {
+        self.headercache.set_assume_valid(anchor);
+    }
+
+    /// Set which downloaded filters are kept
+    pub fn set_filter_retention(&mut self, retention: FilterRetention) {
+        self.filter_retention = retention;
+    }
+
+    /// Initialize caches. Data left inconsistent by an unclean shutdown is rolled back to the
+    /// last consistent tip, see take_recovered.
     pub fn init(&mut self) -> Result<(), Error> {
+        self.migrate()?;
         self.init_headers()?;
+        let report = self.verify(VerifyLevel::Links, &mut |_, _| {})?;
+        self.recovered.extend(report.problems);
+        if !self.recovered.is_empty() {
+            warn!("recovered from {} problems of the chain db, continue at height {}", self.recovered.len(),
+                self.headercache.len().saturating_sub(1));
+        }
+        Ok(())
+    }
+
+    /// Problems found and repaired while opening the DB, empty if it was consistent
+    pub fn take_recovered(&mut self) -> Vec<String> {
+        mem::replace(&mut self.recovered, Vec::new())
+    }
+
+    // upgrade data stored by earlier versions to the current schema
+    fn migrate(&mut self) -> Result<(), Error> {
+        let mut version = self.db.get_keyed_decodable::<u32>(SCHEMA_VERSION_KEY)?.map(|(_, v)| v).unwrap_or(0);
+        if version > SCHEMA_VERSION {
+            return Err(Error::UnsupportedVersion(version));
+        }
+        while version < SCHEMA_VERSION {
+            // version 0 had the layout of version 1, it just did not record its version.
+            // Version 1 stored all headers one by one, those are still read, version 2 adds chunks.
+            // Steps converting data of a version to the next go here.
+            version += 1;
+            info!("migrated chain db to schema version {}", version);
+            self.db.put_keyed_encodable(SCHEMA_VERSION_KEY, &version)?;
+            self.db.batch()?;
+        }
         Ok(())
     }
 
     /// Batch updates. Updates are permanent after finishing a batch.
     pub fn batch(&mut self) -> Result<(), Error> {
+        self.store_headers()?;
         self.db.batch()?;
         Ok(())
     }
 
+    /// Write a consistent copy to a new persistent database at path while the node is running.
+    /// Pending updates are batched first, the copy holds the state of that batch boundary.
+    pub fn backup(&mut self, path: &Path) -> Result<(), Error> {
+        self.batch()?;
+        backup_db(&self.db, path)
+    }
+
+    /// Serialize the content at a batch boundary, e.g. to resume an in-memory node with mem_from.
+    pub fn export(&mut self) -> Result<Vec<u8>, Error> {
+        self.batch()?;
+        export_db(&self.db)
+    }
+
+    // store complete chunks of buried headers, others one by one until their chunk is stored
+    fn store_headers(&mut self) -> Result<(), Error> {
+        let tip = match self.headercache.tip() {
+            Some(tip) => tip.stored.height,
+            None => return Ok(())
+        };
+        while (self.compacted + 1) * HEADER_CHUNK - 1 + BURIED <= tip {
+            let headers = self.headercache.iter_trunk(self.compacted * HEADER_CHUNK).take(HEADER_CHUNK as usize)
+                .map(|h| h.stored.header.clone()).collect::<Vec<_>>();
+            self.db.put_keyed_encodable(&header_chunk_key(self.compacted), &HeaderChunk::new(&headers))?;
+            self.compacted += 1;
+            self.db.put_keyed_encodable(HEADER_CHUNKS_KEY, &self.compacted)?;
+            debug!("stored header chunk {}", self.compacted - 1);
+        }
+        let covered = self.compacted * HEADER_CHUNK;
+        for id in mem::replace(&mut self.unsaved, Vec::new()) {
+            if let Some(cached) = self.headercache.get_header(&id) {
+                match self.headercache.pos_on_trunk(&id) {
+                    Some(height) if height < covered => {},
+                    _ => { self.db.put_hash_keyed(&cached.stored)?; }
+                }
+            }
+        }
+        Ok(())
+    }
+
+    // read headers stored in chunks
+    fn load_header_chunks(&mut self) -> Result<(), Error> {
+        let chunks = match self.db.get_keyed_decodable::<u32>(HEADER_CHUNKS_KEY) {
+            Ok(chunks) => chunks.map(|(_, n)| n).unwrap_or(0),
+            Err(e) => {
+                self.recovered.push(format!("can not read the number of header chunks: {}", e));
+                0
+            }
+        };
+        let mut prev: Option<(sha256d::Hash, f64)> = None;
+        for index in 0..chunks {
+            let chunk = match self.db.get_keyed_decodable::<HeaderChunk>(&header_chunk_key(index)) {
+                Ok(Some((_, chunk))) => if chunk.records.len() == HEADER_CHUNK as usize {
+                    chunk
+                } else {
+                    self.recovered.push(format!("header chunk {} is incomplete", index));
+                    break;
+                },
+                Ok(None) => {
+                    self.recovered.push(format!("header chunk {} is missing", index));
+                    break;
+                },
+                Err(e) => {
+                    self.recovered.push(format!("can not read header chunk {}: {}", index, e));
+                    break;
+                }
+            };
+            for header in chunk.headers(prev.map(|(id, _)| id).unwrap_or_default()) {
+                let id = header.bitcoin_hash();
+                let log2work = self.headercache.log2work(prev.map(|(_, w)| w), &header);
+                let height = self.headercache.len() as u32;
+                self.headercache.add_header_unchecked(&id, &StoredHeader { header, height, log2work });
+                prev = Some((id, log2work));
+            }
+            self.compacted = index + 1;
+        }
+        if self.compacted > 0 {
+            info!("read {} headers from {} chunks", self.headercache.len(), self.compacted);
+        }
+        Ok(())
+    }
+
     fn init_headers(&mut self) -> Result<(), Error> {
-        if let Some(tip) = self.fetch_header_tip()? {
+        self.load_header_chunks()?;
+        let tip = match self.fetch_header_tip() {
+            Ok(tip) => tip,
+            Err(e) => {
+                self.recovered.push(format!("can not read the header tip: {}", e));
+                self.headercache.index_trunk();
+                self.headercache.tip_hash()
+            }
+        };
+        if let Some(tip) = tip {
             info!("reading stored header chain from tip {}", tip);
+            // headers following the chunks are stored one by one
+            let mut recent = Vec::new();
             let mut h = tip;
-            while let Some(stored) = self.fetch_header(&h)? {
-                self.headercache.add_header_unchecked(&h, &stored);
-                if stored.header.prev_blockhash != sha256d::Hash::default() {
-                    h = stored.header.prev_blockhash;
-                } else {
-                    break;
+            let connected = loop {
+                if self.headercache.get_header(&h).is_some() {
+                    break self.headercache.tip_hash() == Some(h);
+                }
+                match self.fetch_header(&h) {
+                    Ok(Some(stored)) => {
+                        let prev = stored.header.prev_blockhash;
+                        recent.push((h, stored));
+                        if prev == sha256d::Hash::default() {
+                            break self.headercache.len() == 0;
+                        }
+                        h = prev;
+                    },
+                    Ok(None) => break false,
+                    Err(e) => {
+                        self.recovered.push(format!("can not read header {}: {}", h, e));
+                        break false;
+                    }
+                }
+            };
+            if connected {
+                for (id, stored) in recent.iter().rev() {
+                    self.headercache.add_header_unchecked(id, stored);
+                }
+            }
+            self.headercache.index_trunk();
+            if !connected {
+                // headers following the chunks were lost or damaged, sync them again
+                warn!("stored header chain has a gap after height {}", self.headercache.len());
+                if let Some(tip) = self.headercache.tip_hash() {
+                    self.store_header_tip(&tip)?;
+                    self.db.batch()?;
                 }
             }
-            self.headercache.reverse_trunk();
             info!("read {} headers", self.headercache.len());
         }
-        else {
+        if self.headercache.len() == 0 {
             let genesis = genesis_block(self.network).header;
             if let Some((cached, _, _)) = self.headercache.add_header(&genesis)? {
                 info!("Initialized with genesis header {}", genesis.bitcoin_hash());
@@ -113,10 +333,170 @@ impl ChainDB {
     }
 
 
+    /// Check headers of the trunk from genesis for linkage, heights and index consistency, with
+    /// deeper levels also their proof of work and stored blocks. The trunk is truncated before
+    /// the first corrupted header, to genesis if that is corrupted, a corrupted block is
+    /// downloaded again. progress is called with
+    /// the number of headers checked and the length of the trunk.
+    pub fn verify(&mut self, level: VerifyLevel, progress: &mut dyn FnMut(u32, u32)) -> Result<VerifyReport, Error> {
+        const PROGRESS_EVERY: u32 = 10000;
+        let total = self.headercache.len() as u32;
+        let mut report = VerifyReport { checked: 0, truncated_at: None, blocks_from: None, problems: Vec::new() };
+        {
+            let mut prev: Option<&CachedHeader> = None;
+            for header in self.iter_trunk(0) {
+                let height = report.checked;
+                if let Some(problem) = self.check_header(height, header, prev, level) {
+                    report.problems.push(format!("height {}: {}", height, problem));
+                    report.truncated_at = Some(height);
+                    break;
+                }
+                if level >= VerifyLevel::Blocks && report.blocks_from.is_none() {
+                    if let Some(problem) = self.check_block(header) {
+                        report.problems.push(format!("block at height {}: {}", height, problem));
+                        report.blocks_from = Some(height);
+                    }
+                }
+                prev = Some(header);
+                report.checked += 1;
+                if report.checked % PROGRESS_EVERY == 0 {
+                    progress(report.checked, total);
+                }
+            }
+        }
+        progress(report.checked, total);
+        for problem in &report.problems {
+            warn!("chain db verification failed at {}", problem);
+        }
+        if let Some(height) = report.blocks_from {
+            self.rewind_tip(BLOCK_TIP_KEY, height)?;
+        }
+        if let Some(height) = report.truncated_at {
+            for key in &[FILTER_HEADER_TIP_KEY, FILTER_TIP_KEY, BLOCK_TIP_KEY] {
+                self.rewind_tip(key, height)?;
+            }
+            self.headercache.truncate(height);
+            if height == 0 {
+                // not even genesis is left, start over from it
+                let genesis = genesis_block(self.network).header;
+                match self.headercache.add_header(&genesis)? {
+                    Some((cached, _, _)) => self.db.put_hash_keyed(&cached.stored)?,
+                    None => return Err(Error::NoTip)
+                };
+            }
+            if let Some(tip) = self.headercache.tip_hash() {
+                self.store_header_tip(&tip)?;
+            }
+            self.unsaved.clear();
+            self.compacted = min(self.compacted, height / HEADER_CHUNK);
+            self.db.put_keyed_encodable(HEADER_CHUNKS_KEY, &self.compacted)?;
+            info!("truncated trunk to {} headers", self.headercache.len());
+        }
+        self.db.batch()?;
+        Ok(report)
+    }
+
+    // why a header of the trunk is corrupted, None if it is not
+    fn check_header(&self, height: u32, header: &CachedHeader, prev: Option<&CachedHeader>, level: VerifyLevel) -> Option<String> {
+        let id = header.bitcoin_hash();
+        if header.stored.height != height {
+            return Some(format!("header {} claims height {}", id, header.stored.height));
+        }
+        if self.pos_on_trunk(&id) != Some(height) {
+            return Some(format!("header {} is not indexed at its height", id));
+        }
+        match prev {
+            None => if id != genesis_block(self.network).bitcoin_hash() {
+                return Some(format!("header {} is not the genesis of {:?}", id, self.network));
+            },
+            Some(prev) => {
+                if header.stored.header.prev_blockhash != prev.bitcoin_hash() {
+                    return Some(format!("header {} does not link to its predecessor", id));
+                }
+                if header.stored.log2work <= prev.stored.log2work {
+                    return Some(format!("header {} does not add work", id));
+                }
+            }
+        }
+        if level >= VerifyLevel::ProofOfWork && header.check_pow(&header.target()).is_err() {
+            return Some(format!("header {} has insufficient proof of work", id));
+        }
+        None
+    }
+
+    // why a stored block is corrupted, None if it is not or not stored
+    fn check_block(&self, header: &CachedHeader) -> Option<String> {
+        match self.fetch_block(&header.bitcoin_hash()) {
+            Ok(Some(block)) => if block.header != header.stored.header {
+                Some(format!("block {} is stored for header {}", block.bitcoin_hash(), header.bitcoin_hash()))
+            } else if block.header.merkle_root != block.merkle_root() {
+                Some(format!("block {} does not match its merkle root", header.bitcoin_hash()))
+            } else {
+                None
+            },
+            Ok(None) => None,
+            Err(e) => Some(format!("block {} can not be read: {}", header.bitcoin_hash(), e))
+        }
+    }
+
+    // move a download tip at or above height to the header below, so the download continues from there
+    fn rewind_tip(&mut self, key: &[u8], height: u32) -> Result<(), Error> {
+        let tip = self.db.get_keyed_decodable::<sha256d::Hash>(key)?.map(|(_, h)| h);
+        if let Some(tip) = tip.and_then(|tip| self.pos_on_trunk(&tip)) {
+            if tip >= height {
+                let below = if height > 0 {
+                    self.get_header_for_height(height - 1).map(|h| h.bitcoin_hash())
+                } else {
+                    Some(genesis_block(self.network).bitcoin_hash())
+                };
+                if let Some(below) = below {
+                    self.db.put_keyed_encodable(key, &below)?;
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Import a sequence of serialized headers, e.g. a bundle shipped with the application.
+    /// Headers are checked as if received from a peer, those already known are skipped.
+    /// Returns the number of headers added, an error if the sequence ends within a header.
+    pub fn import_headers<R: io::Read>(&mut self, mut reader: R) -> Result<u32, Error> {
+        let mut buffer = [0u8; 80];
+        let mut added = 0;
+        loop {
+            let mut read = 0;
+            while read < buffer.len() {
+                match reader.read(&mut buffer[read..]) {
+                    Ok(0) => break,
+                    Ok(n) => read += n,
+                    Err(ref e) if e.kind() == io::ErrorKind::Interrupted => {},
+                    Err(e) => return Err(e.into())
+                }
+            }
+            if read == 0 {
+                break;
+            }
+            if read < buffer.len() {
+                // keep what was imported before the truncated header
+                self.batch()?;
+                return Err(Error::IO(io::Error::new(io::ErrorKind::UnexpectedEof, format!("truncated header after {} headers", added))));
+            }
+            let header: BlockHeader = encode::deserialize(&buffer)?;
+            if self.add_header(&header)?.is_some() {
+                added += 1;
+                if added % HEADER_CHUNK == 0 {
+                    self.batch()?;
+                }
+            }
+        }
+        self.batch()?;
+        Ok(added)
+    }
+
     /// Store a header
     pub fn add_header(&mut self, header: &BlockHeader) -> Result<Option<(StoredHeader, Option<Vec<sha256d::Hash>>, Option<Vec<sha256d::Hash>>)>, Error> {
         if let Some((cached, unwinds, forward)) = self.headercache.add_header(header)? {
-            self.db.put_hash_keyed(&cached.stored)?;
+            self.unsaved.push(cached.bitcoin_hash());
             if let Some(forward) = forward.clone() {
                 if forward.len() > 0 {
                     self.store_header_tip(forward.last().unwrap())?;
@@ -132,6 +512,17 @@ impl ChainDB {
         self.headercache.pos_on_trunk(hash)
     }
 
+    /// height of the block if on trunk, otherwise that of its last ancestor on trunk
+    pub fn trunk_height(&self, hash: &sha256d::Hash) -> Option<u32> {
+        let mut h = *hash;
+        loop {
+            if let Some(height) = self.pos_on_trunk(&h) {
+                return Some(height);
+            }
+            h = self.get_header(&h)?.stored.header.prev_blockhash;
+        }
+    }
+
     /// iterate trunk [from .. tip]
     pub fn iter_trunk<'a> (&'a self, from: u32) -> impl Iterator<Item=&'a CachedHeader> +'a {
         self.headercache.iter_trunk(from)
@@ -157,6 +548,63 @@ impl ChainDB {
         self.headercache.get_header_for_height(height)
     }
 
+    /// median of the timestamps of the 11 blocks on trunk ending at height
+    pub fn median_time_past(&self, height: u32) -> Option<u32> {
+        if height > self.header_tip()?.stored.height {
+            return None;
+        }
+        let mut times = self.iter_trunk_rev(Some(height)).take(11).map(|h| h.stored.header.time).collect::<Vec<_>>();
+        times.sort();
+        Some(times[times.len() / 2])
+    }
+
+    /// Version bit signals of the trunk's retarget period containing height, counted up to height.
+    /// Counts of complete periods are cached.
+    pub fn signal_counts(&self, height: u32) -> Option<SignalCounts> {
+        let (period, _) = versionbits::period_and_threshold(self.network);
+        let last = self.get_header_for_height(height)?;
+        let complete = height % period == period - 1;
+        if complete {
+            if let Some(counts) = self.signals.lock().recover().get(&last.bitcoin_hash()) {
+                return Some(*counts);
+            }
+        }
+        let mut counts = [0u32; VERSION_BITS];
+        for header in self.iter_trunk(height - height % period).take((height % period + 1) as usize) {
+            versionbits::count_signals(&header.stored.header, &mut counts);
+        }
+        if complete {
+            self.signals.lock().recover().insert(last.bitcoin_hash(), counts);
+        }
+        Some(counts)
+    }
+
+    /// State of a version bits deployment for the block following the trunk tip
+    pub fn version_bits_state(&self, deployment: &Deployment) -> ThresholdState {
+        versionbits::threshold_state(self, deployment)
+    }
+
+    /// State of a version bits deployment for the block following the trunk tip, with progress of signalling
+    pub fn deployment_status(&self, deployment: &Deployment) -> DeploymentStatus {
+        versionbits::deployment_status(self, deployment)
+    }
+
+    /// Difficulty, hashrate and block interval over the last day of blocks on trunk
+    pub fn chain_stats(&self) -> Option<ChainStats> {
+        let tip = self.header_tip()?;
+        let height = tip.stored.height;
+        let window = min(height, STATS_WINDOW);
+        let difficulty = difficulty(tip.stored.header.bits);
+        if window == 0 {
+            return Some(ChainStats { height, difficulty, hashrate: 0.0, block_interval: 0.0, window });
+        }
+        let start = self.get_header_for_height(height - window)?;
+        // timestamps are not ordered, do not let the interval go negative
+        let elapsed = max(1, tip.stored.header.time as i64 - start.stored.header.time as i64) as f64;
+        let work = 2f64.powf(tip.stored.log2work) - 2f64.powf(start.stored.log2work);
+        Some(ChainStats { height, difficulty, hashrate: work / elapsed, block_interval: elapsed / window as f64, window })
+    }
+
     /// locator for getheaders message
     pub fn header_locators(&self) -> Vec<sha256d::Hash> {
         self.headercache.locator_hashes()
@@ -173,10 +621,262 @@ impl ChainDB {
         Ok(self.db.get_keyed_decodable::<sha256d::Hash>(HEADER_TIP_KEY)?.map(|(_, h)| h.clone()))
     }
 
-    /// Read header from the DB
+    /// Read a header stored one by one from the DB
     pub fn fetch_header(&self, id: &sha256d::Hash) -> Result<Option<StoredHeader>, Error> {
         Ok(self.db.get_hash_keyed::<StoredHeader>(id)?.map(|(_, header)| header))
     }
+
+    /// Store the BIP157 filter header of a block
+    pub fn store_filter_header(&mut self, block_id: &sha256d::Hash, filter_header: &sha256d::Hash) -> Result<(), Error> {
+        self.db.put_keyed_encodable(filter_header_key(block_id).as_slice(), filter_header)?;
+        Ok(())
+    }
+
+    /// Read the BIP157 filter header of a block
+    pub fn fetch_filter_header(&self, block_id: &sha256d::Hash) -> Result<Option<sha256d::Hash>, Error> {
+        Ok(self.db.get_keyed_decodable::<sha256d::Hash>(filter_header_key(block_id).as_slice())?.map(|(_, h)| h))
+    }
+
+    /// Store the id of the last block on trunk with known filter header
+    pub fn store_filter_header_tip(&mut self, block_id: &sha256d::Hash) -> Result<(), Error> {
+        self.db.put_keyed_encodable(FILTER_HEADER_TIP_KEY, block_id)?;
+        Ok(())
+    }
+
+    /// Find the id of the last block with known filter header
+    pub fn fetch_filter_header_tip(&self) -> Result<Option<sha256d::Hash>, Error> {
+        Ok(self.db.get_keyed_decodable::<sha256d::Hash>(FILTER_HEADER_TIP_KEY)?.map(|(_, h)| h))
+    }
+
+    /// Store the id of the block on trunk up to which all filters are downloaded
+    pub fn store_filter_tip(&mut self, block_id: &sha256d::Hash) -> Result<(), Error> {
+        self.db.put_keyed_encodable(FILTER_TIP_KEY, block_id)?;
+        Ok(())
+    }
+
+    /// Find the id of the block up to which all filters are downloaded
+    pub fn fetch_filter_tip(&self) -> Result<Option<sha256d::Hash>, Error> {
+        Ok(self.db.get_keyed_decodable::<sha256d::Hash>(FILTER_TIP_KEY)?.map(|(_, h)| h))
+    }
+
+    /// Store a downloaded BIP158 filter and whether it matched watched scripts.
+    /// Filters no longer within retention are pruned.
+    pub fn store_filter(&mut self, block_id: &sha256d::Hash, filter: Vec<u8>, matched: bool) -> Result<(), Error> {
+        let filter = match self.filter_retention {
+            FilterRetention::None => None,
+            _ => Some(filter)
+        };
+        self.db.put_keyed_encodable(filter_key(block_id).as_slice(), &StoredFilter { block_id: *block_id, filter, matched })?;
+        if let FilterRetention::Recent(keep) = self.filter_retention {
+            if let Some(height) = self.pos_on_trunk(block_id) {
+                if height > keep {
+                    self.prune_filters(height - keep)?;
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Read a filter, its content is None if pruned
+    pub fn fetch_filter(&self, block_id: &sha256d::Hash) -> Result<Option<StoredFilter>, Error> {
+        Ok(self.db.get_keyed_decodable::<StoredFilter>(filter_key(block_id).as_slice())?.map(|(_, f)| f))
+    }
+
+    /// Drop content of filters below the given height, keeping whether they matched
+    pub fn prune_filters(&mut self, below: u32) -> Result<(), Error> {
+        let from = self.db.get_keyed_decodable::<u32>(FILTER_PRUNED_KEY)?.map(|(_, h)| h).unwrap_or(0);
+        if from >= below {
+            return Ok(());
+        }
+        let blocks = self.iter_trunk(from).take_while(|h| h.stored.height < below).map(|h| h.bitcoin_hash()).collect::<Vec<_>>();
+        for block_id in &blocks {
+            if let Some(mut stored) = self.fetch_filter(block_id)? {
+                if stored.filter.is_some() {
+                    stored.filter = None;
+                    self.db.put_keyed_encodable(filter_key(block_id).as_slice(), &stored)?;
+                }
+            }
+        }
+        self.db.put_keyed_encodable(FILTER_PRUNED_KEY, &below)?;
+        debug!("pruned filters below height {}", below);
+        Ok(())
+    }
+
+    /// Store a downloaded block
+    pub fn store_block(&mut self, block: &Block) -> Result<(), Error> {
+        self.db.put_keyed_encodable(block_key(&block.bitcoin_hash()).as_slice(), block)?;
+        Ok(())
+    }
+
+    /// Read a downloaded block
+    pub fn fetch_block(&self, block_id: &sha256d::Hash) -> Result<Option<Block>, Error> {
+        Ok(self.db.get_keyed_decodable::<Block>(block_key(block_id).as_slice())?.map(|(_, b)| b))
+    }
+
+    /// Store the id of the block on trunk up to which matching blocks are downloaded
+    pub fn store_block_tip(&mut self, block_id: &sha256d::Hash) -> Result<(), Error> {
+        self.db.put_keyed_encodable(BLOCK_TIP_KEY, block_id)?;
+        Ok(())
+    }
+
+    /// Find the id of the block up to which matching blocks are downloaded
+    pub fn fetch_block_tip(&self) -> Result<Option<sha256d::Hash>, Error> {
+        Ok(self.db.get_keyed_decodable::<sha256d::Hash>(BLOCK_TIP_KEY)?.map(|(_, h)| h))
+    }
+}
+
+fn block_key(block_id: &sha256d::Hash) -> Vec<u8> {
+    let mut key = block_id[..].to_vec();
+    key.push(BLOCK_SUFFIX);
+    key
+}
+
+fn filter_key(block_id: &sha256d::Hash) -> Vec<u8> {
+    let mut key = block_id[..].to_vec();
+    key.push(FILTER_SUFFIX);
+    key
+}
+
+fn filter_header_key(block_id: &sha256d::Hash) -> Vec<u8> {
+    let mut key = block_id[..].to_vec();
+    key.push(FILTER_HEADER_SUFFIX);
+    key
+}
+
+/// Read-only access to the chain DB of a running node, e.g. for inspection tools or a second
+/// Constructor sharing the data. A second process can not open the DB, as the storage keeps
+/// state in memory and the data directory is locked while a node uses it.
+#[derive(Clone)]
+pub struct ChainView {
+    chaindb: SharedChainDB
+}
+
+impl ChainView {
+    /// read-only view of a chain DB
+    pub fn new(chaindb: SharedChainDB) -> ChainView {
+        ChainView { chaindb }
+    }
+
+    /// the header with most work
+    pub fn header_tip(&self) -> Option<CachedHeader> {
+        self.chaindb.read().recover().header_tip()
+    }
+
+    /// header by its id
+    pub fn get_header(&self, id: &sha256d::Hash) -> Option<CachedHeader> {
+        self.chaindb.read().recover().get_header(id)
+    }
+
+    /// header on trunk at height
+    pub fn get_header_for_height(&self, height: u32) -> Option<CachedHeader> {
+        self.chaindb.read().recover().get_header_for_height(height)
+    }
+
+    /// log2 of the total work up to and including the header, as stored with it
+    pub fn log2work(&self, id: &sha256d::Hash) -> Option<f64> {
+        self.get_header(id).map(|h| h.stored.log2work)
+    }
+
+    /// work the headers after from add up to and including to, e.g. to compare branches from their
+    /// fork point. None if either is unknown or to does not have more work than from.
+    pub fn work_between(&self, from: &sha256d::Hash, to: &sha256d::Hash) -> Option<f64> {
+        let chaindb = self.chaindb.read().recover();
+        let from = chaindb.get_header(from)?.stored.log2work;
+        let to = chaindb.get_header(to)?.stored.log2work;
+        if to > from {
+            Some(2f64.powf(to) - 2f64.powf(from))
+        } else {
+            None
+        }
+    }
+
+    /// (height, id) of the header with most work
+    pub fn tip(&self) -> Option<(u32, sha256d::Hash)> {
+        self.header_tip().map(|h| (h.stored.height, h.bitcoin_hash()))
+    }
+
+    /// median of the timestamps of the 11 blocks on trunk ending at height
+    pub fn median_time_past(&self, height: u32) -> Option<u32> {
+        self.chaindb.read().recover().median_time_past(height)
+    }
+
+    /// version bit signals of the trunk's retarget period containing height, counted up to height
+    pub fn signal_counts(&self, height: u32) -> Option<SignalCounts> {
+        self.chaindb.read().recover().signal_counts(height)
+    }
+
+    /// state of a version bits deployment for the block following the trunk tip
+    pub fn version_bits_state(&self, deployment: &Deployment) -> ThresholdState {
+        self.chaindb.read().recover().version_bits_state(deployment)
+    }
+
+    /// state of a version bits deployment for the block following the trunk tip, with progress of signalling
+    pub fn deployment_status(&self, deployment: &Deployment) -> DeploymentStatus {
+        self.chaindb.read().recover().deployment_status(deployment)
+    }
+
+    /// difficulty, hashrate and block interval over the last day of blocks on trunk
+    pub fn chain_stats(&self) -> Option<ChainStats> {
+        self.chaindb.read().recover().chain_stats()
+    }
+
+    /// Whether nLockTime and relative locks of a transaction allow it in the next block,
+    /// given the heights that confirmed the outputs it spends
+    pub fn lock_status(&self, tx: &Transaction, confirmed: &HashMap<OutPoint, u32>) -> LockStatus {
+        timelock::lock_status(&self.chaindb.read().recover(), tx, confirmed)
+    }
+
+    /// Verify that a transaction is in a block on trunk with the merkle branch of its position,
+    /// as served by e.g. Electrum servers. Returns the height of the block if proven.
+    pub fn verify_merkle_proof(&self, txid: &sha256d::Hash, block_id: &sha256d::Hash, position: usize, branch: &[sha256d::Hash]) -> Option<u32> {
+        let chaindb = self.chaindb.read().recover();
+        let height = chaindb.pos_on_trunk(block_id)?;
+        let header = chaindb.get_header(block_id)?;
+        let mut root = *txid;
+        let mut position = position;
+        for sibling in branch {
+            let mut engine = sha256d::Hash::engine();
+            if position & 1 == 0 {
+                engine.input(&root[..]);
+                engine.input(&sibling[..]);
+            } else {
+                engine.input(&sibling[..]);
+                engine.input(&root[..]);
+            }
+            root = sha256d::Hash::from_engine(engine);
+            position >>= 1;
+        }
+        if root == header.stored.header.merkle_root {
+            Some(height)
+        } else {
+            None
+        }
+    }
+
+    /// position of hash on trunk if on trunk
+    pub fn pos_on_trunk(&self, hash: &sha256d::Hash) -> Option<u32> {
+        self.chaindb.read().recover().pos_on_trunk(hash)
+    }
+
+    /// headers on trunk [from .. to]
+    pub fn trunk(&self, from: u32, to: u32) -> Vec<CachedHeader> {
+        self.chaindb.read().recover().iter_trunk(from).take_while(|h| h.stored.height <= to).cloned().collect()
+    }
+
+    /// filter header of a block
+    pub fn fetch_filter_header(&self, block_id: &sha256d::Hash) -> Result<Option<sha256d::Hash>, Error> {
+        self.chaindb.read().recover().fetch_filter_header(block_id)
+    }
+
+    /// filter of a block
+    pub fn fetch_filter(&self, block_id: &sha256d::Hash) -> Result<Option<StoredFilter>, Error> {
+        self.chaindb.read().recover().fetch_filter(block_id)
+    }
+
+    /// downloaded block
+    pub fn fetch_block(&self, block_id: &sha256d::Hash) -> Result<Option<Block>, Error> {
+        self.chaindb.read().recover().fetch_block(block_id)
+    }
 }
 
 /// A header enriched with information about its position on the blockchain
@@ -190,6 +890,60 @@ pub struct StoredHeader {
     pub log2work: f64
 }
 
+/// Depth of ChainDB::verify, each level includes the checks of those before
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord)]
+pub enum VerifyLevel {
+    /// linkage, heights and index of headers
+    Links,
+    /// proof of work of headers for the difficulty they claim
+    ProofOfWork,
+    /// stored blocks match their headers
+    Blocks
+}
+
+/// Outcome of ChainDB::verify
+#[derive(Clone, Debug)]
+pub struct VerifyReport {
+    /// number of headers found consistent
+    pub checked: u32,
+    /// height the trunk was truncated at, if corrupted
+    pub truncated_at: Option<u32>,
+    /// height from which blocks are downloaded again, if a stored block was corrupted
+    pub blocks_from: Option<u32>,
+    /// description of problems found
+    pub problems: Vec<String>
+}
+
+/// Statistics of the trunk, computed over a window of blocks ending at the tip
+#[derive(Clone, Debug)]
+pub struct ChainStats {
+    /// height of the tip
+    pub height: u32,
+    /// difficulty of the tip, relative to the minimum difficulty
+    pub difficulty: f64,
+    /// estimated network hashrate in hashes per second
+    pub hashrate: f64,
+    /// average seconds between blocks
+    pub block_interval: f64,
+    /// number of blocks the estimates are computed over
+    pub window: u32
+}
+
+// difficulty of compact target bits as Bitcoin Core computes it
+fn difficulty(bits: u32) -> f64 {
+    let mut shift = (bits >> 24) & 0xff;
+    let mut difficulty = 0x0000ffff as f64 / (bits & 0x00ffffff) as f64;
+    while shift < 29 {
+        difficulty *= 256.0;
+        shift += 1;
+    }
+    while shift > 29 {
+        difficulty /= 256.0;
+        shift -= 1;
+    }
+    difficulty
+}
+
 // need to implement if put_hash_keyed and get_hash_keyed should be used
 impl BitcoinHash for StoredHeader {
     fn bitcoin_hash(&self) -> sha256d::Hash {
@@ -197,6 +951,190 @@ impl BitcoinHash for StoredHeader {
     }
 }
 
+/// A downloaded BIP158 filter
+#[derive(Clone, Debug)]
+pub struct StoredFilter {
+    /// id of the block the filter is for
+    pub block_id: sha256d::Hash,
+    /// filter content, None if pruned
+    pub filter: Option<Vec<u8>>,
+    /// the filter matched scripts watched at the time of download
+    pub matched: bool
+}
+
+impl Encodable for StoredFilter {
+    fn consensus_encode<W: io::Write>(&self, mut w: W) -> Result<usize, encode::Error> {
+        let mut len = self.block_id.consensus_encode(&mut w)?;
+        len += self.matched.consensus_encode(&mut w)?;
+        match self.filter {
+            Some(ref filter) => {
+                len += true.consensus_encode(&mut w)?;
+                len += filter.consensus_encode(&mut w)?;
+            },
+            None => {
+                len += false.consensus_encode(&mut w)?;
+            }
+        }
+        Ok(len)
+    }
+}
+
+impl Decodable for StoredFilter {
+    fn consensus_decode<D: io::Read>(mut d: D) -> Result<StoredFilter, encode::Error> {
+        let block_id = Decodable::consensus_decode(&mut d)?;
+        let matched = Decodable::consensus_decode(&mut d)?;
+        let has_filter: bool = Decodable::consensus_decode(&mut d)?;
+        let filter = if has_filter { Some(Decodable::consensus_decode(&mut d)?) } else { None };
+        Ok(StoredFilter { block_id, filter, matched })
+    }
+}
+
+// headers of a chunk as fixed-size records. The previous hash is implied by the preceding
+// header, times are differences to it and difficulty bits are stored as runs.
+struct HeaderChunk {
+    // (number of headers, bits)
+    bits: Vec<(u32, u32)>,
+    // time of the first header
+    time: u32,
+    // (index, time) of headers whose time difference does not fit the record
+    times: Vec<(u32, u32)>,
+    records: Vec<CompactHeader>
+}
+
+struct CompactHeader {
+    version: i32,
+    merkle_root: sha256d::Hash,
+    // difference to the time of the previous header, TIME_ESCAPE if listed in times
+    time_delta: i16,
+    nonce: u32
+}
+
+const TIME_ESCAPE: i16 = i16::min_value();
+
+impl HeaderChunk {
+    fn new(headers: &[BlockHeader]) -> HeaderChunk {
+        let mut bits: Vec<(u32, u32)> = Vec::new();
+        let mut times = Vec::new();
+        let mut records = Vec::with_capacity(headers.len());
+        let mut prev_time = headers.first().map(|h| h.time).unwrap_or(0);
+        for (index, header) in headers.iter().enumerate() {
+            match bits.last_mut() {
+                Some((n, b)) if *b == header.bits => *n += 1,
+                _ => bits.push((1, header.bits))
+            }
+            let delta = header.time as i64 - prev_time as i64;
+            let time_delta = if delta > TIME_ESCAPE as i64 && delta <= i16::max_value() as i64 {
+                delta as i16
+            } else {
+                times.push((index as u32, header.time));
+                TIME_ESCAPE
+            };
+            prev_time = header.time;
+            records.push(CompactHeader { version: header.version, merkle_root: header.merkle_root, time_delta, nonce: header.nonce });
+        }
+        HeaderChunk { bits, time: headers.first().map(|h| h.time).unwrap_or(0), times, records }
+    }
+
+    // headers of the chunk following the header with id prev
+    fn headers(&self, prev: sha256d::Hash) -> Vec<BlockHeader> {
+        let mut bits = self.bits.iter().flat_map(|(n, b)| (0..*n).map(move |_| *b));
+        let mut times = self.times.iter();
+        let mut headers: Vec<BlockHeader> = Vec::with_capacity(self.records.len());
+        let mut prev_blockhash = prev;
+        let mut time = self.time;
+        for record in &self.records {
+            if record.time_delta == TIME_ESCAPE {
+                if let Some((_, t)) = times.next() {
+                    time = *t;
+                }
+            } else {
+                time = (time as i64 + record.time_delta as i64) as u32;
+            }
+            let header = BlockHeader {
+                version: record.version,
+                prev_blockhash,
+                merkle_root: record.merkle_root,
+                time,
+                bits: bits.next().unwrap_or(0),
+                nonce: record.nonce
+            };
+            prev_blockhash = header.bitcoin_hash();
+            headers.push(header);
+        }
+        headers
+    }
+}
+
+impl Encodable for HeaderChunk {
+    fn consensus_encode<W: io::Write>(&self, mut w: W) -> Result<usize, encode::Error> {
+        let mut len = VarInt(self.bits.len() as u64).consensus_encode(&mut w)?;
+        for (n, bits) in &self.bits {
+            len += n.consensus_encode(&mut w)?;
+            len += bits.consensus_encode(&mut w)?;
+        }
+        len += self.time.consensus_encode(&mut w)?;
+        len += VarInt(self.times.len() as u64).consensus_encode(&mut w)?;
+        for (index, time) in &self.times {
+            len += index.consensus_encode(&mut w)?;
+            len += time.consensus_encode(&mut w)?;
+        }
+        len += VarInt(self.records.len() as u64).consensus_encode(&mut w)?;
+        for record in &self.records {
+            len += record.version.consensus_encode(&mut w)?;
+            len += record.merkle_root.consensus_encode(&mut w)?;
+            len += record.time_delta.consensus_encode(&mut w)?;
+            len += record.nonce.consensus_encode(&mut w)?;
+        }
+        Ok(len)
+    }
+}
+
+impl Decodable for HeaderChunk {
+    fn consensus_decode<D: io::Read>(mut d: D) -> Result<HeaderChunk, encode::Error> {
+        let VarInt(n) = Decodable::consensus_decode(&mut d)?;
+        let mut bits = Vec::new();
+        for _ in 0..n {
+            bits.push((Decodable::consensus_decode(&mut d)?, Decodable::consensus_decode(&mut d)?));
+        }
+        let time = Decodable::consensus_decode(&mut d)?;
+        let VarInt(n) = Decodable::consensus_decode(&mut d)?;
+        let mut times = Vec::new();
+        for _ in 0..n {
+            times.push((Decodable::consensus_decode(&mut d)?, Decodable::consensus_decode(&mut d)?));
+        }
+        let VarInt(n) = Decodable::consensus_decode(&mut d)?;
+        let mut records = Vec::new();
+        for _ in 0..n {
+            records.push(CompactHeader {
+                version: Decodable::consensus_decode(&mut d)?,
+                merkle_root: Decodable::consensus_decode(&mut d)?,
+                time_delta: Decodable::consensus_decode(&mut d)?,
+                nonce: Decodable::consensus_decode(&mut d)?
+            });
+        }
+        Ok(HeaderChunk { bits, time, times, records })
+    }
+}
+
+fn header_chunk_key(index: u32) -> Vec<u8> {
+    let mut key = vec!(HEADER_CHUNK_PREFIX);
+    key.extend_from_slice(&index.to_be_bytes());
+    key
+}
+
+// version of the data layout, increase with a migration step if stored data changes
+const SCHEMA_VERSION: u32 = 2;
+
 const HEADER_TIP_KEY: &[u8] = &[0u8; 1];
+const FILTER_HEADER_TIP_KEY: &[u8] = &[1u8; 1];
+const FILTER_HEADER_SUFFIX: u8 = 1;
+const FILTER_PRUNED_KEY: &[u8] = &[2u8; 1];
+const FILTER_SUFFIX: u8 = 2;
+const FILTER_TIP_KEY: &[u8] = &[3u8; 1];
+const BLOCK_TIP_KEY: &[u8] = &[4u8; 1];
+const SCHEMA_VERSION_KEY: &[u8] = &[5u8; 1];
+const HEADER_CHUNKS_KEY: &[u8] = &[6u8; 1];
+const HEADER_CHUNK_PREFIX: u8 = 6;
+const BLOCK_SUFFIX: u8 = 3;
 
 
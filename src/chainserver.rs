@@ -0,0 +1,175 @@
+//
+// Copyright 2019 Tamas Blummer
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+// http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+//
+//!
+//! # Serve the chain
+//!
+//! Answer requests of other peers for the trunk while serving, so that blocks announced
+//! by this node can be followed.
+//!
+//! Clients predating headers first sync ask with getblocks for block inventory, then for the
+//! blocks. Only blocks downloaded for matching filters can be served, others are not found.
+//!
+
+use bitcoin::{
+    BitcoinHash,
+    network::{
+        message::NetworkMessage,
+        message_blockdata::{GetBlocksMessage, GetHeadersMessage, Inventory, InvType}
+    }
+};
+use bitcoin_hashes::sha256d::Hash as Sha256dHash;
+use chaindb::{ChainDB, SharedChainDB};
+use error::Error;
+use lock::Recover;
+use p2p::{P2PControlSender, PeerId, PeerMessage, PeerMessageReceiver, PeerMessageSender, SharedLocalAddress};
+use stats::SharedStatistics;
+use std::{
+    collections::HashMap,
+    sync::mpsc,
+    thread
+};
+use tracing::{Level, field::display};
+
+// most headers in a headers message
+const MAX_HEADERS: usize = 2000;
+// most blocks in an answer to getblocks
+const MAX_BLOCKS: usize = 500;
+
+pub struct ChainServer {
+    p2p: P2PControlSender<NetworkMessage>,
+    chaindb: SharedChainDB,
+    local: SharedLocalAddress,
+    statistics: SharedStatistics,
+    // last block of a getblocks answer by peer, the tip is announced once it is asked
+    continue_at: HashMap<PeerId, Sha256dHash>
+}
+
+impl ChainServer {
+    pub fn new(chaindb: SharedChainDB, p2p: P2PControlSender<NetworkMessage>, local: SharedLocalAddress, statistics: SharedStatistics) -> PeerMessageSender<NetworkMessage> {
+        let (sender, receiver) = mpsc::sync_channel(p2p.back_pressure);
+
+        let mut chainserver = ChainServer { p2p, chaindb, local, statistics, continue_at: HashMap::new() };
+
+        thread::Builder::new().name("chain server".to_string()).spawn(move || { chainserver.run(receiver) }).unwrap();
+
+        PeerMessageSender::new(sender)
+    }
+
+    fn run(&mut self, receiver: PeerMessageReceiver<NetworkMessage>) {
+        let span = span!(Level::INFO, "chain server");
+        let _enter = span.enter();
+        while let Ok(msg) = receiver.recv() {
+            if !self.local.is_server() {
+                continue;
+            }
+            if let PeerMessage::Disconnected(pid, _) = msg {
+                self.continue_at.remove(&pid);
+            }
+            else if let PeerMessage::Incoming(pid, msg) = msg {
+                let span = span!(Level::DEBUG, "peer", peer = display(pid));
+                let _enter = span.enter();
+                if let Err(e) = match msg {
+                    NetworkMessage::GetHeaders(ref get) => self.get_headers(get, pid),
+                    NetworkMessage::GetBlocks(ref get) => self.get_blocks(get, pid),
+                    NetworkMessage::GetData(ref inv) => self.get_data(inv, pid),
+                    _ => { Ok(()) }
+                } {
+                    error!("Error serving the chain: {}", e);
+                }
+            }
+        }
+    }
+
+    // send trunk headers following the first locator on trunk, up to the stop hash
+    fn get_headers(&mut self, get: &GetHeadersMessage, peer: PeerId) -> Result<(), Error> {
+        let chaindb = self.chaindb.read().recover();
+        let from = fork_point(&chaindb, &get.locator_hashes).map(|h| h + 1).unwrap_or(0);
+        let mut headers = Vec::new();
+        for header in chaindb.iter_trunk(from).take(MAX_HEADERS) {
+            headers.push(header.stored.header.clone());
+            if header.bitcoin_hash() == get.stop_hash {
+                break;
+            }
+        }
+        debug!("serve {} headers from height {} peer={}", headers.len(), from, peer);
+        self.p2p.send_network(peer, NetworkMessage::Headers(headers));
+        Ok(())
+    }
+
+    // send inventory of trunk blocks following the first locator on trunk, up to the stop hash
+    fn get_blocks(&mut self, get: &GetBlocksMessage, peer: PeerId) -> Result<(), Error> {
+        let chaindb = self.chaindb.read().recover();
+        let from = fork_point(&chaindb, &get.locator_hashes).map(|h| h + 1).unwrap_or(0);
+        let mut inv = Vec::new();
+        for header in chaindb.iter_trunk(from).take(MAX_BLOCKS) {
+            let id = header.bitcoin_hash();
+            inv.push(Inventory { inv_type: InvType::Block, hash: id });
+            if id == get.stop_hash {
+                break;
+            }
+        }
+        if inv.len() == MAX_BLOCKS {
+            // the peer asks for the last one when done with the batch
+            self.continue_at.insert(peer, inv[MAX_BLOCKS - 1].hash);
+        }
+        if !inv.is_empty() {
+            debug!("serve {} block inventory from height {} peer={}", inv.len(), from, peer);
+            self.p2p.send_network(peer, NetworkMessage::Inv(inv));
+        }
+        Ok(())
+    }
+
+    // send stored blocks, others are not found
+    fn get_data(&mut self, inv: &Vec<Inventory>, peer: PeerId) -> Result<(), Error> {
+        let mut not_found = Vec::new();
+        let mut continued = false;
+        {
+            let chaindb = self.chaindb.read().recover();
+            for item in inv {
+                if item.inv_type != InvType::Block && item.inv_type != InvType::WitnessBlock {
+                    continue;
+                }
+                match chaindb.fetch_block(&item.hash)? {
+                    Some(block) => {
+                        self.p2p.send_network(peer, NetworkMessage::Block(block));
+                        self.statistics.block_served();
+                    },
+                    None => not_found.push(item.clone())
+                }
+                if self.continue_at.get(&peer) == Some(&item.hash) {
+                    continued = true;
+                }
+            }
+        }
+        if !not_found.is_empty() {
+            debug!("{} blocks not found peer={}", not_found.len(), peer);
+            self.p2p.send_network(peer, NetworkMessage::NotFound(not_found));
+        }
+        if continued {
+            // trigger the next getblocks
+            self.continue_at.remove(&peer);
+            if let Some(tip) = self.chaindb.read().recover().header_tip() {
+                self.p2p.send_network(peer, NetworkMessage::Inv(vec!(Inventory { inv_type: InvType::Block, hash: tip.bitcoin_hash() })));
+            }
+        }
+        Ok(())
+    }
+}
+
+// height of the first locator on trunk, locators are ordered from the tip backwards
+fn fork_point(chaindb: &ChainDB, locator: &Vec<Sha256dHash>) -> Option<u32> {
+    locator.iter().filter_map(|hash| chaindb.pos_on_trunk(hash)).next()
+}
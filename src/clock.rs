@@ -0,0 +1,121 @@
+//
+// Copyright 2019 Tamas Blummer
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+// http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+//
+//!
+//! # Time and randomness
+//!
+//! Processors read time and randomness through these traits, from timeouts, rebroadcast, ping
+//! and trickle timers to the freshness of addresses, bans, bandwidth periods, the time in version
+//! messages and the age of the header tip. A node runs with the system clock and thread random,
+//! a simulation advances a virtual clock and seeds randomness, so that timeouts can be tested
+//! deterministically. A platform might supply its own clock, e.g. one resistant to clock jumps.
+//!
+
+use lock::Recover;
+use rand::{SeedableRng, thread_rng, rngs::StdRng};
+use std::{
+    sync::{Arc, Mutex},
+    time::{Duration, Instant, SystemTime, UNIX_EPOCH}
+};
+
+/// Shared source of time
+pub type SharedClock = Arc<dyn Clock>;
+/// Shared source of randomness
+pub type SharedRandom = Arc<dyn Random>;
+
+/// Source of time
+pub trait Clock: Send + Sync {
+    /// monotonic time, for timeouts and intervals
+    fn now(&self) -> Instant;
+    /// seconds since the unix epoch
+    fn unix_time(&self) -> u64;
+}
+
+/// Source of randomness
+pub trait Random: Send + Sync {
+    /// a generator for the next random choices
+    fn rng(&self) -> StdRng;
+}
+
+/// The system clock
+pub struct SystemClock;
+
+impl Clock for SystemClock {
+    fn now(&self) -> Instant {
+        Instant::now()
+    }
+
+    fn unix_time(&self) -> u64 {
+        SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_secs()
+    }
+}
+
+/// Randomness seeded from the thread's generator
+pub struct ThreadRandom;
+
+impl Random for ThreadRandom {
+    fn rng(&self) -> StdRng {
+        StdRng::from_rng(thread_rng()).expect("can not seed random")
+    }
+}
+
+/// A virtual clock that only moves if advanced
+pub struct SimulatedClock {
+    start: Instant,
+    epoch: u64,
+    elapsed: Mutex<Duration>
+}
+
+impl SimulatedClock {
+    /// a clock starting at the given unix time
+    pub fn new(epoch: u64) -> SimulatedClock {
+        SimulatedClock { start: Instant::now(), epoch, elapsed: Mutex::new(Duration::from_secs(0)) }
+    }
+
+    /// move the clock forward
+    pub fn advance(&self, by: Duration) {
+        *self.elapsed.lock().recover() += by;
+    }
+}
+
+impl Clock for SimulatedClock {
+    fn now(&self) -> Instant {
+        self.start + *self.elapsed.lock().recover()
+    }
+
+    fn unix_time(&self) -> u64 {
+        self.epoch + self.elapsed.lock().recover().as_secs()
+    }
+}
+
+/// Randomness repeating the same sequence of generators for the same seed
+pub struct SeededRandom {
+    seed: u64,
+    next: Mutex<u64>
+}
+
+impl SeededRandom {
+    pub fn new(seed: u64) -> SeededRandom {
+        SeededRandom { seed, next: Mutex::new(0) }
+    }
+}
+
+impl Random for SeededRandom {
+    fn rng(&self) -> StdRng {
+        let mut next = self.next.lock().recover();
+        *next += 1;
+        StdRng::seed_from_u64(self.seed.wrapping_add(*next))
+    }
+}
@@ -0,0 +1,816 @@
+//
+// Copyright 2019 Tamas Blummer
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+// http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+//
+//!
+//! # Configuration DB for a node
+//!
+//! Persists the node's state that is not part of the block chain, also metadata of wallet objects
+//! such as labels, notes and origin, so that wallet frontends need no database of their own.
+//!
+
+use bitcoin::{
+    blockdata::{
+        script::Script,
+        transaction::OutPoint
+    },
+    consensus::{Decodable, Encodable, deserialize, serialize, encode::{self, VarInt}}
+};
+use bitcoin_hashes::sha256d;
+use addressbook::KnownAddress;
+use error::Error;
+use headerdownload::{InvalidHeader, PeerHeight};
+use p2p::{Disconnect, Reputation};
+use payment::PaymentRequest;
+use scheduler::Scheduled;
+use spendwatch::ExpectedSpend;
+use stats::DayStats;
+use wallet::UnconfirmedTx;
+use hammersbald::{
+    BitcoinAdaptor, HammersbaldAPI, persistent,
+    transient,
+};
+use std::{
+    collections::{BTreeMap, HashSet},
+    io,
+    path::Path,
+    sync::{Arc, RwLock}
+};
+
+/// Shared handle to a database storing the node's configuration
+/// protected by an RwLock
+pub type SharedConfigDB = Arc<RwLock<ConfigDB>>;
+
+/// Database storing the node's configuration
+pub struct ConfigDB {
+    db: BitcoinAdaptor
+}
+
+impl ConfigDB {
+    /// Create an in-memory database instance
+    pub fn mem() -> Result<ConfigDB, Error> {
+        info!("working with in memory config db");
+        let db = BitcoinAdaptor::new(transient(2)?);
+        let mut configdb = ConfigDB { db };
+        configdb.migrate()?;
+        Ok(configdb)
+    }
+
+    /// Create an in-memory database instance with the content of a blob created by export
+    pub fn mem_from(blob: &[u8]) -> Result<ConfigDB, Error> {
+        info!("working with in memory config db from an exported state");
+        let mut db = BitcoinAdaptor::new(transient(2)?);
+        import_db(&mut db, blob)?;
+        let mut configdb = ConfigDB { db };
+        configdb.migrate()?;
+        Ok(configdb)
+    }
+
+    /// Create or open a persistent database instance identified by the path
+    pub fn new(path: &Path) -> Result<ConfigDB, Error> {
+        let basename = path.to_str().unwrap().to_string();
+        let db = BitcoinAdaptor::new(persistent((basename.clone()).as_str(), 100, 2)?);
+        let mut configdb = ConfigDB { db };
+        configdb.migrate()?;
+        Ok(configdb)
+    }
+
+    // upgrade data stored by earlier versions to the current schema
+    fn migrate(&mut self) -> Result<(), Error> {
+        let mut version = self.db.get_keyed_decodable::<u32>(SCHEMA_VERSION_KEY)?.map(|(_, v)| v).unwrap_or(0);
+        if version > SCHEMA_VERSION {
+            return Err(Error::UnsupportedVersion(version));
+        }
+        while version < SCHEMA_VERSION {
+            // version 0 had the layout of version 1, it just did not record its version.
+            // Steps converting data of a version to the next go here.
+            version += 1;
+            info!("migrated config db to schema version {}", version);
+            self.db.put_keyed_encodable(SCHEMA_VERSION_KEY, &version)?;
+            self.db.batch()?;
+        }
+        Ok(())
+    }
+
+    /// Batch updates. Updates are permanent after finishing a batch.
+    pub fn batch(&mut self) -> Result<(), Error> {
+        self.db.batch()?;
+        Ok(())
+    }
+
+    /// Write a consistent copy to a new persistent database at path while the node is running.
+    /// Pending updates are batched first, the copy holds the state of that batch boundary.
+    pub fn backup(&mut self, path: &Path) -> Result<(), Error> {
+        self.batch()?;
+        backup_db(&self.db, path)
+    }
+
+    /// Serialize the content at a batch boundary, e.g. to resume an in-memory node with mem_from
+    pub fn export(&mut self) -> Result<Vec<u8>, Error> {
+        self.batch()?;
+        export_db(&self.db)
+    }
+
+    /// Store the set of watched transactions and outpoints
+    pub fn store_watched(&mut self, watched: &Watched) -> Result<(), Error> {
+        self.db.put_keyed_encodable(WATCHED_KEY, watched)?;
+        Ok(())
+    }
+
+    /// Fetch the set of watched transactions and outpoints
+    pub fn fetch_watched(&self) -> Result<Watched, Error> {
+        Ok(self.db.get_keyed_decodable::<Watched>(WATCHED_KEY)?.map(|(_, w)| w).unwrap_or_default())
+    }
+
+    /// Store reputation of misbehaving peers
+    pub fn store_reputations(&mut self, reputations: Vec<Reputation>) -> Result<(), Error> {
+        self.db.put_keyed_encodable(REPUTATIONS_KEY, &Reputations(reputations))?;
+        Ok(())
+    }
+
+    /// Fetch reputation of misbehaving peers
+    pub fn fetch_reputations(&self) -> Result<Vec<Reputation>, Error> {
+        Ok(self.db.get_keyed_decodable::<Reputations>(REPUTATIONS_KEY)?.map(|(_, r)| r.0).unwrap_or_default())
+    }
+
+    /// Store recent disconnects of peers
+    pub fn store_disconnects(&mut self, disconnects: Vec<Disconnect>) -> Result<(), Error> {
+        self.db.put_keyed_encodable(DISCONNECTS_KEY, &Disconnects(disconnects))?;
+        Ok(())
+    }
+
+    /// Fetch recent disconnects of peers
+    pub fn fetch_disconnects(&self) -> Result<Vec<Disconnect>, Error> {
+        Ok(self.db.get_keyed_decodable::<Disconnects>(DISCONNECTS_KEY)?.map(|(_, d)| d.0).unwrap_or_default())
+    }
+
+    /// Store transactions waiting for scheduled broadcast
+    pub fn store_scheduled(&mut self, scheduled: Vec<Scheduled>) -> Result<(), Error> {
+        self.db.put_keyed_encodable(SCHEDULED_KEY, &ScheduledList(scheduled))?;
+        Ok(())
+    }
+
+    /// Fetch transactions waiting for scheduled broadcast
+    pub fn fetch_scheduled(&self) -> Result<Vec<Scheduled>, Error> {
+        Ok(self.db.get_keyed_decodable::<ScheduledList>(SCHEDULED_KEY)?.map(|(_, s)| s.0).unwrap_or_default())
+    }
+
+    /// Store transactions expected to spend their inputs
+    pub fn store_expected_spends(&mut self, expected: Vec<ExpectedSpend>) -> Result<(), Error> {
+        self.db.put_keyed_encodable(EXPECTED_SPENDS_KEY, &ExpectedSpends(expected))?;
+        Ok(())
+    }
+
+    /// Fetch transactions expected to spend their inputs
+    pub fn fetch_expected_spends(&self) -> Result<Vec<ExpectedSpend>, Error> {
+        Ok(self.db.get_keyed_decodable::<ExpectedSpends>(EXPECTED_SPENDS_KEY)?.map(|(_, e)| e.0).unwrap_or_default())
+    }
+
+    /// Store addresses of peers
+    pub fn store_addresses(&mut self, addresses: Vec<KnownAddress>) -> Result<(), Error> {
+        self.db.put_keyed_encodable(ADDRESSES_KEY, &Addresses(addresses))?;
+        Ok(())
+    }
+
+    /// Fetch addresses of peers
+    pub fn fetch_addresses(&self) -> Result<Vec<KnownAddress>, Error> {
+        Ok(self.db.get_keyed_decodable::<Addresses>(ADDRESSES_KEY)?.map(|(_, a)| a.0).unwrap_or_default())
+    }
+
+    /// Store the scripts the outputs of the wallet pay to, the default wallet is named ""
+    pub fn store_wallet_scripts(&mut self, wallet: &str, scripts: Vec<Script>) -> Result<(), Error> {
+        self.db.put_keyed_encodable(wallet_key(WALLET_SCRIPTS_KEY, wallet).as_slice(), &Scripts(scripts))?;
+        Ok(())
+    }
+
+    /// Fetch the scripts the outputs of the wallet pay to, the default wallet is named ""
+    pub fn fetch_wallet_scripts(&self, wallet: &str) -> Result<Vec<Script>, Error> {
+        Ok(self.db.get_keyed_decodable::<Scripts>(wallet_key(WALLET_SCRIPTS_KEY, wallet).as_slice())?.map(|(_, s)| s.0).unwrap_or_default())
+    }
+
+    /// Store unconfirmed transactions of the wallet, the default wallet is named ""
+    pub fn store_unconfirmed(&mut self, wallet: &str, txs: Vec<UnconfirmedTx>) -> Result<(), Error> {
+        self.db.put_keyed_encodable(wallet_key(UNCONFIRMED_KEY, wallet).as_slice(), &UnconfirmedTxs(txs))?;
+        Ok(())
+    }
+
+    /// Fetch unconfirmed transactions of the wallet, the default wallet is named ""
+    pub fn fetch_unconfirmed(&self, wallet: &str) -> Result<Vec<UnconfirmedTx>, Error> {
+        Ok(self.db.get_keyed_decodable::<UnconfirmedTxs>(wallet_key(UNCONFIRMED_KEY, wallet).as_slice())?.map(|(_, t)| t.0).unwrap_or_default())
+    }
+
+    /// Store the names of wallets besides the default one
+    pub fn store_wallet_names(&mut self, names: Vec<String>) -> Result<(), Error> {
+        self.db.put_keyed_encodable(WALLET_NAMES_KEY, &Names(names))?;
+        Ok(())
+    }
+
+    /// Fetch the names of wallets besides the default one
+    pub fn fetch_wallet_names(&self) -> Result<Vec<String>, Error> {
+        Ok(self.db.get_keyed_decodable::<Names>(WALLET_NAMES_KEY)?.map(|(_, n)| n.0).unwrap_or_default())
+    }
+
+    /// Fetch the scripts of all wallets
+    pub fn fetch_all_wallet_scripts(&self) -> Result<Vec<Script>, Error> {
+        let mut scripts = self.fetch_wallet_scripts("")?;
+        for name in self.fetch_wallet_names()? {
+            scripts.extend(self.fetch_wallet_scripts(name.as_str())?);
+        }
+        Ok(scripts)
+    }
+
+    /// Store payments awaited
+    pub fn store_payment_requests(&mut self, requests: Vec<PaymentRequest>) -> Result<(), Error> {
+        self.db.put_keyed_encodable(PAYMENT_REQUESTS_KEY, &PaymentRequests(requests))?;
+        Ok(())
+    }
+
+    /// Fetch payments awaited
+    pub fn fetch_payment_requests(&self) -> Result<Vec<PaymentRequest>, Error> {
+        Ok(self.db.get_keyed_decodable::<PaymentRequests>(PAYMENT_REQUESTS_KEY)?.map(|(_, p)| p.0).unwrap_or_default())
+    }
+
+    /// Store heights peers announced
+    pub fn store_peer_heights(&mut self, heights: Vec<PeerHeight>) -> Result<(), Error> {
+        self.db.put_keyed_encodable(PEER_HEIGHTS_KEY, &PeerHeights(heights))?;
+        Ok(())
+    }
+
+    /// Read heights peers announced
+    pub fn fetch_peer_heights(&self) -> Result<Vec<PeerHeight>, Error> {
+        Ok(self.db.get_keyed_decodable::<PeerHeights>(PEER_HEIGHTS_KEY)?.map(|(_, h)| h.0).unwrap_or_default())
+    }
+
+    /// Store headers of invalid branches
+    pub fn store_invalid_headers(&mut self, invalid: Vec<InvalidHeader>) -> Result<(), Error> {
+        self.db.put_keyed_encodable(INVALID_HEADERS_KEY, &InvalidHeaders(invalid))?;
+        Ok(())
+    }
+
+    /// Read headers of invalid branches
+    pub fn fetch_invalid_headers(&self) -> Result<Vec<InvalidHeader>, Error> {
+        Ok(self.db.get_keyed_decodable::<InvalidHeaders>(INVALID_HEADERS_KEY)?.map(|(_, h)| h.0).unwrap_or_default())
+    }
+
+    /// Store service statistics by day
+    pub fn store_statistics(&mut self, history: Vec<DayStats>) -> Result<(), Error> {
+        self.db.put_keyed_encodable(STATISTICS_KEY, &Statistics(history))?;
+        Ok(())
+    }
+
+    /// Read service statistics by day, oldest first
+    pub fn fetch_statistics(&self) -> Result<Vec<DayStats>, Error> {
+        Ok(self.db.get_keyed_decodable::<Statistics>(STATISTICS_KEY)?.map(|(_, s)| s.0).unwrap_or_default())
+    }
+
+    /// Store metadata of a wallet object, replacing what was stored before. Empty metadata
+    /// removes the object from fetch_all_metadata.
+    pub fn store_metadata(&mut self, object: &MetadataKey, metadata: &Metadata) -> Result<(), Error> {
+        self.db.put_keyed_encodable(object.key().as_slice(), &MetadataMap(metadata.clone()))?;
+        Ok(())
+    }
+
+    /// Fetch metadata of a wallet object, empty if none was stored
+    pub fn fetch_metadata(&self, object: &MetadataKey) -> Result<Metadata, Error> {
+        Ok(self.db.get_keyed_decodable::<MetadataMap>(object.key().as_slice())?.map(|(_, m)| m.0).unwrap_or_default())
+    }
+
+    /// Fetch metadata of all wallet objects that have some
+    pub fn fetch_all_metadata(&self) -> Result<Vec<(MetadataKey, Metadata)>, Error> {
+        let mut all = Vec::new();
+        for (key, data) in records(&self.db)? {
+            if let Some(object) = MetadataKey::from_key(key.as_slice()) {
+                let metadata = deserialize::<MetadataMap>(data.as_slice())?.0;
+                if !metadata.is_empty() {
+                    all.push((object, metadata));
+                }
+            }
+        }
+        Ok(all)
+    }
+}
+
+// the current value of every key
+fn records(db: &BitcoinAdaptor) -> Result<Vec<(Vec<u8>, Vec<u8>)>, Error> {
+    // the store is append only, a key might occur several times, take its latest value once
+    let mut seen = HashSet::new();
+    let mut records = Vec::new();
+    for (_, key, _) in db.iter() {
+        if seen.insert(key.clone()) {
+            if let Some((_, data)) = db.get_keyed(key.as_slice())? {
+                records.push((key, data));
+            }
+        }
+    }
+    Ok(records)
+}
+
+/// Copy the current value of every key to a new persistent database at path.
+/// The caller holds the database still, e.g. through a read lock of its owner.
+pub fn backup_db(db: &BitcoinAdaptor, path: &Path) -> Result<(), Error> {
+    let basename = path.to_str().unwrap().to_string();
+    let mut target = persistent(basename.as_str(), 100, 2)?;
+    if target.iter().next().is_some() {
+        target.shutdown();
+        return Err(Error::IO(io::Error::new(io::ErrorKind::AlreadyExists, format!("backup target {} is not empty", basename))));
+    }
+    let records = records(db)?;
+    for (key, data) in &records {
+        target.put_keyed(key.as_slice(), data.as_slice())?;
+    }
+    target.batch()?;
+    target.shutdown();
+    info!("backed up {} records to {}", records.len(), basename);
+    Ok(())
+}
+
+/// Serialize the current value of every key, to be read back with import_db
+pub fn export_db(db: &BitcoinAdaptor) -> Result<Vec<u8>, Error> {
+    Ok(encode::serialize(&Records(records(db)?)))
+}
+
+/// Store the records of a blob created by export_db
+pub fn import_db(db: &mut BitcoinAdaptor, blob: &[u8]) -> Result<(), Error> {
+    let Records(records) = encode::deserialize(blob)?;
+    for (key, data) in &records {
+        db.put_keyed(key.as_slice(), data.as_slice())?;
+    }
+    db.batch()?;
+    Ok(())
+}
+
+// key and value pairs of an exported database
+struct Records(Vec<(Vec<u8>, Vec<u8>)>);
+
+impl Encodable for Records {
+    fn consensus_encode<W: io::Write>(&self, mut w: W) -> Result<usize, encode::Error> {
+        let mut len = VarInt(self.0.len() as u64).consensus_encode(&mut w)?;
+        for (key, data) in &self.0 {
+            len += key.consensus_encode(&mut w)?;
+            len += data.consensus_encode(&mut w)?;
+        }
+        Ok(len)
+    }
+}
+
+impl Decodable for Records {
+    fn consensus_decode<D: io::Read>(mut d: D) -> Result<Records, encode::Error> {
+        let VarInt(n) = Decodable::consensus_decode(&mut d)?;
+        let mut records = Vec::new();
+        for _ in 0..n {
+            records.push((Decodable::consensus_decode(&mut d)?, Decodable::consensus_decode(&mut d)?));
+        }
+        Ok(Records(records))
+    }
+}
+
+/// name of a wallet object for the user
+pub const LABEL: &str = "label";
+/// free text of the user about a wallet object
+pub const NOTE: &str = "note";
+/// where a wallet object came from, e.g. the payer or the software that created it
+pub const ORIGIN: &str = "origin";
+
+/// Metadata of a wallet object by name, e.g. LABEL, NOTE, ORIGIN
+pub type Metadata = BTreeMap<String, String>;
+
+/// A wallet object metadata is attached to
+#[derive(Clone, Debug, PartialEq, Eq, Hash)]
+pub enum MetadataKey {
+    /// a transaction
+    Tx(sha256d::Hash),
+    /// an address, as the script it pays to
+    Address(Script),
+    /// an output
+    Output(OutPoint)
+}
+
+impl MetadataKey {
+    // key in the db, its kind and id behind a common prefix
+    fn key(&self) -> Vec<u8> {
+        let mut key = METADATA_KEY.to_vec();
+        match *self {
+            MetadataKey::Tx(ref txid) => { key.push(0); key.extend(serialize(txid)); },
+            MetadataKey::Address(ref script) => { key.push(1); key.extend(serialize(script)); },
+            MetadataKey::Output(ref outpoint) => { key.push(2); key.extend(serialize(outpoint)); }
+        }
+        key
+    }
+
+    // None if the key is not of metadata
+    fn from_key(key: &[u8]) -> Option<MetadataKey> {
+        if key.len() < 2 || !key.starts_with(METADATA_KEY) {
+            return None;
+        }
+        let id = &key[2..];
+        match key[1] {
+            0 => deserialize(id).ok().map(MetadataKey::Tx),
+            1 => deserialize(id).ok().map(MetadataKey::Address),
+            2 => deserialize(id).ok().map(MetadataKey::Output),
+            _ => None
+        }
+    }
+}
+
+struct MetadataMap(Metadata);
+
+impl Encodable for MetadataMap {
+    fn consensus_encode<W: io::Write>(&self, mut w: W) -> Result<usize, encode::Error> {
+        let mut len = VarInt(self.0.len() as u64).consensus_encode(&mut w)?;
+        for (name, value) in &self.0 {
+            len += name.consensus_encode(&mut w)?;
+            len += value.consensus_encode(&mut w)?;
+        }
+        Ok(len)
+    }
+}
+
+impl Decodable for MetadataMap {
+    fn consensus_decode<D: io::Read>(mut d: D) -> Result<MetadataMap, encode::Error> {
+        let VarInt(n) = Decodable::consensus_decode(&mut d)?;
+        let mut metadata = Metadata::new();
+        for _ in 0..n {
+            metadata.insert(Decodable::consensus_decode(&mut d)?, Decodable::consensus_decode(&mut d)?);
+        }
+        Ok(MetadataMap(metadata))
+    }
+}
+
+/// Transactions and outpoints the application asked to watch
+#[derive(Clone, Default)]
+pub struct Watched {
+    /// transaction ids with the script they pay to
+    pub txs: Vec<(sha256d::Hash, Script)>,
+    /// outpoints with the script they pay to
+    pub outpoints: Vec<(OutPoint, Script)>
+}
+
+impl Watched {
+    /// add a transaction, returns false if it was already watched
+    pub fn add_tx(&mut self, txid: &sha256d::Hash, script: &Script) -> bool {
+        if self.txs.iter().any(|(t, s)| t == txid && s == script) {
+            return false;
+        }
+        self.txs.push((txid.clone(), script.clone()));
+        true
+    }
+
+    /// add an outpoint, returns false if it was already watched
+    pub fn add_outpoint(&mut self, outpoint: &OutPoint, script: &Script) -> bool {
+        if self.outpoints.iter().any(|(o, s)| o == outpoint && s == script) {
+            return false;
+        }
+        self.outpoints.push((outpoint.clone(), script.clone()));
+        true
+    }
+}
+
+impl Encodable for Watched {
+    fn consensus_encode<W: io::Write>(&self, mut w: W) -> Result<usize, encode::Error> {
+        let mut len = VarInt(self.txs.len() as u64).consensus_encode(&mut w)?;
+        for (txid, script) in &self.txs {
+            len += txid.consensus_encode(&mut w)?;
+            len += script.consensus_encode(&mut w)?;
+        }
+        len += VarInt(self.outpoints.len() as u64).consensus_encode(&mut w)?;
+        for (outpoint, script) in &self.outpoints {
+            len += outpoint.consensus_encode(&mut w)?;
+            len += script.consensus_encode(&mut w)?;
+        }
+        Ok(len)
+    }
+}
+
+impl Decodable for Watched {
+    fn consensus_decode<D: io::Read>(mut d: D) -> Result<Watched, encode::Error> {
+        let mut watched = Watched::default();
+        let VarInt(n) = Decodable::consensus_decode(&mut d)?;
+        for _ in 0..n {
+            let txid: sha256d::Hash = Decodable::consensus_decode(&mut d)?;
+            let script: Script = Decodable::consensus_decode(&mut d)?;
+            watched.txs.push((txid, script));
+        }
+        let VarInt(n) = Decodable::consensus_decode(&mut d)?;
+        for _ in 0..n {
+            let outpoint: OutPoint = Decodable::consensus_decode(&mut d)?;
+            let script: Script = Decodable::consensus_decode(&mut d)?;
+            watched.outpoints.push((outpoint, script));
+        }
+        Ok(watched)
+    }
+}
+
+struct Reputations(Vec<Reputation>);
+
+impl Encodable for Reputations {
+    fn consensus_encode<W: io::Write>(&self, mut w: W) -> Result<usize, encode::Error> {
+        let mut len = VarInt(self.0.len() as u64).consensus_encode(&mut w)?;
+        for reputation in &self.0 {
+            len += reputation.consensus_encode(&mut w)?;
+        }
+        Ok(len)
+    }
+}
+
+impl Decodable for Reputations {
+    fn consensus_decode<D: io::Read>(mut d: D) -> Result<Reputations, encode::Error> {
+        let VarInt(n) = Decodable::consensus_decode(&mut d)?;
+        let mut reputations = Vec::new();
+        for _ in 0..n {
+            reputations.push(Decodable::consensus_decode(&mut d)?);
+        }
+        Ok(Reputations(reputations))
+    }
+}
+
+struct Disconnects(Vec<Disconnect>);
+
+impl Encodable for Disconnects {
+    fn consensus_encode<W: io::Write>(&self, mut w: W) -> Result<usize, encode::Error> {
+        let mut len = VarInt(self.0.len() as u64).consensus_encode(&mut w)?;
+        for disconnect in &self.0 {
+            len += disconnect.consensus_encode(&mut w)?;
+        }
+        Ok(len)
+    }
+}
+
+impl Decodable for Disconnects {
+    fn consensus_decode<D: io::Read>(mut d: D) -> Result<Disconnects, encode::Error> {
+        let VarInt(n) = Decodable::consensus_decode(&mut d)?;
+        let mut disconnects = Vec::new();
+        for _ in 0..n {
+            disconnects.push(Decodable::consensus_decode(&mut d)?);
+        }
+        Ok(Disconnects(disconnects))
+    }
+}
+
+struct ScheduledList(Vec<Scheduled>);
+
+impl Encodable for ScheduledList {
+    fn consensus_encode<W: io::Write>(&self, mut w: W) -> Result<usize, encode::Error> {
+        let mut len = VarInt(self.0.len() as u64).consensus_encode(&mut w)?;
+        for scheduled in &self.0 {
+            len += scheduled.consensus_encode(&mut w)?;
+        }
+        Ok(len)
+    }
+}
+
+impl Decodable for ScheduledList {
+    fn consensus_decode<D: io::Read>(mut d: D) -> Result<ScheduledList, encode::Error> {
+        let VarInt(n) = Decodable::consensus_decode(&mut d)?;
+        let mut scheduled = Vec::new();
+        for _ in 0..n {
+            scheduled.push(Decodable::consensus_decode(&mut d)?);
+        }
+        Ok(ScheduledList(scheduled))
+    }
+}
+
+struct Addresses(Vec<KnownAddress>);
+
+impl Encodable for Addresses {
+    fn consensus_encode<W: io::Write>(&self, mut w: W) -> Result<usize, encode::Error> {
+        let mut len = VarInt(self.0.len() as u64).consensus_encode(&mut w)?;
+        for address in &self.0 {
+            len += address.consensus_encode(&mut w)?;
+        }
+        Ok(len)
+    }
+}
+
+impl Decodable for Addresses {
+    fn consensus_decode<D: io::Read>(mut d: D) -> Result<Addresses, encode::Error> {
+        let VarInt(n) = Decodable::consensus_decode(&mut d)?;
+        let mut addresses = Vec::new();
+        for _ in 0..n {
+            addresses.push(Decodable::consensus_decode(&mut d)?);
+        }
+        Ok(Addresses(addresses))
+    }
+}
+
+struct Scripts(Vec<Script>);
+
+impl Encodable for Scripts {
+    fn consensus_encode<W: io::Write>(&self, mut w: W) -> Result<usize, encode::Error> {
+        let mut len = VarInt(self.0.len() as u64).consensus_encode(&mut w)?;
+        for script in &self.0 {
+            len += script.consensus_encode(&mut w)?;
+        }
+        Ok(len)
+    }
+}
+
+impl Decodable for Scripts {
+    fn consensus_decode<D: io::Read>(mut d: D) -> Result<Scripts, encode::Error> {
+        let VarInt(n) = Decodable::consensus_decode(&mut d)?;
+        let mut scripts = Vec::new();
+        for _ in 0..n {
+            scripts.push(Decodable::consensus_decode(&mut d)?);
+        }
+        Ok(Scripts(scripts))
+    }
+}
+
+// the key of a wallet's data, scripts of the default wallet are at the key of earlier versions
+fn wallet_key(prefix: &[u8], wallet: &str) -> Vec<u8> {
+    let mut key = prefix.to_vec();
+    key.extend(wallet.as_bytes());
+    key
+}
+
+struct Names(Vec<String>);
+
+impl Encodable for Names {
+    fn consensus_encode<W: io::Write>(&self, mut w: W) -> Result<usize, encode::Error> {
+        let mut len = VarInt(self.0.len() as u64).consensus_encode(&mut w)?;
+        for name in &self.0 {
+            len += name.consensus_encode(&mut w)?;
+        }
+        Ok(len)
+    }
+}
+
+impl Decodable for Names {
+    fn consensus_decode<D: io::Read>(mut d: D) -> Result<Names, encode::Error> {
+        let VarInt(n) = Decodable::consensus_decode(&mut d)?;
+        let mut names = Vec::new();
+        for _ in 0..n {
+            names.push(Decodable::consensus_decode(&mut d)?);
+        }
+        Ok(Names(names))
+    }
+}
+
+struct PaymentRequests(Vec<PaymentRequest>);
+
+impl Encodable for PaymentRequests {
+    fn consensus_encode<W: io::Write>(&self, mut w: W) -> Result<usize, encode::Error> {
+        let mut len = VarInt(self.0.len() as u64).consensus_encode(&mut w)?;
+        for request in &self.0 {
+            len += request.consensus_encode(&mut w)?;
+        }
+        Ok(len)
+    }
+}
+
+impl Decodable for PaymentRequests {
+    fn consensus_decode<D: io::Read>(mut d: D) -> Result<PaymentRequests, encode::Error> {
+        let VarInt(n) = Decodable::consensus_decode(&mut d)?;
+        let mut requests = Vec::new();
+        for _ in 0..n {
+            requests.push(Decodable::consensus_decode(&mut d)?);
+        }
+        Ok(PaymentRequests(requests))
+    }
+}
+
+struct ExpectedSpends(Vec<ExpectedSpend>);
+
+impl Encodable for ExpectedSpends {
+    fn consensus_encode<W: io::Write>(&self, mut w: W) -> Result<usize, encode::Error> {
+        let mut len = VarInt(self.0.len() as u64).consensus_encode(&mut w)?;
+        for spend in &self.0 {
+            len += spend.consensus_encode(&mut w)?;
+        }
+        Ok(len)
+    }
+}
+
+impl Decodable for ExpectedSpends {
+    fn consensus_decode<D: io::Read>(mut d: D) -> Result<ExpectedSpends, encode::Error> {
+        let VarInt(n) = Decodable::consensus_decode(&mut d)?;
+        let mut expected = Vec::new();
+        for _ in 0..n {
+            expected.push(Decodable::consensus_decode(&mut d)?);
+        }
+        Ok(ExpectedSpends(expected))
+    }
+}
+
+struct UnconfirmedTxs(Vec<UnconfirmedTx>);
+
+impl Encodable for UnconfirmedTxs {
+    fn consensus_encode<W: io::Write>(&self, mut w: W) -> Result<usize, encode::Error> {
+        let mut len = VarInt(self.0.len() as u64).consensus_encode(&mut w)?;
+        for tx in &self.0 {
+            len += tx.consensus_encode(&mut w)?;
+        }
+        Ok(len)
+    }
+}
+
+impl Decodable for UnconfirmedTxs {
+    fn consensus_decode<D: io::Read>(mut d: D) -> Result<UnconfirmedTxs, encode::Error> {
+        let VarInt(n) = Decodable::consensus_decode(&mut d)?;
+        let mut txs = Vec::new();
+        for _ in 0..n {
+            txs.push(Decodable::consensus_decode(&mut d)?);
+        }
+        Ok(UnconfirmedTxs(txs))
+    }
+}
+
+struct PeerHeights(Vec<PeerHeight>);
+
+impl Encodable for PeerHeights {
+    fn consensus_encode<W: io::Write>(&self, mut w: W) -> Result<usize, encode::Error> {
+        let mut len = VarInt(self.0.len() as u64).consensus_encode(&mut w)?;
+        for height in &self.0 {
+            len += height.consensus_encode(&mut w)?;
+        }
+        Ok(len)
+    }
+}
+
+impl Decodable for PeerHeights {
+    fn consensus_decode<D: io::Read>(mut d: D) -> Result<PeerHeights, encode::Error> {
+        let VarInt(n) = Decodable::consensus_decode(&mut d)?;
+        let mut heights = Vec::new();
+        for _ in 0..n {
+            heights.push(Decodable::consensus_decode(&mut d)?);
+        }
+        Ok(PeerHeights(heights))
+    }
+}
+
+struct InvalidHeaders(Vec<InvalidHeader>);
+
+impl Encodable for InvalidHeaders {
+    fn consensus_encode<W: io::Write>(&self, mut w: W) -> Result<usize, encode::Error> {
+        let mut len = VarInt(self.0.len() as u64).consensus_encode(&mut w)?;
+        for header in &self.0 {
+            len += header.consensus_encode(&mut w)?;
+        }
+        Ok(len)
+    }
+}
+
+impl Decodable for InvalidHeaders {
+    fn consensus_decode<D: io::Read>(mut d: D) -> Result<InvalidHeaders, encode::Error> {
+        let VarInt(n) = Decodable::consensus_decode(&mut d)?;
+        let mut headers = Vec::new();
+        for _ in 0..n {
+            headers.push(Decodable::consensus_decode(&mut d)?);
+        }
+        Ok(InvalidHeaders(headers))
+    }
+}
+
+struct Statistics(Vec<DayStats>);
+
+impl Encodable for Statistics {
+    fn consensus_encode<W: io::Write>(&self, mut w: W) -> Result<usize, encode::Error> {
+        let mut len = VarInt(self.0.len() as u64).consensus_encode(&mut w)?;
+        for day in &self.0 {
+            len += day.consensus_encode(&mut w)?;
+        }
+        Ok(len)
+    }
+}
+
+impl Decodable for Statistics {
+    fn consensus_decode<D: io::Read>(mut d: D) -> Result<Statistics, encode::Error> {
+        let VarInt(n) = Decodable::consensus_decode(&mut d)?;
+        let mut days = Vec::new();
+        for _ in 0..n {
+            days.push(Decodable::consensus_decode(&mut d)?);
+        }
+        Ok(Statistics(days))
+    }
+}
+
+// version of the data layout, increase with a migration step if stored data changes
+const SCHEMA_VERSION: u32 = 1;
+
+const SCHEMA_VERSION_KEY: &[u8] = &[0u8; 1];
+const WATCHED_KEY: &[u8] = &[1u8; 1];
+const REPUTATIONS_KEY: &[u8] = &[2u8; 1];
+const SCHEDULED_KEY: &[u8] = &[3u8; 1];
+const EXPECTED_SPENDS_KEY: &[u8] = &[4u8; 1];
+const WALLET_SCRIPTS_KEY: &[u8] = &[5u8; 1];
+const ADDRESSES_KEY: &[u8] = &[6u8; 1];
+const PEER_HEIGHTS_KEY: &[u8] = &[7u8; 1];
+const INVALID_HEADERS_KEY: &[u8] = &[8u8; 1];
+const DISCONNECTS_KEY: &[u8] = &[9u8; 1];
+const STATISTICS_KEY: &[u8] = &[10u8; 1];
+// followed by the kind and id of the wallet object
+const METADATA_KEY: &[u8] = &[11u8; 1];
+const WALLET_NAMES_KEY: &[u8] = &[12u8; 1];
+// followed by the name of the wallet
+const UNCONFIRMED_KEY: &[u8] = &[13u8; 1];
+const PAYMENT_REQUESTS_KEY: &[u8] = &[14u8; 1];
@@ -19,48 +19,162 @@
 //! Assembles modules of this library to a complete service
 //!
 
+use bandwidth::{Bandwidth, RESTRICTED_CONNECTIONS, SharedBandwidth};
 use bitcoin::{
+    BitcoinHash,
+    blockdata::transaction::Transaction,
+    consensus::{deserialize, serialize},
     network::{
-        constants::Network
-    }
+        constants::Network,
+        message_blockdata::{Inventory, InvType}
+    },
+    util::psbt::PartiallySignedTransaction
 };
-use chaindb::{ChainDB, SharedChainDB};
+use bitcoin_hashes::sha256d::Hash as Sha256dHash;
+use addressbook::{AddressBook, AddrPolicy, SharedAddrPolicy};
+use announcer::Announcer;
+use api::ApiServer;
+use headerstream::{HeaderFeed, HeaderNotice, HeaderStreamServer};
+#[cfg(feature = "electrum")] use electrum::ElectrumServer;
+#[cfg(feature = "esplora")] use esplora::EsploraServer;
+use census::Census;
+use chainserver::ChainServer;
+use filterserver::{FilterServer, FilterServePolicy, SharedFilterServePolicy};
+use fee::{self, FeeFilterWatch, PropagationEstimate, SharedFeeFilters};
+use broadcaster::{Broadcaster, BroadcastPolicy, Package, Packages, SharedBroadcastPolicy, SharedPackages};
+use chaindb::{ChainDB, ChainStats, ChainView, FilterRetention, SharedChainDB};
+use configdb::{ConfigDB, Metadata, MetadataKey, SharedConfigDB};
 use dispatcher::Dispatcher;
 use dns::dns_seed;
+use localnode::{find_local_nodes, LocalDiscovery};
 use error::Error;
+use event::Event;
 use futures::{
     executor::{ThreadPool, ThreadPoolBuilder},
     future,
     Poll as Async,
-    FutureExt, StreamExt,
+    FutureExt, StreamExt, TryFutureExt,
     task::{SpawnExt, Context},
-    Future
+    Future, Stream
 };
+use lock::Recover;
 use std::pin::Pin;
-use futures_timer::Interval;
+use futures_timer::{Delay, Interval};
+use blockdownload::BlockDownload;
+use filterdownload::FilterDownload;
+use filterheaderdownload::FilterHeaderDownload;
 use headerdownload::HeaderDownload;
-use p2p::{P2P, P2PControl, PeerMessageSender, PeerSource};
+use log::LevelFilter;
+use p2p::{BanPolicy, Dialer, Disconnect, LocalAddress, Observer, SharedLocalAddress, P2P, P2PControl, P2PControlSender, PeerId, PeerInfo, PeerMessage, PeerMessageSender, PeerSource, Reputation, TunnelAcceptor};
+use oracle::{TipCheck, TipOracle};
 use ping::Ping;
-use rand::{RngCore, thread_rng};
+use scheduler::{Scheduled, Scheduler, Trigger};
+use replay::{self, read_records, Recorder};
+use propagation::{Arrival, Arrivals, PropagationStats, SharedArrivals};
+use payment::{PaymentRequest, PaymentTxWatch, PaymentWatch, SharedPaymentWatch};
+use socks::{Socks5Dialer, is_onion};
+use spendwatch::{ExpectedSpend, SpendWatch};
+use txfetch::TxFetch;
+use stats::{DayStats, SharedStatistics, Statistics};
+use wallet::{ExportFormat, InputStatus, SharedWallet, SharedWallets, Wallet, Wallets};
+use versionbits::{Deployment, DeploymentStatus, known_deployments, SharedVersionBitsWatch, ThresholdState, VersionBitsWatch, VERSION_BITS};
+use clock::{SharedClock, SharedRandom, SimulatedClock, SystemClock, ThreadRandom};
+use rand::RngCore;
 use std::{
-    collections::HashSet,
+    cmp::min,
+    collections::{HashMap, HashSet},
+    fs::File,
+    io::{self, BufReader},
     net::SocketAddr,
     path::Path,
-    sync::{Arc, mpsc, Mutex, RwLock, atomic::AtomicUsize},
+    sync::{Arc, mpsc, Mutex, RwLock, atomic::{AtomicBool, AtomicUsize, Ordering}},
+    thread
 };
+use syncconfig::SyncConfig;
 use timeout::Timeout;
+use tracing_subscriber::{EnvFilter, FmtSubscriber};
 use downstream::DownStreamDummy;
-use downstream::SharedDownstream;
+use downstream::{Downstreams, SharedDownstream, Subscribers};
 use bitcoin::network::message::NetworkMessage;
 use bitcoin::network::message::RawNetworkMessage;
 use p2p::BitcoinP2PConfig;
-use std::time::Duration;
+use std::time::{Duration, Instant};
 
 const MAX_PROTOCOL_VERSION: u32 = 70001;
+// seconds to keep a broadcast-only connection open after sending the transaction
+const BROADCAST_LINGER: u64 = 5;
+// seconds between storing peer reputations and disconnects
+const STORE_REPUTATIONS: u64 = 60;
+// seconds between accumulating service statistics
+const STORE_STATISTICS: u64 = 60;
+// seconds between checks whether peers are due for rotation
+const ROTATION_CHECK: u64 = 60;
+// seconds of the clock between checks for enough connections
+const KEEP_CONNECTED: u64 = 10;
+// milliseconds between looks at the clock whether connections are due for a check
+const KEEP_CONNECTED_POLL: u64 = 1000;
+// threads of the pool created by the constructor
+const DEFAULT_POOL_SIZE: usize = 2;
+
+/// Parameters that might be changed while the node runs, None leaves a parameter unchanged
+#[derive(Clone, Debug, Default)]
+pub struct Reconfigure {
+    /// keep connections with at least this number of peers
+    pub min_connections: Option<usize>,
+    /// bytes allowed to be sent and received within 24 hours, Some(None) is unlimited
+    pub bandwidth_cap: Option<Option<u64>>,
+    /// maximum level logged through the log crate
+    pub log_level: Option<LevelFilter>,
+    /// when to ban peers and for how long
+    pub ban_policy: Option<BanPolicy>,
+    /// rotate outgoing peers, Some(None) stops rotation
+    pub rotation: Option<Option<RotationPolicy>>
+}
+
+/// Periodic replacement of long-lived outgoing connections, to make the node's place in the
+/// network topology harder to fingerprint and to learn fresh addresses
+#[derive(Clone, Debug)]
+pub struct RotationPolicy {
+    /// time between rotations
+    pub interval: Duration,
+    /// share of outgoing peers disconnected at a rotation, the longest connected first
+    pub fraction: f64
+}
 
 /// The complete stack
 pub struct Constructor {
+    network: Network,
+    chaindb: SharedChainDB,
+    configdb: SharedConfigDB,
     p2p: Arc<P2P<NetworkMessage, RawNetworkMessage, BitcoinP2PConfig>>,
+    p2p_control: P2PControlSender<NetworkMessage>,
+    bandwidth: SharedBandwidth,
+    min_connections: Arc<AtomicUsize>,
+    rotation: Arc<Mutex<Option<RotationPolicy>>>,
+    local_discovery: LocalDiscovery,
+    executor: ThreadPool,
+    tips: Subscribers<(u32, Sha256dHash)>,
+    header_notices: Subscribers<HeaderNotice>,
+    events: Subscribers<Event>,
+    broadcaster: PeerMessageSender<NetworkMessage>,
+    packages: SharedPackages,
+    fee_filters: SharedFeeFilters,
+    blockdownload: PeerMessageSender<NetworkMessage>,
+    broadcast_policy: SharedBroadcastPolicy,
+    addr_policy: SharedAddrPolicy,
+    filter_serve_policy: SharedFilterServePolicy,
+    statistics: SharedStatistics,
+    wallet: SharedWallet,
+    wallets: SharedWallets,
+    local: SharedLocalAddress,
+    random: SharedRandom,
+    clock: SharedClock,
+    dispatcher_input: PeerMessageSender<NetworkMessage>,
+    version_bits: SharedVersionBitsWatch,
+    payment_watch: SharedPaymentWatch,
+    arrivals: SharedArrivals,
+    deployments: Mutex<HashMap<String, Deployment>>,
+    recovered: Mutex<Option<Event>>,
     /// this should be accessed by Lightning
     pub downstream: SharedDownstream
 }
@@ -78,73 +192,799 @@ impl Constructor {
         Ok(Arc::new(RwLock::new(chaindb)))
     }
 
+    /// open config DB
+    pub fn open_config_db(path: Option<&Path>) -> Result<SharedConfigDB, Error> {
+        let configdb =
+            if let Some(path) = path {
+                ConfigDB::new(path)?
+            } else {
+                ConfigDB::mem()?
+            };
+        Ok(Arc::new(RwLock::new(configdb)))
+    }
+
+    /// Construct a stack that keeps everything in memory, no data path needed.
+    /// * state - resume from a blob created by export_state, None to start afresh
+    pub fn new_in_memory(network: Network, listen: Vec<SocketAddr>, state: Option<&[u8]>, sync: SyncConfig) -> Result<Constructor, Error> {
+        let (mut chaindb, configdb) = if let Some(state) = state {
+            let (chain, config): (Vec<u8>, Vec<u8>) = deserialize(state)?;
+            (ChainDB::mem_from(network, chain.as_slice())?, ConfigDB::mem_from(config.as_slice())?)
+        } else {
+            (ChainDB::mem(network)?, ConfigDB::mem()?)
+        };
+        chaindb.init()?;
+        Self::new(network, listen, Arc::new(RwLock::new(chaindb)), Arc::new(RwLock::new(configdb)), sync)
+    }
+
+    /// Serialize chain and config db at a batch boundary, to resume an in-memory stack
+    /// with new_in_memory, downloads continue where they were
+    pub fn export_state(&self) -> Result<Vec<u8>, Error> {
+        let chain = self.chaindb.write().recover().export()?;
+        let config = self.configdb.write().recover().export()?;
+        Ok(serialize(&(chain, config)))
+    }
+
     /// Construct the stack
-    pub fn new(network: Network, listen: Vec<SocketAddr>, chaindb: SharedChainDB) -> Result<Constructor, Error> {
+    /// * sync - download concurrency and chunk sizes, SyncConfig::default() suits most
+    pub fn new(network: Network, listen: Vec<SocketAddr>, chaindb: SharedChainDB, configdb: SharedConfigDB, sync: SyncConfig) -> Result<Constructor, Error> {
+        Self::new_with_clock(network, listen, chaindb, configdb, sync, Arc::new(SystemClock), Arc::new(ThreadRandom))
+    }
+
+    /// Construct the stack with stall detection, rebroadcast and the choice of peers to connect
+    /// driven by the given clock and randomness, e.g. a SimulatedClock and SeededRandom in tests
+    pub fn new_with_clock(network: Network, listen: Vec<SocketAddr>, chaindb: SharedChainDB, configdb: SharedConfigDB, sync: SyncConfig,
+                          clock: SharedClock, random: SharedRandom) -> Result<Constructor, Error> {
         const BACK_PRESSURE: usize = 10;
 
+        let sync = sync.clamped();
         let (to_dispatcher, from_p2p) = mpsc::sync_channel(BACK_PRESSURE);
 
+        let bandwidth = Arc::new(Bandwidth::new(clock.clone()));
+        let local = Arc::new(LocalAddress::new(listen.clone(), bandwidth.clone()));
+        let height = chaindb.read().recover().header_tip().map(|tip| tip.stored.height as usize).unwrap_or(0);
+        let problems = chaindb.write().recover().take_recovered();
+        let recovered = if problems.is_empty() {
+            None
+        } else {
+            let tip = chaindb.read().recover().header_tip().map(|tip| (tip.stored.height, tip.stored.header.bitcoin_hash()));
+            Some(Event::RecoveredFromCorruption { tip, problems })
+        };
 
         let p2pconfig = BitcoinP2PConfig {
             network,
-            nonce: thread_rng().next_u64(),
+            nonce: random.rng().next_u64(),
             max_protocol_version: MAX_PROTOCOL_VERSION,
             user_agent: "murmel: 0.1.0".to_owned(),
-            height: AtomicUsize::new(0),
-            server: !listen.is_empty()
+            height: AtomicUsize::new(height),
+            local: local.clone(),
+            clock: clock.clone()
         };
 
+        let dispatcher_input = PeerMessageSender::new(to_dispatcher);
         let (p2p, p2p_control) =
-            P2P::new(p2pconfig, PeerMessageSender::new(to_dispatcher), BACK_PRESSURE);
+            P2P::new(p2pconfig, dispatcher_input.clone(), BACK_PRESSURE, bandwidth.clone(), clock.clone());
+        p2p.import_reputations(configdb.read().recover().fetch_reputations()?);
+        p2p.import_disconnects(configdb.read().recover().fetch_disconnects()?);
+
+        let broadcast_policy = Arc::new(Mutex::new(BroadcastPolicy::default()));
+        let addr_policy = Arc::new(Mutex::new(AddrPolicy::default()));
+        let filter_serve_policy = Arc::new(Mutex::new(FilterServePolicy::default()));
+        let statistics = Arc::new(Statistics::new(clock.unix_time()));
+        let packages = Arc::new(Mutex::new(Packages::default()));
+        let broadcaster = Broadcaster::new(p2p_control.clone(), broadcast_policy.clone(), packages.clone(), clock.clone(), random.clone());
 
-        #[cfg(feature = "lightning")] let lightning = Arc::new(Mutex::new(LightningConnector::new(network, p2p_control.clone())));
+        #[cfg(feature = "lightning")] let lightning = Arc::new(Mutex::new(LightningConnector::new(network, broadcaster.clone(), configdb.clone())));
         #[cfg(not(feature = "lightning"))] let lightning = Arc::new(Mutex::new(DownStreamDummy {}));
 
+        let tips = Subscribers::new();
+        let events = Subscribers::new();
+
+        let mut wallet = Wallet::new(configdb.clone(), events.clone())?;
+        wallet.rescan(&chaindb.read().recover())?;
+        let wallet = Arc::new(Mutex::new(wallet));
+        let mut wallets = Wallets::new();
+        for name in configdb.read().recover().fetch_wallet_names()? {
+            let wallet_events = Subscribers::new();
+            let mut named = Wallet::named(name.as_str(), configdb.clone(), wallet_events.clone())?;
+            named.rescan(&chaindb.read().recover())?;
+            wallets.add(named, wallet_events);
+        }
+        let wallets = Arc::new(Mutex::new(wallets));
+
+        let header_notices = Subscribers::new();
 
-        let timeout = Arc::new(Mutex::new(Timeout::new(p2p_control.clone())));
+        let version_bits = Arc::new(Mutex::new(VersionBitsWatch::new(events.clone())));
+        let spend_interest = Arc::new(AtomicBool::new(false));
+        let payment_interest = Arc::new(AtomicBool::new(false));
+        let payment_watch = Arc::new(Mutex::new(PaymentWatch::new(configdb.clone(), events.clone(), payment_interest.clone())?));
+        let arrivals = Arc::new(Mutex::new(Arrivals::new(clock.clone())));
+        let downstreams: SharedDownstream = Arc::new(Mutex::new(Downstreams::new(vec!(lightning.clone() as SharedDownstream, wallet.clone() as SharedDownstream,
+            wallets.clone() as SharedDownstream, version_bits.clone() as SharedDownstream, payment_watch.clone() as SharedDownstream,
+            arrivals.clone() as SharedDownstream, Arc::new(Mutex::new(HeaderFeed::new(header_notices.clone()))) as SharedDownstream))));
+
+        let timeout = Arc::new(Mutex::new(Timeout::new(p2p_control.clone(), clock.clone())));
 
         let mut dispatcher = Dispatcher::new(from_p2p);
 
-        dispatcher.add_listener(HeaderDownload::new(chaindb.clone(), p2p_control.clone(), timeout.clone(), lightning.clone()));
-        dispatcher.add_listener(Ping::new(p2p_control.clone(), timeout.clone()));
+        let announcer = Announcer::new(p2p_control.clone(), local.clone(), clock.clone());
+        dispatcher.add_listener(announcer.clone());
+        dispatcher.add_listener(ChainServer::new(chaindb.clone(), p2p_control.clone(), local.clone(), statistics.clone()));
+        dispatcher.add_listener(FilterServer::new(chaindb.clone(), p2p_control.clone(), local.clone(), filter_serve_policy.clone(), clock.clone(), statistics.clone()));
+        dispatcher.add_listener(HeaderDownload::new(chaindb.clone(), p2p_control.clone(), timeout.clone(), downstreams.clone(), tips.clone(), announcer.clone(), configdb.clone(), sync.clone(), clock.clone())?);
+        let mut blockdownload = PeerMessageSender::dummy();
+        if !sync.headers_only {
+            dispatcher.add_listener(FilterHeaderDownload::new(chaindb.clone(), p2p_control.clone(), timeout.clone()));
+            dispatcher.add_listener(FilterDownload::new(chaindb.clone(), configdb.clone(), p2p_control.clone(), timeout.clone(), bandwidth.clone(), events.clone(), sync.clone(), clock.clone()));
+            blockdownload = BlockDownload::new(chaindb.clone(), p2p_control.clone(), timeout.clone(), downstreams.clone(), bandwidth.clone(), sync.clone(), clock.clone(), random.clone());
+            dispatcher.add_listener(blockdownload.clone());
+        }
+        Scheduler::new(chaindb.clone(), configdb.clone(), broadcaster.clone());
+
+        dispatcher.add_listener(AddressBook::new(configdb.clone(), p2p_control.clone(), local.clone(), addr_policy.clone(), clock.clone())?);
+        dispatcher.add_listener(TxFetch::new(p2p_control.clone(), bandwidth.clone(), vec!(spend_interest.clone(), payment_interest)));
+        dispatcher.add_listener(SpendWatch::new(configdb.clone(), ChainView::new(chaindb.clone()), p2p_control.clone(), events.clone(), spend_interest));
+        dispatcher.add_listener(PaymentTxWatch::new(payment_watch.clone(), p2p_control.clone()));
+        dispatcher.add_listener(Ping::new(p2p_control.clone(), timeout.clone(), clock.clone()));
+        dispatcher.add_listener(broadcaster.clone());
+        let fee_filters = Arc::new(Mutex::new(HashMap::new()));
+        dispatcher.add_listener(FeeFilterWatch::new(p2p_control.clone(), fee_filters.clone()));
 
         for addr in &listen {
             p2p_control.send(P2PControl::Bind(addr.clone()));
         }
 
-        Ok(Constructor { p2p, downstream: lightning })
+        let executor = ThreadPoolBuilder::new().name_prefix("bitcoin-connect").pool_size(DEFAULT_POOL_SIZE).create().expect("can not start futures thread pool");
+
+        Ok(Constructor { network, chaindb, configdb, p2p, p2p_control, bandwidth, min_connections: Arc::new(AtomicUsize::new(0)), rotation: Arc::new(Mutex::new(None)), local_discovery: LocalDiscovery::Off, executor, tips, header_notices, events, broadcaster, packages, fee_filters, blockdownload, broadcast_policy, addr_policy, filter_serve_policy, statistics, wallet, wallets, local, random, clock, dispatcher_input, version_bits, payment_watch, arrivals,
+            deployments: Mutex::new(known_deployments(network).into_iter().map(|d| (d.name.clone(), d)).collect()), recovered: Mutex::new(recovered), downstream: lightning })
+    }
+
+    /// Run connections, broadcasts and periodic tasks on the embedder's thread pool
+    /// instead of the one created by the constructor. Call before run.
+    pub fn with_executor(mut self, executor: ThreadPool) -> Constructor {
+        self.executor = executor;
+        self
+    }
+
+    /// Look for a full node on this machine or the local subnet at start and connect it first.
+    /// Off by default. Call before run.
+    pub fn with_local_discovery(mut self, scope: LocalDiscovery) -> Constructor {
+        self.local_discovery = scope;
+        self
+    }
+
+    /// Size the thread pool created by the constructor, the default is two threads. Call before run.
+    pub fn with_pool_size(self, size: usize) -> Result<Constructor, Error> {
+        let executor = ThreadPoolBuilder::new().name_prefix("bitcoin-connect").pool_size(size).create()?;
+        Ok(self.with_executor(executor))
+    }
+
+    /// Record traffic with peers to a replay file from now on, None to stop recording
+    pub fn record_traffic(&self, path: Option<&Path>) -> Result<(), Error> {
+        let recorder = if let Some(path) = path {
+            Some(Arc::new(Recorder::new(path, self.clock.clone())?) as Arc<dyn Observer<NetworkMessage>>)
+        } else {
+            None
+        };
+        self.p2p.set_observer(recorder);
+        Ok(())
+    }
+
+    /// Process traffic of a replay file as if received from peers. Call on a stack that is not run,
+    /// e.g. one created with new_with_clock on in-memory databases, with the simulated clock it was
+    /// created with.
+    /// Returns the number of records replayed.
+    pub fn replay(&self, path: &Path, clock: &SimulatedClock) -> Result<usize, Error> {
+        let records = read_records(path)?;
+        Ok(replay::replay(&records, &self.dispatcher_input, clock))
+    }
+
+    /// Import headers from a bundle file shipped with the application at first start,
+    /// that is if no header beyond genesis is known yet. The bundle is a sequence of
+    /// serialized 80 byte headers from genesis or the header following it, checked as imported.
+    /// Call before run. Returns the number of headers imported.
+    pub fn import_header_bundle(&self, path: &Path) -> Result<u32, Error> {
+        let mut chaindb = self.chaindb.write().recover();
+        if chaindb.header_tip().map(|tip| tip.stored.height).unwrap_or(0) > 0 {
+            return Ok(0);
+        }
+        let added = chaindb.import_headers(BufReader::new(File::open(path)?))?;
+        if let Some(tip) = chaindb.header_tip() {
+            info!("imported {} headers from {}, tip {} at height {}", added, path.to_string_lossy(), tip.bitcoin_hash(), tip.stored.height);
+            self.p2p_control.send(P2PControl::Height(tip.stored.height));
+        }
+        Ok(added)
+    }
+
+    /// Log through a subscriber that only shows events matching the filter directives, e.g.
+    /// "info,murmel[block download]=debug" or "warn,murmel[peer{peer=bitcoin-3}]=trace".
+    /// Events are within a span of the sync phase and one of the peer they relate to.
+    /// Without calling this events are forwarded to the log crate.
+    pub fn with_log_filter(self, directives: &str) -> Result<Constructor, Error> {
+        let filter = EnvFilter::try_new(directives).map_err(|e| Error::Downstream(format!("invalid log filter: {}", e)))?;
+        let subscriber = FmtSubscriber::builder().with_env_filter(filter).finish();
+        tracing::subscriber::set_global_default(subscriber).map_err(|_| Error::Downstream("log subscriber already set".to_owned()))?;
+        Ok(self)
+    }
+
+    /// Change parameters of the running node
+    pub fn reconfigure(&self, changes: Reconfigure) {
+        if let Some(min_connections) = changes.min_connections {
+            info!("keep at least {} connections", min_connections);
+            self.min_connections.store(min_connections, Ordering::Relaxed);
+        }
+        if let Some(cap) = changes.bandwidth_cap {
+            self.bandwidth.set_cap(cap);
+        }
+        if let Some(level) = changes.log_level {
+            log::set_max_level(level);
+        }
+        if let Some(policy) = changes.ban_policy {
+            self.p2p.set_ban_policy(policy);
+        }
+        if let Some(rotation) = changes.rotation {
+            *self.rotation.lock().recover() = rotation;
+        }
+    }
+
+    /// Open outgoing connections through the dialer, e.g. to route them through a proxy or a test harness
+    pub fn set_dialer(&self, dialer: Arc<dyn Dialer>) {
+        self.p2p.set_dialer(dialer);
+    }
+
+    /// Open outgoing connections through a SOCKS5 proxy, e.g. Tor
+    pub fn set_proxy(&self, proxy: SocketAddr) {
+        self.set_dialer(Arc::new(Socks5Dialer::new(proxy)));
+    }
+
+    /// Signal a metered connection. While metered the node only syncs headers, keeps a single
+    /// connection and does not serve other peers, until unmetered connectivity is signalled
+    pub fn set_metered(&self, metered: bool) {
+        self.bandwidth.set_metered(metered);
+    }
+
+    /// Limit bytes sent and received within 24 hours. The node behaves as on a metered
+    /// connection once the cap is reached. None removes the cap.
+    pub fn set_bandwidth_cap(&self, cap: Option<u64>) {
+        self.bandwidth.set_cap(cap);
+    }
+
+    /// Bytes sent and received within the current 24 hours
+    pub fn bandwidth_used(&self) -> u64 {
+        self.bandwidth.used()
+    }
+
+    /// Set which downloaded filters are kept, older ones are pruned to whether they matched
+    pub fn set_filter_retention(&self, retention: FilterRetention) {
+        self.chaindb.write().recover().set_filter_retention(retention);
+    }
+
+    /// Opt in to trust the difficulty claimed by headers up to the (height, id) of a header known to
+    /// be on the valid chain, e.g. from a recent release. This saves recomputing retargets on
+    /// constrained devices. Headers are still linked by hash and proof of work for the claimed
+    /// difficulty is checked, a different header at the anchor's height is rejected.
+    pub fn set_assume_valid(&self, anchor: Option<(u32, Sha256dHash)>) {
+        self.chaindb.write().recover().set_assume_valid(anchor);
+    }
+
+    /// Read-only access to the chain DB while the node runs
+    pub fn chain_view(&self) -> ChainView {
+        ChainView::new(self.chaindb.clone())
+    }
+
+    /// median of the timestamps of the 11 blocks on trunk ending at height,
+    /// the time nLockTime and CSV are evaluated against
+    pub fn median_time_past(&self, height: u32) -> Option<u32> {
+        self.chaindb.read().recover().median_time_past(height)
+    }
+
+    /// Difficulty, estimated network hashrate and average block interval over the last day
+    /// of blocks on trunk, None before the first header
+    pub fn chain_stats(&self) -> Option<ChainStats> {
+        self.chaindb.read().recover().chain_stats()
+    }
+
+    /// unix time adjusted by the median clock offset of connected peers
+    pub fn network_time(&self) -> u64 {
+        self.p2p_control.network_time()
+    }
+
+    /// Set the address other peers can reach this node at, e.g. of a router forwarding the listen port.
+    /// None determines it from listen addresses and what peers report.
+    pub fn set_external_address(&self, address: Option<SocketAddr>) {
+        self.local.set(address);
+    }
+
+    /// The address advertised to other peers, if serving and known
+    pub fn external_address(&self) -> Option<SocketAddr> {
+        self.local.get()
+    }
+
+    /// State of connected peers
+    pub fn peers(&self) -> Vec<PeerInfo> {
+        self.p2p.peer_info()
+    }
+
+    /// Connected peers announcing all of the services, e.g. SERVICE_FILTERS
+    pub fn peers_with_services(&self, services: u64) -> Vec<PeerInfo> {
+        self.p2p.peer_info().into_iter().filter(|peer| peer.services & services == services).collect()
+    }
+
+    /// Distribution of user agents, protocol versions and services among connected and discovered peers
+    pub fn census(&self) -> Result<Census, Error> {
+        let discovered = self.configdb.read().recover().fetch_addresses()?;
+        Ok(Census::new(&self.p2p.peer_info(), &discovered))
+    }
+
+    /// Reputation of peers that misbehaved, including bans, to be shared with other nodes
+    pub fn export_reputations(&self) -> Vec<Reputation> {
+        self.p2p.reputations()
+    }
+
+    /// Merge reputations exported by an other node, bans are applied immediately
+    pub fn import_reputations(&self, reputations: Vec<Reputation>) -> Result<(), Error> {
+        self.p2p.import_reputations(reputations);
+        let mut configdb = self.configdb.write().recover();
+        configdb.store_reputations(self.p2p.reputations())?;
+        configdb.batch()
+    }
+
+    /// Recent disconnects of peers with their reasons, oldest first, including those of earlier runs.
+    /// Helps to find out why connections are not kept.
+    pub fn disconnects(&self) -> Vec<Disconnect> {
+        self.p2p.disconnects()
+    }
+
+    /// Headers and blocks recently joining the trunk with their local arrival time, oldest first
+    pub fn arrivals(&self) -> Vec<Arrival> {
+        self.arrivals.lock().recover().arrivals()
+    }
+
+    /// Distribution of delays from the time in headers to the arrival of headers and blocks
+    pub fn propagation_stats(&self) -> PropagationStats {
+        self.arrivals.lock().recover().stats()
+    }
+
+    /// Uptime, blocks and filters served and bytes exchanged with peers by day, oldest first,
+    /// including earlier runs. Counts of the last minute are not yet included.
+    pub fn statistics(&self) -> Result<Vec<DayStats>, Error> {
+        self.configdb.read().recover().fetch_statistics()
+    }
+
+    /// Serve the client API at the loopback address to clients presenting the token, see the api module.
+    /// Returns the address bound, e.g. if port 0 was given.
+    pub fn serve_api(&self, addr: SocketAddr, token: &str) -> Result<SocketAddr, Error> {
+        ApiServer::new(token, self.events.clone(), self.broadcaster.clone(), self.wallet.clone(), self.configdb.clone()).listen(addr)
+    }
+
+    /// Serve Electrum clients at the address, see the electrum module
+    #[cfg(feature = "electrum")]
+    pub fn serve_electrum(&self, addr: SocketAddr) -> Result<SocketAddr, Error> {
+        ElectrumServer::new(self.chain_view(), self.wallet.clone(), self.events.clone(), self.broadcaster.clone()).listen(addr)
+    }
+
+    /// Serve the Esplora compatible HTTP endpoints at the address, see the esplora module
+    #[cfg(feature = "esplora")]
+    pub fn serve_esplora(&self, addr: SocketAddr) -> Result<SocketAddr, Error> {
+        EsploraServer::new(self.chain_view(), self.wallet.clone()).listen(addr)
+    }
+
+    /// Stream headers connected to and disconnected from the trunk as server-sent events at
+    /// http://addr/headers, see the headerstream module
+    pub fn serve_header_stream(&self, addr: SocketAddr) -> Result<SocketAddr, Error> {
+        HeaderStreamServer::new(self.chain_view(), self.header_notices.clone()).listen(addr)
+    }
+
+    /// Accept connections at the address wrapped in a tunnel, e.g. a tls::TlsAcceptor with the tls feature.
+    /// Peers of the public network can not connect there, use it for private links.
+    pub fn listen_tunneled(&self, addr: SocketAddr, acceptor: Arc<dyn TunnelAcceptor>) {
+        self.p2p_control.send(P2PControl::BindTunneled(addr, acceptor));
+    }
+
+    /// Connect a peer in addition to those maintained by run
+    pub fn add_peer(&self, addr: SocketAddr) -> Result<(), Error> {
+        self.executor.clone().spawn(self.p2p.add_peer("bitcoin", PeerSource::Outgoing(addr)).map(|_| ()))
+            .map_err(|_| Error::Downstream("can not spawn task for peer".to_owned()))
+    }
+
+    /// Broadcast a transaction through a subset of peers, retried until seen propagated
+    pub fn broadcast(&self, tx: Transaction) {
+        self.broadcaster.send(PeerMessage::Outgoing(NetworkMessage::Tx(tx)));
+    }
+
+    /// Whether the transaction paying the fee would pass the minimum fee rates connected peers announced
+    pub fn propagation_estimate(&self, tx: &Transaction, fee: u64) -> PropagationEstimate {
+        fee::estimate(fee::fee_rate(tx, fee), self.p2p_control.peers().as_slice(), &self.fee_filters.lock().recover())
+    }
+
+    /// Broadcast dependent transactions as a package: parents first, to the same peers, retried
+    /// together. Returns the id of the package to follow its state with package.
+    pub fn broadcast_package(&self, txs: Vec<Transaction>) -> Sha256dHash {
+        self.packages.lock().recover().submit(txs)
+    }
+
+    /// A package submitted with broadcast_package, None if unknown
+    pub fn package(&self, id: &Sha256dHash) -> Option<Package> {
+        self.packages.lock().recover().get(id)
+    }
+
+    /// Broadcast a transaction once the trigger happens, also after a restart
+    pub fn schedule_broadcast(&self, tx: Transaction, trigger: Trigger) -> Result<(), Error> {
+        let since = self.chaindb.read().recover().header_tip().map(|tip| tip.stored.height).unwrap_or(0);
+        let mut configdb = self.configdb.write().recover();
+        let mut scheduled = configdb.fetch_scheduled()?;
+        let txid = tx.txid();
+        if !scheduled.iter().any(|s| s.tx.txid() == txid) {
+            info!("schedule transaction {} for {:?}", txid, trigger);
+            scheduled.push(Scheduled { tx, trigger, since });
+            configdb.store_scheduled(scheduled)?;
+            configdb.batch()?;
+        }
+        Ok(())
+    }
+
+    /// Raise Event::PossibleDoubleSpend if an other transaction spends any input of this one,
+    /// until this one is seen in a block
+    pub fn expect_spend(&self, tx: Transaction) -> Result<(), Error> {
+        let mut configdb = self.configdb.write().recover();
+        let mut expected = configdb.fetch_expected_spends()?;
+        let txid = tx.txid();
+        if !expected.iter().any(|e| e.tx.txid() == txid) {
+            expected.push(ExpectedSpend { tx, block: None });
+            configdb.store_expected_spends(expected)?;
+            configdb.batch()?;
+        }
+        Ok(())
+    }
+
+    /// Download blocks of the trunk in bulk, e.g. to rescan the wallet for a script added later.
+    /// Blocks are passed downstream as they arrive, after those near the tip and those matching filters.
+    pub fn download_blocks(&self, blocks: Vec<Sha256dHash>) {
+        self.blockdownload.send(PeerMessage::Outgoing(NetworkMessage::GetData(
+            blocks.into_iter().map(|hash| Inventory { inv_type: InvType::WitnessBlock, hash }).collect())));
+    }
+
+    /// Unspent outputs and balance of the wallet's scripts
+    pub fn wallet(&self) -> SharedWallet {
+        self.wallet.clone()
+    }
+
+    /// Create a wallet with scripts, outputs and events of its own besides the default one,
+    /// or return the wallet of the name if it exists
+    pub fn create_wallet(&self, name: &str) -> Result<SharedWallet, Error> {
+        if name.is_empty() {
+            return Ok(self.wallet.clone());
+        }
+        // the chain db is locked before wallets, as by downstream processing
+        let chaindb = self.chaindb.read().recover();
+        let mut wallets = self.wallets.lock().recover();
+        if let Some(wallet) = wallets.get(name) {
+            return Ok(wallet);
+        }
+        {
+            let mut configdb = self.configdb.write().recover();
+            let mut names = configdb.fetch_wallet_names()?;
+            names.push(name.to_string());
+            configdb.store_wallet_names(names)?;
+            configdb.batch()?;
+        }
+        let events = Subscribers::new();
+        let mut wallet = Wallet::named(name, self.configdb.clone(), events.clone())?;
+        wallet.rescan(&chaindb)?;
+        info!("created wallet {}", name);
+        Ok(wallets.add(wallet, events))
+    }
+
+    /// The wallet of the name, "" for the default wallet
+    pub fn named_wallet(&self, name: &str) -> Option<SharedWallet> {
+        if name.is_empty() {
+            return Some(self.wallet.clone());
+        }
+        self.wallets.lock().recover().get(name)
+    }
+
+    /// Names of wallets besides the default one
+    pub fn wallet_names(&self) -> Vec<String> {
+        self.wallets.lock().recover().names()
+    }
+
+    /// Events of a named wallet, those of the default wallet are among events()
+    pub fn wallet_events(&self, name: &str) -> Option<impl Stream<Item=Event>> {
+        self.wallets.lock().recover().events(name).map(|events| events.subscribe())
+    }
+
+    /// Transaction history of the wallet with amounts, fees and block times, e.g. for accounting
+    pub fn export_history(&self, format: ExportFormat) -> String {
+        // the chain db is locked before the wallet, as by downstream processing
+        let chaindb = self.chaindb.read().recover();
+        self.wallet.lock().recover().export_history(&chaindb, format)
+    }
+
+    /// Outputs received below this value in satoshis raise Event::DustReceived, default is wallet::DUST_THRESHOLD
+    pub fn set_dust_threshold(&self, threshold: u64) {
+        self.wallet.lock().recover().set_dust_threshold(threshold);
+    }
+
+    /// Unconfirmed transactions of the wallet are forgotten if not confirmed within this many blocks,
+    /// default is wallet::UNCONFIRMED_EXPIRY
+    pub fn set_unconfirmed_expiry(&self, blocks: u32) {
+        self.wallet.lock().recover().set_unconfirmed_expiry(blocks);
+    }
+
+    /// Set metadata of a wallet object by name, e.g. configdb::LABEL, an empty value removes it
+    pub fn set_metadata(&self, object: &MetadataKey, name: &str, value: &str) -> Result<(), Error> {
+        let mut configdb = self.configdb.write().recover();
+        let mut metadata = configdb.fetch_metadata(object)?;
+        if value.is_empty() {
+            metadata.remove(name);
+        } else {
+            metadata.insert(name.to_string(), value.to_string());
+        }
+        configdb.store_metadata(object, &metadata)?;
+        configdb.batch()
+    }
+
+    /// Metadata of a wallet object, empty if none was set
+    pub fn metadata(&self, object: &MetadataKey) -> Result<Metadata, Error> {
+        self.configdb.read().recover().fetch_metadata(object)
+    }
+
+    /// Metadata of all wallet objects that have some
+    pub fn all_metadata(&self) -> Result<Vec<(MetadataKey, Metadata)>, Error> {
+        self.configdb.read().recover().fetch_all_metadata()
+    }
+
+    /// Fill in outputs of the wallet spent by the PSBT, see Wallet::update_psbt
+    pub fn update_psbt(&self, psbt: &mut PartiallySignedTransaction) -> Result<Vec<Option<InputStatus>>, Error> {
+        // the chain db is locked before the wallet, as by downstream processing
+        let chaindb = self.chaindb.read().recover();
+        self.wallet.lock().recover().update_psbt(psbt, &chaindb)
+    }
+
+    /// Transactions waiting for scheduled broadcast
+    pub fn scheduled(&self) -> Result<Vec<Scheduled>, Error> {
+        self.configdb.read().recover().fetch_scheduled()
+    }
+
+    /// Broadcast a transaction through a short-lived connection to a random peer not otherwise connected.
+    /// The connection is closed a few seconds after the transaction was sent.
+    pub fn broadcast_via_fresh_peer(&self, tx: Transaction) -> Result<(), Error> {
+        let connected = self.p2p.connected_peers();
+        let eligible = dns_seed(self.network).into_iter().filter(|a| !connected.iter().any(|c| c.ip() == a.ip())).collect::<Vec<_>>();
+        if eligible.is_empty() {
+            return Err(Error::NoPeers);
+        }
+        let addr = eligible[(self.random.rng().next_u32() as usize) % eligible.len()];
+        let txid = tx.txid();
+        let p2p_control = self.p2p_control.clone();
+        let push = self.p2p.connect_peer("bitcoin", addr)
+            .and_then(move |pid| {
+                info!("broadcast transaction {} via fresh peer={}", txid, pid);
+                p2p_control.send_network(pid, NetworkMessage::Tx(tx));
+                Delay::new(Duration::from_secs(BROADCAST_LINGER)).map(move |_| {
+                    p2p_control.send(P2PControl::Disconnect(pid));
+                    Ok(())
+                })
+            })
+            .map(move |r: Result<(), Error>| if let Err(e) = r {
+                warn!("failed to broadcast transaction {} via {}: {}", txid, addr, e);
+            });
+        self.executor.clone().spawn(push).map_err(|_| Error::Downstream("can not spawn task for broadcast".to_owned()))
+    }
+
+    /// Set privacy options for transactions broadcast from now on
+    pub fn set_broadcast_policy(&self, policy: BroadcastPolicy) {
+        *self.broadcast_policy.lock().recover() = policy;
+    }
+
+    /// Set which addresses received from peers are kept from now on
+    pub fn set_addr_policy(&self, policy: AddrPolicy) {
+        *self.addr_policy.lock().recover() = policy;
+    }
+
+    /// Limit filter requests of other peers from now on, e.g. FilterServePolicy::public() for a public server
+    pub fn set_filter_serve_policy(&self, policy: FilterServePolicy) {
+        *self.filter_serve_policy.lock().recover() = policy;
+    }
+
+    /// Stream of (height, hash) of every new chain tip, including tips after a reorg
+    pub fn tip_stream(&self) -> impl Stream<Item=(u32, Sha256dHash)> {
+        self.tips.subscribe()
+    }
+
+    /// Raise Event::VersionBitsSignal for every header connected to the trunk that signals the bit
+    pub fn watch_version_bit(&self, bit: u8) {
+        self.version_bits.lock().recover().watch(bit);
+    }
+
+    /// Stop raising Event::VersionBitsSignal for the bit
+    pub fn unwatch_version_bit(&self, bit: u8) {
+        self.version_bits.lock().recover().unwatch(bit);
+    }
+
+    /// Raise Event::PaymentReceived as a payment of the BIP21 URI is announced and at every
+    /// confirmation up to depth. The address is added to the wallet so that blocks paying to it
+    /// are downloaded.
+    pub fn watch_payment(&self, uri: &str, depth: u32) -> Result<PaymentRequest, Error> {
+        let request = PaymentRequest::parse(uri, self.network, depth)?;
+        self.wallet.lock().recover().add_script(request.script.clone())?;
+        self.payment_watch.lock().recover().watch(request.clone())?;
+        Ok(request)
+    }
+
+    /// Stop raising Event::PaymentReceived for the BIP21 URI
+    pub fn unwatch_payment(&self, uri: &str) -> Result<(), Error> {
+        self.payment_watch.lock().recover().unwatch(uri)
+    }
+
+    /// BIP9 state of a deployment for the block following the trunk tip
+    pub fn version_bits_state(&self, deployment: &Deployment) -> ThresholdState {
+        self.chaindb.read().recover().version_bits_state(deployment)
+    }
+
+    /// Track a deployment by its name, in addition to those known for the network.
+    /// A deployment of the same name is replaced. The bit must be one of the VERSION_BITS.
+    pub fn add_deployment(&self, deployment: Deployment) -> Result<(), Error> {
+        if deployment.bit as usize >= VERSION_BITS {
+            return Err(Error::IO(io::Error::new(io::ErrorKind::InvalidInput, format!("deployment {} signals with bit {}, not one of the {} version bits", deployment.name, deployment.bit, VERSION_BITS))));
+        }
+        self.deployments.lock().recover().insert(deployment.name.clone(), deployment);
+        Ok(())
+    }
+
+    /// State of a tracked deployment for the block following the trunk tip, None if the name is not known
+    pub fn deployment_status(&self, name: &str) -> Option<DeploymentStatus> {
+        let deployment = self.deployments.lock().recover().get(name).cloned()?;
+        Some(self.chaindb.read().recover().deployment_status(&deployment))
+    }
+
+    /// Stream of events the application might want to act on. Recovery of a corrupted
+    /// chain db at startup is reported as Event::RecoveredFromCorruption once run is called
+    pub fn events(&self) -> impl Stream<Item=Event> {
+        self.events.subscribe()
     }
 
-    /// Run the stack. This should be called AFTER registering listener of the ChainWatchInterface,
+    /// Periodically compare our chain tip with those reported by the oracles,
+    /// a mismatch is reported as Event::TipDivergence
+    pub fn cross_check_tip(&self, oracles: Vec<Box<dyn TipOracle>>) {
+        TipCheck::start(self.chaindb.clone(), oracles, self.events.clone());
+    }
+
+    /// Disconnect a peer
+    pub fn disconnect_peer(&self, peer: PeerId) {
+        self.p2p_control.send(P2PControl::Disconnect(peer));
+    }
+
+    /// Disconnect and refuse connections from/to the address for the given duration
+    pub fn ban_peer(&self, addr: SocketAddr, duration: Duration) {
+        self.p2p.ban_address(addr.ip(), duration);
+    }
+
+    /// Run the stack, blocks the calling thread forever. Same as start followed by join.
+    pub fn run(&self, network: Network, peers: Vec<SocketAddr>, min_connections: usize) -> Result<(), Error> {
+        self.start(network, peers, min_connections)?.join()
+    }
+
+    /// Start the stack and return, the stack keeps running in a thread of its own while the
+    /// constructor remains usable. This should be called AFTER registering listener of the ChainWatchInterface,
     /// so they are called as the stack catches up with the blockchain
     /// * peers - connect to these peers at startup (might be empty)
     /// * min_connections - keep connections with at least this number of peers. Peers will be randomly chosen
-    /// from those discovered in earlier runs
-    pub fn run(&mut self, network: Network, peers: Vec<SocketAddr>, min_connections: usize) -> Result<(), Error> {
+    /// from those discovered in earlier runs. Might be changed later with reconfigure
+    pub fn start(&self, network: Network, peers: Vec<SocketAddr>, min_connections: usize) -> Result<NodeHandle, Error> {
+        if let Some(event) = self.recovered.lock().recover().take() {
+            self.events.publish(event);
+        }
 
-        let mut executor = ThreadPoolBuilder::new().name_prefix("bitcoin-connect").pool_size(2).create().expect("can not start futures thread pool");
+        let mut peers = peers;
+        if self.local_discovery != LocalDiscovery::Off {
+            // a local node is connected first
+            for addr in find_local_nodes(network, self.local_discovery, self.local.listen().as_slice()).into_iter().rev() {
+                if !peers.contains(&addr) {
+                    info!("connecting local node {}", addr);
+                    peers.insert(0, addr);
+                }
+            }
+        }
+
+        let mut executor = self.executor.clone();
 
         let p2p = self.p2p.clone();
         for addr in &peers {
             executor.spawn(p2p.add_peer("bitcoin", PeerSource::Outgoing(addr.clone())).map(|_|())).expect("can not spawn task for peers");
         }
 
+        self.min_connections.store(min_connections, Ordering::Relaxed);
         let keep_connected = KeepConnected {
-            min_connections, p2p: self.p2p.clone(),
+            min_connections: self.min_connections.clone(), p2p: self.p2p.clone(),
+            p2p_control: self.p2p_control.clone(),
+            configdb: self.configdb.clone(),
+            bandwidth: self.bandwidth.clone(),
             earlier: HashSet::new(),
             dns: dns_seed(network),
-            cex: executor.clone()
+            cex: executor.clone(),
+            random: self.random.clone(),
+            clock: self.clock.clone(),
+            next: Arc::new(Mutex::new(self.clock.now() + Duration::from_secs(KEEP_CONNECTED)))
         };
-        executor.spawn(Interval::new(Duration::new(10, 0)).for_each(move |_| keep_connected.clone())).expect("can not keep connected");
+        // due in the time of the clock, so that a simulated clock drives it
+        executor.spawn(Interval::new(Duration::from_millis(KEEP_CONNECTED_POLL)).for_each(move |_| keep_connected.clone())).expect("can not keep connected");
+
+        let p2p = self.p2p.clone();
+        let configdb = self.configdb.clone();
+        executor.spawn(Interval::new(Duration::from_secs(STORE_REPUTATIONS)).for_each(move |_| {
+            let mut configdb = configdb.write().recover();
+            if let Err(e) = configdb.store_reputations(p2p.reputations())
+                .and_then(|_| configdb.store_disconnects(p2p.disconnects()))
+                .and_then(|_| configdb.batch()) {
+                error!("can not store peer reputations: {}", e);
+            }
+            future::ready(())
+        })).expect("can not store reputations");
+
+        let statistics = self.statistics.clone();
+        let bandwidth = self.bandwidth.clone();
+        let configdb = self.configdb.clone();
+        let clock = self.clock.clone();
+        executor.spawn(Interval::new(Duration::from_secs(STORE_STATISTICS)).for_each(move |_| {
+            let mut configdb = configdb.write().recover();
+            if let Err(e) = configdb.fetch_statistics().and_then(|mut history| {
+                statistics.accumulate(&mut history, clock.unix_time(), bandwidth.total());
+                configdb.store_statistics(history)
+            }).and_then(|_| configdb.batch()) {
+                error!("can not store statistics: {}", e);
+            }
+            future::ready(())
+        })).expect("can not store statistics");
+
+        let events = self.events.clone();
+        executor.spawn(self.p2p.subscribe_disconnects().for_each(move |disconnect| {
+            events.publish(Event::PeerDisconnected { address: disconnect.address, reason: disconnect.reason });
+            future::ready(())
+        })).expect("can not report disconnects");
+
+        let p2p = self.p2p.clone();
+        let p2p_control = self.p2p_control.clone();
+        let rotation = self.rotation.clone();
+        let clock = self.clock.clone();
+        let mut last_rotation = clock.now();
+        executor.spawn(Interval::new(Duration::from_secs(ROTATION_CHECK)).for_each(move |_| {
+            if let Some(policy) = rotation.lock().recover().clone() {
+                if clock.now() >= last_rotation + policy.interval {
+                    last_rotation = clock.now();
+                    // peer ids are never reused, lower ids connected earlier
+                    let mut outgoing = p2p.peer_info().into_iter().filter(|peer| peer.outgoing).map(|peer| peer.id).collect::<Vec<_>>();
+                    outgoing.sort_by_key(|id| id.token().0);
+                    let n = (outgoing.len() as f64 * policy.fraction).ceil() as usize;
+                    for id in outgoing.iter().take(n) {
+                        debug!("rotating out peer={}", id);
+                        p2p_control.send(P2PControl::Retire(*id));
+                    }
+                }
+            }
+            future::ready(())
+        })).expect("can not rotate peers");
 
         let p2p = self.p2p.clone();
         let mut cex = executor.clone();
-        executor.run(future::poll_fn(move |_| {
+        let thread = thread::Builder::new().name("murmel".to_string()).spawn(move || {
             let needed_services = 0;
             p2p.poll_events("bitcoin", needed_services, &mut cex);
-            Async::Ready(())
-        }));
-        Ok(())
+        })?;
+        Ok(NodeHandle { thread })
+    }
+}
+
+/// A running stack, returned by Constructor::start
+pub struct NodeHandle {
+    thread: thread::JoinHandle<()>
+}
+
+impl NodeHandle {
+    /// Block until the stack stops, Error::Panicked if its thread panicked
+    pub fn join(self) -> Result<(), Error> {
+        let name = self.thread.thread().name().unwrap_or("").to_owned();
+        self.thread.join().map_err(|_| Error::Panicked(name))
     }
 }
 
@@ -154,17 +994,60 @@ struct KeepConnected {
     dns: Vec<SocketAddr>,
     earlier: HashSet<SocketAddr>,
     p2p: Arc<P2P<NetworkMessage, RawNetworkMessage, BitcoinP2PConfig>>,
-    min_connections: usize
+    p2p_control: P2PControlSender<NetworkMessage>,
+    configdb: SharedConfigDB,
+    bandwidth: SharedBandwidth,
+    min_connections: Arc<AtomicUsize>,
+    random: SharedRandom,
+    clock: SharedClock,
+    // time of the next check, shared by the clones polled
+    next: Arc<Mutex<Instant>>
 }
 
 impl Future for KeepConnected {
     type Output = ();
 
     fn poll(mut self: Pin<&mut Self>, _: &mut Context<'_>) -> Async<Self::Output> {
-        if self.p2p.n_connected_peers() < self.min_connections {
-            let eligible = self.dns.iter().cloned().filter(|a| !self.earlier.contains(a)).collect::<Vec<_>>();
+        {
+            let now = self.clock.now();
+            let mut next = self.next.lock().recover();
+            if now < *next {
+                return Async::Ready(());
+            }
+            *next = now + Duration::from_secs(KEEP_CONNECTED);
+        }
+        let min_connections = if self.bandwidth.is_restricted() {
+            min(self.min_connections.load(Ordering::Relaxed), RESTRICTED_CONNECTIONS)
+        } else {
+            self.min_connections.load(Ordering::Relaxed)
+        };
+        if self.bandwidth.is_restricted() {
+            // drop surplus connections while bandwidth is restricted
+            let peers = self.p2p_control.peers();
+            for peer in peers.iter().skip(min_connections) {
+                debug!("disconnect surplus peer={} while bandwidth is restricted", peer);
+                self.p2p_control.send(P2PControl::Retire(*peer));
+            }
+        }
+        if self.p2p.n_connected_peers() < min_connections {
+            // addresses learned in this or earlier runs, seeds only if none left
+            let known = match self.configdb.read().recover().fetch_addresses() {
+                Ok(known) => known.into_iter().filter_map(|a| a.address.socket_addr().ok()).collect::<Vec<_>>(),
+                Err(e) => {
+                    error!("can not read peer addresses: {}", e);
+                    Vec::new()
+                }
+            };
+            // never a second connection to an address
+            let connected = self.p2p.connected_peers().into_iter().map(|a| a.ip()).collect::<HashSet<_>>();
+            // Tor addresses only if the dialer reaches them
+            let onion = self.p2p.reaches_onion();
+            let mut eligible = known.into_iter().filter(|a| !self.earlier.contains(a) && !connected.contains(&a.ip()) && (onion || !is_onion(a))).collect::<Vec<_>>();
+            if eligible.is_empty() {
+                eligible = self.dns.iter().cloned().filter(|a| !self.earlier.contains(a) && !connected.contains(&a.ip())).collect::<Vec<_>>();
+            }
             if eligible.len() > 0 {
-                let mut rng = thread_rng();
+                let mut rng = self.random.rng();
                 let choice = eligible[(rng.next_u32() as usize) % eligible.len()];
                 self.earlier.insert(choice.clone());
                 let add = self.p2p.add_peer("bitcoin", PeerSource::Outgoing(choice)).map(|_| ());
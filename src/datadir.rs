@@ -0,0 +1,82 @@
+//
+// Copyright 2019 Tamas Blummer
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+// http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+//
+//!
+//! # Data directory
+//!
+//! Layout of the files of a node below a root directory:
+//!
+//! * `<network>/chain/` chain DB with headers, filters and blocks
+//! * `<network>/config/` config DB
+//! * `<network>/logs/` for the application's logs
+//! * `<network>/LOCK` locked while a process uses the directory
+//!
+
+use bitcoin::network::constants::Network;
+use error::Error;
+use fs2::FileExt;
+use std::{
+    fs::{self, File, OpenOptions},
+    path::{Path, PathBuf}
+};
+
+/// Files of a node for a network, locked against use by other processes until dropped
+pub struct DataDir {
+    path: PathBuf,
+    // holds the exclusive lock
+    _lock: File
+}
+
+impl DataDir {
+    /// Create the layout below root if not yet there and lock it.
+    /// Fails with Error::Locked if an other process uses it.
+    pub fn open(root: &Path, network: Network) -> Result<DataDir, Error> {
+        let path = root.join(match network {
+            Network::Bitcoin => "bitcoin",
+            Network::Testnet => "testnet",
+            Network::Regtest => "regtest"
+        });
+        fs::create_dir_all(&path)?;
+        let lock = OpenOptions::new().read(true).write(true).create(true).open(path.join("LOCK"))?;
+        if lock.try_lock_exclusive().is_err() {
+            return Err(Error::Locked(path));
+        }
+        for dir in &["chain", "config", "logs"] {
+            fs::create_dir_all(path.join(dir))?;
+        }
+        info!("using data directory {}", path.to_string_lossy());
+        Ok(DataDir { path, _lock: lock })
+    }
+
+    /// directory of this network
+    pub fn path(&self) -> &Path {
+        self.path.as_path()
+    }
+
+    /// path of the chain DB
+    pub fn chain_db(&self) -> PathBuf {
+        self.path.join("chain").join("chain")
+    }
+
+    /// path of the config DB
+    pub fn config_db(&self) -> PathBuf {
+        self.path.join("config").join("config")
+    }
+
+    /// directory for logs
+    pub fn logs(&self) -> PathBuf {
+        self.path.join("logs")
+    }
+}
@@ -18,6 +18,7 @@
 //!
 
 
+use lock::Recover;
 use p2p::{PeerMessageReceiver, PeerMessageSender};
 use std::{
     thread,
@@ -38,13 +39,13 @@ impl<Message: Send + Sync + Clone + 'static> Dispatcher<Message> {
     }
 
     pub fn add_listener(&mut self, listener: PeerMessageSender<Message>) {
-        let mut list = self.listener.lock().unwrap();
+        let mut list = self.listener.lock().recover();
         list.push(listener);
     }
 
     fn incoming_messages_loop (incoming: PeerMessageReceiver<Message>, listener: Arc<Mutex<Vec<PeerMessageSender<Message>>>>) {
         while let Ok(pm) = incoming.recv() {
-            let list = listener.lock().unwrap();
+            let list = listener.lock().recover();
             for listener in list.iter() {
                 listener.send(pm.clone());
             }
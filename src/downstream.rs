@@ -23,6 +23,9 @@ use bitcoin::{
     },
 };
 
+use futures::channel::mpsc;
+
+use lock::Recover;
 use std::sync::{Arc, Mutex};
 
 pub type SharedDownstream = Arc<Mutex<dyn Downstream>>;
@@ -46,4 +49,59 @@ impl Downstream for DownStreamDummy {
     fn header_connected(&mut self, _header: &BlockHeader, _height: u32) {}
 
     fn block_disconnected(&mut self, _header: &BlockHeader) {}
-}
\ No newline at end of file
+}
+
+/// Passes notifications to several downstream modules in turn
+pub struct Downstreams {
+    downstreams: Vec<SharedDownstream>
+}
+
+impl Downstreams {
+    pub fn new(downstreams: Vec<SharedDownstream>) -> Downstreams {
+        Downstreams { downstreams }
+    }
+}
+
+impl Downstream for Downstreams {
+    fn block_connected(&mut self, block: &Block, height: u32) {
+        for downstream in &self.downstreams {
+            downstream.lock().recover().block_connected(block, height);
+        }
+    }
+
+    fn header_connected(&mut self, header: &BlockHeader, height: u32) {
+        for downstream in &self.downstreams {
+            downstream.lock().recover().header_connected(header, height);
+        }
+    }
+
+    fn block_disconnected(&mut self, header: &BlockHeader) {
+        for downstream in &self.downstreams {
+            downstream.lock().recover().block_disconnected(header);
+        }
+    }
+}
+
+/// Subscribers to a stream of notifications
+#[derive(Clone)]
+pub struct Subscribers<T: Clone + Send> {
+    senders: Arc<Mutex<Vec<mpsc::UnboundedSender<T>>>>
+}
+
+impl<T: Clone + Send> Subscribers<T> {
+    pub fn new() -> Subscribers<T> {
+        Subscribers { senders: Arc::new(Mutex::new(Vec::new())) }
+    }
+
+    /// subscribe to future notifications
+    pub fn subscribe(&self) -> mpsc::UnboundedReceiver<T> {
+        let (sender, receiver) = mpsc::unbounded();
+        self.senders.lock().recover().push(sender);
+        receiver
+    }
+
+    /// notify all subscribers, forget those that dropped their stream
+    pub fn publish(&self, item: T) {
+        self.senders.lock().recover().retain(|sender| sender.unbounded_send(item.clone()).is_ok());
+    }
+}
@@ -0,0 +1,317 @@
+//
+// Copyright 2019 Tamas Blummer
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+// http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+//
+//!
+//! # Electrum server
+//!
+//! A subset of the Electrum protocol, so that Electrum based wallets can use a murmel node:
+//! server.version, server.ping, blockchain.headers.subscribe, blockchain.block.header,
+//! blockchain.scripthash.subscribe, blockchain.scripthash.get_history,
+//! blockchain.transaction.get and blockchain.transaction.broadcast. Murmel has no address index,
+//! script hashes are only known for scripts of the wallet, others have an empty history.
+//! Transactions are only found if they are of the wallet and either unconfirmed or their block
+//! was downloaded, verbose output is not offered. Script hashes a client subscribed to are
+//! subscribed at the wallet, which tells of changes of their status, until the client goes away.
+//!
+//! mempool.get_fee_histogram is not offered: murmel keeps no memory pool, and the fee of a
+//! transaction is unknown without the outputs it spends, which a light node does not have.
+//!
+//! Available with the electrum feature.
+//!
+
+use bitcoin::{
+    Transaction,
+    consensus::{deserialize, serialize},
+    network::message::NetworkMessage
+};
+use bitcoin_hashes::{
+    Hash, sha256,
+    hex::{FromHex, ToHex},
+    sha256d::Hash as Sha256dHash
+};
+use chaindb::ChainView;
+use downstream::Subscribers;
+use error::Error;
+use event::Event;
+use futures::{
+    channel::mpsc,
+    executor::block_on_stream,
+    stream::{self, StreamExt}
+};
+use listener::{self, read_line};
+use lock::Recover;
+use p2p::{PeerMessage, PeerMessageSender};
+use serde_json::{self, Value};
+use std::{
+    collections::HashSet,
+    io::{self, BufReader, Write},
+    net::{SocketAddr, TcpStream},
+    sync::{Arc, Mutex},
+    thread,
+    time::Duration
+};
+use wallet::{HistoryItem, SharedWallet};
+
+// protocol version spoken
+const PROTOCOL_VERSION: &str = "1.4";
+// longest request line, a transaction to broadcast in hex with some room
+const MAX_LINE: u64 = 8 * 1024 * 1024 + 1024;
+// seconds between checks for changes of the subscribed tip
+const NOTIFY_INTERVAL: u64 = 1;
+
+/// Serves Electrum clients
+pub struct ElectrumServer {
+    chain: ChainView,
+    wallet: SharedWallet,
+    // events of the wallet, for changes of the status of script hashes
+    events: Subscribers<Event>,
+    broadcaster: PeerMessageSender<NetworkMessage>
+}
+
+// what a client subscribed to, the tip height last notified and script hashes subscribed at the
+// wallet
+#[derive(Default)]
+struct Subscriptions {
+    headers: Option<u32>,
+    scripthashes: HashSet<sha256::Hash>,
+    notifying: bool,
+    watching: bool,
+    closed: bool,
+    // wakes the thread watching script hashes as the client goes away
+    stop: Option<mpsc::UnboundedSender<()>>
+}
+
+impl ElectrumServer {
+    pub fn new(chain: ChainView, wallet: SharedWallet, events: Subscribers<Event>, broadcaster: PeerMessageSender<NetworkMessage>) -> ElectrumServer {
+        ElectrumServer { chain, wallet, events, broadcaster }
+    }
+
+    /// Accept clients at the address in a thread of its own, returns the address bound
+    pub fn listen(self, addr: SocketAddr) -> Result<SocketAddr, Error> {
+        let server = Arc::new(self);
+        let bound = listener::listen("electrum", addr, move |stream| server.clone().serve(stream))?;
+        info!("Electrum server at {}", bound);
+        Ok(bound)
+    }
+
+    fn serve(self: Arc<Self>, stream: TcpStream) -> Result<(), io::Error> {
+        let writer = Arc::new(Mutex::new(stream.try_clone()?));
+        let subscriptions = Arc::new(Mutex::new(Subscriptions::default()));
+        let result = self.clone().requests(stream, writer, subscriptions.clone());
+        // the client went away, its subscriptions at the wallet end
+        let mut subscriptions = subscriptions.lock().recover();
+        subscriptions.closed = true;
+        if let Some(stop) = subscriptions.stop.take() {
+            let _ = stop.unbounded_send(());
+        }
+        let mut wallet = self.wallet.lock().recover();
+        for hash in subscriptions.scripthashes.drain() {
+            wallet.unsubscribe(&hash);
+        }
+        result
+    }
+
+    fn requests(self: Arc<Self>, stream: TcpStream, writer: Arc<Mutex<TcpStream>>, subscriptions: Arc<Mutex<Subscriptions>>) -> Result<(), io::Error> {
+        let mut reader = BufReader::new(stream);
+        while let Some(line) = read_line(&mut reader, MAX_LINE)? {
+            let response = match serde_json::from_str::<Value>(line.as_str()) {
+                Ok(request) => {
+                    let id = request["id"].clone();
+                    let method = request["method"].as_str().unwrap_or("").to_string();
+                    let params = request["params"].as_array().cloned().unwrap_or_default();
+                    if method == "blockchain.scripthash.subscribe" {
+                        // listen to the wallet before subscribing so no change is missed
+                        let watch = {
+                            let mut subscriptions = subscriptions.lock().recover();
+                            let watch = !subscriptions.watching;
+                            subscriptions.watching = true;
+                            watch
+                        };
+                        if watch {
+                            self.clone().watch(writer.clone(), subscriptions.clone())?;
+                        }
+                    }
+                    match self.call(method.as_str(), &params, &subscriptions) {
+                        Ok(result) => json!({"jsonrpc": "2.0", "id": id, "result": result}),
+                        Err(message) => json!({"jsonrpc": "2.0", "id": id, "error": {"code": 1, "message": message}})
+                    }
+                },
+                Err(e) => json!({"jsonrpc": "2.0", "id": Value::Null, "error": {"code": -32700, "message": e.to_string()}})
+            };
+            writeln!(writer.lock().recover(), "{}", response)?;
+            let start = {
+                let mut subscriptions = subscriptions.lock().recover();
+                let start = !subscriptions.notifying && subscriptions.headers.is_some();
+                subscriptions.notifying |= start;
+                start
+            };
+            if start {
+                self.clone().notify(writer.clone(), subscriptions.clone())?;
+            }
+        }
+        Ok(())
+    }
+
+    fn call(&self, method: &str, params: &[Value], subscriptions: &Mutex<Subscriptions>) -> Result<Value, String> {
+        let param = |i: usize| params.get(i).and_then(|p| p.as_str()).ok_or_else(|| format!("missing parameter {}", i));
+        match method {
+            "server.version" => Ok(json!([format!("murmel {}", env!("CARGO_PKG_VERSION")), PROTOCOL_VERSION])),
+            "server.ping" => Ok(Value::Null),
+            "blockchain.headers.subscribe" => {
+                let tip = self.tip()?;
+                subscriptions.lock().recover().headers = tip["height"].as_u64().map(|h| h as u32);
+                Ok(tip)
+            },
+            "blockchain.block.header" => {
+                let height = params.get(0).and_then(|p| p.as_u64()).ok_or_else(|| "missing height".to_string())?;
+                let header = self.chain.get_header_for_height(height as u32).ok_or_else(|| format!("no header at height {}", height))?;
+                Ok(Value::String(serialize(&header.stored.header).to_hex()))
+            },
+            "blockchain.scripthash.subscribe" => {
+                let hash = parse_script_hash(param(0)?).ok_or_else(|| "invalid script hash".to_string())?;
+                let mut subscriptions = subscriptions.lock().recover();
+                let mut wallet = self.wallet.lock().recover();
+                let status = if subscriptions.scripthashes.insert(hash) {
+                    wallet.subscribe(hash)
+                } else {
+                    wallet.status(&hash)
+                };
+                Ok(status.map(|status| Value::String(status.into_inner().to_hex())).unwrap_or(Value::Null))
+            },
+            "blockchain.scripthash.get_history" => {
+                Ok(Value::Array(self.history(param(0)?).iter().map(|item| json!({
+                    "tx_hash": item.txid.to_string(), "height": item.height.unwrap_or(0)
+                })).collect()))
+            },
+            "blockchain.transaction.get" => {
+                if params.get(1).and_then(|p| p.as_bool()).unwrap_or(false) {
+                    return Err("verbose transactions are not supported".to_string());
+                }
+                let txid = Sha256dHash::from_hex(param(0)?).map_err(|e| e.to_string())?;
+                let tx = self.transaction(&txid).ok_or_else(|| format!("transaction {} not found", txid))?;
+                Ok(Value::String(serialize(&tx).to_hex()))
+            },
+            "blockchain.transaction.broadcast" => {
+                let data = Vec::<u8>::from_hex(param(0)?).map_err(|e| e.to_string())?;
+                let tx: Transaction = deserialize(data.as_slice()).map_err(|e| e.to_string())?;
+                let txid = tx.txid();
+                self.broadcaster.send(PeerMessage::Outgoing(NetworkMessage::Tx(tx)));
+                Ok(Value::String(txid.to_string()))
+            },
+            other => Err(format!("unsupported method {}", other))
+        }
+    }
+
+    fn tip(&self) -> Result<Value, String> {
+        let tip = self.chain.header_tip().ok_or_else(|| "no headers yet".to_string())?;
+        Ok(json!({"height": tip.stored.height, "hex": serialize(&tip.stored.header).to_hex()}))
+    }
+
+    // history of the wallet's script with the hash, confirmed by height first, then unconfirmed
+    fn history(&self, scripthash: &str) -> Vec<HistoryItem> {
+        match parse_script_hash(scripthash) {
+            Some(hash) => self.wallet.lock().recover().script_hash_history(&hash),
+            None => Vec::new()
+        }
+    }
+
+    // a transaction of the wallet, unconfirmed or in a downloaded block
+    fn transaction(&self, txid: &Sha256dHash) -> Option<Transaction> {
+        let block = {
+            let wallet = self.wallet.lock().recover();
+            if let Some(tx) = wallet.unconfirmed_transaction(txid) {
+                return Some(tx);
+            }
+            wallet.history_item(txid)?.block?
+        };
+        self.chain.fetch_block(&block).ok()??.txdata.into_iter().find(|tx| tx.txid() == *txid)
+    }
+
+    // notify the client of changes of the tip until it goes away, the client is dropped if
+    // there is no thread for it
+    fn notify(self: Arc<Self>, writer: Arc<Mutex<TcpStream>>, subscriptions: Arc<Mutex<Subscriptions>>) -> Result<(), io::Error> {
+        thread::Builder::new().name("electrumnotify".to_string()).spawn(move || {
+            loop {
+                thread::sleep(Duration::from_secs(NOTIFY_INTERVAL));
+                let notification = {
+                    let mut subscriptions = subscriptions.lock().recover();
+                    if subscriptions.closed {
+                        return;
+                    }
+                    match (subscriptions.headers, self.tip()) {
+                        (Some(height), Ok(tip)) if tip["height"].as_u64() != Some(height as u64) => {
+                            subscriptions.headers = tip["height"].as_u64().map(|h| h as u32);
+                            json!({"jsonrpc": "2.0", "method": "blockchain.headers.subscribe", "params": [tip]})
+                        },
+                        _ => continue
+                    }
+                };
+                if writeln!(writer.lock().recover(), "{}", notification).is_err() {
+                    return;
+                }
+            }
+        })?;
+        Ok(())
+    }
+
+    // notify the client of the changes of the status of its script hashes the wallet tells until
+    // it goes away, the client is dropped if there is no thread for it
+    fn watch(self: Arc<Self>, writer: Arc<Mutex<TcpStream>>, subscriptions: Arc<Mutex<Subscriptions>>) -> Result<(), io::Error> {
+        let (stop, stopped) = mpsc::unbounded();
+        subscriptions.lock().recover().stop = Some(stop);
+        // None once the client went away
+        let events = stream::select(self.events.subscribe().map(Some), stopped.map(|_| None));
+        thread::Builder::new().name("electrumwatch".to_string()).spawn(move || {
+            for event in block_on_stream(events) {
+                let watched = {
+                    let subscriptions = subscriptions.lock().recover();
+                    match event {
+                        Some(_) if subscriptions.closed => return,
+                        None => return,
+                        Some(Event::ScriptHashStatus { hash, status }) if subscriptions.scripthashes.contains(&hash) => Some((hash, status)),
+                        Some(_) => None
+                    }
+                };
+                if let Some((hash, status)) = watched {
+                    let notification = json!({"jsonrpc": "2.0", "method": "blockchain.scripthash.subscribe",
+                        "params": [electrum_hash(&hash), status.map(|status| Value::String(status.into_inner().to_hex())).unwrap_or(Value::Null)]});
+                    if writeln!(writer.lock().recover(), "{}", notification).is_err() {
+                        return;
+                    }
+                }
+            }
+        })?;
+        Ok(())
+    }
+}
+
+/// Electrum's script hash, the reversed sha256 of the script in hex
+pub fn script_hash(script: &[u8]) -> String {
+    electrum_hash(&sha256::Hash::hash(script))
+}
+
+// the reversed hex Electrum uses for a script hash
+fn electrum_hash(hash: &sha256::Hash) -> String {
+    let mut hash = hash.into_inner();
+    hash.reverse();
+    hash.to_hex()
+}
+
+// the hash of Electrum's reversed hex, None if invalid
+fn parse_script_hash(scripthash: &str) -> Option<sha256::Hash> {
+    let mut data = Vec::<u8>::from_hex(scripthash).ok()?;
+    data.reverse();
+    sha256::Hash::from_slice(data.as_slice()).ok()
+}
@@ -23,9 +23,11 @@ use bitcoin::consensus::encode;
 use bitcoin::util;
 use bitcoin::util::bip158;
 use hammersbald;
+use p2p::PeerId;
 use std::convert;
 use std::fmt;
 use std::io;
+use std::path::PathBuf;
 
 /// An error class to offer a unified error interface upstream
 pub enum Error {
@@ -56,7 +58,85 @@ pub enum Error {
     /// Handshake failure
     Handshake,
     /// lost connection
-    Lost(String)
+    Lost(String),
+    /// data was stored in a format of a later version
+    UnsupportedVersion(u32),
+    /// the data directory is used by an other process
+    Locked(PathBuf),
+    /// a payment URI that can not be used
+    BadUri(String),
+    /// a thread of the stack panicked, the name of the thread
+    Panicked(String),
+    /// an error with information on where it happened
+    Context {
+        /// what was done
+        context: String,
+        /// the peer involved, if any
+        peer: Option<PeerId>,
+        /// the error
+        error: Box<Error>
+    }
+}
+
+/// Broad class of an error, telling what is reasonable to do about it
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum Category {
+    /// network or file IO failed, retrying later might help
+    IO,
+    /// the database is inconsistent or failed, abort
+    DB,
+    /// a peer violated the protocol, ban it
+    Protocol,
+    /// data violates consensus rules, ban the peer that sent it
+    Consensus,
+    /// the application or a downstream module failed
+    Application
+}
+
+impl Error {
+    /// the class of this error
+    pub fn category(&self) -> Category {
+        match *self {
+            Error::SpvBadTarget |
+            Error::SpvBadProofOfWork |
+            Error::BadMerkleRoot => Category::Consensus,
+            Error::UnconnectedHeader |
+            Error::Handshake |
+            Error::Util(_) |
+            Error::Serialize(_) => Category::Protocol,
+            Error::NoTip |
+            Error::UnknownUTXO |
+            Error::UnsupportedVersion(_) |
+            Error::Locked(_) |
+            Error::Hammersbald(_) => Category::DB,
+            Error::NoPeers |
+            Error::IO(_) |
+            Error::Lost(_) => Category::IO,
+            Error::Downstream(_) |
+            Error::BadUri(_) |
+            Error::Panicked(_) => Category::Application,
+            Error::Context { ref error, .. } => error.category()
+        }
+    }
+
+    /// the peer involved, if known
+    pub fn peer(&self) -> Option<PeerId> {
+        match *self {
+            Error::Context { peer: Some(peer), .. } => Some(peer),
+            Error::Context { ref error, .. } => error.peer(),
+            _ => None
+        }
+    }
+
+    /// add the peer involved
+    pub fn with_peer(self, peer: PeerId) -> Error {
+        Error::Context { context: String::new(), peer: Some(peer), error: Box::new(self) }
+    }
+
+    /// add what was done when the error happened
+    pub fn context(self, context: &str) -> Error {
+        Error::Context { context: context.to_owned(), peer: None, error: Box::new(self) }
+    }
 }
 
 impl std::error::Error for Error {
@@ -75,7 +155,12 @@ impl std::error::Error for Error {
             Error::Hammersbald(ref err) => err.description(),
             Error::Serialize(ref err) => err.description(),
             Error::Handshake => "handshake",
-            Error::Lost(ref s) => s
+            Error::Lost(ref s) => s,
+            Error::UnsupportedVersion(_) => "data stored by a later version",
+            Error::Locked(_) => "data directory is used by an other process",
+            Error::BadUri(ref s) => s,
+            Error::Panicked(_) => "a thread panicked",
+            Error::Context { ref error, .. } => error.description()
         }
     }
 
@@ -94,7 +179,12 @@ impl std::error::Error for Error {
             Error::Hammersbald(ref err) => Some(err),
             Error::Serialize(ref err) => Some(err),
             Error::Handshake => None,
-            Error::Lost(_) => None
+            Error::Lost(_) => None,
+            Error::UnsupportedVersion(_) => None,
+            Error::Locked(_) => None,
+            Error::BadUri(_) => None,
+            Error::Panicked(_) => None,
+            Error::Context { ref error, .. } => Some(error.as_ref())
         }
     }
 }
@@ -116,10 +206,24 @@ impl fmt::Display for Error {
             },
             Error::Lost(ref s) |
             Error::Downstream(ref s) => write!(f, "{}", s),
+            Error::BadUri(ref s) => write!(f, "bad payment URI {}", s),
+            Error::Panicked(ref name) => write!(f, "thread {} panicked", name),
             Error::IO(ref err) => write!(f, "IO error: {}", err),
             Error::Util(ref err) => write!(f, "Util error: {}", err),
             Error::Hammersbald(ref err) => write!(f, "Hammersbald error: {}", err),
             Error::Serialize(ref err) => write!(f, "Serialize error: {}", err),
+            Error::UnsupportedVersion(v) => write!(f, "data stored in schema version {} of a later release", v),
+            Error::Locked(ref path) => write!(f, "data directory {} is used by an other process", path.to_string_lossy()),
+            Error::Context { ref context, peer, ref error } => {
+                if !context.is_empty() {
+                    write!(f, "{}: ", context)?;
+                }
+                write!(f, "{}", error)?;
+                if let Some(peer) = peer {
+                    write!(f, " peer={}", peer)?;
+                }
+                Ok(())
+            }
         }
     }
 }
@@ -0,0 +1,245 @@
+//
+// Copyright 2019 Tamas Blummer
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+// http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+//
+//!
+//! # Esplora compatible HTTP endpoints
+//!
+//! A read-only subset of the Esplora HTTP API, so that web frontends built for Esplora can run
+//! against a murmel node:
+//!
+//! ```text
+//! GET /blocks/tip/height
+//! GET /blocks/tip/hash
+//! GET /block-height/:height
+//! GET /block/:hash
+//! GET /block/:hash/header
+//! GET /block/:hash/status
+//! GET /tx/:txid
+//! GET /tx/:txid/hex
+//! GET /tx/:txid/status
+//! ```
+//!
+//! Transactions are only found if they pay to or spend from a script of the wallet and their
+//! block was downloaded. The prevout of an input is null unless it spends such a transaction,
+//! the fee is null unless all inputs do. Weight and size of a block are only given if it was
+//! downloaded. Available with the esplora feature.
+//!
+
+use bitcoin::{
+    BitcoinHash, Transaction, TxOut,
+    consensus::serialize
+};
+use bitcoin_hashes::{
+    hex::{FromHex, ToHex},
+    sha256d::Hash as Sha256dHash
+};
+use chaindb::ChainView;
+use error::Error;
+use fee;
+use headercache::CachedHeader;
+use listener::{self, read_http_request, write_http_response};
+use lock::Recover;
+use serde_json::Value;
+use std::{
+    io::{self, BufReader},
+    net::{SocketAddr, TcpStream},
+    sync::Arc
+};
+use wallet::SharedWallet;
+
+/// Serves the Esplora API subset
+pub struct EsploraServer {
+    chain: ChainView,
+    wallet: SharedWallet
+}
+
+// status and body of a response
+enum Response {
+    Text(String),
+    Json(Value),
+    NotFound(String),
+    BadRequest(String)
+}
+
+impl EsploraServer {
+    pub fn new(chain: ChainView, wallet: SharedWallet) -> EsploraServer {
+        EsploraServer { chain, wallet }
+    }
+
+    /// Accept clients at the address in a thread of its own, returns the address bound
+    pub fn listen(self, addr: SocketAddr) -> Result<SocketAddr, Error> {
+        let server = Arc::new(self);
+        let bound = listener::listen("esplora", addr, move |stream| server.serve(stream))?;
+        info!("Esplora API at http://{}", bound);
+        Ok(bound)
+    }
+
+    // one request per connection
+    fn serve(&self, mut stream: TcpStream) -> Result<(), io::Error> {
+        let response = match read_http_request(&mut BufReader::new(stream.try_clone()?))? {
+            (ref method, ref path) if method == "GET" => self.get(path),
+            _ => Response::BadRequest("only GET is supported".to_string())
+        };
+        let (status, content_type, body) = match response {
+            Response::Text(body) => ("200 OK", "text/plain", body),
+            Response::Json(body) => ("200 OK", "application/json", body.to_string()),
+            Response::NotFound(body) => ("404 Not Found", "text/plain", body),
+            Response::BadRequest(body) => ("400 Bad Request", "text/plain", body)
+        };
+        write_http_response(&mut stream, status, content_type, body.as_str())
+    }
+
+    fn get(&self, path: &str) -> Response {
+        let parts = path.trim_matches('/').split('/').collect::<Vec<_>>();
+        match parts.as_slice() {
+            ["blocks", "tip", "height"] => match self.chain.header_tip() {
+                Some(tip) => Response::Text(tip.stored.height.to_string()),
+                None => Response::NotFound("no headers yet".to_string())
+            },
+            ["blocks", "tip", "hash"] => match self.chain.header_tip() {
+                Some(tip) => Response::Text(tip.bitcoin_hash().to_string()),
+                None => Response::NotFound("no headers yet".to_string())
+            },
+            ["block-height", height] => match height.parse::<u32>().ok().and_then(|h| self.chain.get_header_for_height(h)) {
+                Some(header) => Response::Text(header.bitcoin_hash().to_string()),
+                None => Response::NotFound("Block not found".to_string())
+            },
+            ["block", hash] => self.with_header(hash, |header| Response::Json(self.block(header))),
+            ["block", hash, "header"] => self.with_header(hash, |header| Response::Text(serialize(&header.stored.header).to_hex())),
+            ["block", hash, "status"] => self.with_header(hash, |header| Response::Json(self.block_status(header))),
+            ["tx", txid] => self.with_transaction(txid, |tx, status| Response::Json(self.transaction(tx, status))),
+            ["tx", txid, "hex"] => self.with_transaction(txid, |tx, _| Response::Text(serialize(tx).to_hex())),
+            ["tx", txid, "status"] => self.with_transaction(txid, |_, status| Response::Json(status)),
+            _ => Response::NotFound("unknown endpoint".to_string())
+        }
+    }
+
+    fn with_header<F: FnOnce(&CachedHeader) -> Response>(&self, hash: &str, f: F) -> Response {
+        let id = match Sha256dHash::from_hex(hash) {
+            Ok(id) => id,
+            Err(_) => return Response::BadRequest("Invalid hex string".to_string())
+        };
+        match self.chain.get_header(&id) {
+            Some(header) => f(&header),
+            None => Response::NotFound("Block not found".to_string())
+        }
+    }
+
+    fn block(&self, header: &CachedHeader) -> Value {
+        let h = &header.stored.header;
+        let mut block = json!({
+            "id": header.bitcoin_hash().to_string(),
+            "height": header.stored.height,
+            "version": h.version,
+            "timestamp": h.time,
+            "bits": h.bits,
+            "nonce": h.nonce,
+            "difficulty": difficulty(h.bits),
+            "merkle_root": h.merkle_root.to_string(),
+            "previousblockhash": h.prev_blockhash.to_string()
+        });
+        if let Some(mediantime) = self.chain.median_time_past(header.stored.height) {
+            block["mediantime"] = json!(mediantime);
+        }
+        // size and transactions are only known if the block was downloaded
+        if let Ok(Some(stored)) = self.chain.fetch_block(&header.bitcoin_hash()) {
+            let size = serialize(&stored).len() as u64;
+            // header and transaction count count four times, as do transactions without witness
+            let txs = stored.txdata.iter().map(|tx| serialize(tx).len() as u64).sum::<u64>();
+            let weight = (size - txs) * 4 + stored.txdata.iter().map(fee::weight).sum::<u64>();
+            block["tx_count"] = json!(stored.txdata.len());
+            block["size"] = json!(size);
+            block["weight"] = json!(weight);
+        }
+        block
+    }
+
+    fn block_status(&self, header: &CachedHeader) -> Value {
+        let id = header.bitcoin_hash();
+        let in_best_chain = self.chain.pos_on_trunk(&id).is_some();
+        let next_best = if in_best_chain {
+            self.chain.get_header_for_height(header.stored.height + 1).map(|next| next.bitcoin_hash().to_string())
+        } else {
+            None
+        };
+        json!({"in_best_chain": in_best_chain, "height": header.stored.height, "next_best": next_best})
+    }
+
+    fn transaction(&self, tx: &Transaction, status: Value) -> Value {
+        let txid = tx.txid();
+        let vin = tx.input.iter().map(|input| {
+            let coinbase = input.previous_output.is_null();
+            // only outputs of the wallet's stored transactions are known
+            let prevout = if coinbase { None } else {
+                self.confirmed_transaction(&input.previous_output.txid)
+                    .and_then(|(prev, _)| prev.output.get(input.previous_output.vout as usize).cloned())
+            };
+            json!({
+                "txid": input.previous_output.txid.to_string(),
+                "vout": input.previous_output.vout,
+                "prevout": prevout.map(|output| output_json(&output)),
+                "scriptsig": input.script_sig.as_bytes().to_hex(),
+                "witness": input.witness.iter().map(|w| w.to_hex()).collect::<Vec<_>>(),
+                "is_coinbase": coinbase,
+                "sequence": input.sequence
+            })
+        }).collect::<Vec<_>>();
+        let fee = self.wallet.lock().recover().transactions().iter().find(|known| known.txid == txid).and_then(|known| known.fee);
+        json!({
+            "txid": txid.to_string(),
+            "version": tx.version,
+            "locktime": tx.lock_time,
+            "vin": vin,
+            "vout": tx.output.iter().map(output_json).collect::<Vec<_>>(),
+            "size": serialize(tx).len(),
+            "weight": fee::weight(tx),
+            "fee": fee,
+            "status": status
+        })
+    }
+
+    fn with_transaction<F: FnOnce(&Transaction, Value) -> Response>(&self, txid: &str, f: F) -> Response {
+        let txid = match Sha256dHash::from_hex(txid) {
+            Ok(txid) => txid,
+            Err(_) => return Response::BadRequest("Invalid hex string".to_string())
+        };
+        match self.confirmed_transaction(&txid) {
+            Some((tx, status)) => f(&tx, status),
+            None => Response::NotFound("Transaction not found".to_string())
+        }
+    }
+
+    // a transaction of the wallet in a downloaded block with its status
+    fn confirmed_transaction(&self, txid: &Sha256dHash) -> Option<(Transaction, Value)> {
+        let item = self.wallet.lock().recover().history_item(txid)?;
+        // unconfirmed transactions are not stored
+        let (height, block_id) = (item.height?, item.block?);
+        let block = self.chain.fetch_block(&block_id).ok()??;
+        let time = block.header.time;
+        let tx = block.txdata.into_iter().find(|tx| tx.txid() == *txid)?;
+        Some((tx, json!({"confirmed": true, "block_height": height, "block_hash": block_id.to_string(), "block_time": time})))
+    }
+}
+
+fn output_json(output: &TxOut) -> Value {
+    json!({"scriptpubkey": output.script_pubkey.as_bytes().to_hex(), "value": output.value})
+}
+
+// difficulty of the compact target, relative to the target of difficulty 1
+fn difficulty(bits: u32) -> f64 {
+    let exponent = (bits >> 24) as i32;
+    let mantissa = (bits & 0x00ff_ffff).max(1) as f64;
+    0xffff as f64 / mantissa * 256f64.powi(0x1d - exponent)
+}
@@ -0,0 +1,121 @@
+//
+// Copyright 2019 Tamas Blummer
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+// http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+//
+//!
+//! # Events
+//!
+//! Notifications to the application about noteworthy conditions of the node
+//!
+
+use bitcoin::blockdata::{
+    script::Script,
+    transaction::{OutPoint, Transaction}
+};
+use bitcoin_hashes::{sha256, sha256d::Hash as Sha256dHash};
+use p2p::DisconnectReason;
+use std::net::SocketAddr;
+
+/// An event the application might want to act on
+#[derive(Clone, Debug)]
+pub enum Event {
+    /// an oracle reports a chain tip that does not match our chain
+    TipDivergence {
+        /// name of the oracle
+        oracle: String,
+        /// our (height, hash) of the tip
+        ours: (u32, Sha256dHash),
+        /// the oracle's (height, hash) of the tip
+        theirs: (u32, Sha256dHash)
+    },
+    /// the filter of a block matched watched scripts
+    FilterMatch {
+        /// (height, hash) of the block
+        block: (u32, Sha256dHash)
+    },
+    /// a transaction other than the expected one spends an outpoint
+    PossibleDoubleSpend {
+        /// the outpoint spent
+        outpoint: OutPoint,
+        /// the transaction expected to spend it
+        expected: Transaction,
+        /// the transaction spending it instead
+        conflicting: Transaction,
+        /// the block containing the conflicting transaction, None if announced by a peer
+        block: Option<Sha256dHash>
+    },
+    /// a header connected to the trunk signals a watched version bit
+    VersionBitsSignal {
+        /// the bit
+        bit: u8,
+        /// (height, hash) of the header
+        block: (u32, Sha256dHash)
+    },
+    /// the chain db was found corrupted at startup and truncated to its last consistent state
+    RecoveredFromCorruption {
+        /// (height, hash) of the header tip after recovery
+        tip: Option<(u32, Sha256dHash)>,
+        /// problems found
+        problems: Vec<String>
+    },
+    /// a transaction pays to a script of the wallet that was paid to or spent from before
+    AddressReuse {
+        /// the script reused
+        script: Script,
+        /// the transaction paying to it again
+        txid: Sha256dHash
+    },
+    /// an output below the dust threshold pays to the wallet
+    DustReceived {
+        /// the output
+        outpoint: OutPoint,
+        /// its value in satoshis
+        value: u64
+    },
+    /// an unconfirmed transaction of the wallet was double spent by a block, e.g. replaced through RBF,
+    /// or spends outputs of one that was
+    TransactionReplaced {
+        /// the transaction replaced
+        replaced: Sha256dHash,
+        /// the transaction of the block spending any of its inputs or those of its ancestor
+        replacement: Transaction,
+        /// the block containing the replacement
+        block: Sha256dHash
+    },
+    /// the status of a subscribed script hash of the wallet changed
+    ScriptHashStatus {
+        /// Electrum's script hash
+        hash: sha256::Hash,
+        /// the new status, None if the script has no history
+        status: Option<sha256::Hash>
+    },
+    /// a transaction pays an awaited payment, reported as announced and at every confirmation
+    PaymentReceived {
+        /// the BIP21 URI of the request
+        uri: String,
+        /// the paying transaction
+        txid: Sha256dHash,
+        /// satoshis paid to the address
+        amount: u64,
+        /// 0 if unconfirmed
+        confirmations: u32
+    },
+    /// a peer was disconnected
+    PeerDisconnected {
+        /// remote address of the peer
+        address: SocketAddr,
+        /// why it was disconnected
+        reason: DisconnectReason
+    }
+}
@@ -0,0 +1,103 @@
+//
+// Copyright 2019 Tamas Blummer
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+// http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+//
+//!
+//! # Fees
+//!
+//! Weight, virtual size and fee rate of transactions, for wallets constructing them, and the
+//! minimum fee rates peers announced with feefilter (BIP133), to tell whether a transaction
+//! would currently propagate. A peer that announced no minimum takes any fee rate.
+//!
+
+use bitcoin::{
+    blockdata::transaction::Transaction,
+    network::message::NetworkMessage
+};
+use lock::Recover;
+use p2p::{P2PControlSender, PeerId, PeerMessage, PeerMessageReceiver, PeerMessageSender};
+use std::{
+    collections::HashMap,
+    sync::{Arc, Mutex, mpsc},
+    thread
+};
+
+pub type SharedFeeFilters = Arc<Mutex<HashMap<PeerId, u64>>>;
+
+/// weight units of the transaction (BIP141)
+pub fn weight(tx: &Transaction) -> u64 {
+    tx.get_weight() as u64
+}
+
+/// virtual size of the transaction in vbytes, weight / 4 rounded up
+pub fn vsize(tx: &Transaction) -> u64 {
+    (weight(tx) + 3) / 4
+}
+
+/// fee rate in satoshis per 1000 vbytes, the unit of feefilter
+pub fn fee_rate(tx: &Transaction, fee: u64) -> u64 {
+    fee * 1000 / vsize(tx).max(1)
+}
+
+/// Connected peers that would relay a transaction of a fee rate
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct PropagationEstimate {
+    /// fee rate in satoshis per 1000 vbytes
+    pub fee_rate: u64,
+    /// peers whose feefilter the fee rate passes, also those that announced none
+    pub accepting: usize,
+    /// connected peers
+    pub peers: usize,
+    /// the highest minimum announced, if any
+    pub max_filter: Option<u64>
+}
+
+/// Estimate propagation of the fee rate by minimum fee rates of the connected peers
+pub fn estimate(fee_rate: u64, peers: &[PeerId], filters: &HashMap<PeerId, u64>) -> PropagationEstimate {
+    let accepting = peers.iter().filter(|peer| filters.get(*peer).map_or(true, |min| fee_rate >= *min)).count();
+    let max_filter = peers.iter().filter_map(|peer| filters.get(peer)).max().cloned();
+    PropagationEstimate { fee_rate, accepting, peers: peers.len(), max_filter }
+}
+
+/// Records minimum fee rates peers announce
+pub struct FeeFilterWatch {
+    filters: SharedFeeFilters
+}
+
+impl FeeFilterWatch {
+    pub fn new(p2p: P2PControlSender<NetworkMessage>, filters: SharedFeeFilters) -> PeerMessageSender<NetworkMessage> {
+        let (sender, receiver) = mpsc::sync_channel(p2p.back_pressure);
+
+        let mut watch = FeeFilterWatch { filters };
+
+        thread::Builder::new().name("feefilter".to_string()).spawn(move || { watch.run(receiver) }).unwrap();
+
+        PeerMessageSender::new(sender)
+    }
+
+    fn run(&mut self, receiver: PeerMessageReceiver<NetworkMessage>) {
+        while let Ok(msg) = receiver.recv() {
+            match msg {
+                PeerMessage::Incoming(pid, NetworkMessage::FeeFilter(min)) => {
+                    trace!("minimum fee rate {} peer={}", min, pid);
+                    self.filters.lock().recover().insert(pid, min.max(0) as u64);
+                },
+                PeerMessage::Disconnected(pid, _) => {
+                    self.filters.lock().recover().remove(&pid);
+                },
+                _ => {}
+            }
+        }
+    }
+}
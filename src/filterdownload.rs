@@ -0,0 +1,361 @@
+//
+// Copyright 2019 Tamas Blummer
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+// http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+//
+//!
+//! # Download BIP158 filters
+//!
+//! Filters are requested in ranges of blocks, one range in flight with each peer serving
+//! filters, so they download from several peers concurrently. Every filter is verified
+//! against the filter header chain before the range is stored, a peer serving a filter
+//! that does not fit the chain is banned and its range is asked from an other peer.
+//!
+//! If the trunk changes below heights already asked for, ranges above the fork are dropped and
+//! download continues at the fork. Filters of blocks reorged out that still arrive are ignored.
+//!
+
+use bandwidth::SharedBandwidth;
+use bitcoin::{
+    BitcoinHash,
+    network::{
+        message::NetworkMessage,
+        message_filter::{CFilter, GetCFilters}
+    }
+};
+use bitcoin_hashes::{Hash, sha256d::Hash as Sha256dHash};
+use chaindb::{ChainDB, SharedChainDB};
+use clock::SharedClock;
+use configdb::SharedConfigDB;
+use downstream::Subscribers;
+use error::Error;
+use event::Event;
+use filterheaderdownload::filter_header;
+use filtermatcher::FilterMatcher;
+use lock::Recover;
+use p2p::{P2PControlSender, PeerId, PeerMessage, PeerMessageReceiver, PeerMessageSender, SERVICE_FILTERS};
+use std::{
+    collections::{HashMap, VecDeque},
+    mem,
+    sync::mpsc,
+    thread,
+    time::{Duration, Instant}
+};
+use syncconfig::SyncConfig;
+use timeout::{ExpectedReply, SharedTimeout};
+use tracing::{Level, field::display};
+
+// basic filter type of BIP158
+const BASIC_FILTER: u8 = 0;
+
+// seconds to wait before asking again for a range out of retries, doubled with each round
+const RETRY_BACKOFF: u64 = 30;
+// most doublings of the backoff
+const MAX_BACKOFF_ROUNDS: usize = 5;
+
+// a range of filters asked from a peer
+struct Range {
+    start: u32,
+    stop: u32,
+    // number of times the range was asked before
+    attempts: usize,
+    // verified filters received so far in order of height
+    filters: Vec<(Sha256dHash, Vec<u8>)>
+}
+
+pub struct FilterDownload {
+    p2p: P2PControlSender<NetworkMessage>,
+    chaindb: SharedChainDB,
+    configdb: SharedConfigDB,
+    timeout: SharedTimeout<NetworkMessage, ExpectedReply>,
+    bandwidth: SharedBandwidth,
+    events: Subscribers<Event>,
+    config: SyncConfig,
+    clock: SharedClock,
+    matcher: FilterMatcher,
+    // ranges in flight by peer
+    in_flight: HashMap<PeerId, Range>,
+    // ranges to ask again
+    retry: VecDeque<Range>,
+    // ranges out of retries, asked again after the time
+    backoff: Vec<(Instant, Range)>,
+    // ranges stored, by start height
+    done: HashMap<u32, u32>,
+    // first height not yet assigned to a peer
+    next: Option<u32>,
+    // highest height assigned with its block, to notice the trunk changing below it
+    assigned: Option<(u32, Sha256dHash)>
+}
+
+impl FilterDownload {
+    pub fn new(chaindb: SharedChainDB, configdb: SharedConfigDB, p2p: P2PControlSender<NetworkMessage>, timeout: SharedTimeout<NetworkMessage, ExpectedReply>,
+               bandwidth: SharedBandwidth, events: Subscribers<Event>, config: SyncConfig, clock: SharedClock) -> PeerMessageSender<NetworkMessage> {
+        let (sender, receiver) = mpsc::sync_channel(p2p.back_pressure);
+
+        let mut filterdownload = FilterDownload { chaindb, configdb, p2p, timeout, bandwidth, events, config, clock,
+            matcher: FilterMatcher::new(), in_flight: HashMap::new(), retry: VecDeque::new(), backoff: Vec::new(), done: HashMap::new(), next: None, assigned: None };
+
+        thread::Builder::new().name("filter download".to_string()).spawn(move || { filterdownload.run(receiver) }).unwrap();
+
+        PeerMessageSender::new(sender)
+    }
+
+    fn run(&mut self, receiver: PeerMessageReceiver<NetworkMessage>) {
+        let span = span!(Level::INFO, "filter download");
+        let _enter = span.enter();
+        loop {
+            while let Ok(msg) = receiver.recv_timeout(Duration::from_millis(1000)) {
+                if let Err(e) = match msg {
+                    PeerMessage::Disconnected(pid, _) => {
+                        self.disconnected(pid);
+                        Ok(())
+                    }
+                    PeerMessage::Incoming(pid, msg) => {
+                        let span = span!(Level::DEBUG, "peer", peer = display(pid));
+                        let _enter = span.enter();
+                        match msg {
+                            NetworkMessage::CFilter(ref filter) => self.cfilter(filter, pid),
+                            _ => { Ok(()) }
+                        }
+                    },
+                    _ => { Ok(()) }
+                } {
+                    error!("Error processing filters: {}", e);
+                }
+            }
+            self.timeout.lock().recover().check(vec!(ExpectedReply::Filter));
+            if let Err(e) = self.sync() {
+                error!("Error downloading filters: {}", e);
+            }
+        }
+    }
+
+    fn is_serving_filters(&self, peer: PeerId) -> bool {
+        if let Some(peer_version) = self.p2p.peer_version(peer) {
+            return peer_version.services & SERVICE_FILTERS != 0;
+        }
+        false
+    }
+
+    fn disconnected(&mut self, peer: PeerId) {
+        if let Some(range) = self.in_flight.remove(&peer) {
+            self.ask_again(range);
+        }
+    }
+
+    // queue a failed range to be asked from an other peer, once out of retries after a backoff,
+    // as filters are stored in order and a missing range would stop all later ones
+    fn ask_again(&mut self, mut range: Range) {
+        range.filters.clear();
+        if range.attempts >= self.config.retries {
+            let rounds = (range.attempts - self.config.retries).min(MAX_BACKOFF_ROUNDS);
+            let delay = Duration::from_secs(RETRY_BACKOFF << rounds);
+            warn!("filters [{} .. {}] not received after {} attempts, asking again in {} seconds", range.start, range.stop, range.attempts + 1, delay.as_secs());
+            range.attempts += 1;
+            self.backoff.push((self.clock.now() + delay, range));
+            return;
+        }
+        range.attempts += 1;
+        self.retry.push_back(range);
+    }
+
+    // queue ranges again whose backoff expired
+    fn resume(&mut self) {
+        let now = self.clock.now();
+        let (due, waiting) = mem::replace(&mut self.backoff, Vec::new()).into_iter().partition::<Vec<_>, _>(|(at, _)| *at <= now);
+        self.backoff = waiting;
+        self.retry.extend(due.into_iter().map(|(_, range)| range));
+    }
+
+    // assign ranges to idle peers serving filters
+    fn sync(&mut self) -> Result<(), Error> {
+        if self.bandwidth.is_restricted() {
+            return Ok(());
+        }
+        self.resume();
+        self.reorg();
+        if self.next.is_none() {
+            self.next = Some(first_missing(&self.chaindb.read().recover())?);
+        }
+        let limit = {
+            let chaindb = self.chaindb.read().recover();
+            match chaindb.fetch_filter_header_tip()? {
+                Some(tip) => match chaindb.pos_on_trunk(&tip) {
+                    Some(height) => height,
+                    None => return Ok(())
+                },
+                None => return Ok(())
+            }
+        };
+        let idle = self.p2p.peers().into_iter()
+            .filter(|p| !self.in_flight.contains_key(p) && self.is_serving_filters(*p)).collect::<Vec<_>>();
+        for peer in idle {
+            let (start, stop, attempts) = if let Some(range) = self.retry.pop_front() {
+                (range.start, range.stop, range.attempts)
+            } else {
+                let next = self.next.unwrap();
+                if next > limit {
+                    break;
+                }
+                let stop = std::cmp::min(limit, next + self.config.filters_per_request - 1);
+                self.next = Some(stop + 1);
+                (next, stop, 0)
+            };
+            let stop_hash = match self.chaindb.read().recover().get_header_for_height(stop) {
+                Some(header) => header.bitcoin_hash(),
+                None => {
+                    self.retry.push_back(Range { start, stop, attempts, filters: Vec::new() });
+                    break;
+                }
+            };
+            if self.assigned.map(|(height, _)| stop > height).unwrap_or(true) {
+                self.assigned = Some((stop, stop_hash));
+            }
+            debug!("ask for filters [{} .. {}] peer={}", start, stop, peer);
+            self.timeout.lock().recover().expect(peer, (stop - start + 1) as usize, ExpectedReply::Filter);
+            self.p2p.send_network(peer, NetworkMessage::GetCFilters(GetCFilters { filter_type: BASIC_FILTER, start_height: start, stop_hash }));
+            self.in_flight.insert(peer, Range { start, stop, attempts, filters: Vec::new() });
+        }
+        Ok(())
+    }
+
+    // drop ranges above the fork if the trunk changed below the heights assigned
+    fn reorg(&mut self) {
+        let (height, id) = match self.assigned {
+            Some(assigned) => assigned,
+            None => return
+        };
+        let (fork, assigned) = {
+            let chaindb = self.chaindb.read().recover();
+            if chaindb.pos_on_trunk(&id).is_some() {
+                return;
+            }
+            let mut cursor = chaindb.get_header(&id);
+            let fork = loop {
+                match cursor {
+                    Some(header) => if chaindb.pos_on_trunk(&header.bitcoin_hash()).is_some() {
+                        break header.stored.height;
+                    } else {
+                        cursor = chaindb.get_header(&header.stored.header.prev_blockhash);
+                    },
+                    None => break 0
+                }
+            };
+            (fork, chaindb.get_header_for_height(fork).map(|header| (fork, header.bitcoin_hash())))
+        };
+        info!("trunk changed below filters asked up to height {}, continue at the fork at height {}", height, fork);
+        let mut next = fork + 1;
+        self.in_flight.retain(|_, range| range.stop <= fork || { next = next.min(range.start); false });
+        self.retry.retain(|range| range.stop <= fork || { next = next.min(range.start); false });
+        self.backoff.retain(|(_, range)| range.stop <= fork || { next = next.min(range.start); false });
+        self.done.retain(|start, stop| *stop <= fork || { next = next.min(*start); false });
+        self.next = self.next.map(|n| n.min(next));
+        self.assigned = assigned;
+    }
+
+    fn cfilter(&mut self, filter: &CFilter, peer: PeerId) -> Result<(), Error> {
+        self.timeout.lock().recover().received(peer, 1, ExpectedReply::Filter);
+        if filter.filter_type != BASIC_FILTER {
+            return Ok(());
+        }
+        let reorged = {
+            let chaindb = self.chaindb.read().recover();
+            chaindb.get_header(&filter.block_hash).is_some() && chaindb.pos_on_trunk(&filter.block_hash).is_none()
+        };
+        if reorged {
+            debug!("filter {} of a block no longer on the trunk peer={}", filter.block_hash, peer);
+            self.reorg();
+            return Ok(());
+        }
+        let fits = if let Some(range) = self.in_flight.get(&peer) {
+            let height = range.start + range.filters.len() as u32;
+            let chaindb = self.chaindb.read().recover();
+            match chaindb.get_header_for_height(height) {
+                Some(header) if header.bitcoin_hash() == filter.block_hash => {
+                    let previous = if height > 0 {
+                        let prev = chaindb.get_header_for_height(height - 1).map(|h| h.bitcoin_hash()).ok_or(Error::NoTip)?;
+                        chaindb.fetch_filter_header(&prev)?.ok_or(Error::UnconnectedHeader)?
+                    } else {
+                        Sha256dHash::default()
+                    };
+                    let expected = chaindb.fetch_filter_header(&filter.block_hash)?.ok_or(Error::UnconnectedHeader)?;
+                    filter_header(&Sha256dHash::hash(filter.filter.as_slice()), &previous) == expected
+                },
+                _ => false
+            }
+        } else {
+            debug!("unexpected filter {} peer={}", filter.block_hash, peer);
+            return Ok(());
+        };
+        if !fits {
+            info!("filter {} does not fit the filter header chain, banning peer={}", filter.block_hash, peer);
+            if let Some(range) = self.in_flight.remove(&peer) {
+                self.ask_again(range);
+            }
+            self.p2p.ban(peer, 100);
+            return Ok(());
+        }
+        let complete = if let Some(range) = self.in_flight.get_mut(&peer) {
+            range.filters.push((filter.block_hash, filter.filter.clone()));
+            range.start + range.filters.len() as u32 > range.stop
+        } else {
+            false
+        };
+        if complete {
+            if let Some(range) = self.in_flight.remove(&peer) {
+                self.store(range)?;
+            }
+        }
+        Ok(())
+    }
+
+    // match and store a verified range, then advance the filter tip over contiguous ranges
+    fn store(&mut self, range: Range) -> Result<(), Error> {
+        let (watched, wallet_scripts) = {
+            let configdb = self.configdb.read().recover();
+            (configdb.fetch_watched()?, configdb.fetch_all_wallet_scripts()?)
+        };
+        let scripts = watched.txs.iter().map(|(_, s)| s).chain(watched.outpoints.iter().map(|(_, s)| s))
+            .chain(wallet_scripts.iter()).collect::<Vec<_>>();
+        let mut chaindb = self.chaindb.write().recover();
+        for (i, (block_id, filter)) in range.filters.into_iter().enumerate() {
+            let matched = self.matcher.match_any(&block_id, filter.as_slice(), scripts.iter().map(|s| s.as_bytes()))?;
+            if matched {
+                let height = range.start + i as u32;
+                debug!("filter of block {} at height {} matched", block_id, height);
+                self.events.publish(Event::FilterMatch { block: (height, block_id) });
+            }
+            chaindb.store_filter(&block_id, filter, matched)?;
+        }
+        self.done.insert(range.start, range.stop);
+        let mut next = first_missing(&chaindb)?;
+        let mut tip = None;
+        while let Some(stop) = self.done.remove(&next) {
+            tip = Some(stop);
+            next = stop + 1;
+        }
+        if let Some(height) = tip {
+            if let Some(header) = chaindb.get_header_for_height(height) {
+                chaindb.store_filter_tip(&header.bitcoin_hash())?;
+            }
+        }
+        chaindb.batch()?;
+        debug!("stored filters [{} .. {}]", range.start, range.stop);
+        Ok(())
+    }
+}
+
+// first height without downloaded filter, a filter tip reorged out continues at the fork
+fn first_missing(chaindb: &ChainDB) -> Result<u32, Error> {
+    Ok(chaindb.fetch_filter_tip()?.and_then(|tip| chaindb.trunk_height(&tip)).map(|h| h + 1).unwrap_or(0))
+}
@@ -0,0 +1,320 @@
+//
+// Copyright 2019 Tamas Blummer
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+// http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+//
+//!
+//! # Download BIP157 filter headers
+//!
+//! Filter header checkpoints are requested from all peers serving filters and compared
+//! with each other. Peers serving checkpoints other than the majority are banned. Filter
+//! headers are then downloaded between checkpoints. There are no checkpoints compiled in,
+//! a node connected to dishonest peers only is not protected.
+//!
+
+use bitcoin::{
+    BitcoinHash,
+    network::{
+        message::NetworkMessage,
+        message_filter::{CFCheckpt, CFHeaders, GetCFCheckpt, GetCFHeaders}
+    }
+};
+use bitcoin_hashes::{Hash, HashEngine, sha256d::Hash as Sha256dHash};
+use chaindb::SharedChainDB;
+use error::Error;
+use lock::Recover;
+use p2p::{P2PControlSender, PeerId, PeerMessage, PeerMessageReceiver, PeerMessageSender, SERVICE_FILTERS};
+use rand::{RngCore, thread_rng};
+use std::{
+    collections::{HashMap, HashSet},
+    sync::mpsc,
+    thread,
+    time::Duration
+};
+use timeout::{ExpectedReply, SharedTimeout};
+use tracing::{Level, field::display};
+
+/// distance of filter header checkpoints
+pub const CHECKPOINT_INTERVAL: u32 = 1000;
+
+// basic filter type of BIP158
+const BASIC_FILTER: u8 = 0;
+
+/// compute the filter header from the filter hash and the previous filter header
+pub fn filter_header(filter_hash: &Sha256dHash, previous: &Sha256dHash) -> Sha256dHash {
+    let mut engine = Sha256dHash::engine();
+    engine.input(&filter_hash[..]);
+    engine.input(&previous[..]);
+    Sha256dHash::from_engine(engine)
+}
+
+// an outstanding getcfheaders request
+struct Request {
+    peer: PeerId,
+    start_height: u32,
+    stop_height: u32,
+    stop_hash: Sha256dHash
+}
+
+pub struct FilterHeaderDownload {
+    p2p: P2PControlSender<NetworkMessage>,
+    chaindb: SharedChainDB,
+    timeout: SharedTimeout<NetworkMessage, ExpectedReply>,
+    // checkpoints agreed on
+    checkpoints: HashMap<u32, Sha256dHash>,
+    // stop hash of the checkpoints asked for
+    checkpoints_for: Option<Sha256dHash>,
+    // peers asked for checkpoints
+    asked: HashSet<PeerId>,
+    // checkpoints received from peers
+    peer_checkpoints: HashMap<PeerId, Vec<Sha256dHash>>,
+    // outstanding filter header request
+    request: Option<Request>
+}
+
+impl FilterHeaderDownload {
+    pub fn new(chaindb: SharedChainDB, p2p: P2PControlSender<NetworkMessage>, timeout: SharedTimeout<NetworkMessage, ExpectedReply>) -> PeerMessageSender<NetworkMessage> {
+        let (sender, receiver) = mpsc::sync_channel(p2p.back_pressure);
+
+        let mut filterheaderdownload = FilterHeaderDownload { chaindb, p2p, timeout,
+            checkpoints: HashMap::new(), checkpoints_for: None, asked: HashSet::new(), peer_checkpoints: HashMap::new(),
+            request: None };
+
+        thread::Builder::new().name("filter header download".to_string()).spawn(move || { filterheaderdownload.run(receiver) }).unwrap();
+
+        PeerMessageSender::new(sender)
+    }
+
+    fn run(&mut self, receiver: PeerMessageReceiver<NetworkMessage>) {
+        let span = span!(Level::INFO, "filter header sync");
+        let _enter = span.enter();
+        loop {
+            while let Ok(msg) = receiver.recv_timeout(Duration::from_millis(1000)) {
+                if let Err(e) = match msg {
+                    PeerMessage::Disconnected(pid, _) => {
+                        self.disconnected(pid);
+                        Ok(())
+                    }
+                    PeerMessage::Incoming(pid, msg) => {
+                        let span = span!(Level::DEBUG, "peer", peer = display(pid));
+                        let _enter = span.enter();
+                        match msg {
+                            NetworkMessage::CFCheckpt(ref checkpoints) => self.cfcheckpt(checkpoints, pid),
+                            NetworkMessage::CFHeaders(ref headers) => self.cfheaders(headers, pid),
+                            _ => { Ok(()) }
+                        }
+                    },
+                    _ => { Ok(()) }
+                } {
+                    error!("Error processing filter headers: {}", e);
+                }
+            }
+            self.timeout.lock().recover().check(vec!(ExpectedReply::FilterCheckpoints, ExpectedReply::FilterHeader));
+            if let Err(e) = self.sync() {
+                error!("Error syncing filter headers: {}", e);
+            }
+        }
+    }
+
+    fn is_serving_filters(&self, peer: PeerId) -> bool {
+        if let Some(peer_version) = self.p2p.peer_version(peer) {
+            return peer_version.services & SERVICE_FILTERS != 0;
+        }
+        false
+    }
+
+    fn filter_peers(&self) -> Vec<PeerId> {
+        self.p2p.peers().into_iter().filter(|p| self.is_serving_filters(*p)).collect()
+    }
+
+    fn disconnected(&mut self, peer: PeerId) {
+        self.peer_checkpoints.remove(&peer);
+        if self.asked.remove(&peer) && self.asked.is_empty() && !self.peer_checkpoints.is_empty() {
+            self.agree_checkpoints();
+        }
+        if self.request.as_ref().map(|r| r.peer == peer).unwrap_or(false) {
+            self.request = None;
+        }
+    }
+
+    // ask for checkpoints or the next batch of filter headers
+    fn sync(&mut self) -> Result<(), Error> {
+        if self.request.is_some() {
+            return Ok(());
+        }
+        let (checkpoint_stop, next) = {
+            let chaindb = self.chaindb.read().recover();
+            let tip_height = if let Some(tip) = chaindb.header_tip() { tip.stored.height } else { return Err(Error::NoTip) };
+            let checkpoint_height = tip_height - tip_height % CHECKPOINT_INTERVAL;
+            let checkpoint_stop = if checkpoint_height > 0 {
+                chaindb.get_header_for_height(checkpoint_height).map(|h| h.bitcoin_hash())
+            } else {
+                None
+            };
+            // first height without known filter header
+            let next = match chaindb.fetch_filter_header_tip()? {
+                Some(tip) => match chaindb.pos_on_trunk(&tip) {
+                    Some(height) => height + 1,
+                    // filter header tip was reorged out, re-download from the last checkpoint
+                    None => (chaindb.get_header(&tip).map(|h| h.stored.height).unwrap_or(0) / CHECKPOINT_INTERVAL) * CHECKPOINT_INTERVAL
+                },
+                None => 0
+            };
+            if next > tip_height {
+                return Ok(());
+            }
+            let stop_height = std::cmp::min(tip_height, (next / CHECKPOINT_INTERVAL + 1) * CHECKPOINT_INTERVAL);
+            (checkpoint_stop, (next, stop_height, chaindb.get_header_for_height(stop_height).map(|h| h.bitcoin_hash())))
+        };
+
+        if let Some(stop_hash) = checkpoint_stop {
+            if self.checkpoints_for != Some(stop_hash) {
+                return self.ask_checkpoints(stop_hash);
+            }
+            if !self.peer_checkpoints.is_empty() || !self.asked.is_empty() {
+                // wait for checkpoints to be agreed
+                return Ok(());
+            }
+        }
+
+        let (start_height, stop_height, stop_hash) = next;
+        if let Some(stop_hash) = stop_hash {
+            let peers = self.filter_peers();
+            if !peers.is_empty() {
+                let peer = peers[(thread_rng().next_u32() as usize) % peers.len()];
+                debug!("ask for filter headers [{} .. {}] peer={}", start_height, stop_height, peer);
+                self.timeout.lock().recover().expect(peer, 1, ExpectedReply::FilterHeader);
+                self.p2p.send_network(peer, NetworkMessage::GetCFHeaders(GetCFHeaders { filter_type: BASIC_FILTER, start_height, stop_hash }));
+                self.request = Some(Request { peer, start_height, stop_height, stop_hash });
+            }
+        }
+        Ok(())
+    }
+
+    fn ask_checkpoints(&mut self, stop_hash: Sha256dHash) -> Result<(), Error> {
+        let peers = self.filter_peers();
+        if peers.is_empty() {
+            return Ok(());
+        }
+        self.checkpoints_for = Some(stop_hash);
+        self.peer_checkpoints.clear();
+        self.asked.clear();
+        for peer in peers {
+            debug!("ask for filter header checkpoints up to {} peer={}", stop_hash, peer);
+            self.timeout.lock().recover().expect(peer, 1, ExpectedReply::FilterCheckpoints);
+            self.p2p.send_network(peer, NetworkMessage::GetCFCheckpt(GetCFCheckpt { filter_type: BASIC_FILTER, stop_hash }));
+            self.asked.insert(peer);
+        }
+        Ok(())
+    }
+
+    fn cfcheckpt(&mut self, checkpoints: &CFCheckpt, peer: PeerId) -> Result<(), Error> {
+        self.timeout.lock().recover().received(peer, 1, ExpectedReply::FilterCheckpoints);
+        if !self.asked.remove(&peer) || checkpoints.filter_type != BASIC_FILTER || Some(checkpoints.stop_hash) != self.checkpoints_for {
+            debug!("unexpected filter header checkpoints peer={}", peer);
+            return Ok(());
+        }
+        self.peer_checkpoints.insert(peer, checkpoints.filter_headers.clone());
+        if self.asked.is_empty() {
+            self.agree_checkpoints();
+        }
+        Ok(())
+    }
+
+    // accept checkpoints served by the majority of peers, ban the others
+    fn agree_checkpoints(&mut self) {
+        let mut votes: Vec<(Vec<Sha256dHash>, Vec<PeerId>)> = Vec::new();
+        for (peer, checkpoints) in self.peer_checkpoints.drain() {
+            if let Some(vote) = votes.iter_mut().find(|(c, _)| *c == checkpoints) {
+                vote.1.push(peer);
+                continue;
+            }
+            votes.push((checkpoints, vec!(peer)));
+        }
+        votes.sort_by_key(|(_, peers)| std::cmp::Reverse(peers.len()));
+        if votes.len() > 1 && votes[0].1.len() == votes[1].1.len() {
+            warn!("peers disagree on filter header checkpoints without majority");
+            self.checkpoints_for = None;
+            return;
+        }
+        let mut votes = votes.into_iter();
+        if let Some((checkpoints, _)) = votes.next() {
+            for (i, header) in checkpoints.iter().enumerate() {
+                self.checkpoints.insert((i as u32 + 1) * CHECKPOINT_INTERVAL, *header);
+            }
+            debug!("agreed on {} filter header checkpoints", checkpoints.len());
+        }
+        for (_, peers) in votes {
+            for peer in peers {
+                info!("filter header checkpoints differ from majority, banning peer={}", peer);
+                self.p2p.ban(peer, 100);
+            }
+        }
+    }
+
+    fn cfheaders(&mut self, headers: &CFHeaders, peer: PeerId) -> Result<(), Error> {
+        self.timeout.lock().recover().received(peer, 1, ExpectedReply::FilterHeader);
+        let request = match self.request.take() {
+            Some(request) => if request.peer == peer && request.stop_hash == headers.stop_hash && headers.filter_type == BASIC_FILTER {
+                request
+            } else {
+                self.request = Some(request);
+                debug!("unexpected filter headers peer={}", peer);
+                return Ok(());
+            },
+            None => return Ok(())
+        };
+        if headers.filter_hashes.len() as u32 != request.stop_height - request.start_height + 1 {
+            info!("wrong number of filter headers, banning peer={}", peer);
+            self.p2p.ban(peer, 100);
+            return Ok(());
+        }
+        let mut chaindb = self.chaindb.write().recover();
+        let mut previous = if request.start_height > 0 {
+            let block = chaindb.get_header_for_height(request.start_height - 1).map(|h| h.bitcoin_hash()).ok_or(Error::NoTip)?;
+            chaindb.fetch_filter_header(&block)?.ok_or(Error::UnconnectedHeader)?
+        } else {
+            Sha256dHash::default()
+        };
+        if previous != headers.previous_filter {
+            info!("filter headers do not connect, banning peer={}", peer);
+            self.p2p.ban(peer, 100);
+            return Ok(());
+        }
+        // compute and check against checkpoints before storing anything
+        let mut computed = Vec::with_capacity(headers.filter_hashes.len());
+        for (i, filter_hash) in headers.filter_hashes.iter().enumerate() {
+            let height = request.start_height + i as u32;
+            let header = filter_header(filter_hash, &previous);
+            if let Some(checkpoint) = self.checkpoints.get(&height) {
+                if *checkpoint != header {
+                    info!("filter header mismatches checkpoint at height {}, banning peer={}", height, peer);
+                    self.p2p.ban(peer, 100);
+                    return Ok(());
+                }
+            }
+            let block = chaindb.get_header_for_height(height).map(|h| h.bitcoin_hash()).ok_or(Error::NoTip)?;
+            computed.push((block, header));
+            previous = header;
+        }
+        for (block, header) in &computed {
+            chaindb.store_filter_header(block, header)?;
+        }
+        if let Some((block, _)) = computed.last() {
+            chaindb.store_filter_header_tip(block)?;
+        }
+        chaindb.batch()?;
+        debug!("stored filter headers [{} .. {}] from peer={}", request.start_height, request.stop_height, peer);
+        Ok(())
+    }
+}
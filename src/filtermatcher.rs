@@ -0,0 +1,202 @@
+//
+// Copyright 2019 Tamas Blummer
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+// http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+//
+//!
+//! # Match BIP158 filters
+//!
+//! Tests a large set of scripts against a basic filter in a single pass. Scripts are
+//! hashed into the filter's range and sorted, then merged with the Golomb-Rice coded
+//! set while decoding it. Buffers are kept between calls so matching a long sequence
+//! of filters does not allocate.
+//!
+
+use bitcoin::consensus::{Decodable, encode::VarInt};
+use bitcoin_hashes::sha256d::Hash as Sha256dHash;
+use error::Error;
+use std::io;
+
+// Golomb-Rice parameter of basic filters
+const P: u8 = 19;
+// inverse false positive rate of basic filters
+const M: u64 = 784931;
+
+/// Matches scripts against BIP158 basic filters
+pub struct FilterMatcher {
+    // hashed query, reused
+    query: Vec<u64>
+}
+
+impl FilterMatcher {
+    /// create a matcher
+    pub fn new() -> FilterMatcher {
+        FilterMatcher { query: Vec::new() }
+    }
+
+    /// true if any of the scripts is in the filter of the block
+    pub fn match_any<'a, I>(&mut self, block_id: &Sha256dHash, filter: &[u8], scripts: I) -> Result<bool, Error>
+        where I: Iterator<Item=&'a [u8]> {
+        let mut reader = filter;
+        let VarInt(n) = VarInt::consensus_decode(&mut reader)?;
+        if n == 0 {
+            return Ok(false);
+        }
+        let (k0, k1) = keys(block_id);
+        let range = n * M;
+
+        self.query.clear();
+        self.query.extend(scripts.map(|s| map_to_range(siphash24(k0, k1, s), range)));
+        if self.query.is_empty() {
+            return Ok(false);
+        }
+        self.query.sort_unstable();
+
+        let mut bits = BitReader::new(reader);
+        let mut query = self.query.iter().peekable();
+        let mut value = 0u64;
+        for _ in 0..n {
+            value += bits.golomb_rice()?;
+            while let Some(q) = query.peek() {
+                if **q < value {
+                    query.next();
+                } else {
+                    break;
+                }
+            }
+            match query.peek() {
+                Some(q) if **q == value => return Ok(true),
+                Some(_) => {},
+                None => return Ok(false)
+            }
+        }
+        Ok(false)
+    }
+}
+
+// siphash keys are the first 16 bytes of the block hash
+fn keys(block_id: &Sha256dHash) -> (u64, u64) {
+    let mut k0 = 0u64;
+    let mut k1 = 0u64;
+    for i in 0..8 {
+        k0 |= (block_id[i] as u64) << (8 * i);
+        k1 |= (block_id[i + 8] as u64) << (8 * i);
+    }
+    (k0, k1)
+}
+
+// fast reduction of a 64 bit hash into [0, range)
+fn map_to_range(hash: u64, range: u64) -> u64 {
+    ((hash as u128 * range as u128) >> 64) as u64
+}
+
+// reads Golomb-Rice coded values, refilling a 64 bit accumulator a byte at a time
+struct BitReader<'a> {
+    data: &'a [u8],
+    acc: u64,
+    // number of valid bits in acc, counted from the most significant
+    len: u32
+}
+
+impl<'a> BitReader<'a> {
+    fn new(data: &'a [u8]) -> BitReader<'a> {
+        BitReader { data, acc: 0, len: 0 }
+    }
+
+    fn refill(&mut self) -> Result<(), Error> {
+        if self.data.is_empty() {
+            return Err(Error::IO(io::Error::new(io::ErrorKind::UnexpectedEof, "truncated filter")));
+        }
+        while self.len <= 56 && !self.data.is_empty() {
+            self.acc |= (self.data[0] as u64) << (56 - self.len);
+            self.len += 8;
+            self.data = &self.data[1..];
+        }
+        Ok(())
+    }
+
+    fn golomb_rice(&mut self) -> Result<u64, Error> {
+        // unary coded quotient
+        let mut q = 0u64;
+        loop {
+            if self.len == 0 {
+                self.refill()?;
+            }
+            let ones = std::cmp::min((!self.acc).leading_zeros(), self.len);
+            q += ones as u64;
+            self.acc = if ones == 64 { 0 } else { self.acc << ones };
+            self.len -= ones;
+            if self.len > 0 {
+                // consume the terminating zero
+                self.acc <<= 1;
+                self.len -= 1;
+                break;
+            }
+        }
+        // P bit remainder
+        if self.len < P as u32 {
+            self.refill()?;
+            if self.len < P as u32 {
+                return Err(Error::IO(io::Error::new(io::ErrorKind::UnexpectedEof, "truncated filter")));
+            }
+        }
+        let r = self.acc >> (64 - P as u32);
+        self.acc <<= P as u32;
+        self.len -= P as u32;
+        Ok((q << P) + r)
+    }
+}
+
+// SipHash-2-4 of data
+fn siphash24(k0: u64, k1: u64, data: &[u8]) -> u64 {
+    let mut v0 = k0 ^ 0x736f6d6570736575;
+    let mut v1 = k1 ^ 0x646f72616e646f6d;
+    let mut v2 = k0 ^ 0x6c7967656e657261;
+    let mut v3 = k1 ^ 0x7465646279746573;
+
+    macro_rules! round {
+        () => {
+            v0 = v0.wrapping_add(v1); v1 = v1.rotate_left(13); v1 ^= v0; v0 = v0.rotate_left(32);
+            v2 = v2.wrapping_add(v3); v3 = v3.rotate_left(16); v3 ^= v2;
+            v0 = v0.wrapping_add(v3); v3 = v3.rotate_left(21); v3 ^= v0;
+            v2 = v2.wrapping_add(v1); v1 = v1.rotate_left(17); v1 ^= v2; v2 = v2.rotate_left(32);
+        }
+    }
+
+    let mut chunks = data.chunks_exact(8);
+    for chunk in &mut chunks {
+        let mut m = 0u64;
+        for (i, b) in chunk.iter().enumerate() {
+            m |= (*b as u64) << (8 * i);
+        }
+        v3 ^= m;
+        round!();
+        round!();
+        v0 ^= m;
+    }
+    let mut last = (data.len() as u64 & 0xff) << 56;
+    for (i, b) in chunks.remainder().iter().enumerate() {
+        last |= (*b as u64) << (8 * i);
+    }
+    v3 ^= last;
+    round!();
+    round!();
+    v0 ^= last;
+
+    v2 ^= 0xff;
+    round!();
+    round!();
+    round!();
+    round!();
+    v0 ^ v1 ^ v2 ^ v3
+}
@@ -0,0 +1,345 @@
+//
+// Copyright 2019 Tamas Blummer
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+// http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+//
+//!
+//! # Serve filters
+//!
+//! Answer BIP157 requests (getcfilters, getcfheaders, getcfcheckpt) of other peers while serving,
+//! from filters and filter headers downloaded earlier. Filters pruned by the retention policy
+//! can not be served, requests reaching them are not answered.
+//!
+//! A FilterServePolicy limits the requests of an IP address per minute and the number of requests
+//! queued for serving, so that a public filter server can not be trivially exhausted. Requests
+//! above the quota are delayed until the address is within it again, as Neutrino based clients
+//! pipeline their requests, those above the queue limit are dropped. Peers
+//! presenting a priority token as a comment of their user agent, e.g. /lnd:0.8.0(token)/, are
+//! served first and are not limited.
+//!
+//! Replies follow Bitcoin Core, the reference Neutrino based clients such as lnd are tested against:
+//! at most 1000 filters or 2000 filter hashes per request, filters in the order of the blocks,
+//! checkpoints at every 1000th block up to the stop hash. With a strict policy a peer sending a
+//! request that can not be served is disconnected, as by Bitcoin Core.
+//!
+
+use bitcoin::{
+    BitcoinHash,
+    network::{
+        message::NetworkMessage,
+        message_filter::{CFCheckpt, CFHeaders, CFilter, GetCFCheckpt, GetCFHeaders, GetCFilters}
+    }
+};
+use bitcoin_hashes::{Hash, sha256d::Hash as Sha256dHash};
+use chaindb::SharedChainDB;
+use clock::SharedClock;
+use error::Error;
+use filterheaderdownload::CHECKPOINT_INTERVAL;
+use lock::Recover;
+use p2p::{P2PControl, P2PControlSender, PeerId, PeerMessage, PeerMessageReceiver, PeerMessageSender, SharedLocalAddress};
+use stats::SharedStatistics;
+use std::{
+    collections::{HashMap, VecDeque},
+    net::IpAddr,
+    sync::{Arc, Mutex, mpsc},
+    thread,
+    time::{Duration, Instant}
+};
+use tracing::{Level, field::display};
+
+// basic filter type of BIP158
+const BASIC_FILTER: u8 = 0;
+/// most filters asked with one getcfilters
+pub const MAX_FILTERS: u32 = 1000;
+/// most filter hashes asked with one getcfheaders
+pub const MAX_FILTER_HEADERS: u32 = 2000;
+
+pub type SharedFilterServePolicy = Arc<Mutex<FilterServePolicy>>;
+
+/// Limits of serving filters to other peers
+#[derive(Clone, Debug)]
+pub struct FilterServePolicy {
+    /// requests served to an IP address within a minute, more are delayed, None for no limit
+    pub requests_per_minute: Option<u32>,
+    /// requests queued or delayed at once, None for no limit
+    pub max_pending: Option<usize>,
+    /// peers presenting one of these in their user agent are served first and not limited
+    pub priority_tokens: Vec<String>,
+    /// disconnect peers asking for filters that can not be served, as Bitcoin Core does,
+    /// instead of not answering. Neutrino based clients, e.g. lnd, then turn to other peers at once.
+    pub strict: bool
+}
+
+impl Default for FilterServePolicy {
+    fn default() -> FilterServePolicy {
+        FilterServePolicy { requests_per_minute: None, max_pending: None, priority_tokens: Vec::new(), strict: false }
+    }
+}
+
+impl FilterServePolicy {
+    /// Limits suitable for a server open to the public
+    pub fn public() -> FilterServePolicy {
+        FilterServePolicy { requests_per_minute: Some(60), max_pending: Some(50), priority_tokens: Vec::new(), strict: false }
+    }
+
+    /// Behave as Neutrino based clients, e.g. lnd, expect of a Bitcoin Core node serving filters
+    pub fn neutrino() -> FilterServePolicy {
+        FilterServePolicy { strict: true, ..FilterServePolicy::default() }
+    }
+}
+
+// a request waiting to be served
+struct Pending {
+    peer: PeerId,
+    request: NetworkMessage
+}
+
+pub struct FilterServer {
+    p2p: P2PControlSender<NetworkMessage>,
+    chaindb: SharedChainDB,
+    local: SharedLocalAddress,
+    policy: SharedFilterServePolicy,
+    clock: SharedClock,
+    statistics: SharedStatistics,
+    // times of accepted requests within the last minute by IP address
+    recent: HashMap<IpAddr, VecDeque<Instant>>,
+    priority: VecDeque<Pending>,
+    queue: VecDeque<Pending>,
+    // requests above the quota, accepted again after the time
+    delayed: VecDeque<(Instant, Pending)>
+}
+
+impl FilterServer {
+    pub fn new(chaindb: SharedChainDB, p2p: P2PControlSender<NetworkMessage>, local: SharedLocalAddress, policy: SharedFilterServePolicy, clock: SharedClock, statistics: SharedStatistics) -> PeerMessageSender<NetworkMessage> {
+        let (sender, receiver) = mpsc::sync_channel(p2p.back_pressure);
+
+        let mut filterserver = FilterServer { p2p, chaindb, local, policy, clock, statistics, recent: HashMap::new(), priority: VecDeque::new(), queue: VecDeque::new(), delayed: VecDeque::new() };
+
+        thread::Builder::new().name("filter server".to_string()).spawn(move || { filterserver.run(receiver) }).unwrap();
+
+        PeerMessageSender::new(sender)
+    }
+
+    fn run(&mut self, receiver: PeerMessageReceiver<NetworkMessage>) {
+        let span = span!(Level::INFO, "filter server");
+        let _enter = span.enter();
+        loop {
+            // take a new request if there is one, then serve one of the queue
+            let timeout = if self.priority.is_empty() && self.queue.is_empty() { Duration::from_secs(1) } else { Duration::from_millis(0) };
+            match receiver.recv_timeout(timeout) {
+                Ok(PeerMessage::Disconnected(pid, _)) => {
+                    self.priority.retain(|p| p.peer != pid);
+                    self.queue.retain(|p| p.peer != pid);
+                    self.delayed.retain(|(_, p)| p.peer != pid);
+                },
+                Ok(PeerMessage::Incoming(pid, msg)) => if self.local.is_server() {
+                    match msg {
+                        NetworkMessage::GetCFilters(_) | NetworkMessage::GetCFHeaders(_) | NetworkMessage::GetCFCheckpt(_) =>
+                            self.accept(pid, msg),
+                        _ => {}
+                    }
+                },
+                Ok(_) | Err(mpsc::RecvTimeoutError::Timeout) => {},
+                Err(mpsc::RecvTimeoutError::Disconnected) => break
+            }
+            self.resume();
+            if let Some(pending) = self.priority.pop_front().or_else(|| self.queue.pop_front()) {
+                let span = span!(Level::DEBUG, "peer", peer = display(pending.peer));
+                let _enter = span.enter();
+                if let Err(e) = self.serve(pending) {
+                    error!("Error serving filters: {}", e);
+                }
+            }
+        }
+    }
+
+    // queue a request if within the limits of the policy
+    fn accept(&mut self, peer: PeerId, request: NetworkMessage) {
+        let policy = self.policy.lock().recover().clone();
+        let pending = Pending { peer, request };
+        if self.has_priority(peer, &policy) {
+            self.priority.push_back(pending);
+            return;
+        }
+        if let Some(max_pending) = policy.max_pending {
+            if self.queue.len() + self.delayed.len() >= max_pending {
+                // not the peer's fault, it is not banned
+                debug!("too many pending filter requests, dropping request of peer={}", peer);
+                return;
+            }
+        }
+        if let (Some(quota), Some(address)) = (policy.requests_per_minute, self.p2p.peer_address(peer)) {
+            let now = self.clock.now();
+            for recent in self.recent.values_mut() {
+                while recent.front().map(|t| now.duration_since(*t) >= Duration::from_secs(60)).unwrap_or(false) {
+                    recent.pop_front();
+                }
+            }
+            self.recent.retain(|_, recent| !recent.is_empty());
+            let recent = self.recent.entry(address.ip()).or_insert_with(VecDeque::new);
+            if recent.len() as u32 >= quota {
+                // the oldest request leaves the window first
+                let at = recent.front().map(|t| *t + Duration::from_secs(60)).unwrap_or(now);
+                debug!("filter request quota of {} exhausted, delaying request of peer={}", address.ip(), peer);
+                self.delayed.push_back((at, pending));
+                return;
+            }
+            recent.push_back(now);
+        }
+        self.queue.push_back(pending);
+    }
+
+    // accept requests again whose delay expired, in the order they arrived
+    fn resume(&mut self) {
+        let now = self.clock.now();
+        let (due, waiting) = self.delayed.drain(..).partition::<VecDeque<_>, _>(|(at, _)| *at <= now);
+        self.delayed = waiting;
+        for (_, pending) in due {
+            self.accept(pending.peer, pending.request);
+        }
+    }
+
+    // the peer's user agent carries a priority token as a comment
+    fn has_priority(&self, peer: PeerId, policy: &FilterServePolicy) -> bool {
+        if policy.priority_tokens.is_empty() {
+            return false;
+        }
+        let user_agent = match self.p2p.peer_version(peer) {
+            Some(version) => version.user_agent,
+            None => return false
+        };
+        user_agent.split('(').skip(1)
+            .filter_map(|comment| comment.split(')').next())
+            .flat_map(|comment| comment.split(';'))
+            .any(|comment| policy.priority_tokens.iter().any(|token| token.as_str() == comment.trim()))
+    }
+
+    fn serve(&mut self, pending: Pending) -> Result<(), Error> {
+        let served = match pending.request {
+            NetworkMessage::GetCFilters(ref get) => self.get_cfilters(get, pending.peer)?,
+            NetworkMessage::GetCFHeaders(ref get) => self.get_cfheaders(get, pending.peer)?,
+            NetworkMessage::GetCFCheckpt(ref get) => self.get_cfcheckpt(get, pending.peer)?,
+            _ => true
+        };
+        if !served && self.policy.lock().recover().strict {
+            debug!("disconnect peer={} after a filter request that can not be served", pending.peer);
+            self.p2p.send(P2PControl::Disconnect(pending.peer));
+        }
+        Ok(())
+    }
+
+    // blocks of the trunk between start and stop, None if not a valid range
+    fn range(&self, start: u32, stop_hash: &Sha256dHash, max: u32) -> Option<Vec<Sha256dHash>> {
+        let chaindb = self.chaindb.read().recover();
+        let stop = chaindb.pos_on_trunk(stop_hash)?;
+        if start > stop || stop - start >= max {
+            return None;
+        }
+        (start ..= stop).map(|height| chaindb.get_header_for_height(height).map(|h| h.bitcoin_hash())).collect()
+    }
+
+    fn get_cfilters(&mut self, get: &GetCFilters, peer: PeerId) -> Result<bool, Error> {
+        if get.filter_type != BASIC_FILTER {
+            return Ok(false);
+        }
+        let blocks = match self.range(get.start_height, &get.stop_hash, MAX_FILTERS) {
+            Some(blocks) => blocks,
+            None => {
+                debug!("invalid getcfilters range from height {} peer={}", get.start_height, peer);
+                return Ok(false);
+            }
+        };
+        let mut filters = Vec::with_capacity(blocks.len());
+        {
+            let chaindb = self.chaindb.read().recover();
+            for block_hash in blocks {
+                match chaindb.fetch_filter(&block_hash)?.and_then(|stored| stored.filter) {
+                    Some(filter) => filters.push(CFilter { filter_type: BASIC_FILTER, block_hash, filter }),
+                    None => {
+                        debug!("filter of block {} not available peer={}", block_hash, peer);
+                        return Ok(false);
+                    }
+                }
+            }
+        }
+        debug!("serve {} filters from height {} peer={}", filters.len(), get.start_height, peer);
+        self.statistics.filters_served(filters.len());
+        for filter in filters {
+            self.p2p.send_network(peer, NetworkMessage::CFilter(filter));
+        }
+        Ok(true)
+    }
+
+    fn get_cfheaders(&mut self, get: &GetCFHeaders, peer: PeerId) -> Result<bool, Error> {
+        if get.filter_type != BASIC_FILTER {
+            return Ok(false);
+        }
+        let blocks = match self.range(get.start_height, &get.stop_hash, MAX_FILTER_HEADERS) {
+            Some(blocks) => blocks,
+            None => {
+                debug!("invalid getcfheaders range from height {} peer={}", get.start_height, peer);
+                return Ok(false);
+            }
+        };
+        let chaindb = self.chaindb.read().recover();
+        let previous_filter = if get.start_height > 0 {
+            let block = chaindb.get_header_for_height(get.start_height - 1).map(|h| h.bitcoin_hash()).ok_or(Error::NoTip)?;
+            match chaindb.fetch_filter_header(&block)? {
+                Some(header) => header,
+                None => return Ok(false)
+            }
+        } else {
+            Sha256dHash::default()
+        };
+        let mut filter_hashes = Vec::with_capacity(blocks.len());
+        for block in &blocks {
+            match chaindb.fetch_filter(block)?.and_then(|stored| stored.filter) {
+                Some(filter) => filter_hashes.push(Sha256dHash::hash(filter.as_slice())),
+                None => {
+                    debug!("filter of block {} not available peer={}", block, peer);
+                    return Ok(false);
+                }
+            }
+        }
+        debug!("serve {} filter headers from height {} peer={}", filter_hashes.len(), get.start_height, peer);
+        self.p2p.send_network(peer, NetworkMessage::CFHeaders(CFHeaders { filter_type: BASIC_FILTER, stop_hash: get.stop_hash, previous_filter, filter_hashes }));
+        Ok(true)
+    }
+
+    fn get_cfcheckpt(&mut self, get: &GetCFCheckpt, peer: PeerId) -> Result<bool, Error> {
+        if get.filter_type != BASIC_FILTER {
+            return Ok(false);
+        }
+        let chaindb = self.chaindb.read().recover();
+        let stop = match chaindb.pos_on_trunk(&get.stop_hash) {
+            Some(stop) => stop,
+            None => return Ok(false)
+        };
+        let mut filter_headers = Vec::new();
+        let mut height = CHECKPOINT_INTERVAL;
+        while height <= stop {
+            let block = chaindb.get_header_for_height(height).map(|h| h.bitcoin_hash()).ok_or(Error::NoTip)?;
+            match chaindb.fetch_filter_header(&block)? {
+                Some(header) => filter_headers.push(header),
+                None => {
+                    debug!("filter header at height {} not available peer={}", height, peer);
+                    return Ok(false);
+                }
+            }
+            height += CHECKPOINT_INTERVAL;
+        }
+        debug!("serve {} filter header checkpoints peer={}", filter_headers.len(), peer);
+        self.p2p.send_network(peer, NetworkMessage::CFCheckpt(CFCheckpt { filter_type: BASIC_FILTER, stop_hash: get.stop_hash, filter_headers }));
+        Ok(true)
+    }
+}
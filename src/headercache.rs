@@ -16,6 +16,12 @@
 //!
 //! # Cache of headers and the chain with most work
 //!
+//! The trunk and the height of each of its headers are held in memory, rebuilt from the DB at
+//! start, so height lookups and locators take constant time without reading the DB.
+//!
+//! Every header is stored with the log2 of the total work of the chain up to it, so branches are
+//! compared by their tips alone and no work is summed along a branch at a reorg.
+//!
 
 use bitcoin::{
     BitcoinHash,
@@ -29,8 +35,10 @@ use bitcoin_hashes::sha256d::Hash as Sha256dHash;
 use bitcoin_hashes::Hash;
 use chaindb::StoredHeader;
 use error::Error;
+use lock::Recover;
 use std::{
-    collections::HashMap
+    collections::HashMap,
+    sync::Mutex
 };
 
 #[derive(Clone)]
@@ -71,12 +79,17 @@ impl CachedHeader {
     /// is correct, but does not verify that the transactions are valid or encoded
     /// correctly.
     pub fn spv_validate(&self, required_target: &Uint256) -> Result<(), Error> {
-        use byteorder::{ByteOrder, LittleEndian};
-
         let target = &self.target();
         if target != required_target {
             return Err(Error::SpvBadTarget);
         }
+        self.check_pow(target)
+    }
+
+    /// Confirms that the hash of the block is within the target, the target must be that of the block
+    pub fn check_pow(&self, target: &Uint256) -> Result<(), Error> {
+        use byteorder::{ByteOrder, LittleEndian};
+
         let data: [u8; 32] = self.bitcoin_hash().into_inner();
         let mut ret = [0u64; 4];
         LittleEndian::read_u64_into(&data, &mut ret);
@@ -109,13 +122,24 @@ pub struct HeaderCache {
     headers: HashMap<Sha256dHash, CachedHeader>,
     // header chain with most work
     trunk: Vec<Sha256dHash>,
+    // height of headers on trunk
+    positions: HashMap<Sha256dHash, u32>,
+    // target and work by compact difficulty bits. Headers of a retarget period share them,
+    // so decoding the target and dividing for the work happens once per period.
+    targets: HashMap<u32, (Uint256, Uint256)>,
+    // locator computed for the tip
+    locator: Mutex<Option<(Sha256dHash, Vec<Sha256dHash>)>>,
+    // (height, id) of a header trusted to be on the valid chain. Headers up to its height are
+    // accepted with the difficulty they claim instead of recomputing retargets.
+    assume_valid: Option<(u32, Sha256dHash)>
 }
 
 const EXPECTED_CHAIN_LENGTH: usize = 600000;
 
 impl HeaderCache {
     pub fn new(network: Network) -> HeaderCache {
-        HeaderCache { network, headers: HashMap::with_capacity(EXPECTED_CHAIN_LENGTH), trunk: Vec::with_capacity(EXPECTED_CHAIN_LENGTH) }
+        HeaderCache { network, headers: HashMap::with_capacity(EXPECTED_CHAIN_LENGTH), trunk: Vec::with_capacity(EXPECTED_CHAIN_LENGTH),
+            positions: HashMap::with_capacity(EXPECTED_CHAIN_LENGTH), targets: HashMap::new(), locator: Mutex::new(None), assume_valid: None }
     }
 
     pub fn add_header_unchecked(&mut self, id: &Sha256dHash, stored: &StoredHeader) {
@@ -124,8 +148,22 @@ impl HeaderCache {
         self.trunk.push(id.clone());
     }
 
-    pub fn reverse_trunk(&mut self) {
-        self.trunk.reverse()
+    // positions of headers added unchecked in trunk order
+    pub fn index_trunk(&mut self) {
+        self.positions = self.trunk.iter().enumerate().map(|(height, id)| (*id, height as u32)).collect();
+    }
+
+    /// log2 of total work of a header following one with the given log2 of total work, None for genesis
+    pub fn log2work(&mut self, prev: Option<f64>, header: &BlockHeader) -> f64 {
+        let (_, work) = self.target_and_work(header);
+        match prev {
+            Some(prev) => Self::log2(work + Self::exp2(prev)),
+            None => Self::log2(work)
+        }
+    }
+
+    pub fn set_assume_valid(&mut self, anchor: Option<(u32, Sha256dHash)>) {
+        self.assume_valid = anchor;
     }
 
     pub fn len (&self) -> usize {
@@ -159,6 +197,7 @@ impl HeaderCache {
                 log2work: Self::log2(header.work())
             });
             self.trunk.push(new_tip.clone());
+            self.positions.insert(new_tip, 0);
             self.headers.insert(new_tip.clone(), stored.clone());
             return Ok(Some((stored, None, Some(vec!(new_tip)))));
         }
@@ -180,6 +219,11 @@ impl HeaderCache {
         Uint256(b)
     }
 
+    // target and work of a header, computed once for each difficulty
+    fn target_and_work(&mut self, header: &BlockHeader) -> (Uint256, Uint256) {
+        *self.targets.entry(header.bits).or_insert_with(|| (header.target(), header.work()))
+    }
+
     fn max_target() -> Uint256 {
         Uint256::from_u64(0xFFFF).unwrap() << 208
     }
@@ -190,9 +234,24 @@ impl HeaderCache {
         const DIFFCHANGE_TIMESPAN: u32 = 14 * 24 * 3600;
         const TARGET_BLOCK_SPACING: u32 = 600;
 
+        let assumed = match self.assume_valid {
+            Some((height, anchor)) => {
+                if prev.stored.height + 1 == height && next.bitcoin_hash() != anchor {
+                    // a header at the anchor's height, but not the anchor
+                    return Err(Error::SpvBadProofOfWork);
+                }
+                prev.stored.height < height
+            },
+            None => false
+        };
+
         let required_work =
+        // Up to an assume-valid anchor the claimed difficulty is trusted. Proof of work for it
+        // is still checked, so a branch of fake work is not for free.
+            if assumed {
+                self.target_and_work(next).0
         // Compute required difficulty if this is a diffchange block
-            if (prev.stored.height + 1) % DIFFCHANGE_INTERVAL == 0 {
+            } else if (prev.stored.height + 1) % DIFFCHANGE_INTERVAL == 0 {
                 let timespan = {
                     // Scan back DIFFCHANGE_INTERVAL blocks
                     let mut scan = prev.clone();
@@ -216,7 +275,7 @@ impl HeaderCache {
                     }
                 };
                 // Compute new target
-                let mut target = prev.stored.header.target();
+                let mut target = self.target_and_work(&prev.stored.header).0;
                 target = target.mul_u32(timespan);
                 target = target / Uint256::from_u64(DIFFCHANGE_TIMESPAN as u64).unwrap();
                 // Clamp below MAX_TARGET (difficulty 1)
@@ -249,17 +308,18 @@ impl HeaderCache {
                 scan.stored.header.target()
                 // Otherwise just use the last block's difficulty
             } else {
-                prev.stored.header.target()
+                self.target_and_work(&prev.stored.header).0
             };
 
+        let (target, work) = self.target_and_work(next);
         let cached = CachedHeader::new(&next.bitcoin_hash(), StoredHeader {
             header: next.clone(),
             height: prev.stored.height + 1,
-            log2work: Self::log2(next.work() + Self::exp2(prev.stored.log2work))
+            log2work: Self::log2(work + Self::exp2(prev.stored.log2work))
         });
 
         // Check POW
-        if cached.spv_validate(&required_work).is_err() {
+        if target != required_work || cached.check_pow(&target).is_err() {
             return Err(Error::SpvBadProofOfWork);
         }
 
@@ -291,20 +351,23 @@ impl HeaderCache {
                 if forks_at != next.prev_blockhash {
                     let mut unwinds = Vec::new();
 
-                    if let Some(pos) = self.trunk.iter().rposition(|h| { *h == forks_at }) {
+                    if let Some(pos) = self.pos_on_trunk(&forks_at).map(|p| p as usize) {
                         if pos < self.trunk.len() - 1 {
                             // store and cut headers that are no longer on trunk
                             unwinds.extend(self.trunk[pos + 1..].iter().rev().map(|h| *h));
+                            for h in &unwinds {
+                                self.positions.remove(h);
+                            }
                             self.trunk.truncate(pos + 1);
                         }
                     } else {
                         trace!("previous header not in cache (header no longer on trunk) {}", &forks_at);
                         return Err(Error::UnconnectedHeader);
                     }
-                    self.trunk.extend(path_to_new_tip.iter().map(|h| { *h }));
+                    self.extend_trunk(&path_to_new_tip);
                     return Ok((cached, Some(unwinds), Some(path_to_new_tip)));
                 } else {
-                    self.trunk.extend(path_to_new_tip.iter().map(|h| { *h }));
+                    self.extend_trunk(&path_to_new_tip);
                     return Ok((cached, None, Some(path_to_new_tip)));
                 }
             } else {
@@ -315,9 +378,24 @@ impl HeaderCache {
         }
     }
 
+    fn extend_trunk(&mut self, path: &Vec<Sha256dHash>) {
+        for h in path {
+            self.positions.insert(*h, self.trunk.len() as u32);
+            self.trunk.push(*h);
+        }
+    }
+
+    /// forget headers of the trunk at and above height
+    pub fn truncate(&mut self, height: u32) {
+        for id in self.trunk.drain(height as usize..) {
+            self.positions.remove(&id);
+            self.headers.remove(&id);
+        }
+    }
+
     /// position on trunk (chain with most work from genesis to tip)
     pub fn pos_on_trunk(&self, hash: &Sha256dHash) -> Option<u32> {
-        self.trunk.iter().rev().position(|e| { *e == *hash }).map(|p| (self.trunk.len() - p - 1) as u32)
+        self.positions.get(hash).cloned()
     }
 
     /// retrieve the id of the block/header with most work
@@ -384,8 +462,24 @@ impl HeaderCache {
         }
     }
 
-    // locator for getheaders message
+    // locator for getheaders message, only computed again if the tip changed
     pub fn locator_hashes(&self) -> Vec<Sha256dHash> {
+        let tip = match self.tip_hash() {
+            Some(tip) => tip,
+            None => return vec!()
+        };
+        let mut cached = self.locator.lock().recover();
+        if let Some((ref at, ref locator)) = *cached {
+            if *at == tip {
+                return locator.clone();
+            }
+        }
+        let locator = self.compute_locator();
+        *cached = Some((tip, locator.clone()));
+        locator
+    }
+
+    fn compute_locator(&self) -> Vec<Sha256dHash> {
         let mut locator = vec!();
         let mut skip = 1;
         let mut count = 0;
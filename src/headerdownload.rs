@@ -16,60 +16,209 @@
 //!
 //! # Download headers
 //!
-use bitcoin::{BitcoinHash, network::{
+//! The next headers are asked as soon as a full headers message arrives, so the peer looks them up
+//! while the received ones are checked and stored.
+//!
+//! During initial sync headers are asked from two peers at once and the first answer is used,
+//! so a slow or stalling peer does not hold up sync. Peers that lose such races are less likely
+//! to be asked again.
+//!
+//! A block a peer pushes without announcement is accepted if it extends the header tip, its header
+//! is checked like a downloaded one and the block is stored so it need not be downloaded if its
+//! filter matches.
+//!
+//! The height a peer claimed at connect or reached with announced blocks is remembered in the
+//! config DB by address. Headers are asked from peers ahead of us, laggards are only asked if no
+//! connected peer claims to be ahead.
+//!
+//! Headers with invalid proof of work are remembered in the config DB with the point their branch
+//! forks off, so is the tip of a branch a peer serves as its best chain if it forks more than a
+//! retarget period below the trunk and has less work. Peers sending or announcing them or their
+//! descendants are banned without checking the branch again. Headers connected before an invalid
+//! one in the same message are passed downstream as usual.
+//!
+//! A new block announced by several peers leads to asking headers only once within a short time.
+//!
+use bitcoin::{BitcoinHash, consensus::{Decodable, Encodable, encode}, network::{
     message::NetworkMessage,
     message_blockdata::{GetHeadersMessage, Inventory, InvType},
-}, BlockHeader};
+}, Block, BlockHeader};
 use bitcoin_hashes::sha256d::Hash as Sha256dHash;
 use chaindb::SharedChainDB;
+use clock::SharedClock;
+use configdb::SharedConfigDB;
 use error::Error;
+use lock::Recover;
+use lru_cache::LruCache;
 use p2p::{P2PControl, P2PControlSender, PeerId, PeerMessage, PeerMessageReceiver, PeerMessageSender, SERVICE_BLOCKS};
 use std::{
-    collections::VecDeque,
+    collections::{HashMap, VecDeque},
+    io,
+    net::{IpAddr, Ipv6Addr, SocketAddr},
     sync::mpsc,
     thread,
-    time::Duration,
+    time::{Duration, Instant},
 };
+use syncconfig::SyncConfig;
 use timeout::{ExpectedReply, SharedTimeout};
-use downstream::SharedDownstream;
+use tracing::{Level, field::display};
+use downstream::{SharedDownstream, Subscribers};
+
+// number of headers in a full headers message, more might follow
+const MAX_HEADERS: usize = 2000;
+// most peer heights kept
+const MAX_PEER_HEIGHTS: usize = 1000;
+// seconds between storing changed peer heights
+const STORE_INTERVAL: u64 = 60;
+// initial sync is assumed while the header tip is older than this many seconds
+const INITIAL_SYNC_AGE: u32 = 24 * 3600;
+// most invalid headers remembered
+const MAX_INVALID_HEADERS: usize = 1000;
+// most unknown announced blocks remembered, headers are asked once for them
+const RECENT_ANNOUNCEMENTS: usize = 100;
+// seconds an announcement by an other peer does not lead to asking again for headers
+const ANNOUNCEMENT_QUIET: u64 = 30;
+// ban score for a header too far ahead of network time
+const TOO_NEW_SCORE: u32 = 10;
+// a branch with less work forking deeper than this below the trunk tip is remembered as invalid
+const LOW_WORK_DEPTH: u32 = 2016;
+
+/// Height and tip a peer announced, remembered across connections
+#[derive(Clone, Debug)]
+pub struct PeerHeight {
+    /// address of the peer
+    pub ip: IpAddr,
+    /// highest height the peer claimed at connect or announced a block at
+    pub height: u32,
+    /// last block the peer announced
+    pub tip: Sha256dHash,
+    /// unix time the peer was last connected
+    pub last_seen: u32
+}
+
+impl Encodable for PeerHeight {
+    fn consensus_encode<W: io::Write>(&self, mut w: W) -> Result<usize, encode::Error> {
+        let mut len = match self.ip {
+            IpAddr::V4(ip) => ip.to_ipv6_mapped().octets().consensus_encode(&mut w)?,
+            IpAddr::V6(ip) => ip.octets().consensus_encode(&mut w)?
+        };
+        len += self.height.consensus_encode(&mut w)?;
+        len += self.tip.consensus_encode(&mut w)?;
+        len += self.last_seen.consensus_encode(&mut w)?;
+        Ok(len)
+    }
+}
+
+impl Decodable for PeerHeight {
+    fn consensus_decode<D: io::Read>(mut d: D) -> Result<PeerHeight, encode::Error> {
+        let octets: [u8; 16] = Decodable::consensus_decode(&mut d)?;
+        let ip = Ipv6Addr::from(octets);
+        let ip = match ip.to_ipv4() {
+            Some(v4) if octets[..12] == [0u8, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0xff, 0xff] => IpAddr::V4(v4),
+            _ => IpAddr::V6(ip)
+        };
+        Ok(PeerHeight { ip, height: Decodable::consensus_decode(&mut d)?, tip: Decodable::consensus_decode(&mut d)?,
+            last_seen: Decodable::consensus_decode(&mut d)? })
+    }
+}
+
+/// A header of an invalid branch
+#[derive(Clone, Debug)]
+pub struct InvalidHeader {
+    /// id of the header
+    pub id: Sha256dHash,
+    /// last valid header the branch builds on
+    pub fork_point: Sha256dHash
+}
+
+impl Encodable for InvalidHeader {
+    fn consensus_encode<W: io::Write>(&self, mut w: W) -> Result<usize, encode::Error> {
+        Ok(self.id.consensus_encode(&mut w)? + self.fork_point.consensus_encode(&mut w)?)
+    }
+}
+
+impl Decodable for InvalidHeader {
+    fn consensus_decode<D: io::Read>(mut d: D) -> Result<InvalidHeader, encode::Error> {
+        Ok(InvalidHeader { id: Decodable::consensus_decode(&mut d)?, fork_point: Decodable::consensus_decode(&mut d)? })
+    }
+}
 
 pub struct HeaderDownload {
     p2p: P2PControlSender<NetworkMessage>,
     chaindb: SharedChainDB,
     timeout: SharedTimeout<NetworkMessage, ExpectedReply>,
-    downstream: SharedDownstream
+    downstream: SharedDownstream,
+    tips: Subscribers<(u32, Sha256dHash)>,
+    announcer: PeerMessageSender<NetworkMessage>,
+    config: SyncConfig,
+    configdb: SharedConfigDB,
+    clock: SharedClock,
+    // known heights by address
+    heights: HashMap<IpAddr, PeerHeight>,
+    // address of connected peers
+    addresses: HashMap<PeerId, IpAddr>,
+    // heights changed since last store
+    dirty: bool,
+    last_store: Instant,
+    // peers asked for the same headers by the first locator
+    races: HashMap<Sha256dHash, Vec<PeerId>>,
+    // number of races lost by peer
+    lost: HashMap<PeerId, u32>,
+    // fork point by id of invalid headers and tips of low work branches
+    invalid: HashMap<Sha256dHash, Sha256dHash>,
+    // unknown announced blocks headers were asked for, with the time of asking
+    recently_announced: LruCache<Sha256dHash, Instant>
 }
 
 impl HeaderDownload {
-    pub fn new(chaindb: SharedChainDB, p2p: P2PControlSender<NetworkMessage>, timeout: SharedTimeout<NetworkMessage, ExpectedReply>, downstream: SharedDownstream) -> PeerMessageSender<NetworkMessage> {
+    pub fn new(chaindb: SharedChainDB, p2p: P2PControlSender<NetworkMessage>, timeout: SharedTimeout<NetworkMessage, ExpectedReply>, downstream: SharedDownstream, tips: Subscribers<(u32, Sha256dHash)>,
+               announcer: PeerMessageSender<NetworkMessage>, configdb: SharedConfigDB, config: SyncConfig, clock: SharedClock) -> Result<PeerMessageSender<NetworkMessage>, Error> {
         let (sender, receiver) = mpsc::sync_channel(p2p.back_pressure);
 
-        let mut headerdownload = HeaderDownload { chaindb, p2p, timeout, downstream: downstream };
+        let heights = configdb.read().recover().fetch_peer_heights()?.into_iter().map(|h| (h.ip, h)).collect();
+        let invalid = configdb.read().recover().fetch_invalid_headers()?.into_iter().map(|h| (h.id, h.fork_point)).collect();
+        let mut headerdownload = HeaderDownload { chaindb, p2p, timeout, downstream: downstream, tips, announcer, config,
+            last_store: clock.now(), configdb, clock, heights, addresses: HashMap::new(), dirty: false,
+            races: HashMap::new(), lost: HashMap::new(), invalid, recently_announced: LruCache::new(RECENT_ANNOUNCEMENTS) };
 
         thread::Builder::new().name("header download".to_string()).spawn(move || { headerdownload.run(receiver) }).unwrap();
 
-        PeerMessageSender::new(sender)
+        Ok(PeerMessageSender::new(sender))
     }
 
     fn run(&mut self, receiver: PeerMessageReceiver<NetworkMessage>) {
+        let span = span!(Level::INFO, "header sync");
+        let _enter = span.enter();
         loop {
             while let Ok(msg) = receiver.recv_timeout(Duration::from_millis(1000)) {
                 if let Err(e) = match msg {
-                    PeerMessage::Connected(pid,_) => {
+                    PeerMessage::Connected(pid, address) => {
                         if self.is_serving_blocks(pid) {
                             trace!("serving blocks peer={}", pid);
-                            self.get_headers(pid)
+                            self.connected(pid, address);
+                            if self.is_ahead(pid) || !self.addresses.keys().any(|p| self.is_ahead(*p)) {
+                                self.get_headers(pid)
+                            } else {
+                                debug!("not asking lagging peer={} for headers", pid);
+                                Ok(())
+                            }
                         } else {
                             Ok(())
                         }
                     }
-                    PeerMessage::Disconnected(_,_) => {
+                    PeerMessage::Disconnected(pid,_) => {
+                        self.addresses.remove(&pid);
+                        self.lost.remove(&pid);
+                        self.races.retain(|_, peers| !peers.contains(&pid));
                         Ok(())
                     }
                     PeerMessage::Incoming(pid, msg) => {
+                        let span = span!(Level::DEBUG, "peer", peer = display(pid));
+                        let _enter = span.enter();
                         match msg {
                             NetworkMessage::Headers(ref headers) => if self.is_serving_blocks(pid) { self.headers(headers, pid) } else { Ok(()) },
                             NetworkMessage::Inv(ref inv) => if self.is_serving_blocks(pid) { self.inv(inv, pid) } else { Ok(()) },
+                            NetworkMessage::Block(ref block) => if self.is_serving_blocks(pid) { self.block(block, pid) } else { Ok(()) },
                             NetworkMessage::Ping(_) => { Ok(()) }
                             _ => { Ok(()) }
                         }
@@ -79,10 +228,66 @@ impl HeaderDownload {
                     error!("Error processing headers: {}", e);
                 }
             }
-            self.timeout.lock().unwrap().check(vec!(ExpectedReply::Headers));
+            self.timeout.lock().recover().check(vec!(ExpectedReply::Headers));
+            if let Err(e) = self.store() {
+                error!("Error storing peer heights: {}", e);
+            }
+        }
+    }
+
+    // remember the height a peer claims at connect
+    fn connected(&mut self, peer: PeerId, address: Option<SocketAddr>) {
+        if let Some(address) = address {
+            let ip = address.ip();
+            let start_height = self.p2p.peer_version(peer).map(|v| v.start_height).unwrap_or(0);
+            let known = self.heights.entry(ip).or_insert(PeerHeight { ip, height: 0, tip: Sha256dHash::default(), last_seen: 0 });
+            known.height = known.height.max(start_height);
+            known.last_seen = self.clock.unix_time() as u32;
+            self.addresses.insert(peer, ip);
+            self.dirty = true;
+        }
+    }
+
+    // remember a block the peer announced
+    fn announced(&mut self, peer: PeerId, tip: Sha256dHash, height: Option<u32>) {
+        if let Some(ip) = self.addresses.get(&peer) {
+            if let Some(known) = self.heights.get_mut(ip) {
+                known.tip = tip;
+                if let Some(height) = height {
+                    known.height = known.height.max(height);
+                }
+                self.dirty = true;
+            }
         }
     }
 
+    // the peer claims to know more blocks than we do
+    fn is_ahead(&self, peer: PeerId) -> bool {
+        let claimed = self.addresses.get(&peer).and_then(|ip| self.heights.get(ip)).map(|h| h.height).unwrap_or(0);
+        let ours = self.chaindb.read().recover().header_tip().map(|tip| tip.stored.height).unwrap_or(0);
+        claimed > ours
+    }
+
+    // store changed heights, only those of the most recently seen peers are kept
+    fn store(&mut self) -> Result<(), Error> {
+        if !self.dirty || self.clock.now().duration_since(self.last_store) < Duration::from_secs(STORE_INTERVAL) {
+            return Ok(());
+        }
+        let mut heights = self.heights.values().cloned().collect::<Vec<_>>();
+        if heights.len() > MAX_PEER_HEIGHTS {
+            heights.sort_by(|a, b| b.last_seen.cmp(&a.last_seen));
+            heights.truncate(MAX_PEER_HEIGHTS);
+            let keep = heights.iter().map(|h| h.ip).collect::<Vec<_>>();
+            self.heights.retain(|ip, _| keep.contains(ip));
+        }
+        let mut configdb = self.configdb.write().recover();
+        configdb.store_peer_heights(heights)?;
+        configdb.batch()?;
+        self.dirty = false;
+        self.last_store = self.clock.now();
+        Ok(())
+    }
+
     fn is_serving_blocks(&self, peer: PeerId) -> bool {
         if let Some(peer_version) = self.p2p.peer_version(peer) {
             return peer_version.services & SERVICE_BLOCKS != 0;
@@ -96,12 +301,27 @@ impl HeaderDownload {
         for inventory in v {
             // only care for blocks
             if inventory.inv_type == InvType::Block {
-                let chaindb = self.chaindb.read().unwrap();
-                if chaindb.get_header(&inventory.hash).is_none() {
-                    debug!("received inv for new block {} peer={}", inventory.hash, peer);
-                    // ask for header(s) if observing a new block
-                    ask_for_headers = true;
+                if let Some(fork_point) = self.invalid.get(&inventory.hash) {
+                    info!("announced block {} of invalid branch forking at {}, banning peer={}", inventory.hash, fork_point, peer);
+                    self.p2p.ban(peer, 100);
+                    return Ok(());
                 }
+                let height = self.chaindb.read().recover().get_header(&inventory.hash).map(|h| h.stored.height);
+                if height.is_none() {
+                    // several peers announce a new block at about the same time, ask only once
+                    let now = self.clock.now();
+                    let asked = self.recently_announced.get_mut(&inventory.hash)
+                        .map(|at| now.duration_since(*at) < Duration::from_secs(ANNOUNCEMENT_QUIET)).unwrap_or(false);
+                    if asked {
+                        trace!("headers for block {} were asked already peer={}", inventory.hash, peer);
+                    } else {
+                        debug!("received inv for new block {} peer={}", inventory.hash, peer);
+                        // ask for header(s) if observing a new block
+                        self.recently_announced.insert(inventory.hash, now);
+                        ask_for_headers = true;
+                    }
+                }
+                self.announced(peer, inventory.hash, height);
             }
         }
         if ask_for_headers {
@@ -112,26 +332,158 @@ impl HeaderDownload {
 
     /// get headers this peer is ahead of us
     fn get_headers(&mut self, peer: PeerId) -> Result<(), Error> {
-        if self.timeout.lock().unwrap().is_busy_with(peer, ExpectedReply::Headers) {
+        if self.timeout.lock().recover().is_busy_with(peer, ExpectedReply::Headers) {
             return Ok(());
         }
-        let chaindb = self.chaindb.read().unwrap();
-        let locator = chaindb.header_locators();
+        // cached until the tip moves, release the lock before sending
+        let locator = self.chaindb.read().recover().header_locators();
         if locator.len() > 0 {
-            let first = if locator.len() > 0 {
-                *locator.first().unwrap()
-            } else {
-                Sha256dHash::default()
+            self.ask_headers(peer, locator);
+        }
+        Ok(())
+    }
+
+    // send getheaders, during initial sync also to a second peer racing the first
+    fn ask_headers(&mut self, peer: PeerId, locator: Vec<Sha256dHash>) {
+        let mut asked = vec!(peer);
+        if self.is_initial_sync() {
+            if let Some(racer) = self.racer(peer) {
+                asked.push(racer);
+            }
+        }
+        for p in &asked {
+            self.timeout.lock().recover().expect(*p, 1, ExpectedReply::Headers);
+            self.p2p.send_network(*p, NetworkMessage::GetHeaders(GetHeadersMessage::new(locator.clone(), Sha256dHash::default())));
+        }
+        if asked.len() > 1 {
+            trace!("race for headers following {} peer={} peer={}", locator[0], asked[0], asked[1]);
+            self.races.insert(locator[0], asked);
+        }
+    }
+
+    // a peer ahead of us not busy with headers that lost the fewest races
+    fn racer(&self, peer: PeerId) -> Option<PeerId> {
+        let timeout = self.timeout.lock().recover();
+        self.addresses.keys()
+            .filter(|p| **p != peer && self.is_ahead(**p) && !timeout.is_busy_with(**p, ExpectedReply::Headers))
+            .min_by_key(|p| self.lost.get(*p).cloned().unwrap_or(0)).cloned()
+    }
+
+    fn is_initial_sync(&self) -> bool {
+        match self.chaindb.read().recover().header_tip() {
+            Some(tip) => tip.stored.header.time + INITIAL_SYNC_AGE < self.clock.unix_time() as u32,
+            None => true
+        }
+    }
+
+    // a block sent without being asked, stored if it extends the tip
+    fn block(&mut self, block: &Block, peer: PeerId) -> Result<(), Error> {
+        let id = block.bitcoin_hash();
+        let extends_tip = {
+            let chaindb = self.chaindb.read().recover();
+            if chaindb.get_header(&id).is_some() {
+                // asked for or known already
+                return Ok(());
+            }
+            chaindb.header_tip().map(|tip| tip.bitcoin_hash() == block.header.prev_blockhash).unwrap_or(false)
+        };
+        if !extends_tip {
+            // catch up with headers first
+            return self.get_headers(peer);
+        }
+        if block.header.merkle_root != block.merkle_root() {
+            info!("merkle root of unsolicited block {} does not match, banning peer={}", id, peer);
+            self.p2p.ban(peer, 100);
+            return Ok(());
+        }
+        debug!("unsolicited block {} extends the tip peer={}", id, peer);
+        self.connect(&vec!(block.header), peer)?;
+        let mut chaindb = self.chaindb.write().recover();
+        if chaindb.pos_on_trunk(&id).is_some() {
+            chaindb.store_block(block)?;
+            chaindb.batch()?;
+        }
+        Ok(())
+    }
+
+    // ban the peer and remember the header, its branch forks where its parent does if that is invalid
+    fn invalid_header(&mut self, header: &BlockHeader, peer: PeerId) -> Result<(), Error> {
+        let id = header.bitcoin_hash();
+        let fork_point = self.invalid.get(&header.prev_blockhash).cloned().unwrap_or(header.prev_blockhash);
+        info!("header {} of invalid branch forking at {}, banning peer={}", id, fork_point, peer);
+        self.p2p.ban(peer, 100);
+        self.remember_invalid(id, fork_point)
+    }
+
+    // ban the peer and remember the tip of the branch it serves if that forks deep and has less work
+    fn low_work(&mut self, last: &BlockHeader, peer: PeerId) -> Result<(), Error> {
+        let id = last.bitcoin_hash();
+        let fork_point = {
+            let chaindb = self.chaindb.read().recover();
+            let (tip, mut cursor) = match (chaindb.header_tip(), chaindb.get_header(&id)) {
+                (Some(tip), Some(header)) => (tip, header),
+                _ => return Ok(())
             };
-            self.timeout.lock().unwrap().expect(peer, 1, ExpectedReply::Headers);
-            self.p2p.send_network(peer, NetworkMessage::GetHeaders(GetHeadersMessage::new(locator, first)));
+            if chaindb.pos_on_trunk(&id).is_some() || cursor.stored.log2work >= tip.stored.log2work {
+                return Ok(());
+            }
+            while chaindb.pos_on_trunk(&cursor.bitcoin_hash()).is_none() {
+                cursor = match chaindb.get_header(&cursor.stored.header.prev_blockhash) {
+                    Some(prev) => prev,
+                    None => return Ok(())
+                };
+            }
+            if tip.stored.height - cursor.stored.height <= LOW_WORK_DEPTH {
+                return Ok(());
+            }
+            cursor.bitcoin_hash()
+        };
+        info!("best header {} of peer is on a low work branch forking at {}, banning peer={}", id, fork_point, peer);
+        self.p2p.ban(peer, 100);
+        self.remember_invalid(id, fork_point)
+    }
+
+    fn remember_invalid(&mut self, id: Sha256dHash, fork_point: Sha256dHash) -> Result<(), Error> {
+        if self.invalid.len() < MAX_INVALID_HEADERS && self.invalid.insert(id, fork_point).is_none() {
+            let mut configdb = self.configdb.write().recover();
+            configdb.store_invalid_headers(self.invalid.iter().map(|(id, fork_point)| InvalidHeader { id: *id, fork_point: *fork_point }).collect())?;
+            configdb.batch()?;
         }
         Ok(())
     }
 
     fn headers(&mut self, headers: &Vec<BlockHeader>, peer: PeerId) -> Result<(), Error> {
-        self.timeout.lock().unwrap().received(peer, 1, ExpectedReply::Headers);
+        self.timeout.lock().recover().received(peer, 1, ExpectedReply::Headers);
+        if let Some(first) = headers.first() {
+            if let Some(racers) = self.races.remove(&first.prev_blockhash) {
+                // this peer won the race
+                for slower in racers.into_iter().filter(|p| *p != peer) {
+                    debug!("slower with headers than peer={} peer={}", peer, slower);
+                    *self.lost.entry(slower).or_insert(0) += 1;
+                }
+            }
+        }
+        if self.config.pipeline_headers && headers.len() == MAX_HEADERS {
+            // the peer looks up the next batch while this one is checked,
+            // unless the batch is known already e.g. from a faster peer
+            let last = headers[MAX_HEADERS - 1].bitcoin_hash();
+            if self.chaindb.read().recover().get_header(&last).is_none() {
+                self.ask_headers(peer, vec!(last));
+            }
+        }
+        self.connect(headers, peer)?;
+        if let Some(last) = headers.last() {
+            let id = last.bitcoin_hash();
+            let height = self.chaindb.read().recover().get_header(&id).map(|h| h.stored.height);
+            if height.is_some() {
+                self.announced(peer, id, height);
+            }
+        }
+        Ok(())
+    }
 
+    // add headers to the chain, checking proof of work
+    fn connect(&mut self, headers: &Vec<BlockHeader>, peer: PeerId) -> Result<(), Error> {
         if headers.len() > 0 {
             // current height
             let mut height;
@@ -139,7 +491,7 @@ impl HeaderDownload {
             let mut some_new = false;
             let mut moved_tip = None;
             {
-                let chaindb = self.chaindb.read().unwrap();
+                let chaindb = self.chaindb.read().recover();
 
                 if let Some(tip) = chaindb.header_tip() {
                     height = tip.stored.height;
@@ -148,14 +500,34 @@ impl HeaderDownload {
                 }
             }
 
+            // headers of a time later than this are not accepted yet
+            let latest = self.p2p.network_time().saturating_add(self.config.max_future_drift as u64);
+            let mut too_new = None;
+            let mut invalid = None;
             let mut headers_queue = VecDeque::new();
             headers_queue.extend(headers.iter());
-            while !headers_queue.is_empty() {
+            while !headers_queue.is_empty() && too_new.is_none() && invalid.is_none() {
                 let mut disconnected_headers = Vec::new();
                 let mut connected_headers = Vec::new();
+                let mut batch_tip = None;
+                let mut bad = None;
                 {
-                    let mut chaindb = self.chaindb.write().unwrap();
-                    while let Some(header) = headers_queue.pop_front() {
+                    let mut chaindb = self.chaindb.write().recover();
+                    let mut batched = 0;
+                    while batched < self.config.header_batch {
+                        let header = match headers_queue.pop_front() {
+                            Some(header) => header,
+                            None => break
+                        };
+                        batched += 1;
+                        if self.invalid.contains_key(&header.bitcoin_hash()) || self.invalid.contains_key(&header.prev_blockhash) {
+                            bad = Some(header.clone());
+                            break;
+                        }
+                        if header.time as u64 > latest {
+                            too_new = Some(header.bitcoin_hash());
+                            break;
+                        }
                         // add to blockchain - this also checks proof of work
                         match chaindb.add_header(&header) {
                             Ok(Some((stored, unwinds, forwards))) => {
@@ -165,6 +537,7 @@ impl HeaderDownload {
 
                                 if let Some(forwards) = forwards {
                                     moved_tip = Some(forwards.last().unwrap().clone());
+                                    batch_tip = Some((stored.height, forwards.last().unwrap().clone()));
                                 }
                                 height = stored.height;
 
@@ -176,9 +549,8 @@ impl HeaderDownload {
                             }
                             Ok(None) => {}
                             Err(Error::SpvBadProofOfWork) => {
-                                info!("Incorrect POW, banning peer={}", peer);
-                                self.p2p.ban(peer, 100);
-                                return Ok(());
+                                bad = Some(header.clone());
+                                break;
                             }
                             Err(e) => {
                                 debug!("error {} processing header {} ", e, header.bitcoin_hash());
@@ -189,27 +561,47 @@ impl HeaderDownload {
                     chaindb.batch()?;
                 }
                 // must call downstream outside of chaindb lock as it might also lock chaindb
-                let mut downstream = self.downstream.lock().unwrap();
+                let mut downstream = self.downstream.lock().recover();
                 for header in &disconnected_headers {
                     downstream.block_disconnected(header);
                 }
                 for (header, height) in &connected_headers {
                     downstream.header_connected(header, *height);
                 }
+                if let Some(tip) = batch_tip {
+                    self.tips.publish(tip);
+                }
+                invalid = bad;
             }
 
-            if some_new {
-                // ask if peer knows even more
-                self.get_headers(peer)?;
+            if let Some(header) = invalid {
+                self.invalid_header(&header, peer)?;
+            } else if let Some(id) = too_new {
+                // not remembered as invalid, the header might be valid once its time has come, and
+                // the peer might only have a clock ahead of ours. Dropped, headers after it too,
+                // with a small score so only a peer insisting on it is banned eventually.
+                info!("dropped header {} more than {} seconds ahead of network time peer={}", id, self.config.max_future_drift, peer);
+                self.p2p.ban(peer, TOO_NEW_SCORE);
+            } else {
+                if headers.len() < MAX_HEADERS {
+                    // the last header is the tip of the peer's best chain
+                    self.low_work(&headers[headers.len() - 1], peer)?;
+                }
+                if some_new {
+                    // ask if peer knows even more
+                    self.get_headers(peer)?;
+                }
             }
 
             if let Some(new_tip) = moved_tip {
                 info!("received {} headers new tip={} from peer={}", headers.len(), new_tip, peer);
                 self.p2p.send(P2PControl::Height(height));
+                // propagate the new tip to peers that do not know it
+                self.announcer.send(PeerMessage::Outgoing(NetworkMessage::Inv(vec!(Inventory { inv_type: InvType::Block, hash: new_tip }))));
             } else {
                 debug!("received {} known or orphan headers [{} .. {}] from peer={}", headers.len(), headers[0].bitcoin_hash(), headers[headers.len()-1].bitcoin_hash(), peer);
             }
         }
         Ok(())
     }
-}
\ No newline at end of file
+}
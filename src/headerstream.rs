@@ -0,0 +1,127 @@
+//
+// Copyright 2019 Tamas Blummer
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+// http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+//
+//!
+//! # Header stream
+//!
+//! Server-sent events of headers connected to and disconnected from the trunk, for dashboards
+//! and explorers that would otherwise poll. A client GETs /headers and receives the current tip
+//! followed by an event for every change:
+//!
+//! ```text
+//! event: header
+//! data: {"height":600000,"hash":"...","header":"<hex>"}
+//!
+//! event: reorg
+//! data: {"disconnected":"..."}
+//! ```
+//!
+
+use bitcoin::{
+    BitcoinHash,
+    blockdata::block::{Block, BlockHeader},
+    consensus::serialize
+};
+use bitcoin_hashes::hex::ToHex;
+use chaindb::ChainView;
+use downstream::{Downstream, Subscribers};
+use error::Error;
+use futures::executor::block_on_stream;
+use listener::{self, read_http_request, write_http_response};
+use std::{
+    io::{self, BufReader, Write},
+    net::{SocketAddr, TcpStream},
+    sync::Arc
+};
+
+/// A change of the trunk
+#[derive(Clone, Debug)]
+pub enum HeaderNotice {
+    /// a header was connected at the height
+    Connected(BlockHeader, u32),
+    /// a header was disconnected by a reorg
+    Disconnected(BlockHeader)
+}
+
+/// Publishes changes of the trunk
+pub struct HeaderFeed {
+    notices: Subscribers<HeaderNotice>
+}
+
+impl HeaderFeed {
+    pub fn new(notices: Subscribers<HeaderNotice>) -> HeaderFeed {
+        HeaderFeed { notices }
+    }
+}
+
+impl Downstream for HeaderFeed {
+    fn block_connected(&mut self, _block: &Block, _height: u32) {}
+
+    fn header_connected(&mut self, header: &BlockHeader, height: u32) {
+        self.notices.publish(HeaderNotice::Connected(header.clone(), height));
+    }
+
+    fn block_disconnected(&mut self, header: &BlockHeader) {
+        self.notices.publish(HeaderNotice::Disconnected(header.clone()));
+    }
+}
+
+/// Streams changes of the trunk to HTTP clients as server-sent events
+pub struct HeaderStreamServer {
+    chain: ChainView,
+    notices: Subscribers<HeaderNotice>
+}
+
+impl HeaderStreamServer {
+    pub fn new(chain: ChainView, notices: Subscribers<HeaderNotice>) -> HeaderStreamServer {
+        HeaderStreamServer { chain, notices }
+    }
+
+    /// Accept clients at the address in a thread of its own, returns the address bound
+    pub fn listen(self, addr: SocketAddr) -> Result<SocketAddr, Error> {
+        let server = Arc::new(self);
+        let bound = listener::listen("headerstream", addr, move |stream| server.serve(stream))?;
+        info!("header stream at http://{}/headers", bound);
+        Ok(bound)
+    }
+
+    fn serve(&self, mut stream: TcpStream) -> Result<(), io::Error> {
+        let (method, path) = read_http_request(&mut BufReader::new(stream.try_clone()?))?;
+        if (method.as_str(), path.as_str()) != ("GET", "/headers") {
+            return write_http_response(&mut stream, "404 Not Found", "text/plain", "unknown endpoint");
+        }
+        // subscribe before reading the tip, so that no change is missed
+        let notices = self.notices.subscribe();
+        write!(stream, "HTTP/1.1 200 OK\r\nContent-Type: text/event-stream\r\nCache-Control: no-cache\r\nAccess-Control-Allow-Origin: *\r\n\r\n")?;
+        if let Some(tip) = self.chain.header_tip() {
+            write_notice(&mut stream, &HeaderNotice::Connected(tip.stored.header, tip.stored.height))?;
+        }
+        for notice in block_on_stream(notices) {
+            write_notice(&mut stream, &notice)?;
+        }
+        Ok(())
+    }
+}
+
+fn write_notice(stream: &mut TcpStream, notice: &HeaderNotice) -> Result<(), io::Error> {
+    match *notice {
+        HeaderNotice::Connected(ref header, height) =>
+            write!(stream, "event: header\ndata: {{\"height\":{},\"hash\":\"{}\",\"header\":\"{}\"}}\n\n",
+                   height, header.bitcoin_hash(), serialize(header).to_hex())?,
+        HeaderNotice::Disconnected(ref header) =>
+            write!(stream, "event: reorg\ndata: {{\"disconnected\":\"{}\"}}\n\n", header.bitcoin_hash())?
+    }
+    stream.flush()
+}
@@ -29,29 +29,71 @@
 extern crate bitcoin;
 extern crate bitcoin_hashes;
 extern crate byteorder;
+extern crate fs2;
 extern crate futures;
 extern crate futures_timer;
 extern crate hammersbald;
 extern crate serde;
 #[macro_use] extern crate serde_derive;
-#[macro_use] extern crate log;
+#[macro_use] extern crate tracing;
+extern crate tracing_subscriber;
+extern crate log;
 extern crate lru_cache;
 extern crate mio;
 extern crate rand;
 
 #[cfg(feature="lightning")] extern crate lightning;
 #[cfg(feature="lightning")] mod lightning;
+#[cfg(feature="tls")] extern crate rustls;
+#[cfg(feature="tls")] pub mod tls;
+#[cfg(any(feature="electrum", feature="esplora"))] #[macro_use] extern crate serde_json;
+#[cfg(feature="electrum")] pub mod electrum;
+#[cfg(feature="esplora")] pub mod esplora;
 mod headercache;
+mod listener;
 
 pub mod ping;
+pub mod addressbook;
 pub mod dns;
+pub mod localnode;
 pub mod timeout;
+pub mod clock;
+pub mod bandwidth;
 pub mod headerdownload;
+pub mod filterheaderdownload;
+pub mod filterdownload;
+pub mod filtermatcher;
+pub mod blockdownload;
+pub mod broadcaster;
+pub mod fee;
+pub mod announcer;
+pub mod chainserver;
+pub mod filterserver;
+pub mod scheduler;
+pub mod spendwatch;
+pub mod txfetch;
+pub mod payment;
+pub mod wallet;
 pub mod downstream;
 pub mod dispatcher;
 pub mod p2p;
+pub mod socks;
 pub mod error;
+pub mod lock;
 pub mod chaindb;
+pub mod timelock;
+pub mod versionbits;
+pub mod configdb;
+pub mod datadir;
+pub mod syncconfig;
+pub mod event;
+pub mod oracle;
+pub mod census;
+pub mod stats;
+pub mod propagation;
+pub mod replay;
+pub mod api;
+pub mod headerstream;
 pub mod constructor;
 
 pub use error::Error;
\ No newline at end of file
@@ -20,9 +20,10 @@
 //!
 
 use bitcoin::{
+    BitcoinHash,
     blockdata::{
         block::{Block, BlockHeader},
-        transaction::Transaction,
+        transaction::{OutPoint, Transaction},
         script::Script,
     },
     network::{
@@ -38,11 +39,16 @@ use lightning::{
     util::logger::{Level, Logger, Record}
 };
 
+use configdb::SharedConfigDB;
 use downstream::Downstream;
 
-use p2p::P2PControlSender;
+use lock::Recover;
+use p2p::{PeerMessage, PeerMessageSender};
 
-use std::sync::{Arc, Weak, Mutex};
+use std::{
+    collections::HashMap,
+    sync::{Arc, Weak, Mutex}
+};
 
 struct LightningLogger{
     level: Level
@@ -58,52 +64,136 @@ impl Logger for LightningLogger {
 
 pub type SharedLightningConnector = Arc<Mutex<LightningConnector>>;
 
+/// Listener for confirmations in the shape of rust-lightning's Confirm and Listen traits
+pub trait Confirm : Send + Sync {
+    /// transactions of a block added to the trunk that match watched outpoints or scripts,
+    /// each with its position in the block
+    fn transactions_confirmed(&self, header: &BlockHeader, txdata: &[(usize, &Transaction)], height: u32);
+
+    /// a previously confirmed transaction is no longer on the trunk
+    fn transaction_unconfirmed(&self, txid: &Sha256dHash);
+
+    /// a new header with most work
+    fn best_block_updated(&self, header: &BlockHeader, height: u32);
+}
+
 /// connector to lightning network
 pub struct LightningConnector {
     util: ChainWatchInterfaceUtil,
-    p2p: P2PControlSender
+    broadcaster: PeerMessageSender<NetworkMessage>,
+    configdb: SharedConfigDB,
+    confirm: Vec<Weak<dyn Confirm>>,
+    // watched transactions confirmed by blocks on the trunk
+    confirmed: HashMap<Sha256dHash, Vec<Sha256dHash>>
 }
 
 impl Downstream for LightningConnector {
     /// called by the node if new block added to trunk (longest chain)
     /// this will notify listeners on lightning side
     fn block_connected(&mut self, block: &Block, height: u32) {
-        self.util.block_connected_with_filtering(block, height)
+        self.util.block_connected_with_filtering(block, height);
+
+        let txdata = block.txdata.iter().enumerate().filter(|(_, tx)| self.util.does_match_tx(tx)).collect::<Vec<_>>();
+        if !txdata.is_empty() {
+            self.confirmed.insert(block.bitcoin_hash(), txdata.iter().map(|(_, tx)| tx.txid()).collect());
+            for listener in self.confirm_listeners() {
+                listener.transactions_confirmed(&block.header, txdata.as_slice(), height);
+            }
+        }
     }
 
-    fn header_connected(&mut self, block: &BlockHeader, height: u32) {}
+    /// called by the node if new header added to trunk (longest chain)
+    fn header_connected(&mut self, header: &BlockHeader, height: u32) {
+        for listener in self.confirm_listeners() {
+            listener.best_block_updated(header, height);
+        }
+    }
 
     /// called by the node if a block is removed from trunk (orphaned from longest chain)
     /// this will notify listeners on lightning side
     fn block_disconnected(&mut self, header: &BlockHeader) {
-        self.util.block_disconnected(header)
+        self.util.block_disconnected(header);
+
+        if let Some(txids) = self.confirmed.remove(&header.bitcoin_hash()) {
+            for listener in self.confirm_listeners() {
+                for txid in txids.iter().rev() {
+                    listener.transaction_unconfirmed(txid);
+                }
+            }
+        }
     }
 }
 
 impl LightningConnector {
-    /// create a connector
-    pub fn new (network: Network, p2p: P2PControlSender) -> LightningConnector {
+    /// create a connector, re-arming watches registered in earlier runs
+    pub fn new (network: Network, broadcaster: PeerMessageSender<NetworkMessage>, configdb: SharedConfigDB) -> LightningConnector {
+        let util = ChainWatchInterfaceUtil::new(network, Arc::new(LightningLogger{level: Level::Info}));
+        match configdb.read().recover().fetch_watched() {
+            Ok(watched) => {
+                for (txid, script) in &watched.txs {
+                    util.install_watch_tx(txid, script);
+                }
+                for (outpoint, script) in &watched.outpoints {
+                    util.install_watch_outpoint((outpoint.txid, outpoint.vout), script);
+                }
+                info!("re-armed {} watched transactions and {} watched outpoints", watched.txs.len(), watched.outpoints.len());
+            },
+            Err(e) => error!("can not read watched transactions and outpoints: {}", e)
+        }
         LightningConnector {
-            util: ChainWatchInterfaceUtil::new(network, Arc::new(LightningLogger{level: Level::Info})),
-            p2p
+            util,
+            broadcaster,
+            configdb,
+            confirm: Vec::new(),
+            confirmed: HashMap::new()
         }
     }
 
-    /// broadcast transaction to all connected peers
+    /// install a listener for confirmed and unconfirmed transactions and best block updates
+    pub fn register_confirm(&mut self, listener: Weak<dyn Confirm>) {
+        self.confirm.push(listener)
+    }
+
+    // listeners still alive, forget dropped ones
+    fn confirm_listeners(&mut self) -> Vec<Arc<dyn Confirm>> {
+        self.confirm.retain(|l| l.upgrade().is_some());
+        self.confirm.iter().filter_map(|l| l.upgrade()).collect()
+    }
+
+    /// broadcast transaction through a subset of peers until seen propagated
     pub fn broadcast (&self, tx: Transaction) {
-        self.p2p.broadcast(NetworkMessage::Tx(tx))
+        self.broadcaster.send(PeerMessage::Outgoing(NetworkMessage::Tx(tx)))
     }
 }
 
 impl ChainWatchInterface for LightningConnector {
 
-    fn install_watch_tx(&self, _txid: &Sha256dHash, _script_pub_key: &Script) {
-        unimplemented!()
+    /// install a listener to be called with transactions paying to the script
+    fn install_watch_tx(&self, txid: &Sha256dHash, script_pub_key: &Script) {
+        self.util.install_watch_tx(txid, script_pub_key);
+        let mut configdb = self.configdb.write().recover();
+        match configdb.fetch_watched() {
+            Ok(mut watched) => if watched.add_tx(txid, script_pub_key) {
+                if let Err(e) = configdb.store_watched(&watched).and_then(|_| configdb.batch()) {
+                    error!("can not persist watched transaction {}: {}", txid, e);
+                }
+            },
+            Err(e) => error!("can not read watched transactions: {}", e)
+        }
     }
 
     /// install a listener to be called with transactions that spend the outpoint
     fn install_watch_outpoint(&self, outpoint: (Sha256dHash, u32), out_script: &Script) {
-        self.util.install_watch_outpoint(outpoint, out_script)
+        self.util.install_watch_outpoint(outpoint, out_script);
+        let mut configdb = self.configdb.write().recover();
+        match configdb.fetch_watched() {
+            Ok(mut watched) => if watched.add_outpoint(&OutPoint { txid: outpoint.0, vout: outpoint.1 }, out_script) {
+                if let Err(e) = configdb.store_watched(&watched).and_then(|_| configdb.batch()) {
+                    error!("can not persist watched outpoint {}:{}: {}", outpoint.0, outpoint.1, e);
+                }
+            },
+            Err(e) => error!("can not read watched outpoints: {}", e)
+        }
     }
 
     /// install a listener to be called for every transaction
@@ -0,0 +1,99 @@
+//
+// Copyright 2019 Tamas Blummer
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+// http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+//
+//!
+//! # Listeners of the client facing servers
+//!
+//! Accepting connections, each served in a thread of its own, and the little of HTTP the client
+//! API, the header stream and the Electrum and Esplora servers need.
+//!
+
+use error::Error;
+use std::{
+    io::{self, BufRead, Read, Write},
+    net::{SocketAddr, TcpListener, TcpStream},
+    thread
+};
+
+// longest line of an HTTP request or its headers
+const MAX_HTTP_LINE: u64 = 8 * 1024;
+// most headers of an HTTP request
+const MAX_HTTP_HEADERS: usize = 100;
+
+/// Accept connections at the address in a thread of its own and serve each in a thread of its
+/// own, returns the address bound. A connection that can not get a thread is closed.
+pub fn listen<F>(name: &str, addr: SocketAddr, serve: F) -> Result<SocketAddr, Error>
+    where F: Fn(TcpStream) -> Result<(), io::Error> + Send + Sync + Clone + 'static {
+    let listener = TcpListener::bind(addr)?;
+    let bound = listener.local_addr()?;
+    let client = format!("{}client", name);
+    let name = name.to_string();
+    thread::Builder::new().name(name.clone()).spawn(move || {
+        for stream in listener.incoming() {
+            match stream {
+                Ok(stream) => {
+                    let serve = serve.clone();
+                    let name = name.clone();
+                    if let Err(e) = thread::Builder::new().name(client.clone())
+                        .spawn(move || if let Err(e) = serve(stream) {
+                            debug!("{} connection ended with {}", name, e);
+                        }) {
+                        warn!("{} can not start a thread for a client: {}", client, e);
+                    }
+                },
+                Err(e) => warn!("{} can not accept: {}", name, e)
+            }
+        }
+    })?;
+    Ok(bound)
+}
+
+/// A line without its end, None at the end of the stream, an error if longer than max
+pub fn read_line<R: BufRead>(reader: &mut R, max: u64) -> Result<Option<String>, io::Error> {
+    let mut line = String::new();
+    if reader.by_ref().take(max).read_line(&mut line)? == 0 {
+        return Ok(None);
+    }
+    if !line.ends_with('\n') && line.len() as u64 >= max {
+        return Err(io::Error::new(io::ErrorKind::InvalidData, "line too long"));
+    }
+    Ok(Some(line.trim_end().to_string()))
+}
+
+/// Method and path without query of an HTTP request, its headers are skipped
+pub fn read_http_request<R: BufRead>(reader: &mut R) -> Result<(String, String), io::Error> {
+    let request = read_line(reader, MAX_HTTP_LINE)?.unwrap_or_default();
+    let mut headers = 0;
+    while let Some(header) = read_line(reader, MAX_HTTP_LINE)? {
+        if header.is_empty() {
+            break;
+        }
+        headers += 1;
+        if headers > MAX_HTTP_HEADERS {
+            return Err(io::Error::new(io::ErrorKind::InvalidData, "too many headers"));
+        }
+    }
+    let mut words = request.split_whitespace();
+    let method = words.next().unwrap_or("").to_string();
+    let path = words.next().map(|p| p.split('?').next().unwrap_or("")).unwrap_or("").to_string();
+    Ok((method, path))
+}
+
+/// Write a complete HTTP response and close
+pub fn write_http_response<W: Write>(writer: &mut W, status: &str, content_type: &str, body: &str) -> Result<(), io::Error> {
+    write!(writer, "HTTP/1.1 {}\r\nContent-Type: {}\r\nContent-Length: {}\r\nAccess-Control-Allow-Origin: *\r\nConnection: close\r\n\r\n{}",
+           status, content_type, body.len(), body)?;
+    writer.flush()
+}
@@ -0,0 +1,130 @@
+//
+// Copyright 2019 Tamas Blummer
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+// http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+//
+//!
+//! # Local node discovery
+//!
+//! Probe the standard port of the network on this machine and optionally on the local IPv4
+//! subnet for a full node, e.g. a bitcoind run by the same user. A node found is connected
+//! at start as a fast source the user likely trusts.
+//!
+//! The subnet is that of an IPv4 address this node listens at, the node itself is not probed.
+//! Probes run on a few threads at once.
+//!
+
+use bitcoin::network::constants::Network;
+use lock::Recover;
+use std::{
+    net::{IpAddr, Ipv4Addr, SocketAddr, TcpStream},
+    sync::{Arc, Mutex, mpsc},
+    thread,
+    time::Duration
+};
+
+// wait this long for a probed port to accept
+const PROBE_TIMEOUT: Duration = Duration::from_millis(300);
+// threads probing at once
+const PROBE_THREADS: usize = 16;
+
+/// Where to look for a local node
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum LocalDiscovery {
+    /// do not look
+    Off,
+    /// this machine only
+    Localhost,
+    /// this machine and the /24 subnet of the IPv4 address listened at
+    Subnet
+}
+
+// standard port of the network
+fn default_port(network: Network) -> u16 {
+    match network {
+        Network::Bitcoin => 8333,
+        Network::Testnet => 18333,
+        Network::Regtest => 18444
+    }
+}
+
+/// Addresses accepting connections at the standard port within the scope, other than those
+/// this node listens at
+pub fn find_local_nodes(network: Network, scope: LocalDiscovery, listen: &[SocketAddr]) -> Vec<SocketAddr> {
+    let port = default_port(network);
+    let mut candidates = Vec::new();
+    if scope != LocalDiscovery::Off {
+        candidates.push(SocketAddr::new(IpAddr::V4(Ipv4Addr::LOCALHOST), port));
+    }
+    if scope == LocalDiscovery::Subnet {
+        if let Some(own) = interface_ipv4(listen) {
+            let octets = own.octets();
+            for host in 1..255u8 {
+                candidates.push(SocketAddr::new(IpAddr::V4(Ipv4Addr::new(octets[0], octets[1], octets[2], host)), port));
+            }
+        } else {
+            debug!("not listening at an IPv4 address of an interface, the local subnet is not probed");
+        }
+    }
+    candidates.retain(|addr| !is_own(addr, listen));
+
+    // probe in parallel, a subnet would take minutes one by one
+    let queue = Arc::new(Mutex::new(candidates));
+    let (sender, receiver) = mpsc::channel();
+    let mut threads = 0;
+    for _ in 0..PROBE_THREADS {
+        let queue = queue.clone();
+        let sender = sender.clone();
+        match thread::Builder::new().name("probe".to_string()).spawn(move || probe(&queue, &sender)) {
+            Ok(_) => threads += 1,
+            Err(e) => warn!("can not start thread to probe for local nodes: {}", e)
+        }
+    }
+    if threads == 0 {
+        probe(&queue, &sender);
+    }
+    // results end as the last sender is gone
+    drop(sender);
+    let found = receiver.iter().collect::<Vec<_>>();
+    info!("found {} local nodes", found.len());
+    found
+}
+
+// probe candidates until none is left, send those accepting
+fn probe(queue: &Mutex<Vec<SocketAddr>>, found: &mpsc::Sender<SocketAddr>) {
+    loop {
+        let addr = match queue.lock().recover().pop() {
+            Some(addr) => addr,
+            None => return
+        };
+        match TcpStream::connect_timeout(&addr, PROBE_TIMEOUT) {
+            Ok(_) => { found.send(addr).ok(); },
+            Err(e) => trace!("no node at {}: {}", addr, e)
+        }
+    }
+}
+
+// an IPv4 address of an interface this node listens at
+fn interface_ipv4(listen: &[SocketAddr]) -> Option<Ipv4Addr> {
+    listen.iter().filter_map(|addr| match addr.ip() {
+        IpAddr::V4(ip) if !ip.is_unspecified() && !ip.is_loopback() => Some(ip),
+        _ => None
+    }).next()
+}
+
+// the address is one this node listens at, a listener at the unspecified address takes all
+// local addresses of its port
+fn is_own(addr: &SocketAddr, listen: &[SocketAddr]) -> bool {
+    listen.iter().any(|own| own.port() == addr.port() &&
+        (own.ip() == addr.ip() || (own.ip().is_unspecified() && (addr.ip().is_loopback() || Some(addr.ip()) == interface_ipv4(listen).map(IpAddr::V4)))))
+}
@@ -0,0 +1,38 @@
+//
+// Copyright 2019 Tamas Blummer
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+// http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+//
+//!
+//! # Locking
+//!
+//! A thread that panics while holding a Mutex or RwLock poisons it. Other threads
+//! continue with the data as the panicking thread left it, instead of panicking too.
+//!
+
+use std::sync::PoisonError;
+
+/// Acquire a lock even if poisoned
+pub trait Recover<Guard> {
+    /// the guard of the lock, also if an other thread panicked while holding it
+    fn recover(self) -> Guard;
+}
+
+impl<Guard> Recover<Guard> for Result<Guard, PoisonError<Guard>> {
+    fn recover(self) -> Guard {
+        self.unwrap_or_else(|poisoned| {
+            warn!("recovering a lock poisoned by a panicked thread");
+            poisoned.into_inner()
+        })
+    }
+}
@@ -0,0 +1,97 @@
+//
+// Copyright 2019 Tamas Blummer
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+// http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+//
+//!
+//! # Cross-check the chain tip
+//!
+//! Compare our chain tip with independent sources, e.g. block explorers queried by the
+//! application over HTTPS, to detect an eclipse attack.
+//!
+
+use bitcoin::BitcoinHash;
+use bitcoin_hashes::sha256d::Hash as Sha256dHash;
+use chaindb::SharedChainDB;
+use downstream::Subscribers;
+use error::Error;
+use event::Event;
+use lock::Recover;
+use std::{
+    thread,
+    time::Duration
+};
+
+// seconds between checks
+const CHECK_SECS: u64 = 600;
+// an oracle this many blocks ahead of us is considered divergent
+const MAX_BEHIND: u32 = 2;
+
+/// An independent source of the chain tip
+pub trait TipOracle : Send {
+    /// name used in logs and events
+    fn name(&self) -> String;
+
+    /// the oracle's (height, hash) of the chain tip
+    fn tip(&self) -> Result<(u32, Sha256dHash), Error>;
+}
+
+/// Periodically compares our tip with that of oracles
+pub struct TipCheck {
+    chaindb: SharedChainDB,
+    oracles: Vec<Box<dyn TipOracle>>,
+    events: Subscribers<Event>
+}
+
+impl TipCheck {
+    pub fn start(chaindb: SharedChainDB, oracles: Vec<Box<dyn TipOracle>>, events: Subscribers<Event>) {
+        let tipcheck = TipCheck { chaindb, oracles, events };
+        thread::Builder::new().name("tip check".to_string()).spawn(move || { tipcheck.run() }).unwrap();
+    }
+
+    fn run(&self) {
+        loop {
+            for oracle in &self.oracles {
+                match oracle.tip() {
+                    Ok(theirs) => self.check(oracle.name(), theirs),
+                    Err(e) => debug!("tip oracle {} failed: {}", oracle.name(), e)
+                }
+            }
+            thread::sleep(Duration::from_secs(CHECK_SECS));
+        }
+    }
+
+    fn check(&self, oracle: String, theirs: (u32, Sha256dHash)) {
+        let (ours, agree) = {
+            let chaindb = self.chaindb.read().recover();
+            if let Some(tip) = chaindb.header_tip() {
+                let ours = (tip.stored.height, tip.bitcoin_hash());
+                let agree = if theirs.0 > ours.0 {
+                    // we might not yet know the newest blocks
+                    theirs.0 - ours.0 <= MAX_BEHIND
+                } else {
+                    chaindb.pos_on_trunk(&theirs.1) == Some(theirs.0)
+                };
+                (ours, agree)
+            } else {
+                return;
+            }
+        };
+        if agree {
+            trace!("tip oracle {} agrees at height {}", oracle, theirs.0);
+        } else {
+            warn!("tip oracle {} reports tip {} at height {}, ours is {} at height {}", oracle, theirs.1, theirs.0, ours.1, ours.0);
+            self.events.publish(Event::TipDivergence { oracle, ours, theirs });
+        }
+    }
+}
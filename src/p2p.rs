@@ -20,7 +20,7 @@
 //!
 
 use bitcoin::{
-    consensus::{Decodable, encode}
+    consensus::{Decodable, Encodable, encode}
 };
 use bitcoin::network::{
     address::Address,
@@ -29,8 +29,13 @@ use bitcoin::network::{
     message_network::VersionMessage
 };
 
-use error::Error;
-use futures::{Poll as Async, Future, future, FutureExt, task::{Waker}, TryFutureExt};
+use addressbook::is_routable;
+use bandwidth::SharedBandwidth;
+use clock::SharedClock;
+use downstream::Subscribers;
+use error::{Category, Error};
+use futures::{channel::mpsc as futures_mpsc, Poll as Async, Future, future, FutureExt, task::{Waker}, TryFutureExt};
+use lock::Recover;
 use mio::{
     Event, Events, net::{TcpListener, TcpStream}, Poll, PollOpt, Ready,
     Token,
@@ -43,22 +48,35 @@ use std::{
     fmt,
     io,
     io::{Read, Write},
-    net::{Shutdown, SocketAddr},
+    net::{IpAddr, Ipv6Addr, Shutdown, SocketAddr},
+    panic,
     str::FromStr,
     sync::{Arc, atomic::{AtomicBool, AtomicUsize, Ordering}, mpsc, Mutex,
            RwLock
     },
     thread,
-    time::{Duration, SystemTime, UNIX_EPOCH}
+    time::Duration
 };
 use std::marker::PhantomData;
+use tracing::{Level, field::display};
 use bitcoin::consensus::serialize;
 use futures::task::{Spawn, SpawnExt};
 
 const IO_BUFFER_SIZE:usize = 1024*1024;
 const EVENT_BUFFER_SIZE:usize = 1024;
 const CONNECT_TIMEOUT_SECONDS: u64 = 5;
+/// largest message payload accepted, as Bitcoin Core
+pub const MAX_MESSAGE_SIZE: usize = 32 * 1024 * 1024;
+// default ban score threshold
 const BAN :u32 = 100;
+// default seconds an address stays banned after reaching the ban score
+const BAN_DURATION: u64 = 24 * 3600;
+// peer clock offsets in seconds beyond this are not believed
+const MAX_TIME_ADJUSTMENT: i64 = 70 * 60;
+// number of connected peers needed to adjust the local clock
+const MIN_TIME_SAMPLES: usize = 5;
+// number of recent disconnects remembered
+const MAX_DISCONNECTS: usize = 100;
 
 /// do we serve blocks?
 pub const SERVICE_BLOCKS:u64 = 1;
@@ -67,7 +85,7 @@ pub const SERVICE_WITNESS:u64 =  1 << 3;
 /// require filters
 pub const SERVICE_FILTERS:u64 = 1 << 6;
 /// A peer's Id
-#[derive(Hash, Eq, PartialEq, Copy, Clone)]
+#[derive(Hash, Eq, PartialEq, Copy, Clone, Debug)]
 pub struct PeerId {
     network: &'static str,
     // mio token used in networking
@@ -80,7 +98,191 @@ impl fmt::Display for PeerId {
         Ok(())
     }
 }
+
+impl PeerId {
+    pub fn new(network: &'static str, token: Token) -> PeerId {
+        PeerId { network, token }
+    }
+
+    /// the mio token identifying the connection
+    pub fn token(&self) -> Token {
+        self.token
+    }
+}
 type PeerMap<Message> = HashMap<PeerId, Mutex<Peer<Message>>>;
+/// When to ban a peer and for how long
+#[derive(Clone, Debug)]
+pub struct BanPolicy {
+    /// ban a peer once its ban score reaches this
+    pub threshold: u32,
+    /// keep the address banned this long
+    pub duration: Duration
+}
+
+impl Default for BanPolicy {
+    fn default() -> BanPolicy {
+        BanPolicy { threshold: BAN, duration: Duration::from_secs(BAN_DURATION) }
+    }
+}
+
+// reputation of addresses that misbehaved
+type BanList = Arc<Mutex<HashMap<IpAddr, Reputation>>>;
+
+/// Misbehaviour record of an address, shared between nodes with export/import
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct Reputation {
+    /// address of the peer
+    pub ip: IpAddr,
+    /// ban score accumulated over all connections
+    pub score: u32,
+    /// unix time until the address is banned, 0 if not banned
+    pub banned_until: u64
+}
+
+impl Reputation {
+    /// true if the address is banned at the given unix time
+    pub fn is_banned(&self, now: u64) -> bool {
+        self.banned_until > now
+    }
+}
+
+impl Encodable for Reputation {
+    fn consensus_encode<W: io::Write>(&self, mut w: W) -> Result<usize, encode::Error> {
+        let mut len = match self.ip {
+            IpAddr::V4(ip) => ip.to_ipv6_mapped().octets().consensus_encode(&mut w)?,
+            IpAddr::V6(ip) => ip.octets().consensus_encode(&mut w)?
+        };
+        len += self.score.consensus_encode(&mut w)?;
+        len += self.banned_until.consensus_encode(&mut w)?;
+        Ok(len)
+    }
+}
+
+impl Decodable for Reputation {
+    fn consensus_decode<D: io::Read>(mut d: D) -> Result<Reputation, encode::Error> {
+        let octets: [u8; 16] = Decodable::consensus_decode(&mut d)?;
+        let ip = Ipv6Addr::from(octets);
+        let ip = match ip.to_ipv4() {
+            Some(v4) if octets[..12] == [0u8, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0xff, 0xff] => IpAddr::V4(v4),
+            _ => IpAddr::V6(ip)
+        };
+        Ok(Reputation { ip, score: Decodable::consensus_decode(&mut d)?, banned_until: Decodable::consensus_decode(&mut d)? })
+    }
+}
+
+/// Why a peer was disconnected
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub enum DisconnectReason {
+    /// the peer closed the connection or it broke after the handshake
+    RemoteClose,
+    /// the peer reached the ban score threshold or connects from a banned address
+    Banned,
+    /// the peer did not answer requests in time, its address is banned as with Banned
+    Timeout,
+    /// the connection failed or was rejected before the handshake completed
+    Handshake,
+    /// the peer was no longer useful, e.g. rotated out or surplus
+    Stale,
+    /// the application asked to disconnect
+    Requested
+}
+
+impl DisconnectReason {
+    /// true if the peer's address was banned
+    pub fn is_ban(&self) -> bool {
+        *self == DisconnectReason::Banned || *self == DisconnectReason::Timeout
+    }
+}
+
+impl fmt::Display for DisconnectReason {
+    fn fmt(&self, f: &mut fmt::Formatter) -> Result<(), fmt::Error> {
+        let s = match *self {
+            DisconnectReason::RemoteClose => "remote close",
+            DisconnectReason::Banned => "banned",
+            DisconnectReason::Timeout => "timeout",
+            DisconnectReason::Handshake => "handshake failed",
+            DisconnectReason::Stale => "stale",
+            DisconnectReason::Requested => "requested"
+        };
+        write!(f, "{}", s)
+    }
+}
+
+// codes 0 and 1 match the not banned and banned flag stored before reasons were recorded
+impl Encodable for DisconnectReason {
+    fn consensus_encode<W: io::Write>(&self, w: W) -> Result<usize, encode::Error> {
+        let code: u8 = match *self {
+            DisconnectReason::RemoteClose => 0,
+            DisconnectReason::Banned => 1,
+            DisconnectReason::Timeout => 2,
+            DisconnectReason::Handshake => 3,
+            DisconnectReason::Stale => 4,
+            DisconnectReason::Requested => 5
+        };
+        code.consensus_encode(w)
+    }
+}
+
+impl Decodable for DisconnectReason {
+    fn consensus_decode<D: io::Read>(d: D) -> Result<DisconnectReason, encode::Error> {
+        Ok(match u8::consensus_decode(d)? {
+            0 => DisconnectReason::RemoteClose,
+            1 => DisconnectReason::Banned,
+            2 => DisconnectReason::Timeout,
+            3 => DisconnectReason::Handshake,
+            4 => DisconnectReason::Stale,
+            5 => DisconnectReason::Requested,
+            _ => return Err(encode::Error::ParseFailed("unknown disconnect reason"))
+        })
+    }
+}
+
+/// A past disconnect of a peer
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct Disconnect {
+    /// remote address of the peer
+    pub address: SocketAddr,
+    /// unix time of the disconnect
+    pub time: u64,
+    /// why it was disconnected
+    pub reason: DisconnectReason
+}
+
+impl Encodable for Disconnect {
+    fn consensus_encode<W: io::Write>(&self, mut w: W) -> Result<usize, encode::Error> {
+        let mut len = self.address.to_string().consensus_encode(&mut w)?;
+        len += self.time.consensus_encode(&mut w)?;
+        len += self.reason.consensus_encode(&mut w)?;
+        Ok(len)
+    }
+}
+
+// recent disconnects, oldest first, and those notified of new ones
+struct DisconnectLog {
+    recent: Mutex<VecDeque<Disconnect>>,
+    subscribers: Subscribers<Disconnect>
+}
+
+impl DisconnectLog {
+    fn record(&self, disconnect: Disconnect) {
+        {
+            let mut recent = self.recent.lock().recover();
+            recent.push_back(disconnect.clone());
+            while recent.len() > MAX_DISCONNECTS {
+                recent.pop_front();
+            }
+        }
+        self.subscribers.publish(disconnect);
+    }
+}
+
+impl Decodable for Disconnect {
+    fn consensus_decode<D: io::Read>(mut d: D) -> Result<Disconnect, encode::Error> {
+        let address = SocketAddr::from_str(String::consensus_decode(&mut d)?.as_str())
+            .map_err(|_| encode::Error::ParseFailed("invalid socket address"))?;
+        Ok(Disconnect { address, time: Decodable::consensus_decode(&mut d)?, reason: Decodable::consensus_decode(&mut d)? })
+    }
+}
 
 /// A message from network to downstream
 #[derive(Clone)]
@@ -88,15 +290,29 @@ pub enum PeerMessage<Message: Send + Sync + Clone> {
     Outgoing(Message),
     Incoming(PeerId, Message),
     Connected(PeerId, Option<SocketAddr>),
-    Disconnected(PeerId, bool) // true if banned
+    Disconnected(PeerId, DisconnectReason)
 }
 
+/// Feedback of message processors to the P2P layer
 pub enum P2PControl<Message: Clone> {
+    /// send a message to a peer
     Send(PeerId, Message),
+    /// send a message to all peers
     Broadcast(Message),
+    /// increase a peer's ban score, it is disconnected and banned at the threshold of the ban policy
     Ban(PeerId, u32),
+    /// as Ban, for a peer that did not answer in time
+    Stalled(PeerId, u32),
+    /// height of the trunk announced to peers connecting
     Height(u32),
-    Bind(SocketAddr)
+    /// accept connections at the address
+    Bind(SocketAddr),
+    /// accept connections at the address, wrapped in a tunnel, e.g. TLS
+    BindTunneled(SocketAddr, Arc<dyn TunnelAcceptor>),
+    /// disconnect a peer without banning it
+    Disconnect(PeerId),
+    /// disconnect a peer no longer useful, e.g. rotated out or surplus, without banning it
+    Retire(PeerId)
 }
 
 type P2PControlReceiver<Message> = mpsc::Receiver<P2PControl<Message>>;
@@ -105,16 +321,17 @@ type P2PControlReceiver<Message> = mpsc::Receiver<P2PControl<Message>>;
 pub struct P2PControlSender<Message: Clone> {
     sender: Arc<Mutex<mpsc::Sender<P2PControl<Message>>>>,
     peers: Arc<RwLock<PeerMap<Message>>>,
+    clock: SharedClock,
     pub back_pressure: usize
 }
 
 impl<Message: Send + Sync + Clone> P2PControlSender<Message> {
-    fn new (sender: mpsc::Sender<P2PControl<Message>>, peers: Arc<RwLock<PeerMap<Message>>>, back_pressure: usize) -> P2PControlSender<Message> {
-        P2PControlSender { sender: Arc::new(Mutex::new(sender)), peers, back_pressure }
+    fn new (sender: mpsc::Sender<P2PControl<Message>>, peers: Arc<RwLock<PeerMap<Message>>>, clock: SharedClock, back_pressure: usize) -> P2PControlSender<Message> {
+        P2PControlSender { sender: Arc::new(Mutex::new(sender)), peers, clock, back_pressure }
     }
 
     pub fn send (&self, control: P2PControl<Message>) {
-        self.sender.lock().unwrap().send(control).expect("P2P control send failed");
+        self.sender.lock().recover().send(control).expect("P2P control send failed");
     }
 
     pub fn send_network (&self, peer: PeerId, msg: Message) {
@@ -122,7 +339,7 @@ impl<Message: Send + Sync + Clone> P2PControlSender<Message> {
     }
 
     pub fn send_random_network (&self, msg: Message) -> Option<PeerId> {
-        let peers = self.peers.read().unwrap().keys().cloned().collect::<Vec<PeerId>>();
+        let peers = self.peers.read().recover().keys().cloned().collect::<Vec<PeerId>>();
         if peers.len() > 0 {
             let peer = peers[(thread_rng().next_u32() % peers.len() as u32) as usize];
             self.send(P2PControl::Send(peer, msg));
@@ -141,18 +358,93 @@ impl<Message: Send + Sync + Clone> P2PControlSender<Message> {
     }
 
     pub fn peer_version (&self, peer: PeerId) -> Option<VersionCarrier> {
-        if let Some(peer) = self.peers.read().unwrap().get(&peer) {
-            let locked_peer = peer.lock().unwrap();
+        if let Some(peer) = self.peers.read().recover().get(&peer) {
+            let locked_peer = peer.lock().recover();
             return locked_peer.version.clone();
         }
         None
     }
 
+    /// Unix time adjusted by the median clock offset of connected peers, as a node would use to
+    /// judge header timestamps. Offsets beyond MAX_TIME_ADJUSTMENT are ignored, as is the
+    /// adjustment until MIN_TIME_SAMPLES peers are connected.
+    pub fn network_time (&self) -> u64 {
+        let mut offsets = self.peers.read().recover().values().filter_map(|peer| {
+            let locked_peer = peer.lock().recover();
+            if locked_peer.connected && locked_peer.time_offset.abs() <= MAX_TIME_ADJUSTMENT {
+                Some(locked_peer.time_offset)
+            } else {
+                None
+            }
+        }).collect::<Vec<_>>();
+        let now = self.clock.unix_time() as i64;
+        if offsets.len() < MIN_TIME_SAMPLES {
+            return now as u64;
+        }
+        offsets.sort();
+        (now + offsets[offsets.len() / 2]) as u64
+    }
+
     pub fn peers (&self) -> Vec<PeerId> {
-        self.peers.read().unwrap().keys().cloned().collect::<Vec<_>>()
+        self.peers.read().recover().keys().cloned().collect::<Vec<_>>()
+    }
+
+    pub fn peer_address (&self, peer: PeerId) -> Option<SocketAddr> {
+        if let Some(peer) = self.peers.read().recover().get(&peer) {
+            return Some(peer.lock().recover().address);
+        }
+        None
+    }
+
+    /// true if we connected the peer, false if the peer connected us
+    pub fn is_outgoing (&self, peer: PeerId) -> Option<bool> {
+        if let Some(peer) = self.peers.read().recover().get(&peer) {
+            return Some(peer.lock().recover().outgoing);
+        }
+        None
+    }
+
+    /// record round trip time of the last ping
+    pub fn set_ping_time (&self, peer: PeerId, time: Duration) {
+        if let Some(peer) = self.peers.read().recover().get(&peer) {
+            peer.lock().recover().ping = Some(time);
+        }
     }
 }
 
+/// Observable state of a connected peer
+#[derive(Clone, Debug)]
+pub struct PeerInfo {
+    /// the peer's id
+    pub id: PeerId,
+    /// remote address
+    pub address: SocketAddr,
+    /// true if we connected the peer, false if the peer connected us
+    pub outgoing: bool,
+    /// services the peer announced in its version message
+    pub services: u64,
+    /// the peer's software
+    pub user_agent: String,
+    /// protocol version agreed with the peer
+    pub version: u32,
+    /// height the peer announced at connect
+    pub start_height: u32,
+    /// unix time of the last write to the peer
+    pub last_send: u64,
+    /// unix time of the last read from the peer
+    pub last_recv: u64,
+    /// bytes sent to the peer
+    pub bytes_sent: u64,
+    /// bytes received from the peer
+    pub bytes_received: u64,
+    /// round trip time of the last ping
+    pub ping: Option<Duration>,
+    /// ban score
+    pub ban: u32,
+    /// why the last earlier connection with the peer's address ended, if remembered
+    pub last_disconnect: Option<DisconnectReason>
+}
+
 #[derive(Clone)]
 pub enum PeerSource {
     Outgoing(SocketAddr),
@@ -178,7 +470,7 @@ impl<Message: Send + Sync + Clone> PeerMessageSender<Message> {
 
     pub fn send (&self, msg: PeerMessage<Message>) {
         if let Some(ref sender) = self.sender {
-            sender.lock().unwrap().send(msg).expect("P2P message send failed");
+            sender.lock().recover().send(msg).expect("P2P message send failed");
         }
     }
 }
@@ -258,6 +550,8 @@ pub trait P2PConfig<Message: Version + Send + Sync + 'static, Envelope: Command
     fn user_agent(&self) -> &str;
     fn get_height(&self) -> u32;
     fn set_height(&self, u32);
+    /// an outgoing peer saw us at this address
+    fn reported_address(&self, address: &SocketAddr);
     fn max_protocol_version(&self) -> u32;
     fn min_protocol_version(&self) -> u32;
     fn verack(&self) -> Message;
@@ -277,8 +571,83 @@ pub struct BitcoinP2PConfig {
     pub user_agent: String,
     // this node's maximum protocol version
     pub max_protocol_version: u32,
-    // serving others
-    pub server: bool,
+    // our address and services as seen by others
+    pub local: SharedLocalAddress,
+    // source of the time in version messages
+    pub clock: SharedClock
+}
+
+pub type SharedLocalAddress = Arc<LocalAddress>;
+
+/// The address other peers can reach this node at and the services offered there.
+/// An address set explicitly is used as is, otherwise a routable listen address, otherwise
+/// the IP outgoing peers reported most often with the port we listen at. Mapping a port with
+/// UPnP is not supported, set the address if the node is behind NAT.
+pub struct LocalAddress {
+    // ports accepting connections, empty if not serving
+    listen: Vec<SocketAddr>,
+    bandwidth: SharedBandwidth,
+    configured: RwLock<Option<SocketAddr>>,
+    // IPs outgoing peers saw us at with number of peers
+    reported: Mutex<HashMap<IpAddr, usize>>
+}
+
+impl LocalAddress {
+    pub fn new (listen: Vec<SocketAddr>, bandwidth: SharedBandwidth) -> LocalAddress {
+        LocalAddress { listen, bandwidth, configured: RwLock::new(None), reported: Mutex::new(HashMap::new()) }
+    }
+
+    /// set the address to advertise, None to determine it
+    pub fn set (&self, address: Option<SocketAddr>) {
+        *self.configured.write().recover() = address;
+    }
+
+    /// addresses listened at
+    pub fn listen (&self) -> Vec<SocketAddr> {
+        self.listen.clone()
+    }
+
+    /// accepting connections
+    pub fn is_server (&self) -> bool {
+        !self.listen.is_empty()
+    }
+
+    /// services offered, none unless serving and bandwidth is available
+    pub fn services (&self) -> u64 {
+        if self.listen.is_empty() || self.bandwidth.is_restricted() {
+            0
+        } else {
+            SERVICE_BLOCKS + SERVICE_WITNESS +
+                // announce that this node is capable of serving BIP157 messages
+                SERVICE_FILTERS
+        }
+    }
+
+    /// record the address a peer saw us at
+    pub fn report (&self, address: &SocketAddr) {
+        if is_routable(&address.ip()) {
+            *self.reported.lock().recover().entry(address.ip()).or_insert(0) += 1;
+        }
+    }
+
+    /// the address other peers can connect to, None if not serving or not known
+    pub fn get (&self) -> Option<SocketAddr> {
+        if let Some(configured) = *self.configured.read().recover() {
+            return Some(configured);
+        }
+        let first = self.listen.first()?;
+        if let Some(routable) = self.listen.iter().find(|a| is_routable(&a.ip())) {
+            return Some(*routable);
+        }
+        let reported = self.reported.lock().recover();
+        reported.iter().max_by_key(|(_, n)| **n).map(|(ip, _)| SocketAddr::new(*ip, first.port()))
+    }
+
+    /// address with services as sent in version and addr messages, unspecified if not known
+    pub fn address (&self) -> Address {
+        let address = self.get().unwrap_or(SocketAddr::from(([0, 0, 0, 0], 0)));
+        Address::new(&address, self.services())
+    }
 }
 
 struct PassThroughBufferReader<'a> {
@@ -295,24 +664,15 @@ impl P2PConfig<NetworkMessage, RawNetworkMessage> for BitcoinP2PConfig {
     // compile this node's version message for outgoing connections
     fn version (&self, remote: &SocketAddr, max_protocol_version: u32) -> NetworkMessage {
         // now in unix time
-        let timestamp = SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_secs() as i64;
-
-        let services = if !self.server {
-            0
-        } else {
-            SERVICE_BLOCKS + SERVICE_WITNESS +
-                // announce that this node is capable of serving BIP157 messages
-                SERVICE_FILTERS
-        };
+        let timestamp = self.clock.unix_time() as i64;
 
         // build message
         NetworkMessage::Version(VersionMessage {
             version: min(max_protocol_version, self.max_protocol_version),
-            services,
+            services: self.local.services(),
             timestamp,
-            receiver: Address::new(remote, 1),
-            // sender is only dummy
-            sender: Address::new(remote, 1),
+            receiver: Address::new(remote, 0),
+            sender: self.local.address(),
             nonce: self.nonce,
             user_agent: self.user_agent.clone(),
             start_height: self.height.load(Ordering::Relaxed) as i32,
@@ -340,6 +700,10 @@ impl P2PConfig<NetworkMessage, RawNetworkMessage> for BitcoinP2PConfig {
         self.height.store (height as usize, Ordering::Relaxed)
     }
 
+    fn reported_address(&self, address: &SocketAddr) {
+        self.local.report(address)
+    }
+
     fn max_protocol_version(&self) -> u32 {
         self.max_protocol_version
     }
@@ -368,37 +732,107 @@ impl P2PConfig<NetworkMessage, RawNetworkMessage> for BitcoinP2PConfig {
 
     // decode a message from the buffer if possible
     fn decode(&self, src: &mut Buffer) -> Result<Option<RawNetworkMessage>, io::Error> {
-        // attempt to decode
-        let passthrough = PassThroughBufferReader{buffer: src};
-        let decode: Result<RawNetworkMessage, encode::Error> =
-            Decodable::consensus_decode(passthrough);
-
-        match decode {
-            Ok(m) => {
-                // success: free the read data in buffer and return the message
-                src.commit();
-                Ok(Some(m))
-            }
-            Err(encode::Error::Io(e)) => {
-                if e.kind() == io::ErrorKind::UnexpectedEof {
-                    // need more data, rollback and retry after additional read
-                    src.rollback();
-                    return Ok(None)
-                } else {
-                    error!("{:?}", e);
-                    src.commit();
-                    return Err(e);
-                }
-            },
-            Err(e) => {
-                error!("{:?}", e);
+        decode_message(src)
+    }
+}
+
+/// Decode a message in Bitcoin's wire format from the buffer if it is complete. A message announcing
+/// a payload larger than MAX_MESSAGE_SIZE or failing to decode is an error, also if decoding panics,
+/// so a malformed message only costs the connection of the peer sending it.
+pub fn decode_message(src: &mut Buffer) -> Result<Option<RawNetworkMessage>, io::Error> {
+    // magic, command, payload length and checksum
+    let mut header = [0u8; 24];
+    if src.read_ahead(&mut header)? == header.len() {
+        let len = u32::from_le_bytes([header[16], header[17], header[18], header[19]]) as usize;
+        if len > MAX_MESSAGE_SIZE {
+            return Err(io::Error::new(io::ErrorKind::InvalidData, format!("message payload of {} bytes", len)));
+        }
+    }
+    // attempt to decode
+    let decode = panic::catch_unwind(panic::AssertUnwindSafe(|| {
+        let passthrough = PassThroughBufferReader{buffer: &mut *src};
+        let decode: Result<RawNetworkMessage, encode::Error> = Decodable::consensus_decode(passthrough);
+        decode
+    })).map_err(|_| io::Error::new(io::ErrorKind::InvalidData, "panic decoding message"))?;
+
+    match decode {
+        Ok(m) => {
+            // success: free the read data in buffer and return the message
+            src.commit();
+            Ok(Some(m))
+        }
+        Err(encode::Error::Io(e)) => {
+            if e.kind() == io::ErrorKind::UnexpectedEof {
+                // need more data, rollback and retry after additional read
+                src.rollback();
+                return Ok(None)
+            } else {
+                debug!("{:?}", e);
                 src.commit();
-                Err(io::Error::new(io::ErrorKind::InvalidData, e))
+                return Err(e);
             }
+        },
+        Err(e) => {
+            debug!("{:?}", e);
+            src.commit();
+            Err(io::Error::new(io::ErrorKind::InvalidData, e))
         }
     }
 }
 
+/// Opens outgoing connections. An embedder can route connections through its own proxy, VPN or
+/// test harness by setting a dialer on P2P. The stream must be a TCP socket as it is polled by mio,
+/// a tunnel would offer a local socket. A std::net::TcpStream established in an other way is
+/// converted with TcpStream::from_stream.
+pub trait Dialer: Send + Sync {
+    /// start connecting to the address, the stream may still be connecting when returned
+    fn connect(&self, addr: &SocketAddr) -> io::Result<TcpStream>;
+
+    /// true if Tor addresses in OnionCat encoding can be connected, they are not dialed otherwise
+    fn reaches_onion(&self) -> bool {
+        false
+    }
+}
+
+/// Connect directly through the operating system
+pub struct TcpDialer;
+
+impl Dialer for TcpDialer {
+    fn connect(&self, addr: &SocketAddr) -> io::Result<TcpStream> {
+        TcpStream::connect(addr)
+    }
+}
+
+/// Wraps the byte stream of a connection, e.g. in TLS
+pub trait Tunnel: Send {
+    /// read plain data, WouldBlock if data was received but none is yet available, 0 at end of stream
+    fn read(&mut self, stream: &mut TcpStream, buf: &mut [u8]) -> io::Result<usize>;
+    /// write plain data, wrapped data not yet sent is kept for flush
+    fn write(&mut self, stream: &mut TcpStream, buf: &[u8]) -> io::Result<usize>;
+    /// send wrapped data kept from earlier, true if nothing is left
+    fn flush(&mut self, stream: &mut TcpStream) -> io::Result<bool>;
+    /// true if wrapped data is waiting to be sent
+    fn wants_write(&self) -> bool;
+}
+
+/// Creates a tunnel for each connection accepted by a listener
+pub trait TunnelAcceptor: Send + Sync {
+    /// a tunnel for a newly accepted connection
+    fn accept(&self) -> Result<Box<dyn Tunnel>, Error>;
+}
+
+/// Observes traffic with peers, e.g. to record it for replay
+pub trait Observer<Message>: Send + Sync {
+    /// a peer completed the handshake
+    fn connected(&self, peer: PeerId, address: Option<SocketAddr>);
+    /// a peer was disconnected
+    fn disconnected(&self, peer: PeerId, reason: DisconnectReason);
+    /// a message was received from a peer
+    fn incoming(&self, peer: PeerId, message: &Message);
+    /// a message is about to be sent to a peer
+    fn outgoing(&self, peer: PeerId, message: &Message);
+}
+
 /// The P2P network layer
 pub struct P2P<Message: Version + Send + Sync + Clone + 'static,
     Envelope: Command + Send + Sync + 'static,
@@ -419,6 +853,24 @@ pub struct P2P<Message: Version + Send + Sync + Clone + 'static,
     waker: Arc<Mutex<HashMap<PeerId, Waker>>>,
     // server
     listener: Arc<Mutex<HashMap<Token, Arc<TcpListener>>>>,
+    // wrap connections accepted by these listeners
+    acceptors: Mutex<HashMap<Token, Arc<dyn TunnelAcceptor>>>,
+    // banned addresses
+    banned: BanList,
+    // when to ban
+    ban_policy: Mutex<BanPolicy>,
+    // opens outgoing connections
+    dialer: Mutex<Arc<dyn Dialer>>,
+    // observes traffic
+    observer: RwLock<Option<Arc<dyn Observer<Message>>>>,
+    // connected peers by the nonce of their version message
+    remote_nonces: Mutex<HashMap<u64, PeerId>>,
+    // recent disconnects, also of peers failing to connect
+    disconnects: Arc<DisconnectLog>,
+    // bandwidth budget
+    bandwidth: SharedBandwidth,
+    // source of time for bans, disconnects and time offsets
+    clock: SharedClock,
     e: PhantomData<Envelope>
 }
 
@@ -426,7 +878,7 @@ impl<Message: Version + Send + Sync + Clone,
     Envelope: Command + Send + Sync,
     Config: P2PConfig<Message, Envelope> + Send + Sync> P2P<Message, Envelope, Config> {
     /// create a new P2P network controller
-    pub fn new(config: Config, dispatcher: PeerMessageSender<Message>, back_pressure: usize) -> (Arc<P2P<Message, Envelope, Config>>, P2PControlSender<Message>) {
+    pub fn new(config: Config, dispatcher: PeerMessageSender<Message>, back_pressure: usize, bandwidth: SharedBandwidth, clock: SharedClock) -> (Arc<P2P<Message, Envelope, Config>>, P2PControlSender<Message>) {
         let (control_sender, control_receiver) = mpsc::channel();
 
         let peers = Arc::new(RwLock::new(PeerMap::new()));
@@ -439,6 +891,15 @@ impl<Message: Version + Send + Sync + Clone,
             next_peer_id: AtomicUsize::new(0),
             waker: Arc::new(Mutex::new(HashMap::new())),
             listener: Arc::new(Mutex::new(HashMap::new())),
+            acceptors: Mutex::new(HashMap::new()),
+            banned: Arc::new(Mutex::new(HashMap::new())),
+            ban_policy: Mutex::new(BanPolicy::default()),
+            dialer: Mutex::new(Arc::new(TcpDialer)),
+            observer: RwLock::new(None),
+            remote_nonces: Mutex::new(HashMap::new()),
+            disconnects: Arc::new(DisconnectLog { recent: Mutex::new(VecDeque::new()), subscribers: Subscribers::new() }),
+            bandwidth,
+            clock: clock.clone(),
             e: PhantomData{}
         });
 
@@ -446,59 +907,117 @@ impl<Message: Version + Send + Sync + Clone,
 
         thread::Builder::new().name("p2pcntrl".to_string()).spawn(move || p2p2.control_loop(control_receiver)).unwrap();
 
-        (p2p, P2PControlSender::new(control_sender, peers, back_pressure))
+        (p2p, P2PControlSender::new(control_sender, peers, clock, back_pressure))
     }
 
     pub fn connected_peers (&self) -> Vec<SocketAddr> {
-        self.peers.read().unwrap().values()
+        self.peers.read().recover().values()
             .filter_map(|peer|
-                if let Ok(a) = peer.lock().unwrap().stream.peer_addr() {
+                if let Ok(a) = peer.lock().recover().stream.peer_addr() {
                     Some(a)
                 } else {None}).collect()
     }
 
     pub fn n_connected_peers (&self) -> usize {
-        self.peers.read().unwrap().len()
+        self.peers.read().recover().len()
+    }
+
+    /// state of peers that completed the handshake
+    pub fn peer_info (&self) -> Vec<PeerInfo> {
+        let disconnects = self.disconnects.recent.lock().recover().clone();
+        self.peers.read().recover().iter().filter_map(|(pid, peer)| {
+            let locked_peer = peer.lock().recover();
+            if !locked_peer.connected {
+                return None;
+            }
+            if let Some(ref version) = locked_peer.version {
+                Some(PeerInfo {
+                    id: *pid,
+                    address: locked_peer.address,
+                    outgoing: locked_peer.outgoing,
+                    services: version.services,
+                    user_agent: version.user_agent.clone(),
+                    version: version.version,
+                    start_height: version.start_height,
+                    last_send: locked_peer.last_send,
+                    last_recv: locked_peer.last_recv,
+                    bytes_sent: locked_peer.bytes_sent,
+                    bytes_received: locked_peer.bytes_received,
+                    ping: locked_peer.ping,
+                    ban: locked_peer.ban,
+                    last_disconnect: disconnects.iter().rev().find(|d| d.address == locked_peer.address).map(|d| d.reason)
+                })
+            } else {
+                None
+            }
+        }).collect()
     }
 
     fn control_loop (&self, receiver: P2PControlReceiver<Message>) {
         while let Ok(control) = receiver.recv() {
             match control {
                 P2PControl::Ban(peer_id, score) => {
-                    self.ban(peer_id, score);
+                    self.ban(peer_id, score, DisconnectReason::Banned);
+                },
+                P2PControl::Stalled(peer_id, score) => {
+                    self.ban(peer_id, score, DisconnectReason::Timeout);
                 },
                 P2PControl::Height(height) => {
                     self.config.set_height(height);
                 }
                 P2PControl::Bind(addr) => {
-                    match self.add_listener(&addr) {
+                    match self.add_listener(&addr, None) {
                         Ok(()) => info!("listen to {}", addr),
                         Err(err) => info!("failed to listen to {} with {}", addr, err)
                     }
                 },
+                P2PControl::BindTunneled(addr, acceptor) => {
+                    match self.add_listener(&addr, Some(acceptor)) {
+                        Ok(()) => info!("listen to {} through a tunnel", addr),
+                        Err(err) => info!("failed to listen to {} with {}", addr, err)
+                    }
+                },
                 P2PControl::Broadcast(message) => {
-                    for peer in self.peers.read().unwrap().values() {
-                        peer.lock().unwrap().send(message.clone()).expect("could not send to peer");
+                    for peer in self.peers.read().recover().values() {
+                        peer.lock().recover().send(message.clone()).expect("could not send to peer");
                     }
                 }
                 P2PControl::Send(peer_id, message) => {
-                    if let Some (peer) = self.peers.read().unwrap().get (&peer_id) {
-                        peer.lock().unwrap().send(message).expect("could not send to peer");
+                    if let Some (peer) = self.peers.read().recover().get (&peer_id) {
+                        peer.lock().recover().send(message).expect("could not send to peer");
                     }
                 }
+                P2PControl::Disconnect(peer_id) => {
+                    debug!("disconnect on request peer={}", peer_id);
+                    self.disconnect(peer_id, DisconnectReason::Requested);
+                }
+                P2PControl::Retire(peer_id) => {
+                    debug!("retire peer={}", peer_id);
+                    self.disconnect(peer_id, DisconnectReason::Stale);
+                }
             }
         }
         panic!("P2P Control loop failed");
     }
 
-    fn add_listener (&self, bind: &SocketAddr) -> Result<(), io::Error> {
+    fn add_listener (&self, bind: &SocketAddr, acceptor: Option<Arc<dyn TunnelAcceptor>>) -> Result<(), io::Error> {
         let listener = TcpListener::bind(bind)?;
         let token = Token(self.next_peer_id.fetch_add(1, Ordering::Relaxed));
+        if let Some(acceptor) = acceptor {
+            self.acceptors.lock().recover().insert(token, acceptor);
+        }
         self.poll.register(&listener, token, Ready::readable(), PollOpt::edge())?;
-        self.listener.lock().unwrap().insert(token, Arc::new(listener));
+        self.listener.lock().recover().insert(token, Arc::new(listener));
         Ok(())
     }
 
+    // the tunnel wrapping connections accepted by the listener, if any
+    fn acceptor (&self, listener: &Arc<TcpListener>) -> Option<Arc<dyn TunnelAcceptor>> {
+        let listeners = self.listener.lock().recover();
+        let token = listeners.iter().find(|(_, l)| Arc::ptr_eq(l, listener)).map(|(t, _)| *t)?;
+        self.acceptors.lock().recover().get(&token).cloned()
+    }
+
     /// return a future that does not complete until the peer is connected
     pub fn add_peer (&self, network: &'static str, source: PeerSource) -> impl Future<Output=Result<SocketAddr, Error>> + Send {
         // new token, never re-using previously connected peer's id
@@ -509,19 +1028,18 @@ impl<Message: Version + Send + Sync + Clone,
         let peers = self.peers.clone();
         let peers2 = self.peers.clone();
         let waker = self.waker.clone();
+        let disconnects = self.disconnects.clone();
+        let clock = self.clock.clone();
 
         self.connecting(pid, source)
             .map_err(move |e| {
-                let mut peers = peers2.write().unwrap();
-                if let Some(peer) = peers.remove(&pid) {
-                    peer.lock().unwrap().stream.shutdown(Shutdown::Both).unwrap_or(());
-                }
+                Self::abandon(&peers2, &disconnects, clock.unix_time(), pid);
                 e
             })
             .and_then (move |addr| {
             future::poll_fn(move |ctx| {
-                if peers.read().unwrap().get(&pid).is_some() {
-                    waker.lock().unwrap().insert(pid, ctx.waker().clone());
+                if peers.read().recover().get(&pid).is_some() {
+                    waker.lock().recover().insert(pid, ctx.waker().clone());
                     Async::Pending
                 } else {
                     debug!("finished orderly peer={}", pid);
@@ -531,6 +1049,33 @@ impl<Message: Version + Send + Sync + Clone,
         })
     }
 
+    /// return a future that completes with the peer's id as soon as the handshake is complete
+    pub fn connect_peer (&self, network: &'static str, addr: SocketAddr) -> impl Future<Output=Result<PeerId, Error>> + Send {
+        let token = Token(self.next_peer_id.fetch_add(1, Ordering::Relaxed));
+        let pid = PeerId{network, token};
+
+        let peers = self.peers.clone();
+        let disconnects = self.disconnects.clone();
+        let clock = self.clock.clone();
+
+        self.connecting(pid, PeerSource::Outgoing(addr))
+            .map_err(move |e| {
+                Self::abandon(&peers, &disconnects, clock.unix_time(), pid);
+                e
+            })
+            .map_ok(move |_| pid)
+    }
+
+    // drop a peer that did not complete the handshake in time
+    fn abandon(peers: &RwLock<PeerMap<Message>>, disconnects: &DisconnectLog, now: u64, pid: PeerId) {
+        if let Some(peer) = peers.write().recover().remove(&pid) {
+            let locked_peer = peer.lock().recover();
+            locked_peer.stream.shutdown(Shutdown::Both).unwrap_or(());
+            debug!("handshake with {} not completed peer={}", locked_peer.address, pid);
+            disconnects.record(Disconnect { address: locked_peer.address, time: now, reason: DisconnectReason::Handshake });
+        }
+    }
+
     fn connecting(&self, pid: PeerId, source: PeerSource) -> impl Future<Output=Result<SocketAddr, Error>> + Send {
 
 
@@ -541,23 +1086,38 @@ impl<Message: Version + Send + Sync + Clone,
         let peers2 = self.peers.clone();
         let poll = self.poll.clone();
         let waker = self.waker.clone();
+        let banned = self.banned.clone();
+        let clock = self.clock.clone();
+        let dialer = self.dialer.lock().recover().clone();
+        let disconnects = self.disconnects.clone();
+        let acceptor = match source {
+            PeerSource::Incoming(ref listener) => self.acceptor(listener),
+            PeerSource::Outgoing(_) => None
+        };
 
         future::poll_fn(move |_| {
-            match Self::connect(version.clone(), peers.clone(), poll.clone(), pid, source.clone()) {
+            let mut tried = None;
+            match Self::connect(version.clone(), peers.clone(), poll.clone(), banned.clone(), clock.unix_time(), dialer.as_ref(), acceptor.clone(), pid, source.clone(), &mut tried) {
                 Ok(addr) => Async::Ready(Ok(addr)),
-                Err(e) => { Async::Ready(Err(e)) }
+                Err(e) => {
+                    // failed dials and rejected connects are recorded as the peer never got into peers
+                    if let Some(address) = tried {
+                        disconnects.record(Disconnect { address, time: clock.unix_time(), reason: DisconnectReason::Handshake });
+                    }
+                    Async::Ready(Err(e))
+                }
             }
         }).and_then(move |addr| {
             use futures_timer::TryFutureExt;
 
             future::poll_fn(move |ctx|
-                if let Some(peer) = peers2.read().unwrap().get(&pid) {
+                if let Some(peer) = peers2.read().recover().get(&pid) {
                     // return pid if peer is connected (handshake perfect)
-                    if peer.lock().unwrap().connected {
+                    if peer.lock().recover().connected {
                         trace!("woke up to handshake");
                         Async::Ready(Ok(addr))
                     } else {
-                        waker.lock().unwrap().insert(pid, ctx.waker().clone());
+                        waker.lock().recover().insert(pid, ctx.waker().clone());
                         Async::Pending
                     }
                 } else {
@@ -568,40 +1128,48 @@ impl<Message: Version + Send + Sync + Clone,
         })
     }
 
-    // initiate connection to peer
-    fn connect(version: Message, peers: Arc<RwLock<PeerMap<Message>>>, poll: Arc<Poll>, pid: PeerId, source: PeerSource) -> Result<SocketAddr, Error> {
+    // initiate connection to peer, tried is set to the address of the peer once known
+    fn connect(version: Message, peers: Arc<RwLock<PeerMap<Message>>>, poll: Arc<Poll>, banned: BanList, now: u64, dialer: &dyn Dialer,
+               acceptor: Option<Arc<dyn TunnelAcceptor>>, pid: PeerId, source: PeerSource, tried: &mut Option<SocketAddr>) -> Result<SocketAddr, Error> {
         let outgoing;
         let addr;
         let stream;
+        let mut tunnel = None;
         match source {
             PeerSource::Outgoing(a) => {
+                *tried = Some(a);
                 if let PeerSource::Outgoing(a) = source {
-                    if peers.read().unwrap().values()
-                        .any(|peer|
-                            if let Ok(addr) = peer.lock().unwrap().stream.peer_addr() {
-                                a.ip() == addr.ip()
-                            } else { false }) {
+                    if Self::has_address(&peers.read().recover(), &a.ip()) {
                         debug!("rejecting outgoing connect for a peer already connected");
                         return Err(Error::Handshake);
                     }
+                    if Self::is_banned(&banned, &a.ip(), now) {
+                        debug!("rejecting outgoing connect to banned {}", a);
+                        return Err(Error::Handshake);
+                    }
                 }
 
                 addr = a;
                 outgoing = true;
                 info!("trying outgoing connect to {} peer={}", addr, pid);
-                stream = TcpStream::connect(&addr)?;
+                stream = dialer.connect(&addr)?;
             },
             PeerSource::Incoming(listener) => {
                 let (s, a) = listener.accept()?;
-                if peers.read().unwrap().values()
-                    .any(|peer|
-                        if let Ok(addr) = peer.lock().unwrap().stream.peer_addr() {
-                            a.ip() == addr.ip()
-                        } else { false }) {
+                *tried = Some(a);
+                if Self::has_address(&peers.read().recover(), &a.ip()) {
                     debug!("rejecting incoming connect from a peer already connected");
                     s.shutdown(Shutdown::Both).unwrap_or(());
                     return Err(Error::Handshake);
                 }
+                if Self::is_banned(&banned, &a.ip(), now) {
+                    debug!("rejecting incoming connect from banned {}", a);
+                    s.shutdown(Shutdown::Both).unwrap_or(());
+                    return Err(Error::Handshake);
+                }
+                if let Some(acceptor) = acceptor {
+                    tunnel = Some(acceptor.accept()?);
+                }
                 addr = a;
                 stream = s;
                 info!("trying incoming connect to {} peer={}", addr, pid);
@@ -610,9 +1178,15 @@ impl<Message: Version + Send + Sync + Clone,
         };
 
         // create lock protected peer object
-        let peer = Mutex::new(Peer::new(pid, stream, poll.clone(), outgoing)?);
+        let peer = Mutex::new(Peer::new(pid, addr, stream, tunnel, poll.clone(), outgoing)?);
 
-        let mut peers = peers.write().unwrap();
+        let mut peers = peers.write().recover();
+
+        // an other connect to the address might have raced this one
+        if Self::has_address(&peers, &addr.ip()) {
+            debug!("rejecting connect to {} as it was connected meanwhile peer={}", addr, pid);
+            return Err(Error::Handshake);
+        }
 
         // add to peer map
         peers.insert(pid, peer);
@@ -620,29 +1194,41 @@ impl<Message: Version + Send + Sync + Clone,
         let stored_peer = peers.get(&pid).unwrap();
 
         if outgoing {
-            stored_peer.lock().unwrap().register_write()?;
+            stored_peer.lock().recover().register_write()?;
         } else {
-            stored_peer.lock().unwrap().register_read()?;
+            stored_peer.lock().recover().register_read()?;
         }
         if outgoing {
             // send this node's version message to peer
-            peers.get(&pid).unwrap().lock().unwrap().send(version)?;
+            peers.get(&pid).unwrap().lock().recover().send(version)?;
         }
 
         Ok(addr)
     }
 
-    fn disconnect (&self, pid: PeerId, banned: bool) {
-        self.dispatcher.send(PeerMessage::Disconnected(pid, banned));
+    fn disconnect (&self, pid: PeerId, reason: DisconnectReason) {
+        if let Some(ref observer) = *self.observer.read().recover() {
+            observer.disconnected(pid, reason);
+        }
+        self.dispatcher.send(PeerMessage::Disconnected(pid, reason));
+        self.remote_nonces.lock().recover().retain(|_, p| *p != pid);
+        let mut address = None;
         {
             // remove from peers before waking up, so disconnect is recognized
-            let mut peers = self.peers.write().unwrap();
+            let mut peers = self.peers.write().recover();
             if let Some(peer) = peers.remove(&pid) {
-                peer.lock().unwrap().stream.shutdown(Shutdown::Both).unwrap_or(());
+                let locked_peer = peer.lock().recover();
+                locked_peer.stream.shutdown(Shutdown::Both).unwrap_or(());
+                address = Some(locked_peer.address);
             }
         }
+        if let Some(address) = address {
+            // disconnects of an already removed peer are not recorded twice
+            debug!("disconnected {} for {} peer={}", address, reason, pid);
+            self.disconnects.record(Disconnect { address, time: self.now(), reason });
+        }
         {
-            let mut wakers = self.waker.lock().unwrap();
+            let mut wakers = self.waker.lock().recover();
             if let Some(waker) = wakers.remove(&pid) {
                 debug!("waking for disconnect peer={}", pid);
                 waker.wake();
@@ -651,31 +1237,146 @@ impl<Message: Version + Send + Sync + Clone,
     }
 
     fn connected(&self, pid: PeerId, address: Option<SocketAddr>) {
+        if let Some(ref observer) = *self.observer.read().recover() {
+            observer.connected(pid, address);
+        }
         self.dispatcher.send(PeerMessage::Connected(pid, address));
     }
 
-    fn ban (&self, pid: PeerId, increment: u32) {
-        let mut disconnect = false;
-        if let Some(peer) = self.peers.read().unwrap().get(&pid) {
-            let mut locked_peer = peer.lock().unwrap();
+    fn ban (&self, pid: PeerId, increment: u32, reason: DisconnectReason) {
+        let policy = self.ban_policy.lock().recover().clone();
+        let mut disconnect = None;
+        if let Some(peer) = self.peers.read().recover().get(&pid) {
+            let mut locked_peer = peer.lock().recover();
             locked_peer.ban += increment;
             trace!("ban score {} for peer={}", locked_peer.ban, pid);
-            if locked_peer.ban >= BAN {
-                disconnect = true;
+            let ip = locked_peer.address.ip();
+            let mut banned = self.banned.lock().recover();
+            let reputation = banned.entry(ip).or_insert(Reputation { ip, score: 0, banned_until: 0 });
+            reputation.score += increment;
+            if locked_peer.ban >= policy.threshold {
+                reputation.banned_until = self.now() + policy.duration.as_secs();
+                disconnect = Some(locked_peer.address);
             }
         }
-        if disconnect {
+        if disconnect.is_some() {
             debug!("ban peer={}", pid);
-            self.disconnect(pid, true);
+            self.disconnect(pid, reason);
         }
     }
 
+    /// change when peers are banned, applies to future bans
+    pub fn set_ban_policy (&self, policy: BanPolicy) {
+        info!("ban at score {} for {} seconds", policy.threshold, policy.duration.as_secs());
+        *self.ban_policy.lock().recover() = policy;
+    }
+
+    /// open future outgoing connections through the dialer
+    pub fn set_dialer (&self, dialer: Arc<dyn Dialer>) {
+        *self.dialer.lock().recover() = dialer;
+    }
+
+    /// true if the dialer connects Tor addresses
+    pub fn reaches_onion (&self) -> bool {
+        self.dialer.lock().recover().reaches_onion()
+    }
+
+    /// report traffic with peers to the observer from now on, None to stop
+    pub fn set_observer (&self, observer: Option<Arc<dyn Observer<Message>>>) {
+        *self.observer.write().recover() = observer;
+    }
+
+    /// reputation of all addresses that misbehaved
+    pub fn reputations (&self) -> Vec<Reputation> {
+        self.banned.lock().recover().values().cloned().collect()
+    }
+
+    /// merge reputations, e.g. from an other node, keeping the worse record of each address.
+    /// Peers connected from addresses now banned are disconnected.
+    pub fn import_reputations (&self, reputations: Vec<Reputation>) {
+        let now = self.now();
+        let mut newly_banned = Vec::new();
+        {
+            let mut banned = self.banned.lock().recover();
+            for imported in reputations {
+                let reputation = banned.entry(imported.ip).or_insert(Reputation { ip: imported.ip, score: 0, banned_until: 0 });
+                if !reputation.is_banned(now) && imported.is_banned(now) {
+                    newly_banned.push(imported.ip);
+                }
+                reputation.score = max(reputation.score, imported.score);
+                reputation.banned_until = max(reputation.banned_until, imported.banned_until);
+            }
+        }
+        let connected = self.peers.read().recover().iter()
+            .filter_map(|(pid, peer)| if newly_banned.contains(&peer.lock().recover().address.ip()) { Some(*pid) } else { None })
+            .collect::<Vec<_>>();
+        for pid in connected {
+            self.disconnect(pid, DisconnectReason::Banned);
+        }
+    }
+
+    /// recent disconnects of peers, oldest first
+    pub fn disconnects (&self) -> Vec<Disconnect> {
+        self.disconnects.recent.lock().recover().iter().cloned().collect()
+    }
+
+    /// remember disconnects of an earlier run, e.g. as stored in the config db
+    pub fn import_disconnects (&self, imported: Vec<Disconnect>) {
+        let mut disconnects = self.disconnects.recent.lock().recover();
+        let mut merged = imported;
+        merged.extend(disconnects.drain(..));
+        merged.sort_by_key(|d| d.time);
+        let skip = merged.len().saturating_sub(MAX_DISCONNECTS);
+        disconnects.extend(merged.into_iter().skip(skip));
+    }
+
+    /// notify of future disconnects
+    pub fn subscribe_disconnects (&self) -> futures_mpsc::UnboundedReceiver<Disconnect> {
+        self.disconnects.subscribers.subscribe()
+    }
+
+    /// ban an address for the given duration and disconnect peers connected from it
+    pub fn ban_address (&self, ip: IpAddr, duration: Duration) {
+        info!("ban {} for {} seconds", ip, duration.as_secs());
+        self.banned.lock().recover().entry(ip).or_insert(Reputation { ip, score: 0, banned_until: 0 }).banned_until = self.now() + duration.as_secs();
+        let connected = self.peers.read().recover().iter()
+            .filter_map(|(pid, peer)| if peer.lock().recover().address.ip() == ip { Some(*pid) } else { None })
+            .collect::<Vec<_>>();
+        for pid in connected {
+            self.disconnect(pid, DisconnectReason::Banned);
+        }
+    }
+
+    // why a broken connection ended, depending on whether the handshake completed
+    fn closed_reason (&self, pid: PeerId) -> DisconnectReason {
+        match self.peers.read().recover().get(&pid) {
+            Some(peer) if !peer.lock().recover().connected => DisconnectReason::Handshake,
+            _ => DisconnectReason::RemoteClose
+        }
+    }
+
+    // is a peer connected or connecting at the address
+    fn has_address (peers: &PeerMap<Message>, ip: &IpAddr) -> bool {
+        peers.values().any(|peer| peer.lock().recover().address.ip() == *ip)
+    }
+
+    // is the address currently banned
+    fn is_banned (banned: &BanList, ip: &IpAddr, now: u64) -> bool {
+        if let Some(reputation) = banned.lock().recover().get(ip) {
+            return reputation.is_banned(now);
+        }
+        false
+    }
+
     fn event_processor (&self, event: Event, pid: PeerId, needed_services: u64, iobuf: &mut [u8]) -> Result<(), Error> {
+        let span = span!(Level::DEBUG, "peer", peer = display(pid));
+        let _enter = span.enter();
         let readiness = UnixReady::from(event.readiness());
         // check for error first
         if readiness.is_hup() || readiness.is_error() {
             info!("left us peer={}", pid);
-            self.disconnect(pid, false);
+            let reason = self.closed_reason(pid);
+            self.disconnect(pid, reason);
         } else {
             // check for ability to write before read, to get rid of data before buffering more read
             // token should only be registered for write if there is a need to write
@@ -684,9 +1385,9 @@ impl<Message: Version + Send + Sync + Clone,
                 trace!("writeable peer={}", pid);
 
                 // figure peer's entry in the peer map, provided it is still connected, ignore event if not
-                if let Some(peer) = self.peers.read().unwrap().get(&pid) {
+                if let Some(peer) = self.peers.read().recover().get(&pid) {
                     // get and lock the peer from the peer map entry
-                    let mut locked_peer = peer.lock().unwrap();
+                    let mut locked_peer = peer.lock().recover();
                     loop {
                         let mut get_next = true;
                         // if there is previously unfinished write
@@ -695,7 +1396,7 @@ impl<Message: Version + Send + Sync + Clone,
                                 trace!("try write {} bytes to peer={}", len, pid);
                                 // try writing it out now
                                 let mut wrote = 0;
-                                while let Ok(wlen) = locked_peer.stream.write(&iobuf[wrote..len]) {
+                                while let Ok(wlen) = locked_peer.write_stream(&iobuf[wrote..len]) {
                                     if wlen == 0 {
                                         trace!("would block on peer={}", pid);
                                         // do not fetch next message until there is an unfinished write
@@ -703,6 +1404,9 @@ impl<Message: Version + Send + Sync + Clone,
                                         break;
                                     }
                                     trace!("wrote {} bytes to peer={}", wlen, pid);
+                                    self.bandwidth.account(wlen);
+                                    locked_peer.bytes_sent += wlen as u64;
+                                    locked_peer.last_send = self.now();
                                     // advance buffer and drop used store
                                     locked_peer.write_buffer.advance(wlen);
                                     locked_peer.write_buffer.commit();
@@ -716,6 +1420,9 @@ impl<Message: Version + Send + Sync + Clone,
                         if get_next {
                             // get an outgoing message from the channel (if any)
                             if let Some(msg) = locked_peer.try_receive() {
+                                if let Some(ref observer) = *self.observer.read().recover() {
+                                    observer.outgoing(pid, &msg);
+                                }
                                 // serialize the message
                                 let raw = self.config.wrap(msg);
                                 trace!("next message {} to peer={}", raw.command(), pid);
@@ -723,9 +1430,11 @@ impl<Message: Version + Send + Sync + Clone,
                                 self.config.encode(&raw, &mut locked_peer.write_buffer)?;
                             } else {
                                 // no unfinished write and no outgoing message
-                                // keep registered only for read events
-                                trace!("done writing to peer={}", pid);
-                                locked_peer.reregister_read()?;
+                                // keep registered only for read events, once the tunnel sent all
+                                if locked_peer.flush_stream()? {
+                                    trace!("done writing to peer={}", pid);
+                                    locked_peer.reregister_read()?;
+                                }
                                 break;
                             }
                         }
@@ -743,17 +1452,28 @@ impl<Message: Version + Send + Sync + Clone,
                 let mut disconnect = false;
                 // how to disconnect
                 let mut ban = false;
+                // the handshake was complete before this read
+                let mut was_connected = false;
                 // new handshake if set
                 let mut handshake = false;
                 // peer address
                 let mut address = None;
                 // read lock peer map and retrieve peer
-                if let Some(peer) = self.peers.read().unwrap().get(&pid) {
+                if let Some(peer) = self.peers.read().recover().get(&pid) {
                     // lock the peer from the peer
-                    let mut locked_peer = peer.lock().unwrap();
+                    let mut locked_peer = peer.lock().recover();
+                    was_connected = locked_peer.connected;
                     // read the peer's socket
-                    if let Ok(len) = locked_peer.stream.read(iobuf) {
+                    let read = locked_peer.read_stream(iobuf);
+                    if read.as_ref().err().map(|e| e.kind() == io::ErrorKind::WouldBlock).unwrap_or(false) {
+                        // the tunnel received data but has none for us yet
+                        trace!("nothing to read yet from peer={}", pid);
+                    }
+                    else if let Ok(len) = read {
                         trace!("received {} bytes from peer={}", len, pid);
+                        self.bandwidth.account(len);
+                        locked_peer.bytes_received += len as u64;
+                        locked_peer.last_recv = self.now();
                         if len == 0 {
                             debug!("read zero length message, disconnecting peer={}", pid);
                             disconnect = true;
@@ -761,7 +1481,17 @@ impl<Message: Version + Send + Sync + Clone,
                         // accumulate in a buffer
                         locked_peer.read_buffer.write_all(&iobuf[0..len])?;
                         // extract messages from the buffer
-                        while let Some(msg) = self.config.decode(&mut locked_peer.read_buffer)? {
+                        loop {
+                            let msg = match self.config.decode(&mut locked_peer.read_buffer) {
+                                Ok(Some(msg)) => msg,
+                                Ok(None) => break,
+                                Err(e) => {
+                                    debug!("Ban for malformed message {} peer={}", e, pid);
+                                    disconnect = true;
+                                    ban = true;
+                                    break;
+                                }
+                            };
                             trace!("received {} peer={}", msg.command(), pid);
                             if locked_peer.connected {
                                 // regular processing after handshake
@@ -770,6 +1500,8 @@ impl<Message: Version + Send + Sync + Clone,
                             else {
                                 // have to get both version and verack to complete handhsake
                                 if !(locked_peer.version.is_some() && locked_peer.got_verack) {
+                                    let span = span!(Level::DEBUG, "handshake");
+                                    let _enter = span.enter();
                                     // before handshake complete
                                     if let Ok(msg) = self.config.unwrap(msg) {
                                         if let Some(version) = msg.is_version() {
@@ -786,7 +1518,13 @@ impl<Message: Version + Send + Sync + Clone,
                                                 ban = true;
                                                 debug!("rejecting to connect to myself peer={}", pid);
                                                 break;
+                                            } else if self.remote_nonces.lock().recover().get(&version.nonce).map(|other| *other != pid).unwrap_or(false) {
+                                                // the same node through an other address
+                                                disconnect = true;
+                                                debug!("rejecting second connection to a peer already connected peer={}", pid);
+                                                break;
                                             } else {
+                                                self.remote_nonces.lock().recover().insert(version.nonce, pid);
                                                 if version.version < self.config.min_protocol_version() || (needed_services & version.services) != needed_services {
                                                     debug!("rejecting peer of version {} and services {:b} peer={}", version.version, version.services, pid);
                                                     disconnect = true;
@@ -800,6 +1538,10 @@ impl<Message: Version + Send + Sync + Clone,
                                                         let version = self.config.version(&addr, version.version);
                                                         locked_peer.send(version)?;
                                                     } else {
+                                                        // inbound peers can not tell an address others could connect
+                                                        if let Ok(address) = version.receiver.socket_addr() {
+                                                            self.config.reported_address(&address);
+                                                        }
                                                         // outgoing connects should not be behind this
                                                         if version.start_height < self.config.get_height() {
                                                             debug!("rejecting to connect with height {} peer={}", version.start_height, pid);
@@ -815,6 +1557,7 @@ impl<Message: Version + Send + Sync + Clone,
                                                     let mut vm = version.clone();
                                                     // reduce protocol version to our capabilities
                                                     vm.version = min(vm.version, self.config.max_protocol_version());
+                                                    locked_peer.time_offset = vm.timestamp as i64 - self.now() as i64;
                                                     locked_peer.version = Some(vm);
                                                 }
                                             }
@@ -862,13 +1605,20 @@ impl<Message: Version + Send + Sync + Clone,
                 }
                 if disconnect {
                     info!("disconnecting peer={}", pid);
-                    self.disconnect(pid, ban);
+                    let reason = if ban {
+                        DisconnectReason::Banned
+                    } else if was_connected {
+                        DisconnectReason::RemoteClose
+                    } else {
+                        DisconnectReason::Handshake
+                    };
+                    self.disconnect(pid, reason);
                 }
                 else {
                     if handshake {
                         info!("handshake peer={}", pid);
                         self.connected (pid, address);
-                        if let Some(w) = self.waker.lock().unwrap().remove(&pid) {
+                        if let Some(w) = self.waker.lock().recover().remove(&pid) {
                             trace!("waking for handshake");
                             w.wake();
                         }
@@ -878,11 +1628,14 @@ impl<Message: Version + Send + Sync + Clone,
                     for msg in incoming {
                         trace!("processing {} for peer={}", msg.command(), pid);
                         if let Ok(m) = self.config.unwrap(msg) {
+                            if let Some(ref observer) = *self.observer.read().recover() {
+                                observer.incoming(pid, &m);
+                            }
                             self.dispatcher.send(PeerMessage::Incoming(pid, m));
                         }
                         else {
                             debug!("Ban for malformed message peer={}", pid);
-                            self.disconnect(pid, true);
+                            self.disconnect(pid, DisconnectReason::Banned);
                         }
                     }
                 }
@@ -908,16 +1661,30 @@ impl<Message: Version + Send + Sync + Clone,
             for event in events.iter() {
                 // check for listener
                 if let Some(server) = self.is_listener(event.token()) {
-                    trace!("incoming connection request");
-                    spawn.spawn(self.add_peer(network, PeerSource::Incoming(server)).map(|_| ())).expect("can not add peer for incoming connection");
+                    if self.bandwidth.is_restricted() {
+                        // do not serve others while bandwidth is restricted
+                        if let Ok((stream, addr)) = server.accept() {
+                            debug!("rejecting incoming connect from {} while bandwidth is restricted", addr);
+                            stream.shutdown(Shutdown::Both).unwrap_or(());
+                        }
+                    } else {
+                        trace!("incoming connection request");
+                        spawn.spawn(self.add_peer(network, PeerSource::Incoming(server)).map(|_| ())).expect("can not add peer for incoming connection");
+                    }
                 } else {
                     // construct the id of the peer the event concerns
                     let pid = PeerId { network, token: event.token() };
                     if let Err(error) = self.event_processor(event, pid, needed_services, iobuf.as_mut_slice()) {
-                        use std::error::Error;
-
-                        debug!("error {:?} peer={}", error.source(), pid);
-                        self.ban(pid, 10);
+                        let error = error.with_peer(pid);
+                        debug!("{:?} error {}", error.category(), error);
+                        match error.category() {
+                            Category::Protocol | Category::Consensus => self.ban(pid, 10, DisconnectReason::Banned),
+                            Category::IO => {
+                                let reason = self.closed_reason(pid);
+                                self.disconnect(pid, reason)
+                            },
+                            Category::DB | Category::Application => {}
+                        }
                     }
                 }
             }
@@ -925,11 +1692,15 @@ impl<Message: Version + Send + Sync + Clone,
     }
 
     fn is_listener(&self, token: Token) -> Option<Arc<TcpListener>> {
-        if let Some(server) = self.listener.lock().unwrap().get(&token) {
+        if let Some(server) = self.listener.lock().recover().get(&token) {
             return Some(server.clone())
         }
         None
     }
+
+    fn now(&self) -> u64 {
+        self.clock.unix_time()
+    }
 }
 
 /// a peer
@@ -940,6 +1711,8 @@ struct Peer<Message> {
     poll: Arc<Poll>,
     // the connection to remote peer
     stream: TcpStream,
+    // wraps the connection, e.g. in TLS
+    tunnel: Option<Box<dyn Tunnel>>,
     // temporary buffer for not yet completely read incoming messages
     read_buffer: Buffer,
     // temporary buffer for not yet completely written outgoing messages
@@ -959,19 +1732,66 @@ struct Peer<Message> {
     // ban score
     ban: u32,
     // outgoing or incoming connection
-    outgoing: bool
+    outgoing: bool,
+    // remote address
+    address: SocketAddr,
+    // bytes written to the peer
+    bytes_sent: u64,
+    // bytes read from the peer
+    bytes_received: u64,
+    // unix time of last write
+    last_send: u64,
+    // unix time of last read
+    last_recv: u64,
+    // round trip time of the last ping
+    ping: Option<Duration>,
+    // seconds the peer's clock is ahead of ours, as of its version message
+    time_offset: i64
 }
 
 impl<Message> Peer<Message> {
     /// create a new peer
-    pub fn new (pid: PeerId, stream: TcpStream, poll: Arc<Poll>, outgoing: bool) -> Result<Peer<Message>, Error> {
+    pub fn new (pid: PeerId, address: SocketAddr, stream: TcpStream, tunnel: Option<Box<dyn Tunnel>>, poll: Arc<Poll>, outgoing: bool) -> Result<Peer<Message>, Error> {
         let (sender, receiver) = mpsc::channel();
-        let peer = Peer{pid, poll: poll.clone(), stream, read_buffer: Buffer::new(), write_buffer: Buffer::new(),
+        let peer = Peer{pid, poll: poll.clone(), stream, tunnel, read_buffer: Buffer::new(), write_buffer: Buffer::new(),
             got_verack: false, version: None, sender, receiver, writeable: AtomicBool::new(false),
-            connected: false, ban: 0, outgoing };
+            connected: false, ban: 0, outgoing, address, bytes_sent: 0, bytes_received: 0,
+            last_send: 0, last_recv: 0, ping: None, time_offset: 0 };
         Ok(peer)
     }
 
+    // read from the connection, through the tunnel if any
+    fn read_stream (&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        if let Some(ref mut tunnel) = self.tunnel {
+            let result = tunnel.read(&mut self.stream, buf);
+            // the tunnel might need to answer, e.g. during its handshake
+            if tunnel.wants_write() {
+                self.reregister_write().map_err(|e| io::Error::new(io::ErrorKind::Other, e.to_string()))?;
+            }
+            result
+        } else {
+            self.stream.read(buf)
+        }
+    }
+
+    // write to the connection, through the tunnel if any
+    fn write_stream (&mut self, buf: &[u8]) -> io::Result<usize> {
+        if let Some(ref mut tunnel) = self.tunnel {
+            tunnel.write(&mut self.stream, buf)
+        } else {
+            self.stream.write(buf)
+        }
+    }
+
+    // send what the tunnel kept, true if nothing is left
+    fn flush_stream (&mut self) -> io::Result<bool> {
+        if let Some(ref mut tunnel) = self.tunnel {
+            tunnel.flush(&mut self.stream)
+        } else {
+            Ok(true)
+        }
+    }
+
     // re-register for peer readable events
     fn reregister_read(&self) -> Result<(), Error> {
         if self.writeable.swap(false, Ordering::Acquire) {
@@ -1041,8 +1861,8 @@ pub struct Buffer {
 }
 
 impl Buffer {
-    // create new buffer
-    fn new () -> Buffer {
+    /// create new buffer
+    pub fn new () -> Buffer {
         Buffer{ chunks: VecDeque::new(), pos: (0, 0), checkpoint: (0, 0) }
     }
 
@@ -0,0 +1,319 @@
+//
+// Copyright 2019 Tamas Blummer
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+// http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+//
+//!
+//! # Await payments
+//!
+//! A merchant registers the BIP21 URI it handed to a payer, e.g.
+//! bitcoin:bc1q...?amount=0.001&label=order42, and is told by Event::PaymentReceived as a
+//! transaction paying at least the amount to the address is announced by a peer, then again
+//! at every confirmation until the requested depth is reached. Until the payment confirms any
+//! transaction paying the request counts, so a fee bump replacing the announced one is
+//! reported as it confirms. Awaited requests are stored in the config db.
+//!
+//! Announced transactions are fetched by TxFetch while payments are awaited. A payment seen
+//! first in a block is reported with its confirmations only.
+//!
+
+use bitcoin::{
+    BitcoinHash,
+    blockdata::{
+        block::{Block, BlockHeader},
+        script::Script,
+        transaction::Transaction
+    },
+    consensus::{Decodable, Encodable, encode},
+    network::{
+        constants::Network,
+        message::NetworkMessage
+    },
+    util::address::Address
+};
+use bitcoin_hashes::sha256d::Hash as Sha256dHash;
+use configdb::SharedConfigDB;
+use downstream::{Downstream, Subscribers};
+use error::Error;
+use event::Event;
+use lock::Recover;
+use p2p::{P2PControlSender, PeerMessage, PeerMessageReceiver, PeerMessageSender};
+use std::{
+    io,
+    str::FromStr,
+    sync::{Arc, Mutex, mpsc, atomic::Ordering},
+    thread
+};
+use tracing::{Level, field::display};
+use txfetch::SharedInterest;
+
+// satoshis in a bitcoin
+const COIN: u64 = 100_000_000;
+
+pub type SharedPaymentWatch = Arc<Mutex<PaymentWatch>>;
+
+/// A payment requested with a BIP21 URI
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct PaymentRequest {
+    /// the URI as given
+    pub uri: String,
+    /// script of the address to pay to
+    pub script: Script,
+    /// least amount in satoshis, any amount if None
+    pub amount: Option<u64>,
+    /// confirmations after which the payment is no longer reported
+    pub depth: u32
+}
+
+impl PaymentRequest {
+    /// Parse a BIP21 URI for an address of the network. Parameters other than amount are
+    /// ignored unless required by a req- prefix.
+    pub fn parse(uri: &str, network: Network, depth: u32) -> Result<PaymentRequest, Error> {
+        let bad = |what: &str| Error::BadUri(format!("{}: {}", what, uri));
+        let scheme = "bitcoin:";
+        if uri.len() < scheme.len() || !uri[..scheme.len()].eq_ignore_ascii_case(scheme) {
+            return Err(bad("not a bitcoin URI"));
+        }
+        let mut parts = uri[scheme.len()..].splitn(2, '?');
+        let address = Address::from_str(parts.next().unwrap_or("")).map_err(|e| bad(e.to_string().as_str()))?;
+        let testing = |n| n == Network::Testnet || n == Network::Regtest;
+        if address.network != network && !(testing(address.network) && testing(network)) {
+            return Err(bad("address of an other network"));
+        }
+        let mut amount = None;
+        for param in parts.next().unwrap_or("").split('&').filter(|p| !p.is_empty()) {
+            let mut kv = param.splitn(2, '=');
+            match (kv.next().unwrap_or(""), kv.next()) {
+                ("amount", Some(value)) => amount = Some(parse_amount(value).ok_or_else(|| bad("invalid amount"))?),
+                (key, _) if key.starts_with("req-") => return Err(bad("unsupported required parameter")),
+                _ => {}
+            }
+        }
+        Ok(PaymentRequest { uri: uri.to_string(), script: address.script_pubkey(), amount, depth })
+    }
+
+    // amount the transaction pays if it satisfies the request
+    fn paid_by(&self, tx: &Transaction) -> Option<u64> {
+        let paid = tx.output.iter().filter(|o| o.script_pubkey == self.script).map(|o| o.value).sum::<u64>();
+        if paid > 0 && paid >= self.amount.unwrap_or(0) {
+            Some(paid)
+        } else {
+            None
+        }
+    }
+}
+
+impl Encodable for PaymentRequest {
+    fn consensus_encode<W: io::Write>(&self, mut w: W) -> Result<usize, encode::Error> {
+        let mut len = self.uri.consensus_encode(&mut w)?;
+        len += self.script.consensus_encode(&mut w)?;
+        len += (self.amount.is_some() as u8).consensus_encode(&mut w)?;
+        len += self.amount.unwrap_or(0).consensus_encode(&mut w)?;
+        len += self.depth.consensus_encode(&mut w)?;
+        Ok(len)
+    }
+}
+
+impl Decodable for PaymentRequest {
+    fn consensus_decode<D: io::Read>(mut d: D) -> Result<PaymentRequest, encode::Error> {
+        let uri = Decodable::consensus_decode(&mut d)?;
+        let script = Decodable::consensus_decode(&mut d)?;
+        let has_amount: u8 = Decodable::consensus_decode(&mut d)?;
+        let amount: u64 = Decodable::consensus_decode(&mut d)?;
+        Ok(PaymentRequest { uri, script, amount: if has_amount != 0 { Some(amount) } else { None }, depth: Decodable::consensus_decode(&mut d)? })
+    }
+}
+
+// decimal bitcoins to satoshis, without rounding
+fn parse_amount(value: &str) -> Option<u64> {
+    let mut parts = value.splitn(2, '.');
+    let coins = parts.next()?;
+    let fraction = parts.next().unwrap_or("");
+    if (coins.is_empty() && fraction.is_empty()) || fraction.len() > 8
+        || !coins.chars().chain(fraction.chars()).all(|c| c.is_ascii_digit()) {
+        return None;
+    }
+    let coins = if coins.is_empty() { 0 } else { coins.parse::<u64>().ok()? };
+    let fraction = format!("{:0<8}", fraction).parse::<u64>().ok()?;
+    coins.checked_mul(COIN)?.checked_add(fraction)
+}
+
+// an awaited payment and what was reported of it
+struct Awaited {
+    request: PaymentRequest,
+    // paying transaction and amount once seen
+    payment: Option<(Sha256dHash, u64)>,
+    // (height, id) of the block confirming the payment
+    block: Option<(u32, Sha256dHash)>,
+    // confirmations reported, None if nothing was reported yet
+    reported: Option<u32>
+}
+
+/// Payments awaited
+pub struct PaymentWatch {
+    configdb: SharedConfigDB,
+    events: Subscribers<Event>,
+    // raised while a payment is not yet seen
+    interest: SharedInterest,
+    awaited: Vec<Awaited>,
+    // height of the trunk
+    tip: u32
+}
+
+impl PaymentWatch {
+    /// Await the payments stored
+    pub fn new(configdb: SharedConfigDB, events: Subscribers<Event>, interest: SharedInterest) -> Result<PaymentWatch, Error> {
+        let awaited = configdb.read().recover().fetch_payment_requests()?.into_iter()
+            .map(|request| Awaited { request, payment: None, block: None, reported: None }).collect();
+        let watch = PaymentWatch { configdb, events, interest, awaited, tip: 0 };
+        watch.raise_interest();
+        Ok(watch)
+    }
+
+    /// await a payment, a request already awaited is not added again
+    pub fn watch(&mut self, request: PaymentRequest) -> Result<(), Error> {
+        if !self.awaited.iter().any(|a| a.request == request) {
+            self.awaited.push(Awaited { request, payment: None, block: None, reported: None });
+            self.store()?;
+        }
+        Ok(())
+    }
+
+    /// stop awaiting payments of the URI
+    pub fn unwatch(&mut self, uri: &str) -> Result<(), Error> {
+        self.awaited.retain(|a| a.request.uri != uri);
+        self.store()
+    }
+
+    fn store(&self) -> Result<(), Error> {
+        self.raise_interest();
+        let mut configdb = self.configdb.write().recover();
+        configdb.store_payment_requests(self.awaited())?;
+        configdb.batch()
+    }
+
+    fn raise_interest(&self) {
+        self.interest.store(self.awaited.iter().any(|a| a.payment.is_none()), Ordering::Relaxed);
+    }
+
+    /// payments awaited
+    pub fn awaited(&self) -> Vec<PaymentRequest> {
+        self.awaited.iter().map(|a| a.request.clone()).collect()
+    }
+
+    /// check a transaction announced by a peer
+    pub fn transaction(&mut self, tx: &Transaction) {
+        let txid = tx.txid();
+        for awaited in self.awaited.iter_mut().filter(|a| a.payment.is_none()) {
+            if let Some(amount) = awaited.request.paid_by(tx) {
+                info!("payment of {} satoshis for {} announced in transaction {}", amount, awaited.request.uri, txid);
+                awaited.payment = Some((txid, amount));
+            }
+        }
+        self.report();
+    }
+
+    // report confirmations not yet reported, forget payments deep enough
+    fn report(&mut self) {
+        let tip = self.tip;
+        for awaited in self.awaited.iter_mut() {
+            if let Some((txid, amount)) = awaited.payment {
+                let confirmations = awaited.block.map(|(height, _)| (tip + 1).saturating_sub(height)).unwrap_or(0).min(awaited.request.depth);
+                let from = awaited.reported.map(|r| r + 1).unwrap_or(confirmations);
+                for c in from..=confirmations {
+                    self.events.publish(Event::PaymentReceived { uri: awaited.request.uri.clone(), txid, amount, confirmations: c });
+                }
+                if from <= confirmations {
+                    awaited.reported = Some(confirmations);
+                }
+            }
+        }
+        let before = self.awaited.len();
+        self.awaited.retain(|a| a.reported.map_or(true, |r| r < a.request.depth));
+        if self.awaited.len() != before {
+            if let Err(e) = self.store() {
+                error!("Error storing awaited payments: {}", e);
+            }
+        } else {
+            self.raise_interest();
+        }
+    }
+}
+
+impl Downstream for PaymentWatch {
+    fn block_connected(&mut self, block: &Block, height: u32) {
+        let block_id = block.bitcoin_hash();
+        for tx in &block.txdata {
+            let txid = tx.txid();
+            // any payment while unconfirmed, the one announced might have been replaced
+            for awaited in self.awaited.iter_mut().filter(|a| a.block.is_none()) {
+                if let Some(amount) = awaited.request.paid_by(tx) {
+                    if awaited.payment.map_or(false, |(announced, _)| announced != txid) {
+                        info!("payment for {} confirmed by transaction {} instead of the announced one", awaited.request.uri, txid);
+                        awaited.reported = None;
+                    }
+                    awaited.payment = Some((txid, amount));
+                    awaited.block = Some((height, block_id));
+                }
+            }
+        }
+        self.tip = self.tip.max(height);
+        self.report();
+    }
+
+    fn header_connected(&mut self, _header: &BlockHeader, height: u32) {
+        self.tip = height;
+        self.report();
+    }
+
+    // the payment is unconfirmed again, confirmations are reported anew as it confirms again
+    fn block_disconnected(&mut self, header: &BlockHeader) {
+        let block_id = header.bitcoin_hash();
+        for awaited in self.awaited.iter_mut().filter(|a| a.block.map(|(_, id)| id) == Some(block_id)) {
+            awaited.block = None;
+            awaited.reported = None;
+        }
+        self.tip = self.tip.saturating_sub(1);
+    }
+}
+
+/// Checks transactions relayed by peers for awaited payments
+pub struct PaymentTxWatch {
+    watch: SharedPaymentWatch
+}
+
+impl PaymentTxWatch {
+    pub fn new(watch: SharedPaymentWatch, p2p: P2PControlSender<NetworkMessage>) -> PeerMessageSender<NetworkMessage> {
+        let (sender, receiver) = mpsc::sync_channel(p2p.back_pressure);
+
+        let mut txwatch = PaymentTxWatch { watch };
+
+        thread::Builder::new().name("payment watch".to_string()).spawn(move || { txwatch.run(receiver) }).unwrap();
+
+        PeerMessageSender::new(sender)
+    }
+
+    fn run(&mut self, receiver: PeerMessageReceiver<NetworkMessage>) {
+        let span = span!(Level::INFO, "payment watch");
+        let _enter = span.enter();
+        while let Ok(msg) = receiver.recv() {
+            if let PeerMessage::Incoming(pid, msg) = msg {
+                let span = span!(Level::DEBUG, "peer", peer = display(pid));
+                let _enter = span.enter();
+                if let NetworkMessage::Tx(ref tx) = msg {
+                    self.watch.lock().recover().transaction(tx);
+                }
+            }
+        }
+    }
+}
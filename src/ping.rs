@@ -18,6 +18,8 @@
 //!
 
 use bitcoin::network::message::NetworkMessage;
+use clock::SharedClock;
+use lock::Recover;
 use p2p::{
     P2PControlSender, PeerId, PeerMessage, PeerMessageReceiver, PeerMessageSender
 };
@@ -26,7 +28,7 @@ use std::{
     collections::HashMap,
     sync::mpsc,
     thread,
-    time::Duration
+    time::{Duration, Instant}
 };
 use timeout::{ExpectedReply, SharedTimeout};
 
@@ -36,14 +38,15 @@ const SECS: u64 = 60;
 pub struct Ping {
     p2p: P2PControlSender<NetworkMessage>,
     timeout: SharedTimeout<NetworkMessage, ExpectedReply>,
-    asked: HashMap<PeerId, u64>
+    clock: SharedClock,
+    asked: HashMap<PeerId, (u64, Instant)>
 }
 
 
 impl Ping {
-    pub fn new(p2p: P2PControlSender<NetworkMessage>, timeout: SharedTimeout<NetworkMessage, ExpectedReply>) -> PeerMessageSender<NetworkMessage>  {
+    pub fn new(p2p: P2PControlSender<NetworkMessage>, timeout: SharedTimeout<NetworkMessage, ExpectedReply>, clock: SharedClock) -> PeerMessageSender<NetworkMessage>  {
         let (sender, receiver) = mpsc::sync_channel(p2p.back_pressure);
-        let mut ping = Ping { p2p, timeout, asked: HashMap::new() };
+        let mut ping = Ping { p2p, timeout, clock, asked: HashMap::new() };
 
         thread::Builder::new().name("ping".to_string()).spawn(move || { ping.run(receiver) }).unwrap();
 
@@ -55,14 +58,17 @@ impl Ping {
             while let Ok(msg) = receiver.recv_timeout(Duration::from_millis(SECS*1000)) {
                 match msg {
                     PeerMessage::Disconnected(pid,_) => {
-                        self.timeout.lock().unwrap().forget(pid);
+                        self.timeout.lock().recover().forget(pid);
                         self.asked.remove(&pid);
                     },
                     PeerMessage::Incoming(pid, msg) => {
                         match msg {
                             NetworkMessage::Pong(n) => {
-                                if self.asked.remove(&pid) == Some(n) {
-                                    self.timeout.lock().unwrap().received(pid, 1, ExpectedReply::Pong);
+                                if let Some((ask, sent)) = self.asked.remove(&pid) {
+                                    if ask == n {
+                                        self.p2p.set_ping_time(pid, self.clock.now().duration_since(sent));
+                                        self.timeout.lock().recover().received(pid, 1, ExpectedReply::Pong);
+                                    }
                                 }
                             }
                             _ => { }
@@ -71,12 +77,12 @@ impl Ping {
                     _ => {}
                 }
             }
-            self.timeout.lock().unwrap().check(vec!(ExpectedReply::Pong));
+            self.timeout.lock().recover().check(vec!(ExpectedReply::Pong));
             for peer in self.p2p.peers() {
-                if !self.timeout.lock().unwrap().is_busy(peer) {
+                if !self.timeout.lock().recover().is_busy(peer) {
                     let ask = thread_rng().next_u64();
-                    self.asked.insert(peer, ask);
-                    self.timeout.lock().unwrap().expect(peer, 1, ExpectedReply::Pong);
+                    self.asked.insert(peer, (ask, self.clock.now()));
+                    self.timeout.lock().recover().expect(peer, 1, ExpectedReply::Pong);
                     self.p2p.send_network(peer, NetworkMessage::Ping(ask));
                 }
             }
@@ -0,0 +1,160 @@
+//
+// Copyright 2019 Tamas Blummer
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+// http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+//
+//!
+//! # Block propagation
+//!
+//! The local time a header joined the trunk and its block arrived, compared with the time in
+//! the header, measures how long blocks take to reach this node. Headers older than
+//! MAX_HEADER_AGE at arrival are catching up, not propagating, and are not recorded.
+//!
+//! The miner's clock might run ahead of ours, delays can be negative.
+//!
+
+use bitcoin::{
+    BitcoinHash,
+    blockdata::block::{Block, BlockHeader}
+};
+use bitcoin_hashes::sha256d::Hash as Sha256dHash;
+use clock::SharedClock;
+use downstream::Downstream;
+use std::{
+    collections::VecDeque,
+    sync::{Arc, Mutex}
+};
+
+/// arrivals remembered
+pub const MAX_ARRIVALS: usize = 1000;
+/// seconds a header might be older than its arrival to be recorded, as in the two hours
+/// a header might be ahead of the network time
+pub const MAX_HEADER_AGE: u64 = 2 * 3600;
+
+pub type SharedArrivals = Arc<Mutex<Arrivals>>;
+
+/// Arrival of a block at this node
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Arrival {
+    /// height on the trunk
+    pub height: u32,
+    /// id of the block
+    pub block: Sha256dHash,
+    /// time in the header
+    pub header_time: u32,
+    /// unix time the header joined the trunk
+    pub header_received: u64,
+    /// unix time the block arrived, None if not downloaded
+    pub block_received: Option<u64>
+}
+
+impl Arrival {
+    /// seconds from the time in the header to its arrival
+    pub fn header_delay(&self) -> i64 {
+        self.header_received as i64 - self.header_time as i64
+    }
+
+    /// seconds from the time in the header to the arrival of the block
+    pub fn block_delay(&self) -> Option<i64> {
+        self.block_received.map(|received| received as i64 - self.header_time as i64)
+    }
+}
+
+/// Distribution of delays in seconds
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct DelayStats {
+    /// number of delays
+    pub count: usize,
+    /// shortest delay
+    pub min: i64,
+    /// median delay
+    pub median: i64,
+    /// 90th percentile
+    pub p90: i64,
+    /// longest delay
+    pub max: i64
+}
+
+impl DelayStats {
+    fn new(mut delays: Vec<i64>) -> DelayStats {
+        if delays.is_empty() {
+            return DelayStats::default();
+        }
+        delays.sort();
+        let at = |q: usize| delays[(delays.len() - 1) * q / 100];
+        DelayStats { count: delays.len(), min: delays[0], median: at(50), p90: at(90), max: delays[delays.len() - 1] }
+    }
+}
+
+/// Delays of headers and blocks recently arrived
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct PropagationStats {
+    /// from the time in the header to its arrival
+    pub headers: DelayStats,
+    /// from the time in the header to the arrival of the block
+    pub blocks: DelayStats
+}
+
+/// Records arrivals of headers and blocks joining the trunk
+pub struct Arrivals {
+    clock: SharedClock,
+    arrivals: VecDeque<Arrival>
+}
+
+impl Arrivals {
+    pub fn new(clock: SharedClock) -> Arrivals {
+        Arrivals { clock, arrivals: VecDeque::new() }
+    }
+
+    /// arrivals recorded, oldest first
+    pub fn arrivals(&self) -> Vec<Arrival> {
+        self.arrivals.iter().cloned().collect()
+    }
+
+    /// distribution of delays of the arrivals recorded
+    pub fn stats(&self) -> PropagationStats {
+        PropagationStats {
+            headers: DelayStats::new(self.arrivals.iter().map(|a| a.header_delay()).collect()),
+            blocks: DelayStats::new(self.arrivals.iter().filter_map(|a| a.block_delay()).collect())
+        }
+    }
+}
+
+impl Downstream for Arrivals {
+    fn block_connected(&mut self, block: &Block, _height: u32) {
+        let id = block.bitcoin_hash();
+        let now = self.clock.unix_time();
+        if let Some(arrival) = self.arrivals.iter_mut().rev().find(|a| a.block == id) {
+            if arrival.block_received.is_none() {
+                arrival.block_received = Some(now);
+            }
+        }
+    }
+
+    fn header_connected(&mut self, header: &BlockHeader, height: u32) {
+        let now = self.clock.unix_time();
+        if now.saturating_sub(header.time as u64) > MAX_HEADER_AGE {
+            return;
+        }
+        let block = header.bitcoin_hash();
+        if self.arrivals.iter().any(|a| a.block == block) {
+            return;
+        }
+        self.arrivals.push_back(Arrival { height, block, header_time: header.time, header_received: now, block_received: None });
+        if self.arrivals.len() > MAX_ARRIVALS {
+            self.arrivals.pop_front();
+        }
+    }
+
+    fn block_disconnected(&mut self, _header: &BlockHeader) {}
+}
@@ -0,0 +1,220 @@
+//
+// Copyright 2019 Tamas Blummer
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+// http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+//
+//!
+//! # Replay of P2P traffic
+//!
+//! A recorder observes traffic with peers and appends it to a file, flushed at most every few
+//! seconds and as recording stops. Replaying the file feeds recorded connects, disconnects and
+//! incoming messages to the dispatcher of a stack that is not connected to the network, so sync
+//! bugs reported from the field can be reproduced offline. The simulated clock of the stack is
+//! advanced to the time of each record before it is fed, so that timeouts fire as recorded.
+//! Outgoing messages are recorded for reference, they are not replayed.
+//!
+
+use bitcoin::{
+    consensus::{Decodable, Encodable, encode},
+    network::message::{NetworkMessage, RawNetworkMessage}
+};
+use clock::{SharedClock, SimulatedClock};
+use error::Error;
+use lock::Recover;
+use mio::Token;
+use p2p::{DisconnectReason, Observer, PeerId, PeerMessage, PeerMessageSender};
+use std::{
+    fs::{File, OpenOptions},
+    io::{self, BufReader, BufWriter, Write},
+    net::SocketAddr,
+    path::Path,
+    str::FromStr,
+    sync::Mutex,
+    time::{Duration, Instant}
+};
+
+// seconds between flushes of the replay file
+const FLUSH_INTERVAL: u64 = 5;
+// magic of the envelope of recorded messages, the network is not recorded
+const MAGIC: u32 = 0;
+
+/// Traffic with a peer
+#[derive(Clone, Debug)]
+pub enum Traffic {
+    /// handshake completed
+    Connected(Option<SocketAddr>),
+    /// disconnected
+    Disconnected(DisconnectReason),
+    /// message received
+    Incoming(NetworkMessage),
+    /// message sent
+    Outgoing(NetworkMessage)
+}
+
+/// An entry of a replay file
+#[derive(Clone, Debug)]
+pub struct Record {
+    /// milliseconds since recording started
+    pub elapsed: u64,
+    /// token of the peer's connection
+    pub peer: usize,
+    /// what happened
+    pub traffic: Traffic
+}
+
+/// Appends traffic with peers to a replay file
+pub struct Recorder {
+    clock: SharedClock,
+    start: Instant,
+    file: Mutex<ReplayFile>
+}
+
+// the file written with the time of its last flush
+struct ReplayFile {
+    writer: BufWriter<File>,
+    flushed: Instant
+}
+
+impl Recorder {
+    /// append to the file at path, create if not there
+    pub fn new(path: &Path, clock: SharedClock) -> Result<Recorder, Error> {
+        let file = OpenOptions::new().create(true).append(true).open(path)?;
+        info!("recording P2P traffic to {}", path.to_string_lossy());
+        let start = clock.now();
+        Ok(Recorder { clock, start, file: Mutex::new(ReplayFile { writer: BufWriter::new(file), flushed: start }) })
+    }
+
+    fn record(&self, peer: PeerId, traffic: Traffic) {
+        let now = self.clock.now();
+        let elapsed = now - self.start;
+        let record = Record {
+            elapsed: elapsed.as_secs() * 1000 + elapsed.subsec_millis() as u64,
+            peer: peer.token().0,
+            traffic
+        };
+        let mut file = self.file.lock().recover();
+        if let Err(e) = record.consensus_encode(&mut file.writer) {
+            warn!("can not record traffic with peer={}: {}", peer, e);
+        }
+        if now >= file.flushed + Duration::from_secs(FLUSH_INTERVAL) {
+            file.flushed = now;
+            if let Err(e) = file.writer.flush() {
+                warn!("can not flush replay file: {}", e);
+            }
+        }
+    }
+}
+
+impl Drop for Recorder {
+    fn drop(&mut self) {
+        if let Err(e) = self.file.lock().recover().writer.flush() {
+            warn!("can not flush replay file: {}", e);
+        }
+    }
+}
+
+impl Observer<NetworkMessage> for Recorder {
+    fn connected(&self, peer: PeerId, address: Option<SocketAddr>) {
+        self.record(peer, Traffic::Connected(address));
+    }
+
+    fn disconnected(&self, peer: PeerId, reason: DisconnectReason) {
+        self.record(peer, Traffic::Disconnected(reason));
+    }
+
+    fn incoming(&self, peer: PeerId, message: &NetworkMessage) {
+        self.record(peer, Traffic::Incoming(message.clone()));
+    }
+
+    fn outgoing(&self, peer: PeerId, message: &NetworkMessage) {
+        self.record(peer, Traffic::Outgoing(message.clone()));
+    }
+}
+
+impl Encodable for Record {
+    fn consensus_encode<W: io::Write>(&self, mut w: W) -> Result<usize, encode::Error> {
+        let mut len = self.elapsed.consensus_encode(&mut w)?;
+        len += (self.peer as u64).consensus_encode(&mut w)?;
+        let raw = |payload: &NetworkMessage| RawNetworkMessage { magic: MAGIC, payload: payload.clone() };
+        match self.traffic {
+            Traffic::Connected(ref address) => {
+                len += 0u8.consensus_encode(&mut w)?;
+                len += address.map(|a| a.to_string()).unwrap_or_default().consensus_encode(&mut w)?;
+            },
+            Traffic::Disconnected(reason) => {
+                len += 1u8.consensus_encode(&mut w)?;
+                len += reason.consensus_encode(&mut w)?;
+            },
+            Traffic::Incoming(ref message) => {
+                len += 2u8.consensus_encode(&mut w)?;
+                len += raw(message).consensus_encode(&mut w)?;
+            },
+            Traffic::Outgoing(ref message) => {
+                len += 3u8.consensus_encode(&mut w)?;
+                len += raw(message).consensus_encode(&mut w)?;
+            }
+        }
+        Ok(len)
+    }
+}
+
+impl Decodable for Record {
+    fn consensus_decode<D: io::Read>(mut d: D) -> Result<Record, encode::Error> {
+        let elapsed = Decodable::consensus_decode(&mut d)?;
+        let peer = u64::consensus_decode(&mut d)? as usize;
+        let traffic = match u8::consensus_decode(&mut d)? {
+            0 => Traffic::Connected(SocketAddr::from_str(String::consensus_decode(&mut d)?.as_str()).ok()),
+            1 => Traffic::Disconnected(Decodable::consensus_decode(&mut d)?),
+            2 => Traffic::Incoming(RawNetworkMessage::consensus_decode(&mut d)?.payload),
+            3 => Traffic::Outgoing(RawNetworkMessage::consensus_decode(&mut d)?.payload),
+            _ => return Err(encode::Error::ParseFailed("unknown kind of recorded traffic"))
+        };
+        Ok(Record { elapsed, peer, traffic })
+    }
+}
+
+/// Read the records of a replay file. A record truncated as the recording node stopped ends the file.
+pub fn read_records(path: &Path) -> Result<Vec<Record>, Error> {
+    let mut reader = BufReader::new(File::open(path)?);
+    let mut records = Vec::new();
+    loop {
+        match Record::consensus_decode(&mut reader) {
+            Ok(record) => records.push(record),
+            Err(encode::Error::Io(ref e)) if e.kind() == io::ErrorKind::UnexpectedEof => break,
+            Err(e) => return Err(e.into())
+        }
+    }
+    Ok(records)
+}
+
+/// Feed recorded connects, disconnects and incoming messages to the dispatcher, in recorded order,
+/// after advancing the clock by the time between records. Returns the number of records replayed.
+pub fn replay(records: &[Record], dispatcher: &PeerMessageSender<NetworkMessage>, clock: &SimulatedClock) -> usize {
+    let mut replayed = 0;
+    let mut at = 0;
+    for record in records {
+        if record.elapsed > at {
+            clock.advance(Duration::from_millis(record.elapsed - at));
+            at = record.elapsed;
+        }
+        let peer = PeerId::new("bitcoin", Token(record.peer));
+        match record.traffic {
+            Traffic::Connected(address) => dispatcher.send(PeerMessage::Connected(peer, address)),
+            Traffic::Disconnected(reason) => dispatcher.send(PeerMessage::Disconnected(peer, reason)),
+            Traffic::Incoming(ref message) => dispatcher.send(PeerMessage::Incoming(peer, message.clone())),
+            Traffic::Outgoing(_) => continue
+        }
+        replayed += 1;
+    }
+    replayed
+}
@@ -0,0 +1,200 @@
+//
+// Copyright 2019 Tamas Blummer
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+// http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+//
+//!
+//! # Scheduled broadcast
+//!
+//! Signed transactions waiting in the config DB for a block height, a median time past or the
+//! confirmation of a parent transaction, then handed to the broadcaster. Lightning justice and
+//! timeout transactions are typical.
+//!
+//! A parent is seen confirmed only in downloaded blocks, so the application must watch a script
+//! the parent pays to.
+//!
+
+use bitcoin::{
+    BitcoinHash,
+    blockdata::transaction::Transaction,
+    consensus::{Decodable, Encodable, encode},
+    network::message::NetworkMessage
+};
+use bitcoin_hashes::sha256d::Hash as Sha256dHash;
+use chaindb::SharedChainDB;
+use configdb::SharedConfigDB;
+use error::Error;
+use lock::Recover;
+use p2p::{PeerMessage, PeerMessageSender};
+use std::{
+    collections::HashSet,
+    io,
+    thread,
+    time::Duration
+};
+use tracing::Level;
+
+/// When to broadcast a scheduled transaction
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum Trigger {
+    /// the trunk reached this height
+    Height(u32),
+    /// the median time past of the trunk tip reached this unix time
+    MedianTime(u32),
+    /// a block containing the transaction with this id was downloaded
+    Confirmed(Sha256dHash)
+}
+
+/// A transaction waiting for broadcast
+#[derive(Clone, Debug)]
+pub struct Scheduled {
+    /// the signed transaction
+    pub tx: Transaction,
+    /// broadcast once this happens
+    pub trigger: Trigger,
+    /// height of the trunk when scheduled, a confirming block is searched from here
+    pub since: u32
+}
+
+impl Encodable for Scheduled {
+    fn consensus_encode<W: io::Write>(&self, mut w: W) -> Result<usize, encode::Error> {
+        let mut len = self.tx.consensus_encode(&mut w)?;
+        len += match self.trigger {
+            Trigger::Height(height) => 0u8.consensus_encode(&mut w)? + height.consensus_encode(&mut w)?,
+            Trigger::MedianTime(time) => 1u8.consensus_encode(&mut w)? + time.consensus_encode(&mut w)?,
+            Trigger::Confirmed(ref txid) => 2u8.consensus_encode(&mut w)? + txid.consensus_encode(&mut w)?
+        };
+        len += self.since.consensus_encode(&mut w)?;
+        Ok(len)
+    }
+}
+
+impl Decodable for Scheduled {
+    fn consensus_decode<D: io::Read>(mut d: D) -> Result<Scheduled, encode::Error> {
+        let tx = Decodable::consensus_decode(&mut d)?;
+        let trigger = match u8::consensus_decode(&mut d)? {
+            0 => Trigger::Height(Decodable::consensus_decode(&mut d)?),
+            1 => Trigger::MedianTime(Decodable::consensus_decode(&mut d)?),
+            2 => Trigger::Confirmed(Decodable::consensus_decode(&mut d)?),
+            _ => return Err(encode::Error::ParseFailed("unknown trigger of scheduled transaction"))
+        };
+        let since = Decodable::consensus_decode(&mut d)?;
+        Ok(Scheduled { tx, trigger, since })
+    }
+}
+
+pub struct Scheduler {
+    chaindb: SharedChainDB,
+    configdb: SharedConfigDB,
+    broadcaster: PeerMessageSender<NetworkMessage>,
+    // parents whose confirmation is searched for
+    parents: HashSet<Sha256dHash>,
+    // last height searched for confirming blocks
+    scanned: Option<u32>
+}
+
+impl Scheduler {
+    /// check scheduled transactions every second and pass those due to the broadcaster
+    pub fn new(chaindb: SharedChainDB, configdb: SharedConfigDB, broadcaster: PeerMessageSender<NetworkMessage>) {
+        let mut scheduler = Scheduler { chaindb, configdb, broadcaster, parents: HashSet::new(), scanned: None };
+
+        thread::Builder::new().name("scheduler".to_string()).spawn(move || { scheduler.run() }).unwrap();
+    }
+
+    fn run(&mut self) {
+        let span = span!(Level::INFO, "scheduler");
+        let _enter = span.enter();
+        loop {
+            thread::sleep(Duration::from_millis(1000));
+            if let Err(e) = self.check() {
+                error!("Error checking scheduled transactions: {}", e);
+            }
+        }
+    }
+
+    fn check(&mut self) -> Result<(), Error> {
+        let scheduled = self.configdb.read().recover().fetch_scheduled()?;
+        if scheduled.is_empty() {
+            self.parents.clear();
+            return Ok(());
+        }
+        let confirmed = self.confirmed_parents(&scheduled)?;
+        let (height, mtp) = {
+            let chaindb = self.chaindb.read().recover();
+            match chaindb.header_tip() {
+                Some(tip) => (tip.stored.height, chaindb.median_time_past(tip.stored.height).unwrap_or(0)),
+                None => return Ok(())
+            }
+        };
+        let due = scheduled.into_iter().filter(|s| match s.trigger {
+            Trigger::Height(h) => height >= h,
+            Trigger::MedianTime(t) => mtp >= t,
+            Trigger::Confirmed(ref txid) => confirmed.contains(txid)
+        }).collect::<Vec<_>>();
+        if due.is_empty() {
+            return Ok(());
+        }
+        {
+            // fetch again, transactions might have been scheduled meanwhile
+            let due = due.iter().map(|s| s.tx.txid()).collect::<HashSet<_>>();
+            let mut configdb = self.configdb.write().recover();
+            let mut waiting = configdb.fetch_scheduled()?;
+            waiting.retain(|s| !due.contains(&s.tx.txid()));
+            configdb.store_scheduled(waiting)?;
+            configdb.batch()?;
+        }
+        for s in due {
+            info!("scheduled transaction {} is due at {:?}", s.tx.txid(), s.trigger);
+            self.broadcaster.send(PeerMessage::Outgoing(NetworkMessage::Tx(s.tx)));
+        }
+        Ok(())
+    }
+
+    // search downloaded blocks for parents of scheduled transactions,
+    // newly scheduled ones from the height they were scheduled at
+    fn confirmed_parents(&mut self, scheduled: &Vec<Scheduled>) -> Result<HashSet<Sha256dHash>, Error> {
+        let mut confirmed = HashSet::new();
+        let mut from = self.scanned.map(|h| h + 1);
+        let mut parents = HashSet::new();
+        for s in scheduled {
+            if let Trigger::Confirmed(txid) = s.trigger {
+                if !self.parents.contains(&txid) {
+                    from = Some(from.map(|f| f.min(s.since)).unwrap_or(s.since));
+                }
+                parents.insert(txid);
+            }
+        }
+        self.parents = parents;
+        if self.parents.is_empty() {
+            return Ok(confirmed);
+        }
+        let chaindb = self.chaindb.read().recover();
+        let block_tip = match chaindb.fetch_block_tip()?.and_then(|tip| chaindb.trunk_height(&tip)) {
+            Some(height) => height,
+            None => return Ok(confirmed)
+        };
+        for header in chaindb.iter_trunk(from.unwrap_or(0)).take_while(|h| h.stored.height <= block_tip) {
+            if let Some(block) = chaindb.fetch_block(&header.bitcoin_hash())? {
+                for tx in &block.txdata {
+                    let txid = tx.txid();
+                    if self.parents.contains(&txid) {
+                        debug!("parent {} confirmed at height {}", txid, header.stored.height);
+                        confirmed.insert(txid);
+                    }
+                }
+            }
+        }
+        self.scanned = Some(block_tip);
+        Ok(confirmed)
+    }
+}
@@ -0,0 +1,145 @@
+//
+// Copyright 2019 Tamas Blummer
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+// http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+//
+//!
+//! # SOCKS5 proxy
+//!
+//! A Dialer connecting through a SOCKS5 proxy (RFC 1928) without authentication, e.g. Tor.
+//! Addresses of Tor hidden services in OnionCat encoding (fd87:d87e:eb43::/48) are asked of the
+//! proxy by their .onion name, others by their IP address.
+//!
+//! The handshake with the proxy blocks, each step at most for a timeout, the stream is handed to
+//! P2P once the proxy connected it.
+//!
+
+use mio::net::TcpStream;
+use p2p::Dialer;
+use std::{
+    io::{self, Read, Write},
+    net::{IpAddr, SocketAddr, TcpStream as StdTcpStream},
+    time::Duration
+};
+
+// seconds to wait for the proxy to connect
+const PROXY_TIMEOUT_SECONDS: u64 = 30;
+// RFC 4648 base32 alphabet as used in .onion names
+const BASE32: &[u8] = b"abcdefghijklmnopqrstuvwxyz234567";
+
+/// Connect through a SOCKS5 proxy
+pub struct Socks5Dialer {
+    proxy: SocketAddr
+}
+
+impl Socks5Dialer {
+    pub fn new(proxy: SocketAddr) -> Socks5Dialer {
+        Socks5Dialer { proxy }
+    }
+
+    fn handshake(&self, stream: &mut StdTcpStream, addr: &SocketAddr) -> io::Result<()> {
+        // version 5, one method: no authentication
+        stream.write_all(&[5, 1, 0])?;
+        let mut reply = [0u8; 2];
+        stream.read_exact(&mut reply)?;
+        if reply != [5, 0] {
+            return Err(io::Error::new(io::ErrorKind::Other, "SOCKS5 proxy requires authentication"));
+        }
+        // version 5, connect, reserved
+        let mut request = vec!(5u8, 1, 0);
+        match (addr.ip(), onion_name(addr)) {
+            (_, Some(name)) => {
+                request.push(3);
+                request.push(name.len() as u8);
+                request.extend_from_slice(name.as_bytes());
+            },
+            (IpAddr::V4(ip), None) => {
+                request.push(1);
+                request.extend_from_slice(&ip.octets());
+            },
+            (IpAddr::V6(ip), None) => {
+                request.push(4);
+                request.extend_from_slice(&ip.octets());
+            }
+        }
+        request.extend_from_slice(&[(addr.port() >> 8) as u8, addr.port() as u8]);
+        stream.write_all(request.as_slice())?;
+
+        let mut reply = [0u8; 4];
+        stream.read_exact(&mut reply)?;
+        if reply[0] != 5 || reply[1] != 0 {
+            return Err(io::Error::new(io::ErrorKind::ConnectionRefused, format!("SOCKS5 proxy could not connect to {}, reply {}", addr, reply[1])));
+        }
+        // skip the address the proxy bound
+        let len = match reply[3] {
+            1 => 4,
+            4 => 16,
+            3 => {
+                let mut len = [0u8; 1];
+                stream.read_exact(&mut len)?;
+                len[0] as usize
+            },
+            _ => return Err(io::Error::new(io::ErrorKind::InvalidData, "invalid SOCKS5 reply"))
+        };
+        let mut bound = vec!(0u8; len + 2);
+        stream.read_exact(bound.as_mut_slice())
+    }
+}
+
+impl Dialer for Socks5Dialer {
+    fn connect(&self, addr: &SocketAddr) -> io::Result<TcpStream> {
+        let timeout = Duration::from_secs(PROXY_TIMEOUT_SECONDS);
+        let mut stream = StdTcpStream::connect_timeout(&self.proxy, timeout)?;
+        stream.set_read_timeout(Some(timeout))?;
+        stream.set_write_timeout(Some(timeout))?;
+        self.handshake(&mut stream, addr)?;
+        stream.set_read_timeout(None)?;
+        stream.set_write_timeout(None)?;
+        TcpStream::from_stream(stream)
+    }
+
+    // the proxy is expected to be Tor, or to resolve .onion names as Tor would
+    fn reaches_onion(&self) -> bool {
+        true
+    }
+}
+
+/// a Tor address in OnionCat encoding, fd87:d87e:eb43::/48
+pub fn is_onion(addr: &SocketAddr) -> bool {
+    match addr.ip() {
+        IpAddr::V6(ip) => ip.octets()[..6] == [0xfd, 0x87, 0xd8, 0x7e, 0xeb, 0x43],
+        _ => false
+    }
+}
+
+/// the .onion name of a Tor address in OnionCat encoding
+pub fn onion_name(addr: &SocketAddr) -> Option<String> {
+    match addr.ip() {
+        IpAddr::V6(ip) if is_onion(addr) => {
+            let mut name = String::with_capacity(22);
+            let mut buffer = 0u32;
+            let mut bits = 0;
+            for byte in &ip.octets()[6..] {
+                buffer = (buffer << 8) | *byte as u32;
+                bits += 8;
+                while bits >= 5 {
+                    bits -= 5;
+                    name.push(BASE32[((buffer >> bits) & 0x1f) as usize] as char);
+                }
+            }
+            name.push_str(".onion");
+            Some(name)
+        },
+        _ => None
+    }
+}
@@ -0,0 +1,226 @@
+//
+// Copyright 2019 Tamas Blummer
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+// http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+//
+//!
+//! # Watch for double spends
+//!
+//! The application tells which transaction it expects to spend some outpoints, e.g. a payment
+//! received. Transactions announced by peers and received blocks are checked for a different
+//! transaction spending any of those outpoints, raising Event::PossibleDoubleSpend.
+//!
+//! Announced transactions are fetched by TxFetch while there are expected spends. Blocks are only
+//! seen if downloaded for matching a watched script.
+//!
+//! Expected spends are persisted in the config DB with the block confirming them. They are watched
+//! until buried by BURIED blocks, if their block leaves the trunk they count as unconfirmed again.
+//!
+
+use bitcoin::{
+    BitcoinHash,
+    blockdata::{
+        block::Block,
+        transaction::{OutPoint, Transaction}
+    },
+    consensus::{Decodable, Encodable, encode},
+    network::message::NetworkMessage
+};
+use bitcoin_hashes::sha256d::Hash as Sha256dHash;
+use chaindb::ChainView;
+use configdb::SharedConfigDB;
+use downstream::Subscribers;
+use error::Error;
+use event::Event;
+use lock::Recover;
+use p2p::{P2PControlSender, PeerMessage, PeerMessageReceiver, PeerMessageSender};
+use std::{
+    collections::HashMap,
+    io,
+    sync::{mpsc, atomic::Ordering},
+    thread,
+    time::Duration
+};
+use tracing::{Level, field::display};
+use txfetch::SharedInterest;
+
+/// blocks on top of the confirming one after which an expected spend is no longer watched
+pub const BURIED: u32 = 6;
+
+/// A transaction expected to spend its inputs
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct ExpectedSpend {
+    /// the transaction
+    pub tx: Transaction,
+    /// the block confirming it, None while unconfirmed
+    pub block: Option<Sha256dHash>
+}
+
+impl Encodable for ExpectedSpend {
+    fn consensus_encode<W: io::Write>(&self, mut w: W) -> Result<usize, encode::Error> {
+        let mut len = self.tx.consensus_encode(&mut w)?;
+        match self.block {
+            Some(ref block) => {
+                len += 1u8.consensus_encode(&mut w)?;
+                len += block.consensus_encode(&mut w)?;
+            },
+            None => len += 0u8.consensus_encode(&mut w)?
+        }
+        Ok(len)
+    }
+}
+
+impl Decodable for ExpectedSpend {
+    fn consensus_decode<D: io::Read>(mut d: D) -> Result<ExpectedSpend, encode::Error> {
+        let tx = Decodable::consensus_decode(&mut d)?;
+        let block = match u8::consensus_decode(&mut d)? {
+            0 => None,
+            _ => Some(Decodable::consensus_decode(&mut d)?)
+        };
+        Ok(ExpectedSpend { tx, block })
+    }
+}
+
+pub struct SpendWatch {
+    configdb: SharedConfigDB,
+    chain: ChainView,
+    events: Subscribers<Event>,
+    // raised while there are expected spends
+    interest: SharedInterest,
+    // expected spending transaction by outpoint
+    expected: HashMap<OutPoint, Transaction>
+}
+
+impl SpendWatch {
+    pub fn new(configdb: SharedConfigDB, chain: ChainView, p2p: P2PControlSender<NetworkMessage>, events: Subscribers<Event>, interest: SharedInterest) -> PeerMessageSender<NetworkMessage> {
+        let (sender, receiver) = mpsc::sync_channel(p2p.back_pressure);
+
+        let mut spendwatch = SpendWatch { configdb, chain, events, interest, expected: HashMap::new() };
+
+        thread::Builder::new().name("spend watch".to_string()).spawn(move || { spendwatch.run(receiver) }).unwrap();
+
+        PeerMessageSender::new(sender)
+    }
+
+    fn run(&mut self, receiver: PeerMessageReceiver<NetworkMessage>) {
+        let span = span!(Level::INFO, "spend watch");
+        let _enter = span.enter();
+        loop {
+            if let Err(e) = self.load() {
+                error!("Error reading expected spends: {}", e);
+            }
+            while let Ok(msg) = receiver.recv_timeout(Duration::from_millis(1000)) {
+                if let Err(e) = match msg {
+                    PeerMessage::Incoming(pid, msg) => {
+                        let span = span!(Level::DEBUG, "peer", peer = display(pid));
+                        let _enter = span.enter();
+                        match msg {
+                            NetworkMessage::Tx(ref tx) => { self.check(tx, None); Ok(()) },
+                            NetworkMessage::Block(ref block) => self.block(block),
+                            _ => { Ok(()) }
+                        }
+                    },
+                    _ => { Ok(()) }
+                } {
+                    error!("Error checking for double spends: {}", e);
+                }
+            }
+        }
+    }
+
+    // refresh expected spends, the application might have added some
+    fn load(&mut self) -> Result<(), Error> {
+        let expected = self.configdb.read().recover().fetch_expected_spends()?;
+        self.expected.clear();
+        for spend in expected {
+            for input in &spend.tx.input {
+                self.expected.insert(input.previous_output, spend.tx.clone());
+            }
+        }
+        self.interest.store(!self.expected.is_empty(), Ordering::Relaxed);
+        Ok(())
+    }
+
+    fn block(&mut self, block: &Block) -> Result<(), Error> {
+        if self.expected.is_empty() {
+            return Ok(());
+        }
+        let block_id = block.bitcoin_hash();
+        let mut confirmed = Vec::new();
+        for tx in &block.txdata {
+            if self.check(tx, Some(block_id)) {
+                confirmed.push(tx.txid());
+            }
+        }
+        let changed = {
+            let mut configdb = self.configdb.write().recover();
+            let stored = configdb.fetch_expected_spends()?;
+            let mut expected = Vec::with_capacity(stored.len());
+            for mut spend in stored.iter().cloned() {
+                if confirmed.contains(&spend.tx.txid()) {
+                    spend.block = Some(block_id);
+                }
+                if let Some(block) = spend.block {
+                    match self.confirmations(&block) {
+                        // buried, no further watching
+                        Some(confirmations) if confirmations > BURIED => continue,
+                        Some(_) => {},
+                        None => spend.block = None
+                    }
+                }
+                expected.push(spend);
+            }
+            let changed = expected != stored;
+            if changed {
+                configdb.store_expected_spends(expected)?;
+                configdb.batch()?;
+            }
+            changed
+        };
+        if changed {
+            self.load()?;
+        }
+        Ok(())
+    }
+
+    // confirmations of a block on the trunk, None if not on the trunk
+    fn confirmations(&self, block: &Sha256dHash) -> Option<u32> {
+        let height = self.chain.pos_on_trunk(block)?;
+        let (tip, _) = self.chain.tip()?;
+        Some(tip.saturating_sub(height) + 1)
+    }
+
+    // raise event for a transaction spending an expected outpoint other than the expected one,
+    // returns true if the transaction is an expected one
+    fn check(&mut self, tx: &Transaction, block: Option<Sha256dHash>) -> bool {
+        let txid = tx.txid();
+        let mut is_expected = false;
+        for input in &tx.input {
+            if let Some(expected) = self.expected.get(&input.previous_output) {
+                let expected_id = expected.txid();
+                if expected_id == txid {
+                    is_expected = true;
+                } else {
+                    warn!("transaction {} spends {}:{} expected to be spent by {}", txid, input.previous_output.txid, input.previous_output.vout, expected_id);
+                    self.events.publish(Event::PossibleDoubleSpend {
+                        outpoint: input.previous_output,
+                        expected: expected.clone(),
+                        conflicting: tx.clone(),
+                        block
+                    });
+                }
+            }
+        }
+        is_expected
+    }
+}
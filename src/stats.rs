@@ -0,0 +1,117 @@
+//
+// Copyright 2019 Tamas Blummer
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+// http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+//
+//!
+//! # Service statistics
+//!
+//! Uptime, blocks and filters served and bytes exchanged with peers, accumulated per day and
+//! persisted in the config DB, so that operators of a public server can report service levels.
+//!
+
+use bitcoin::consensus::{Decodable, Encodable, encode};
+use std::{
+    io,
+    sync::{Arc, atomic::{AtomicU64, Ordering}}
+};
+
+/// days of statistics kept
+pub const MAX_DAYS: usize = 366;
+
+// seconds of a day
+const DAY: u64 = 24 * 3600;
+
+pub type SharedStatistics = Arc<Statistics>;
+
+/// Statistics of a day
+#[derive(Clone, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct DayStats {
+    /// days since the unix epoch
+    pub day: u64,
+    /// seconds the node was running
+    pub uptime: u64,
+    /// blocks sent to peers
+    pub blocks_served: u64,
+    /// filters sent to peers
+    pub filters_served: u64,
+    /// bytes sent to and received from peers
+    pub bytes: u64
+}
+
+impl Encodable for DayStats {
+    fn consensus_encode<W: io::Write>(&self, mut w: W) -> Result<usize, encode::Error> {
+        let mut len = self.day.consensus_encode(&mut w)?;
+        len += self.uptime.consensus_encode(&mut w)?;
+        len += self.blocks_served.consensus_encode(&mut w)?;
+        len += self.filters_served.consensus_encode(&mut w)?;
+        len += self.bytes.consensus_encode(&mut w)?;
+        Ok(len)
+    }
+}
+
+impl Decodable for DayStats {
+    fn consensus_decode<D: io::Read>(mut d: D) -> Result<DayStats, encode::Error> {
+        Ok(DayStats {
+            day: Decodable::consensus_decode(&mut d)?,
+            uptime: Decodable::consensus_decode(&mut d)?,
+            blocks_served: Decodable::consensus_decode(&mut d)?,
+            filters_served: Decodable::consensus_decode(&mut d)?,
+            bytes: Decodable::consensus_decode(&mut d)?
+        })
+    }
+}
+
+/// Counts of the running node not yet accumulated into the history
+pub struct Statistics {
+    blocks_served: AtomicU64,
+    filters_served: AtomicU64,
+    // unix time and total bytes at the last accumulation
+    last_time: AtomicU64,
+    last_bytes: AtomicU64
+}
+
+impl Statistics {
+    /// start counting at the unix time
+    pub fn new(now: u64) -> Statistics {
+        Statistics { blocks_served: AtomicU64::new(0), filters_served: AtomicU64::new(0), last_time: AtomicU64::new(now), last_bytes: AtomicU64::new(0) }
+    }
+
+    /// count a block sent to a peer
+    pub fn block_served(&self) {
+        self.blocks_served.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// count filters sent to a peer
+    pub fn filters_served(&self, n: usize) {
+        self.filters_served.fetch_add(n as u64, Ordering::Relaxed);
+    }
+
+    /// Add what was counted since the last call to the day of now in the history, ordered by day.
+    /// * bytes - total bytes exchanged with peers since start
+    pub fn accumulate(&self, history: &mut Vec<DayStats>, now: u64, bytes: u64) {
+        let day = now / DAY;
+        if history.last().map(|last| last.day) != Some(day) {
+            history.push(DayStats { day, ..DayStats::default() });
+        }
+        if history.len() > MAX_DAYS {
+            let surplus = history.len() - MAX_DAYS;
+            history.drain(..surplus);
+        }
+        let today = history.last_mut().expect("just added");
+        today.uptime += now.saturating_sub(self.last_time.swap(now, Ordering::Relaxed));
+        today.blocks_served += self.blocks_served.swap(0, Ordering::Relaxed);
+        today.filters_served += self.filters_served.swap(0, Ordering::Relaxed);
+        today.bytes += bytes.saturating_sub(self.last_bytes.swap(bytes, Ordering::Relaxed));
+    }
+}
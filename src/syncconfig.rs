@@ -0,0 +1,64 @@
+//
+// Copyright 2019 Tamas Blummer
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+// http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+//
+//!
+//! # Sync configuration
+//!
+//! Download concurrency and chunk sizes. Defaults suit a desktop, small devices might
+//! want fewer requests in flight, servers more.
+//!
+
+/// Tuning of header, filter and block download
+#[derive(Clone, Debug)]
+pub struct SyncConfig {
+    /// number of blocks asked from a peer at once
+    pub blocks_per_peer: usize,
+    /// number of filters asked with one getcfilters, at most 1000
+    pub filters_per_request: u32,
+    /// number of headers stored within one DB batch
+    pub header_batch: usize,
+    /// number of times a request is asked again from an other peer at once, thereafter filters
+    /// and matching blocks are asked again after a growing pause, other blocks are given up
+    pub retries: usize,
+    /// only sync headers, never download filters or blocks
+    pub headers_only: bool,
+    /// ask for the next headers as soon as a full headers message arrives, before storing it
+    pub pipeline_headers: bool,
+    /// random blocks near each block matching watched scripts asked along with it and then
+    /// discarded, so the blocks asked do not reveal the wallet to the peer. 0 disables decoys
+    pub decoy_blocks: usize,
+    /// seconds the time of a header might be ahead of the network adjusted time, a header later
+    /// than that is dropped and its sender's ban score raised slightly
+    pub max_future_drift: u32
+}
+
+impl Default for SyncConfig {
+    fn default() -> SyncConfig {
+        SyncConfig { blocks_per_peer: 16, filters_per_request: 100, header_batch: 2000, retries: 3, headers_only: false, pipeline_headers: true, decoy_blocks: 0, max_future_drift: 2 * 3600 }
+    }
+}
+
+impl SyncConfig {
+    /// the configuration with sizes out of their usable range moved to the nearest usable value,
+    /// a batch or request of zero would never progress
+    pub fn clamped(&self) -> SyncConfig {
+        SyncConfig {
+            blocks_per_peer: self.blocks_per_peer.max(1),
+            filters_per_request: self.filters_per_request.max(1).min(1000),
+            header_batch: self.header_batch.max(1),
+            ..self.clone()
+        }
+    }
+}
@@ -0,0 +1,117 @@
+//
+// Copyright 2019 Tamas Blummer
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+// http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+//
+//!
+//! # Time locks
+//!
+//! Evaluate nLockTime (BIP113) and sequence based relative locks (BIP68) of a transaction
+//! against the trunk, to tell whether it could be included in the next block.
+//!
+//! Relative locks count from the block that confirmed the spent output, murmel does not
+//! track outputs, so the caller tells the heights of confirmation.
+//!
+
+use bitcoin::blockdata::transaction::{OutPoint, Transaction};
+use chaindb::ChainDB;
+use std::{
+    cmp::max,
+    collections::HashMap
+};
+
+// nLockTime below this is a height, at or above a unix time
+const LOCKTIME_THRESHOLD: u32 = 500_000_000;
+// sequence number of an input that opts out of nLockTime
+const SEQUENCE_FINAL: u32 = 0xffffffff;
+// BIP68 flags and mask
+const SEQUENCE_LOCKTIME_DISABLE_FLAG: u32 = 1 << 31;
+const SEQUENCE_LOCKTIME_TYPE_FLAG: u32 = 1 << 22;
+const SEQUENCE_LOCKTIME_MASK: u32 = 0x0000ffff;
+// relative time locks are in units of 512 seconds
+const SEQUENCE_LOCKTIME_GRANULARITY: u32 = 9;
+
+/// Whether a transaction's locks allow it in the next block
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum LockStatus {
+    /// could be included in the next block
+    Final,
+    /// the trunk tip must reach both height and median time past before the transaction could be
+    /// included in the next block
+    Pending {
+        /// tip height needed
+        height: u32,
+        /// median time past at the tip needed
+        time: u32
+    },
+    /// an input with a relative lock spends an output not yet confirmed
+    Unconfirmed,
+    /// there is no trunk to evaluate against
+    Unknown
+}
+
+/// Evaluate the locks of a transaction at the trunk tip.
+/// confirmed maps spent outputs to the height of the block that confirmed them,
+/// outputs not in the map are assumed unconfirmed.
+pub fn lock_status(chaindb: &ChainDB, tx: &Transaction, confirmed: &HashMap<OutPoint, u32>) -> LockStatus {
+    let tip = match chaindb.header_tip() {
+        Some(tip) => tip.stored.height,
+        None => return LockStatus::Unknown
+    };
+    let mtp = match chaindb.median_time_past(tip) {
+        Some(mtp) => mtp,
+        None => return LockStatus::Unknown
+    };
+    // required tip height and median time past
+    let mut height = 0u32;
+    let mut time = 0u32;
+
+    // absolute lock, BIP113 compares times to the median time past of the tip
+    if tx.lock_time != 0 && tx.input.iter().any(|input| input.sequence != SEQUENCE_FINAL) {
+        if tx.lock_time < LOCKTIME_THRESHOLD {
+            height = max(height, tx.lock_time);
+        } else {
+            time = max(time, tx.lock_time.saturating_add(1));
+        }
+    }
+
+    // relative locks, BIP68
+    if tx.version as i32 >= 2 {
+        for input in &tx.input {
+            if input.sequence & SEQUENCE_LOCKTIME_DISABLE_FLAG != 0 {
+                continue;
+            }
+            let coin_height = match confirmed.get(&input.previous_output) {
+                Some(coin_height) => *coin_height,
+                None => return LockStatus::Unconfirmed
+            };
+            let value = input.sequence & SEQUENCE_LOCKTIME_MASK;
+            if input.sequence & SEQUENCE_LOCKTIME_TYPE_FLAG != 0 {
+                let coin_time = match chaindb.median_time_past(coin_height.saturating_sub(1)) {
+                    Some(coin_time) => coin_time,
+                    None => return LockStatus::Unconfirmed
+                };
+                time = max(time, coin_time.saturating_add(value << SEQUENCE_LOCKTIME_GRANULARITY));
+            } else {
+                // the next block at tip + 1 must be at least value blocks after the confirming one
+                height = max(height, (coin_height + value).saturating_sub(1));
+            }
+        }
+    }
+
+    if height <= tip && time <= mtp {
+        LockStatus::Final
+    } else {
+        LockStatus::Pending { height, time }
+    }
+}
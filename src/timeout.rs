@@ -17,12 +17,12 @@
 //! # Keep track of peer timeouts
 //!
 
+use clock::SharedClock;
 use p2p::{P2PControl, P2PControlSender, PeerId};
 use std::{
     cmp::min,
     collections::HashMap,
-    sync::{Arc, Mutex},
-    time::{SystemTime, UNIX_EPOCH}
+    sync::{Arc, Mutex}
 };
 use std::hash::Hash;
 
@@ -43,12 +43,13 @@ pub enum ExpectedReply {
 pub struct Timeout<Message: Send + Sync + Clone, Reply : Eq + Hash + std::fmt::Debug> {
     timeouts: HashMap<PeerId, u64>,
     expected: HashMap<PeerId, HashMap<Reply, usize>>,
-    p2p: P2PControlSender<Message>
+    p2p: P2PControlSender<Message>,
+    clock: SharedClock
 }
 
 impl<Message: Send + Sync + Clone, Reply: Eq + Hash + std::fmt::Debug> Timeout<Message, Reply> {
-    pub fn new (p2p: P2PControlSender<Message>) -> Timeout<Message, Reply> {
-        Timeout{p2p, timeouts: HashMap::new(), expected: HashMap::new()}
+    pub fn new (p2p: P2PControlSender<Message>, clock: SharedClock) -> Timeout<Message, Reply> {
+        Timeout{p2p, clock, timeouts: HashMap::new(), expected: HashMap::new()}
     }
 
     pub fn forget (&mut self, peer: PeerId) {
@@ -57,7 +58,7 @@ impl<Message: Send + Sync + Clone, Reply: Eq + Hash + std::fmt::Debug> Timeout<M
     }
 
     pub fn expect (&mut self, peer: PeerId, n: usize, what: Reply) {
-        self.timeouts.insert(peer, Self::now() + TIMEOUT);
+        self.timeouts.insert(peer, self.now() + TIMEOUT);
         *self.expected.entry(peer).or_insert(HashMap::new()).entry(what).or_insert(0) += n;
     }
 
@@ -65,7 +66,7 @@ impl<Message: Send + Sync + Clone, Reply: Eq + Hash + std::fmt::Debug> Timeout<M
         if let Some(expected) = self.expected.get(&peer) {
             if let Some(m) = expected.get(&what) {
                 if *m > 0 {
-                    self.timeouts.insert(peer, Self::now() + TIMEOUT);
+                    self.timeouts.insert(peer, self.now() + TIMEOUT);
                 }
             }
         }
@@ -99,10 +100,10 @@ impl<Message: Send + Sync + Clone, Reply: Eq + Hash + std::fmt::Debug> Timeout<M
     pub fn check (&mut self, expected: Vec<Reply>) {
         let mut banned = Vec::new();
         for (peer, timeout) in &self.timeouts {
-            if *timeout < Self::now () {
+            if *timeout < self.now() {
                 if expected.iter().any(|expected| if let Some(e) = self.expected.get(peer) { if let Some(n) = e.get(expected) { *n>0 } else { false } } else { false }) {
                     debug!("too slow answering {:?} requests {:?}, banning peer={}", expected, self.expected.get(peer), *peer);
-                    self.p2p.send(P2PControl::Ban(*peer, 100));
+                    self.p2p.send(P2PControl::Stalled(*peer, 100));
                     banned.push(*peer);
                 }
             }
@@ -113,7 +114,7 @@ impl<Message: Send + Sync + Clone, Reply: Eq + Hash + std::fmt::Debug> Timeout<M
         }
     }
 
-    fn now() -> u64 {
-        SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_secs()
+    fn now(&self) -> u64 {
+        self.clock.unix_time()
     }
 }
\ No newline at end of file
@@ -0,0 +1,119 @@
+//
+// Copyright 2019 Tamas Blummer
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+// http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+//
+//!
+//! # TLS for inbound connections
+//!
+//! Connections accepted by a listener are wrapped in TLS with a configured certificate, optionally
+//! requiring clients to present a certificate of a configured authority. This is not part of the
+//! Bitcoin protocol, it is meant for private links e.g. between an application and the murmel
+//! node serving it filters. Available with the tls feature.
+//!
+
+use error::Error;
+use mio::net::TcpStream;
+use p2p::{Tunnel, TunnelAcceptor};
+use rustls::{
+    AllowAnyAuthenticatedClient, NoClientAuth, RootCertStore, ServerConfig, ServerSession, Session,
+    internal::pemfile::{certs, pkcs8_private_keys, rsa_private_keys}
+};
+use std::{
+    fs::File,
+    io::{self, BufReader, Read, Write},
+    path::Path,
+    sync::Arc
+};
+
+/// Wraps accepted connections in TLS
+pub struct TlsAcceptor {
+    config: Arc<ServerConfig>
+}
+
+impl TlsAcceptor {
+    /// Serve the certificate chain and private key read from PEM files. If client_ca is given
+    /// only clients presenting a certificate signed by an authority in that PEM file are accepted.
+    pub fn from_pem(certificate: &Path, key: &Path, client_ca: Option<&Path>) -> Result<TlsAcceptor, Error> {
+        let chain = certs(&mut BufReader::new(File::open(certificate)?))
+            .map_err(|_| invalid("can not read certificates"))?;
+        let mut keys = pkcs8_private_keys(&mut BufReader::new(File::open(key)?))
+            .map_err(|_| invalid("can not read private key"))?;
+        if keys.is_empty() {
+            keys = rsa_private_keys(&mut BufReader::new(File::open(key)?))
+                .map_err(|_| invalid("can not read private key"))?;
+        }
+        let key = keys.into_iter().next().ok_or_else(|| invalid("no private key"))?;
+        let mut config = match client_ca {
+            Some(path) => {
+                let mut roots = RootCertStore::empty();
+                roots.add_pem_file(&mut BufReader::new(File::open(path)?))
+                    .map_err(|_| invalid("can not read client authorities"))?;
+                ServerConfig::new(AllowAnyAuthenticatedClient::new(roots))
+            },
+            None => ServerConfig::new(NoClientAuth::new())
+        };
+        config.set_single_cert(chain, key).map_err(|e| invalid(e.to_string().as_str()))?;
+        Ok(TlsAcceptor { config: Arc::new(config) })
+    }
+}
+
+impl TunnelAcceptor for TlsAcceptor {
+    fn accept(&self) -> Result<Box<dyn Tunnel>, Error> {
+        Ok(Box::new(TlsTunnel { session: ServerSession::new(&self.config) }))
+    }
+}
+
+/// TLS session of an accepted connection
+pub struct TlsTunnel {
+    session: ServerSession
+}
+
+impl Tunnel for TlsTunnel {
+    fn read(&mut self, stream: &mut TcpStream, buf: &mut [u8]) -> io::Result<usize> {
+        if self.session.read_tls(stream)? == 0 {
+            return Ok(0);
+        }
+        self.session.process_new_packets().map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e.to_string()))?;
+        self.flush(stream)?;
+        match self.session.read(buf)? {
+            0 => Err(io::Error::new(io::ErrorKind::WouldBlock, "no application data yet")),
+            len => Ok(len)
+        }
+    }
+
+    fn write(&mut self, stream: &mut TcpStream, buf: &[u8]) -> io::Result<usize> {
+        let len = self.session.write(buf)?;
+        self.flush(stream)?;
+        Ok(len)
+    }
+
+    fn flush(&mut self, stream: &mut TcpStream) -> io::Result<bool> {
+        while self.session.wants_write() {
+            match self.session.write_tls(stream) {
+                Ok(_) => {},
+                Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => return Ok(false),
+                Err(e) => return Err(e)
+            }
+        }
+        Ok(true)
+    }
+
+    fn wants_write(&self) -> bool {
+        self.session.wants_write()
+    }
+}
+
+fn invalid(what: &str) -> Error {
+    Error::IO(io::Error::new(io::ErrorKind::InvalidInput, what))
+}
@@ -0,0 +1,97 @@
+//
+// Copyright 2019 Tamas Blummer
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+// http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+//
+//!
+//! # Fetch announced transactions
+//!
+//! Transactions announced by peers are asked for once, while any of the watches interested in
+//! them, e.g. for double spends or awaited payments, raised its interest and bandwidth is not
+//! restricted. The transactions arrive with the tx message every processor receives.
+//!
+
+use bandwidth::SharedBandwidth;
+use bitcoin::network::{
+    message::NetworkMessage,
+    message_blockdata::{Inventory, InvType}
+};
+use bitcoin_hashes::sha256d::Hash as Sha256dHash;
+use lru_cache::LruCache;
+use p2p::{P2PControlSender, PeerId, PeerMessage, PeerMessageReceiver, PeerMessageSender};
+use std::{
+    sync::{Arc, mpsc, atomic::{AtomicBool, Ordering}},
+    thread
+};
+use tracing::{Level, field::display};
+
+// number of announced transactions remembered to not ask twice
+const SEEN_TRANSACTIONS: usize = 10000;
+
+/// Set by a watch while it wants announced transactions
+pub type SharedInterest = Arc<AtomicBool>;
+
+pub struct TxFetch {
+    p2p: P2PControlSender<NetworkMessage>,
+    bandwidth: SharedBandwidth,
+    interests: Vec<SharedInterest>,
+    // transactions asked for
+    seen: LruCache<Sha256dHash, ()>
+}
+
+impl TxFetch {
+    pub fn new(p2p: P2PControlSender<NetworkMessage>, bandwidth: SharedBandwidth, interests: Vec<SharedInterest>) -> PeerMessageSender<NetworkMessage> {
+        let (sender, receiver) = mpsc::sync_channel(p2p.back_pressure);
+
+        let mut txfetch = TxFetch { p2p, bandwidth, interests, seen: LruCache::new(SEEN_TRANSACTIONS) };
+
+        thread::Builder::new().name("tx fetch".to_string()).spawn(move || { txfetch.run(receiver) }).unwrap();
+
+        PeerMessageSender::new(sender)
+    }
+
+    fn run(&mut self, receiver: PeerMessageReceiver<NetworkMessage>) {
+        let span = span!(Level::INFO, "tx fetch");
+        let _enter = span.enter();
+        while let Ok(msg) = receiver.recv() {
+            if let PeerMessage::Incoming(pid, msg) = msg {
+                let span = span!(Level::DEBUG, "peer", peer = display(pid));
+                let _enter = span.enter();
+                match msg {
+                    NetworkMessage::Inv(ref inv) => self.inv(inv, pid),
+                    NetworkMessage::Tx(ref tx) => { self.seen.insert(tx.txid(), ()); },
+                    _ => {}
+                }
+            }
+        }
+    }
+
+    // ask for announced transactions not yet seen
+    fn inv(&mut self, v: &Vec<Inventory>, peer: PeerId) {
+        if !self.interests.iter().any(|i| i.load(Ordering::Relaxed)) || self.bandwidth.is_restricted() {
+            return;
+        }
+        let mut ask = Vec::new();
+        for inventory in v {
+            if (inventory.inv_type == InvType::Transaction || inventory.inv_type == InvType::WitnessTransaction)
+                && !self.seen.contains_key(&inventory.hash) {
+                self.seen.insert(inventory.hash, ());
+                ask.push(Inventory { inv_type: InvType::WitnessTransaction, hash: inventory.hash });
+            }
+        }
+        if !ask.is_empty() {
+            trace!("ask for {} announced transactions peer={}", ask.len(), peer);
+            self.p2p.send_network(peer, NetworkMessage::GetData(ask));
+        }
+    }
+}
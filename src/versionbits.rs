@@ -0,0 +1,260 @@
+//
+// Copyright 2019 Tamas Blummer
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+// http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+//
+//!
+//! # Version bits
+//!
+//! Miners signal readiness for a soft fork by setting a bit of the block version (BIP9).
+//! Signals are counted per retarget period, a deployment locks in once a period reaches the
+//! threshold and becomes active a period later. The state is evaluated at period boundaries
+//! from the median time past of the last block of the previous period (BIP9) or from the
+//! height of the boundary (BIP8), the latter might lock in on timeout without the threshold.
+//!
+//! Subscribers are notified of headers signalling bits they watch.
+//!
+
+use bitcoin::{
+    BitcoinHash,
+    blockdata::block::{Block, BlockHeader},
+    network::constants::Network
+};
+use chaindb::ChainDB;
+use downstream::{Downstream, Subscribers};
+use event::Event;
+use std::{
+    collections::HashSet,
+    sync::{Arc, Mutex}
+};
+
+/// number of version bits usable for signalling
+pub const VERSION_BITS: usize = 29;
+// the top three bits of a version signalling with bits
+const TOP_MASK: u32 = 0xe0000000;
+const TOP_BITS: u32 = 0x20000000;
+
+/// Signal counts of all bits within a period
+pub type SignalCounts = [u32; VERSION_BITS];
+
+pub type SharedVersionBitsWatch = Arc<Mutex<VersionBitsWatch>>;
+
+/// State of a deployment for the blocks of a period
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ThresholdState {
+    /// not yet started signalling
+    Defined,
+    /// signals are counted
+    Started,
+    /// the threshold was reached, active from the next period
+    LockedIn,
+    /// the rules are enforced
+    Active,
+    /// the threshold was not reached before the timeout
+    Failed
+}
+
+/// When signals of a deployment are counted
+#[derive(Clone, Debug)]
+pub enum Schedule {
+    /// BIP9, by median time past of the last block before a period
+    Time {
+        /// signals count from this time
+        start: u32,
+        /// the deployment fails at this time if not locked in
+        timeout: u32
+    },
+    /// BIP8, by height of the first block of a period
+    Height {
+        /// signals count from this height
+        start: u32,
+        /// the deployment fails or locks in at this height if not locked in before
+        timeout: u32,
+        /// lock in at timeout instead of failing
+        lock_in_on_timeout: bool
+    }
+}
+
+/// A soft fork signalled with a version bit
+#[derive(Clone, Debug)]
+pub struct Deployment {
+    /// name the deployment is known by
+    pub name: String,
+    /// the bit signalling readiness
+    pub bit: u8,
+    /// when signals are counted
+    pub schedule: Schedule,
+    /// signals needed within a period to lock in, if other than the network's default
+    pub threshold: Option<u32>,
+    /// a deployment locked in earlier becomes active at this height
+    pub min_activation_height: u32
+}
+
+/// State of a deployment for the block following the trunk tip
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct DeploymentStatus {
+    /// the state
+    pub state: ThresholdState,
+    /// height of the first block of the period the state began with
+    pub since: u32,
+    /// signals within the current period so far
+    pub signals: u32,
+    /// signals needed within a period to lock in
+    pub threshold: u32
+}
+
+/// Deployments of the network known at the time of this release
+pub fn known_deployments(network: Network) -> Vec<Deployment> {
+    let deployment = |name: &str, bit, start, timeout, threshold, min_activation_height| Deployment {
+        name: name.to_string(), bit, schedule: Schedule::Time { start, timeout }, threshold, min_activation_height
+    };
+    match network {
+        Network::Bitcoin => vec!(
+            deployment("csv", 0, 1462060800, 1493596800, None, 0),
+            deployment("segwit", 1, 1479168000, 1510704000, None, 0),
+            // speedy trial with a lower threshold
+            deployment("taproot", 2, 1619222400, 1628640000, Some(1815), 709632)
+        ),
+        Network::Testnet => vec!(
+            deployment("csv", 0, 1456790400, 1493596800, None, 0),
+            deployment("segwit", 1, 1462060800, 1493596800, None, 0),
+            deployment("taproot", 2, 1619222400, 1628640000, None, 0)
+        ),
+        Network::Regtest => vec!()
+    }
+}
+
+/// blocks per period and signals needed within a period to lock in
+pub fn period_and_threshold(network: Network) -> (u32, u32) {
+    match network {
+        Network::Bitcoin => (2016, 1916),
+        Network::Testnet => (2016, 1512),
+        Network::Regtest => (144, 108)
+    }
+}
+
+/// whether a header signals the bit
+pub fn signals(header: &BlockHeader, bit: u8) -> bool {
+    let version = header.version as u32;
+    (bit as usize) < VERSION_BITS && version & TOP_MASK == TOP_BITS && version & (1 << bit) != 0
+}
+
+/// add signals of a header to counts
+pub fn count_signals(header: &BlockHeader, counts: &mut SignalCounts) {
+    for bit in 0..VERSION_BITS {
+        if signals(header, bit as u8) {
+            counts[bit] += 1;
+        }
+    }
+}
+
+/// State of the deployment for the block following the trunk tip
+pub fn threshold_state(chaindb: &ChainDB, deployment: &Deployment) -> ThresholdState {
+    deployment_status(chaindb, deployment).state
+}
+
+/// State of the deployment for the block following the trunk tip, with progress of signalling
+pub fn deployment_status(chaindb: &ChainDB, deployment: &Deployment) -> DeploymentStatus {
+    let (period, threshold) = period_and_threshold(chaindb.network());
+    let threshold = deployment.threshold.unwrap_or(threshold);
+    let next = match chaindb.header_tip() {
+        Some(tip) => tip.stored.height + 1,
+        None => return DeploymentStatus { state: ThresholdState::Defined, since: 0, signals: 0, threshold }
+    };
+    // a bit outside of the version bits is never signalled
+    let count = |last: u32| chaindb.signal_counts(last).and_then(|c| c.get(deployment.bit as usize).cloned()).unwrap_or(0);
+    let mut state = ThresholdState::Defined;
+    let mut since = 0;
+    let mut boundary = period;
+    while boundary <= next {
+        // the last block of the previous period decides
+        let last = boundary - 1;
+        let (started, timed_out) = match deployment.schedule {
+            Schedule::Time { start, timeout } => match chaindb.median_time_past(last) {
+                Some(mtp) => (mtp >= start, mtp >= timeout),
+                None => break
+            },
+            Schedule::Height { start, timeout, .. } => (boundary >= start, boundary >= timeout)
+        };
+        let lock_in_on_timeout = match deployment.schedule {
+            Schedule::Height { lock_in_on_timeout, .. } => lock_in_on_timeout,
+            Schedule::Time { .. } => false
+        };
+        let next_state = match state {
+            ThresholdState::Defined => if timed_out && !lock_in_on_timeout {
+                ThresholdState::Failed
+            } else if started {
+                ThresholdState::Started
+            } else {
+                ThresholdState::Defined
+            },
+            ThresholdState::Started => if count(last) >= threshold {
+                ThresholdState::LockedIn
+            } else if timed_out {
+                if lock_in_on_timeout { ThresholdState::LockedIn } else { ThresholdState::Failed }
+            } else {
+                ThresholdState::Started
+            },
+            ThresholdState::LockedIn => if boundary >= deployment.min_activation_height {
+                ThresholdState::Active
+            } else {
+                ThresholdState::LockedIn
+            },
+            ThresholdState::Active => ThresholdState::Active,
+            ThresholdState::Failed => ThresholdState::Failed
+        };
+        if next_state != state {
+            state = next_state;
+            since = boundary;
+        }
+        boundary += period;
+    }
+    let signals = if next % period == 0 { 0 } else { count(next - 1) };
+    DeploymentStatus { state, since, signals, threshold }
+}
+
+/// Publishes an event for every header connected to the trunk that signals a watched bit
+pub struct VersionBitsWatch {
+    bits: HashSet<u8>,
+    events: Subscribers<Event>
+}
+
+impl VersionBitsWatch {
+    pub fn new(events: Subscribers<Event>) -> VersionBitsWatch {
+        VersionBitsWatch { bits: HashSet::new(), events }
+    }
+
+    /// notify of headers signalling the bit from now on
+    pub fn watch(&mut self, bit: u8) {
+        self.bits.insert(bit);
+    }
+
+    /// stop notifications for the bit
+    pub fn unwatch(&mut self, bit: u8) {
+        self.bits.remove(&bit);
+    }
+}
+
+impl Downstream for VersionBitsWatch {
+    fn block_connected(&mut self, _block: &Block, _height: u32) {}
+
+    fn header_connected(&mut self, header: &BlockHeader, height: u32) {
+        for bit in &self.bits {
+            if signals(header, *bit) {
+                self.events.publish(Event::VersionBitsSignal { bit: *bit, block: (height, header.bitcoin_hash()) });
+            }
+        }
+    }
+
+    fn block_disconnected(&mut self, _header: &BlockHeader) {}
+}
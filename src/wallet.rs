@@ -0,0 +1,809 @@
+//
+// Copyright 2019 Tamas Blummer
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+// http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+//
+//!
+//! # Wallet
+//!
+//! Unspent outputs paying to the wallet's scripts, tagged with their maturity, as wallet UIs
+//! show them, and the transactions paying to or spending from each script. The scripts are
+//! persisted in the config DB and watched by filter download, outputs and history are rebuilt
+//! from the downloaded blocks at start.
+//!
+//! The status of a script is tracked as Electrum does, a hash over its confirmed and unconfirmed
+//! history, subscribers of a script hash are told by Event::ScriptHashStatus as it changes.
+//!
+//! Named wallets besides the default one have scripts, outputs, history and events of their own,
+//! so that a node can serve several accounts without one seeing the other's.
+//!
+//! Unconfirmed transactions of the wallet are persisted and applied again after a restart, unless
+//! confirmed or spending outputs a block spent meanwhile. Transactions of a block disconnected
+//! are unconfirmed again. An unconfirmed transaction not confirmed within UNCONFIRMED_EXPIRY
+//! blocks of being first seen is forgotten with its unconfirmed descendants, as peers dropped it
+//! from their memory pools by then. An unconfirmed transaction double spent
+//! by a block, e.g. replaced through RBF, is undone with its unconfirmed descendants, raising
+//! Event::TransactionReplaced. Transactions relayed by peers replace nothing, as they are not
+//! validated and any peer could forge one spending an input of the wallet.
+//!
+//! The transaction history with amounts, fees and block times can be exported as CSV or JSON.
+//! A fee is only known if all inputs of the transaction spend outputs of the wallet.
+//!
+//! A PSBT spending outputs of the wallet is updated with the outputs spent and the transactions
+//! creating them if their block was downloaded, so that a hardware wallet can sign it.
+//!
+//! Payments to a script that received before and outputs below the dust threshold are reported
+//! as events, as both harm privacy or cost more to spend than they are worth.
+//!
+//! Filters already scanned are not matched again with a script added later.
+//!
+
+use bitcoin::{
+    BitcoinHash,
+    blockdata::{
+        block::{Block, BlockHeader},
+        script::Script,
+        transaction::{OutPoint, Transaction, TxOut}
+    },
+    consensus::{Decodable, Encodable, encode},
+    util::psbt::PartiallySignedTransaction
+};
+use bitcoin_hashes::{Hash, sha256, sha256d::Hash as Sha256dHash};
+use chaindb::ChainDB;
+use configdb::SharedConfigDB;
+use downstream::{Downstream, Subscribers};
+use error::Error;
+use event::Event;
+use lock::Recover;
+use std::{
+    collections::{HashMap, HashSet},
+    io,
+    sync::{Arc, Mutex}
+};
+
+/// number of confirmations before a coinbase output can be spent
+pub const COINBASE_MATURITY: u32 = 100;
+
+pub type SharedWallet = Arc<Mutex<Wallet>>;
+pub type SharedWallets = Arc<Mutex<Wallets>>;
+
+/// outputs of lower value are reported as dust unless configured otherwise
+pub const DUST_THRESHOLD: u64 = 546;
+
+/// blocks after which an unconfirmed transaction is forgotten unless configured otherwise, about
+/// the two weeks bitcoind keeps a transaction in its memory pool
+pub const UNCONFIRMED_EXPIRY: u32 = 2016;
+
+/// Maturity of an unspent output
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum Maturity {
+    /// not yet in a block
+    Unconfirmed,
+    /// coinbase output with fewer than COINBASE_MATURITY confirmations
+    Immature(u32),
+    /// spendable with this many confirmations
+    Confirmed(u32)
+}
+
+/// An unspent output paying to the wallet
+#[derive(Clone, Debug)]
+pub struct Utxo {
+    /// where the output is
+    pub outpoint: OutPoint,
+    /// amount and script
+    pub output: TxOut,
+    /// height of the confirming block, None if unconfirmed
+    pub height: Option<u32>,
+    /// id of the confirming block, None if unconfirmed
+    pub block: Option<Sha256dHash>,
+    /// output of a coinbase transaction
+    pub coinbase: bool
+}
+
+/// A transaction paying to or spending from a script of the wallet
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct HistoryItem {
+    /// id of the transaction
+    pub txid: Sha256dHash,
+    /// height of the confirming block, None if unconfirmed
+    pub height: Option<u32>,
+    /// id of the confirming block, None if unconfirmed
+    pub block: Option<Sha256dHash>
+}
+
+/// A transaction of the wallet with its effect on the balance, amounts in satoshis
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct WalletTx {
+    /// id of the transaction
+    pub txid: Sha256dHash,
+    /// height of the confirming block, None if unconfirmed
+    pub height: Option<u32>,
+    /// id of the confirming block, None if unconfirmed
+    pub block: Option<Sha256dHash>,
+    /// sum of outputs paying to the wallet
+    pub received: u64,
+    /// sum of the wallet's outputs spent
+    pub sent: u64,
+    /// fee paid, None unless all inputs spend outputs of the wallet
+    pub fee: Option<u64>
+}
+
+/// What the wallet knows of an output spent by an input of a PSBT
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct InputStatus {
+    /// the output spent
+    pub outpoint: OutPoint,
+    /// height of the block confirming the output, None if unconfirmed
+    pub height: Option<u32>,
+    /// id of the block confirming the output, None if unconfirmed
+    pub block: Option<Sha256dHash>,
+    /// maturity of the output at the current trunk height
+    pub maturity: Maturity
+}
+
+/// A transaction of the wallet not yet in a block
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct UnconfirmedTx {
+    /// the transaction
+    pub tx: Transaction,
+    /// height of the trunk as the transaction was first seen
+    pub seen: u32
+}
+
+impl Encodable for UnconfirmedTx {
+    fn consensus_encode<W: io::Write>(&self, mut w: W) -> Result<usize, encode::Error> {
+        Ok(self.tx.consensus_encode(&mut w)? + self.seen.consensus_encode(&mut w)?)
+    }
+}
+
+impl Decodable for UnconfirmedTx {
+    fn consensus_decode<D: io::Read>(mut d: D) -> Result<UnconfirmedTx, encode::Error> {
+        Ok(UnconfirmedTx { tx: Decodable::consensus_decode(&mut d)?, seen: Decodable::consensus_decode(&mut d)? })
+    }
+}
+
+/// Formats of the transaction history export
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ExportFormat {
+    /// comma separated values with a header line
+    Csv,
+    /// an array of objects
+    Json
+}
+
+/// Sum of outputs in satoshis
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct Balance {
+    /// all unspent outputs, also unconfirmed and immature
+    pub total: u64,
+    /// confirmed outputs, coinbase outputs only if mature
+    pub spendable: u64
+}
+
+pub struct Wallet {
+    // "" for the default wallet
+    name: String,
+    configdb: SharedConfigDB,
+    scripts: HashSet<Script>,
+    utxos: HashMap<OutPoint, Utxo>,
+    // outputs spent by a block, restored if the block is disconnected
+    spent: HashMap<Sha256dHash, Vec<Utxo>>,
+    // transactions paying to or spending from a script, in order seen
+    history: HashMap<Script, Vec<HistoryItem>>,
+    // transactions of the wallet, in order seen
+    txs: Vec<WalletTx>,
+    // transactions not yet in a block, in order added
+    unconfirmed: Vec<UnconfirmedTx>,
+    // transactions of the wallet by confirming block, unconfirmed again if it is disconnected
+    confirmed: HashMap<Sha256dHash, Vec<Transaction>>,
+    // outputs spent by an unconfirmed transaction, restored if it is replaced
+    spent_unconfirmed: HashMap<Sha256dHash, Vec<Utxo>>,
+    // height of the trunk
+    tip: u32,
+    events: Subscribers<Event>,
+    dust_threshold: u64,
+    // blocks after which an unconfirmed transaction is forgotten
+    expiry: u32,
+    // number of subscribers and status last told by subscribed script hash
+    subscriptions: HashMap<sha256::Hash, (usize, Option<sha256::Hash>)>
+}
+
+impl Wallet {
+    /// a wallet with the scripts stored in the config DB, without outputs until rescan
+    pub fn new(configdb: SharedConfigDB, events: Subscribers<Event>) -> Result<Wallet, Error> {
+        Wallet::named("", configdb, events)
+    }
+
+    /// a named wallet with its scripts stored in the config DB, without outputs until rescan
+    pub fn named(name: &str, configdb: SharedConfigDB, events: Subscribers<Event>) -> Result<Wallet, Error> {
+        let scripts = configdb.read().recover().fetch_wallet_scripts(name)?.into_iter().collect();
+        Ok(Wallet { name: name.to_string(), configdb, scripts, utxos: HashMap::new(), spent: HashMap::new(), history: HashMap::new(), txs: Vec::new(), unconfirmed: Vec::new(), confirmed: HashMap::new(), spent_unconfirmed: HashMap::new(), tip: 0,
+            events, dust_threshold: DUST_THRESHOLD, expiry: UNCONFIRMED_EXPIRY, subscriptions: HashMap::new() })
+    }
+
+    /// outputs received below this value in satoshis raise Event::DustReceived
+    pub fn set_dust_threshold(&mut self, threshold: u64) {
+        self.dust_threshold = threshold;
+    }
+
+    /// unconfirmed transactions are forgotten if not confirmed within this many blocks
+    pub fn set_unconfirmed_expiry(&mut self, blocks: u32) {
+        self.expiry = blocks;
+    }
+
+    /// rebuild outputs from downloaded blocks of the trunk
+    pub fn rescan(&mut self, chaindb: &ChainDB) -> Result<(), Error> {
+        self.utxos.clear();
+        self.spent.clear();
+        self.history.clear();
+        self.txs.clear();
+        self.unconfirmed.clear();
+        self.confirmed.clear();
+        self.spent_unconfirmed.clear();
+        if let Some(tip) = chaindb.header_tip() {
+            self.tip = tip.stored.height;
+        }
+        if let Some(block_tip) = chaindb.fetch_block_tip()?.and_then(|tip| chaindb.trunk_height(&tip)) {
+            for header in chaindb.iter_trunk(0).take_while(|h| h.stored.height <= block_tip) {
+                let id = header.bitcoin_hash();
+                if let Some(filter) = chaindb.fetch_filter(&id)? {
+                    if filter.matched {
+                        if let Some(block) = chaindb.fetch_block(&id)? {
+                            self.connect(&block, header.stored.height);
+                        }
+                    }
+                }
+            }
+        }
+        self.restore_unconfirmed()?;
+        info!("wallet has {} unspent outputs and {} unconfirmed transactions after rescan", self.utxos.len(), self.unconfirmed.len());
+        Ok(())
+    }
+
+    // apply stored unconfirmed transactions not confirmed, conflicting or expired meanwhile
+    fn restore_unconfirmed(&mut self) -> Result<(), Error> {
+        let stored = self.configdb.read().recover().fetch_unconfirmed(self.name.as_str())?;
+        let spent = self.spent.values().flat_map(|utxos| utxos.iter().map(|u| u.outpoint)).collect::<HashSet<_>>();
+        let count = stored.len();
+        for unconfirmed in stored {
+            let txid = unconfirmed.tx.txid();
+            let confirmed = self.txs.iter().any(|known| known.txid == txid && known.height.is_some());
+            if !confirmed && !self.is_expired(&unconfirmed) && !unconfirmed.tx.input.iter().any(|input| spent.contains(&input.previous_output)) {
+                self.apply_unconfirmed(&unconfirmed.tx);
+                self.unconfirmed.push(unconfirmed);
+            }
+        }
+        if self.unconfirmed.len() != count {
+            self.store_unconfirmed()?;
+        }
+        Ok(())
+    }
+
+    fn store_unconfirmed(&self) -> Result<(), Error> {
+        let mut configdb = self.configdb.write().recover();
+        configdb.store_unconfirmed(self.name.as_str(), self.unconfirmed.clone())?;
+        configdb.batch()
+    }
+
+    /// add a script the wallet's outputs pay to
+    pub fn add_script(&mut self, script: Script) -> Result<(), Error> {
+        if self.scripts.insert(script) {
+            let mut configdb = self.configdb.write().recover();
+            configdb.store_wallet_scripts(self.name.as_str(), self.scripts.iter().cloned().collect())?;
+            configdb.batch()?;
+        }
+        Ok(())
+    }
+
+    /// name of the wallet, "" for the default wallet
+    pub fn name(&self) -> &str {
+        self.name.as_str()
+    }
+
+    /// scripts the wallet's outputs pay to
+    pub fn scripts(&self) -> Vec<Script> {
+        self.scripts.iter().cloned().collect()
+    }
+
+    /// transactions paying to or spending from the script, in order seen
+    pub fn history(&self, script: &Script) -> Vec<HistoryItem> {
+        self.history.get(script).cloned().unwrap_or_default()
+    }
+
+    /// history of the script with the Electrum script hash, confirmed by height, then unconfirmed
+    pub fn script_hash_history(&self, hash: &sha256::Hash) -> Vec<HistoryItem> {
+        let mut history = self.scripts.iter().find(|script| script_hash(script) == *hash)
+            .map(|script| self.history(script)).unwrap_or_default();
+        history.sort_by_key(|item| item.height.unwrap_or(u32::max_value()));
+        history
+    }
+
+    /// Electrum's status of the script with the hash, the sha256 of "txid:height:" for every
+    /// transaction of its history, height 0 if unconfirmed. None if there is no history.
+    pub fn status(&self, hash: &sha256::Hash) -> Option<sha256::Hash> {
+        let history = self.script_hash_history(hash);
+        if history.is_empty() {
+            return None;
+        }
+        let status = history.iter().map(|item| format!("{}:{}:", item.txid, item.height.unwrap_or(0))).collect::<String>();
+        Some(sha256::Hash::hash(status.as_bytes()))
+    }
+
+    /// Raise Event::ScriptHashStatus as the status of the script with the hash changes,
+    /// returns the current status. Subscriptions are counted, every subscribe is to be matched
+    /// by an unsubscribe.
+    pub fn subscribe(&mut self, hash: sha256::Hash) -> Option<sha256::Hash> {
+        let status = self.status(&hash);
+        let subscription = self.subscriptions.entry(hash).or_insert((0, status));
+        subscription.0 += 1;
+        subscription.1 = status;
+        status
+    }
+
+    /// stop raising Event::ScriptHashStatus for the script hash once its last subscriber unsubscribed
+    pub fn unsubscribe(&mut self, hash: &sha256::Hash) {
+        let last = match self.subscriptions.get_mut(hash) {
+            Some(subscription) => {
+                subscription.0 -= 1;
+                subscription.0 == 0
+            },
+            None => false
+        };
+        if last {
+            self.subscriptions.remove(hash);
+        }
+    }
+
+    /// a transaction of the history of any script
+    pub fn history_item(&self, txid: &Sha256dHash) -> Option<HistoryItem> {
+        self.history.values().flat_map(|history| history.iter()).find(|item| item.txid == *txid).cloned()
+    }
+
+    /// a transaction of the wallet not yet in a block
+    pub fn unconfirmed_transaction(&self, txid: &Sha256dHash) -> Option<Transaction> {
+        self.unconfirmed.iter().find(|u| u.tx.txid() == *txid).map(|u| u.tx.clone())
+    }
+
+    /// add outputs and remove spent ones of a transaction not yet in a block, e.g. one sent
+    pub fn add_unconfirmed(&mut self, tx: &Transaction) {
+        let txid = tx.txid();
+        if !self.unconfirmed.iter().any(|known| known.tx.txid() == txid) {
+            self.unconfirmed.push(UnconfirmedTx { tx: tx.clone(), seen: self.tip });
+            if let Err(e) = self.store_unconfirmed() {
+                error!("can not store unconfirmed transaction {}: {}", txid, e);
+            }
+        }
+        self.apply_unconfirmed(tx);
+        self.notify_status();
+    }
+
+    fn apply_unconfirmed(&mut self, tx: &Transaction) {
+        let txid = tx.txid();
+        let mut spent = Vec::new();
+        for input in &tx.input {
+            if let Some(utxo) = self.utxos.remove(&input.previous_output) {
+                self.record(&utxo.output.script_pubkey, txid, None, None);
+                spent.push(utxo);
+            }
+        }
+        self.add_outputs(tx, None, None);
+        self.record_tx(tx, &spent, None, None);
+        if !spent.is_empty() {
+            self.spent_unconfirmed.insert(txid, spent);
+        }
+    }
+
+    // unconfirmed transactions other than this one spending any of its inputs
+    fn conflicting(&self, tx: &Transaction) -> Vec<Sha256dHash> {
+        let txid = tx.txid();
+        let inputs = tx.input.iter().map(|input| input.previous_output).collect::<HashSet<_>>();
+        self.unconfirmed.iter().filter(|u| u.tx.txid() != txid && u.tx.input.iter().any(|input| inputs.contains(&input.previous_output)))
+            .map(|u| u.tx.txid()).collect()
+    }
+
+    fn is_expired(&self, unconfirmed: &UnconfirmedTx) -> bool {
+        self.tip >= unconfirmed.seen.saturating_add(self.expiry)
+    }
+
+    // an unconfirmed transaction and the unconfirmed transactions spending its outputs, parents first
+    fn descendants(&self, txid: &Sha256dHash) -> Vec<Sha256dHash> {
+        let mut evicted = vec!(*txid);
+        let mut next = 0;
+        while next < evicted.len() {
+            let parent = evicted[next];
+            for child in self.unconfirmed.iter().filter(|u| u.tx.input.iter().any(|input| input.previous_output.txid == parent)).map(|u| u.tx.txid()) {
+                if !evicted.contains(&child) {
+                    evicted.push(child);
+                }
+            }
+            next += 1;
+        }
+        evicted
+    }
+
+    // forget unconfirmed transactions not confirmed in time, with their descendants
+    fn expire(&mut self) {
+        let expired = self.unconfirmed.iter().filter(|u| self.is_expired(u)).map(|u| u.tx.txid()).collect::<Vec<_>>();
+        if expired.is_empty() {
+            return;
+        }
+        for txid in expired {
+            // gone already as descendant of an other expired transaction
+            if self.unconfirmed_transaction(&txid).is_none() {
+                continue;
+            }
+            for txid in self.descendants(&txid).iter().rev() {
+                info!("unconfirmed transaction {} expired", txid);
+                self.undo(txid);
+            }
+        }
+        if let Err(e) = self.store_unconfirmed() {
+            error!("can not store unconfirmed transactions: {}", e);
+        }
+        self.notify_status();
+    }
+
+    // undo an unconfirmed transaction double spent by a transaction of a block, and the unconfirmed
+    // transactions spending its outputs
+    fn replace(&mut self, txid: &Sha256dHash, replacement: &Transaction, block: Sha256dHash) {
+        let evicted = self.descendants(txid);
+        // children first, so that outputs of a parent they restore are removed with the parent
+        for txid in evicted.iter().rev() {
+            info!("unconfirmed transaction {} replaced by {}", txid, replacement.txid());
+            self.undo(txid);
+            self.events.publish(Event::TransactionReplaced { replaced: *txid, replacement: replacement.clone(), block });
+        }
+    }
+
+    // forget an unconfirmed transaction, restoring the outputs it spent
+    fn undo(&mut self, txid: &Sha256dHash) {
+        self.utxos.retain(|outpoint, utxo| !(outpoint.txid == *txid && utxo.height.is_none()));
+        if let Some(spent) = self.spent_unconfirmed.remove(txid) {
+            for utxo in spent {
+                self.utxos.insert(utxo.outpoint, utxo);
+            }
+        }
+        for history in self.history.values_mut() {
+            history.retain(|item| item.txid != *txid);
+        }
+        self.history.retain(|_, history| !history.is_empty());
+        self.txs.retain(|known| known.txid != *txid);
+        self.unconfirmed.retain(|known| known.tx.txid() != *txid);
+    }
+
+    /// transactions of the wallet, confirmed ones by height, then unconfirmed ones in order seen
+    pub fn transactions(&self) -> Vec<WalletTx> {
+        let mut txs = self.txs.clone();
+        txs.sort_by_key(|tx| tx.height.unwrap_or(u32::max_value()));
+        txs
+    }
+
+    /// The transaction history with the time of confirming blocks as unix time
+    pub fn export_history(&self, chaindb: &ChainDB, format: ExportFormat) -> String {
+        let rows = self.transactions().into_iter().map(|tx| {
+            let time = tx.block.and_then(|block| chaindb.get_header(&block)).map(|header| header.stored.header.time);
+            (tx, time)
+        }).collect::<Vec<_>>();
+        match format {
+            ExportFormat::Csv => {
+                let mut csv = "txid,height,block,time,received,sent,net,fee\n".to_string();
+                for (tx, time) in rows {
+                    csv.push_str(format!("{},{},{},{},{},{},{},{}\n", tx.txid, opt(tx.height), opt(tx.block), opt(time),
+                                         tx.received, tx.sent, tx.received as i64 - tx.sent as i64, opt(tx.fee)).as_str());
+                }
+                csv
+            },
+            ExportFormat::Json => {
+                let json = |value: Option<String>| value.unwrap_or("null".to_string());
+                let objects = rows.into_iter().map(|(tx, time)| format!(
+                    "{{\"txid\":\"{}\",\"height\":{},\"block\":{},\"time\":{},\"received\":{},\"sent\":{},\"net\":{},\"fee\":{}}}",
+                    tx.txid, json(tx.height.map(|h| h.to_string())), json(tx.block.map(|b| format!("\"{}\"", b))), json(time.map(|t| t.to_string())),
+                    tx.received, tx.sent, tx.received as i64 - tx.sent as i64, json(tx.fee.map(|f| f.to_string())))).collect::<Vec<_>>();
+                format!("[{}]", objects.join(","))
+            }
+        }
+    }
+
+    /// Fill in the outputs spent by inputs of the PSBT that are outputs of the wallet: witness_utxo
+    /// for segwit outputs, non_witness_utxo if the block of the transaction was downloaded. Returns
+    /// the status of the outputs spent in the order of inputs, None for inputs not of the wallet.
+    pub fn update_psbt(&self, psbt: &mut PartiallySignedTransaction, chaindb: &ChainDB) -> Result<Vec<Option<InputStatus>>, Error> {
+        let mut status = Vec::new();
+        for (txin, input) in psbt.global.unsigned_tx.input.iter().zip(psbt.inputs.iter_mut()) {
+            let utxo = match self.utxos.get(&txin.previous_output) {
+                Some(utxo) => utxo,
+                None => {
+                    status.push(None);
+                    continue;
+                }
+            };
+            let script = &utxo.output.script_pubkey;
+            if script.is_v0_p2wpkh() || script.is_v0_p2wsh() {
+                input.witness_utxo = Some(utxo.output.clone());
+            }
+            if let Some(block_id) = utxo.block {
+                if let Some(block) = chaindb.fetch_block(&block_id)? {
+                    input.non_witness_utxo = block.txdata.into_iter().find(|tx| tx.txid() == utxo.outpoint.txid);
+                }
+            }
+            status.push(Some(InputStatus { outpoint: utxo.outpoint, height: utxo.height, block: utxo.block, maturity: self.maturity(utxo) }));
+        }
+        Ok(status)
+    }
+
+    /// maturity of an output at the current trunk height
+    pub fn maturity(&self, utxo: &Utxo) -> Maturity {
+        match utxo.height {
+            None => Maturity::Unconfirmed,
+            Some(height) => {
+                let confirmations = (self.tip + 1).saturating_sub(height);
+                if utxo.coinbase && confirmations < COINBASE_MATURITY {
+                    Maturity::Immature(confirmations)
+                } else {
+                    Maturity::Confirmed(confirmations)
+                }
+            }
+        }
+    }
+
+    /// unspent outputs with their maturity
+    pub fn utxos(&self) -> Vec<(Utxo, Maturity)> {
+        self.utxos.values().map(|u| (u.clone(), self.maturity(u))).collect()
+    }
+
+    /// total and spendable balance
+    pub fn balance(&self) -> Balance {
+        let mut balance = Balance::default();
+        for utxo in self.utxos.values() {
+            balance.total += utxo.output.value;
+            if let Maturity::Confirmed(_) = self.maturity(utxo) {
+                balance.spendable += utxo.output.value;
+            }
+        }
+        balance
+    }
+
+    fn add_outputs(&mut self, tx: &Transaction, height: Option<u32>, block: Option<Sha256dHash>) {
+        let txid = tx.txid();
+        // warn only as the transaction is first seen, not again as it confirms
+        let first_seen = !self.txs.iter().any(|known| known.txid == txid);
+        for (vout, output) in tx.output.iter().enumerate() {
+            if self.scripts.contains(&output.script_pubkey) {
+                let outpoint = OutPoint { txid, vout: vout as u32 };
+                let reused = self.history.get(&output.script_pubkey).map_or(false, |history| history.iter().any(|item| item.txid != txid));
+                if first_seen && reused {
+                    warn!("transaction {} pays again to a script of the wallet", txid);
+                    self.events.publish(Event::AddressReuse { script: output.script_pubkey.clone(), txid });
+                }
+                if first_seen && output.value < self.dust_threshold {
+                    warn!("dust output {}:{} of {} satoshis received", txid, vout, output.value);
+                    self.events.publish(Event::DustReceived { outpoint, value: output.value });
+                }
+                self.utxos.insert(outpoint, Utxo { outpoint, output: output.clone(), height, block, coinbase: tx.is_coin_base() });
+                self.record(&output.script_pubkey, txid, height, block);
+            }
+        }
+    }
+
+    // tell subscribers of script hashes whose status changed
+    fn notify_status(&mut self) {
+        let hashes = self.subscriptions.keys().cloned().collect::<Vec<_>>();
+        for hash in hashes {
+            let status = self.status(&hash);
+            if let Some(subscription) = self.subscriptions.get_mut(&hash) {
+                if subscription.1 != status {
+                    subscription.1 = status;
+                    self.events.publish(Event::ScriptHashStatus { hash, status });
+                }
+            }
+        }
+    }
+
+    // a transaction seen again is updated with its confirmation
+    fn record(&mut self, script: &Script, txid: Sha256dHash, height: Option<u32>, block: Option<Sha256dHash>) {
+        let history = self.history.entry(script.clone()).or_insert_with(Vec::new);
+        if let Some(item) = history.iter_mut().find(|item| item.txid == txid) {
+            item.height = height;
+            item.block = block;
+        } else {
+            history.push(HistoryItem { txid, height, block });
+        }
+    }
+
+    // a transaction seen before keeps its amounts and is updated with its confirmation
+    fn record_tx(&mut self, tx: &Transaction, spent: &[Utxo], height: Option<u32>, block: Option<Sha256dHash>) {
+        let txid = tx.txid();
+        if let Some(known) = self.txs.iter_mut().find(|known| known.txid == txid) {
+            known.height = height;
+            known.block = block;
+            return;
+        }
+        let received = tx.output.iter().filter(|o| self.scripts.contains(&o.script_pubkey)).map(|o| o.value).sum::<u64>();
+        let sent = spent.iter().map(|u| u.output.value).sum::<u64>();
+        if received == 0 && sent == 0 {
+            return;
+        }
+        let fee = if !tx.is_coin_base() && spent.len() == tx.input.len() {
+            sent.checked_sub(tx.output.iter().map(|o| o.value).sum::<u64>())
+        } else {
+            None
+        };
+        self.txs.push(WalletTx { txid, height, block, received, sent, fee });
+    }
+
+    fn connect(&mut self, block: &Block, height: u32) {
+        let block_id = block.bitcoin_hash();
+        let unconfirmed = self.unconfirmed.len();
+        for tx in &block.txdata {
+            for replaced in self.conflicting(tx) {
+                self.replace(&replaced, tx, block_id);
+            }
+        }
+        let mut spent = Vec::new();
+        for tx in &block.txdata {
+            let mut spent_by_tx = Vec::new();
+            if !tx.is_coin_base() {
+                let txid = tx.txid();
+                for input in &tx.input {
+                    if let Some(utxo) = self.utxos.remove(&input.previous_output) {
+                        self.record(&utxo.output.script_pubkey, txid, Some(height), Some(block_id));
+                        spent_by_tx.push(utxo);
+                    }
+                }
+            }
+            self.add_outputs(tx, Some(height), Some(block_id));
+            self.record_tx(tx, &spent_by_tx, Some(height), Some(block_id));
+            if !tx.is_coin_base() && self.txs.iter().any(|known| known.txid == tx.txid()) {
+                self.confirmed.entry(block_id).or_insert_with(Vec::new).push(tx.clone());
+            }
+            spent.extend(spent_by_tx);
+        }
+        if !spent.is_empty() {
+            self.spent.insert(block_id, spent);
+        }
+        self.tip = self.tip.max(height);
+        // unconfirmed transactions confirmed by the block are no longer kept
+        if !self.unconfirmed.is_empty() {
+            let txids = block.txdata.iter().map(|tx| tx.txid()).collect::<HashSet<_>>();
+            self.unconfirmed.retain(|u| !txids.contains(&u.tx.txid()));
+            self.spent_unconfirmed.retain(|txid, _| !txids.contains(txid));
+        }
+        if self.unconfirmed.len() != unconfirmed {
+            if let Err(e) = self.store_unconfirmed() {
+                error!("can not store unconfirmed transactions: {}", e);
+            }
+        }
+    }
+}
+
+impl Downstream for Wallet {
+    fn block_connected(&mut self, block: &Block, height: u32) {
+        self.connect(block, height);
+        self.notify_status();
+    }
+
+    fn header_connected(&mut self, _header: &BlockHeader, height: u32) {
+        self.tip = height;
+        self.expire();
+    }
+
+    // transactions of the block are unconfirmed again, those of its coinbase are gone
+    fn block_disconnected(&mut self, header: &BlockHeader) {
+        let block_id = header.bitcoin_hash();
+        let coinbase = self.utxos.values().filter(|u| u.coinbase && u.block == Some(block_id)).map(|u| u.outpoint.txid).collect::<HashSet<_>>();
+        self.utxos.retain(|_, u| !(u.coinbase && u.block == Some(block_id)));
+        self.txs.retain(|tx| !coinbase.contains(&tx.txid));
+        for history in self.history.values_mut() {
+            for item in history.iter_mut().filter(|item| item.block == Some(block_id)) {
+                item.height = None;
+                item.block = None;
+            }
+        }
+        for tx in self.txs.iter_mut().filter(|tx| tx.block == Some(block_id)) {
+            tx.height = None;
+            tx.block = None;
+        }
+        for utxo in self.utxos.values_mut().filter(|u| u.block == Some(block_id)) {
+            utxo.height = None;
+            utxo.block = None;
+        }
+        if let Some(spent) = self.spent.remove(&block_id) {
+            for utxo in spent {
+                self.utxos.insert(utxo.outpoint, utxo);
+            }
+        }
+        if let Some(txs) = self.confirmed.remove(&block_id) {
+            for tx in txs {
+                // outputs the transaction spent stay spent while it is unconfirmed
+                let txid = tx.txid();
+                let spent = tx.input.iter().filter_map(|input| self.utxos.remove(&input.previous_output)).collect::<Vec<_>>();
+                if !spent.is_empty() {
+                    self.spent_unconfirmed.insert(txid, spent);
+                }
+                if !self.unconfirmed.iter().any(|known| known.tx.txid() == txid) {
+                    self.unconfirmed.push(UnconfirmedTx { tx, seen: self.tip });
+                }
+            }
+            if let Err(e) = self.store_unconfirmed() {
+                error!("can not store unconfirmed transactions: {}", e);
+            }
+        }
+        self.tip = self.tip.saturating_sub(1);
+        self.notify_status();
+    }
+}
+
+/// Named wallets with the events of each
+#[derive(Default)]
+pub struct Wallets {
+    wallets: HashMap<String, (SharedWallet, Subscribers<Event>)>
+}
+
+impl Wallets {
+    pub fn new() -> Wallets {
+        Wallets { wallets: HashMap::new() }
+    }
+
+    /// add a wallet publishing to its own events
+    pub fn add(&mut self, wallet: Wallet, events: Subscribers<Event>) -> SharedWallet {
+        let name = wallet.name.clone();
+        let wallet = Arc::new(Mutex::new(wallet));
+        self.wallets.insert(name, (wallet.clone(), events));
+        wallet
+    }
+
+    /// the wallet of the name
+    pub fn get(&self, name: &str) -> Option<SharedWallet> {
+        self.wallets.get(name).map(|(wallet, _)| wallet.clone())
+    }
+
+    /// events of the wallet of the name
+    pub fn events(&self, name: &str) -> Option<Subscribers<Event>> {
+        self.wallets.get(name).map(|(_, events)| events.clone())
+    }
+
+    /// names of the wallets
+    pub fn names(&self) -> Vec<String> {
+        self.wallets.keys().cloned().collect()
+    }
+}
+
+impl Downstream for Wallets {
+    fn block_connected(&mut self, block: &Block, height: u32) {
+        for (wallet, _) in self.wallets.values() {
+            wallet.lock().recover().block_connected(block, height);
+        }
+    }
+
+    fn header_connected(&mut self, header: &BlockHeader, height: u32) {
+        for (wallet, _) in self.wallets.values() {
+            wallet.lock().recover().header_connected(header, height);
+        }
+    }
+
+    fn block_disconnected(&mut self, header: &BlockHeader) {
+        for (wallet, _) in self.wallets.values() {
+            wallet.lock().recover().block_disconnected(header);
+        }
+    }
+}
+
+/// Electrum's script hash, the sha256 of the script
+pub fn script_hash(script: &Script) -> sha256::Hash {
+    sha256::Hash::hash(script.as_bytes())
+}
+
+// empty if None, for CSV
+fn opt<T: ToString>(value: Option<T>) -> String {
+    value.map(|v| v.to_string()).unwrap_or_default()
+}
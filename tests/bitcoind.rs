@@ -0,0 +1,215 @@
+//
+// Copyright 2019 Tamas Blummer
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+// http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+//
+//!
+//! # Conformance with Bitcoin Core
+//!
+//! Run murmel against a regtest bitcoind through handshake, header sync, broadcast, reorg and
+//! block download. The binaries are taken from BITCOIND_EXE and BITCOIN_CLI_EXE or the path,
+//! the test is skipped if they are not found. Compact filters are only served by bitcoind
+//! version 0.21 and later, block download is not tested with earlier versions.
+//!
+
+extern crate bitcoin;
+extern crate hex;
+extern crate murmel;
+extern crate tempfile;
+
+use bitcoin::{
+    Script, Transaction,
+    consensus::deserialize,
+    network::constants::Network
+};
+use murmel::{
+    constructor::Constructor,
+    syncconfig::SyncConfig
+};
+use std::{
+    env,
+    net::{SocketAddr, TcpListener},
+    process::{Child, Command, Stdio},
+    thread,
+    time::{Duration, Instant}
+};
+use tempfile::TempDir;
+
+// longest wait for murmel to follow bitcoind
+const PATIENCE: Duration = Duration::from_secs(60);
+
+struct Bitcoind {
+    process: Child,
+    datadir: TempDir,
+    cli: String,
+    port: u16,
+    rpcport: u16,
+    // major and minor version
+    version: (u32, u32)
+}
+
+impl Bitcoind {
+    // start a regtest node in a temporary directory, None if there is no bitcoind
+    fn start() -> Option<Bitcoind> {
+        let exe = env::var("BITCOIND_EXE").unwrap_or("bitcoind".to_string());
+        let cli = env::var("BITCOIN_CLI_EXE").unwrap_or("bitcoin-cli".to_string());
+        let output = Command::new(&exe).arg("-version").output().ok()?;
+        Command::new(&cli).arg("-version").output().ok()?;
+        let version = parse_version(&String::from_utf8_lossy(&output.stdout))?;
+
+        let datadir = tempfile::tempdir().unwrap();
+        let port = free_port();
+        let rpcport = free_port();
+        let mut command = Command::new(&exe);
+        command.arg("-regtest").arg("-server").arg("-listen").arg("-fallbackfee=0.0001")
+            .arg(format!("-datadir={}", datadir.path().display()))
+            .arg(format!("-port={}", port))
+            .arg(format!("-rpcport={}", rpcport))
+            .stdout(Stdio::null());
+        if supports_filters(version) {
+            command.arg("-blockfilterindex").arg("-peerblockfilters");
+        }
+        let process = command.spawn().unwrap();
+        let bitcoind = Bitcoind { process, datadir, cli, port, rpcport, version };
+        bitcoind.cli(&["-rpcwait", "getblockcount"]);
+        Some(bitcoind)
+    }
+
+    // call RPC, panic on error
+    fn cli(&self, args: &[&str]) -> String {
+        let output = Command::new(&self.cli)
+            .arg("-regtest")
+            .arg(format!("-datadir={}", self.datadir.path().display()))
+            .arg(format!("-rpcport={}", self.rpcport))
+            .arg("-rpcwallet=murmel")
+            .args(args)
+            .output().unwrap();
+        if !output.status.success() {
+            panic!("bitcoin-cli {:?} failed: {}", args, String::from_utf8_lossy(&output.stderr));
+        }
+        String::from_utf8_lossy(&output.stdout).trim().to_string()
+    }
+
+    fn best_block(&self) -> (u32, String) {
+        (self.cli(&["getblockcount"]).parse().unwrap(), self.cli(&["getbestblockhash"]))
+    }
+
+    fn generate(&self, n: u32, address: &str) {
+        self.cli(&["generatetoaddress", n.to_string().as_str(), address]);
+    }
+}
+
+impl Drop for Bitcoind {
+    fn drop(&mut self) {
+        if Command::new(&self.cli)
+            .arg("-regtest")
+            .arg(format!("-datadir={}", self.datadir.path().display()))
+            .arg(format!("-rpcport={}", self.rpcport))
+            .arg("stop").output().is_err() {
+            self.process.kill().ok();
+        }
+        self.process.wait().ok();
+    }
+}
+
+// parse e.g. "Bitcoin Core Daemon version v0.21.1" or "Bitcoin Core version v22.0.0"
+fn parse_version(s: &str) -> Option<(u32, u32)> {
+    let v = s.lines().next()?.rsplit(' ').next()?.trim_start_matches('v');
+    let mut parts = v.split(|c: char| !c.is_ascii_digit());
+    Some((parts.next()?.parse().ok()?, parts.next()?.parse().ok()?))
+}
+
+fn supports_filters(version: (u32, u32)) -> bool {
+    version.0 > 0 || version.1 >= 21
+}
+
+fn free_port() -> u16 {
+    TcpListener::bind("127.0.0.1:0").unwrap().local_addr().unwrap().port()
+}
+
+// string value of a key in JSON output of bitcoin-cli
+fn json_str(json: &str, key: &str) -> String {
+    let pattern = format!("\"{}\": \"", key);
+    let start = json.find(pattern.as_str()).unwrap_or_else(|| panic!("no {} in {}", key, json)) + pattern.len();
+    json[start..].split('"').next().unwrap().to_string()
+}
+
+fn wait_for<F: Fn() -> bool>(what: &str, f: F) {
+    let start = Instant::now();
+    while !f() {
+        if start.elapsed() > PATIENCE {
+            panic!("timeout waiting for {}", what);
+        }
+        thread::sleep(Duration::from_millis(200));
+    }
+}
+
+#[test]
+fn conformance_with_bitcoind() {
+    let bitcoind = match Bitcoind::start() {
+        Some(bitcoind) => bitcoind,
+        None => {
+            println!("bitcoind not found, skipping conformance test");
+            return;
+        }
+    };
+    println!("testing with bitcoind {}.{}", bitcoind.version.0, bitcoind.version.1);
+
+    // a wallet for bitcoind to mine to and sign with
+    bitcoind.cli(&["createwallet", "murmel"]);
+    let miner = bitcoind.cli(&["getnewaddress"]);
+    bitcoind.generate(101, &miner);
+
+    // a transaction murmel broadcasts, signed but not sent by bitcoind
+    let payee = bitcoind.cli(&["getnewaddress"]);
+    let psbt = json_str(&bitcoind.cli(&["walletcreatefundedpsbt", "[]", format!("{{\"{}\":1}}", payee).as_str()]), "psbt");
+    let psbt = json_str(&bitcoind.cli(&["walletprocesspsbt", psbt.as_str()]), "psbt");
+    let hex = json_str(&bitcoind.cli(&["finalizepsbt", psbt.as_str()]), "hex");
+    let tx: Transaction = deserialize(&hex::decode(hex).unwrap()).unwrap();
+    let txid = tx.txid().to_string();
+
+    let chaindb = Constructor::open_db(None, Network::Regtest, 0).unwrap();
+    let configdb = Constructor::open_config_db(None).unwrap();
+    let constructor = Constructor::new(Network::Regtest, vec!(), chaindb, configdb, SyncConfig::default()).unwrap();
+    let chain = constructor.chain_view();
+    let wallet = constructor.wallet();
+    constructor.broadcast(tx);
+    let peer = SocketAddr::from(([127, 0, 0, 1], bitcoind.port));
+    let _node = constructor.start(Network::Regtest, vec!(peer), 1).unwrap();
+
+    // handshake and header sync
+    let follows = || {
+        let (height, hash) = bitcoind.best_block();
+        chain.tip().map(|(h, id)| h == height && id.to_string() == hash).unwrap_or(false)
+    };
+    wait_for("header sync", &follows);
+
+    // broadcast
+    wait_for("broadcast", || bitcoind.cli(&["getrawmempool"]).contains(txid.as_str()));
+
+    // reorg to a longer fork of the tip's parent
+    let (_, tip) = bitcoind.best_block();
+    bitcoind.cli(&["invalidateblock", tip.as_str()]);
+    bitcoind.generate(2, &miner);
+    assert_eq!(bitcoind.best_block().0, 102);
+    wait_for("reorg", &follows);
+
+    // block download of a block matching the wallet's filter
+    if supports_filters(bitcoind.version) {
+        let address = bitcoind.cli(&["getnewaddress"]);
+        let script = json_str(&bitcoind.cli(&["getaddressinfo", address.as_str()]), "scriptPubKey");
+        wallet.lock().unwrap().add_script(Script::from(hex::decode(script).unwrap())).unwrap();
+        bitcoind.generate(1, &address);
+        wait_for("block download", || wallet.lock().unwrap().balance().total > 0);
+    }
+}
@@ -0,0 +1,100 @@
+//
+// Copyright 2019 Tamas Blummer
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+// http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+//
+//!
+//! # Headers across a restart
+//!
+//! Headers of the trunk are stored in chunks of a retarget period once buried, those after the
+//! last complete chunk one by one. All of them are read again after a restart. A bundle of
+//! headers ending within a header is reported after the complete ones were imported. A chain
+//! corrupted down to its genesis starts over from genesis.
+//!
+
+extern crate bitcoin;
+extern crate murmel;
+extern crate tempfile;
+
+mod common;
+
+use bitcoin::{
+    BitcoinHash,
+    blockdata::constants::genesis_block,
+    consensus::serialize,
+    network::constants::Network
+};
+use common::mine;
+use murmel::{chaindb::ChainDB, constructor::Constructor};
+
+// past the first chunk and buried, but the second chunk is not complete
+const BLOCKS: u32 = 2400;
+
+#[test]
+fn restart_with_partial_chunk() {
+    let dir = tempfile::tempdir().unwrap();
+    let path = dir.path().join("chain");
+    let headers = mine(BLOCKS);
+    let tip = headers.last().unwrap().bitcoin_hash();
+    {
+        let chaindb = Constructor::open_db(Some(path.as_path()), Network::Regtest, 0).unwrap();
+        let mut chaindb = chaindb.write().unwrap();
+        // regtest headers keep their difficulty over the retarget
+        chaindb.set_assume_valid(Some((BLOCKS, tip)));
+        for (i, header) in headers.iter().enumerate() {
+            chaindb.add_header(header).unwrap();
+            if i % 500 == 499 {
+                chaindb.batch().unwrap();
+            }
+        }
+        chaindb.batch().unwrap();
+    }
+    let chaindb = Constructor::open_db(Some(path.as_path()), Network::Regtest, 0).unwrap();
+    let mut chaindb = chaindb.write().unwrap();
+    assert!(chaindb.take_recovered().is_empty());
+    let stored = chaindb.header_tip().unwrap();
+    assert_eq!(stored.stored.height, BLOCKS);
+    assert_eq!(stored.bitcoin_hash(), tip);
+    assert_eq!(chaindb.get_header_for_height(2100).map(|h| h.bitcoin_hash()), Some(headers[2099].bitcoin_hash()));
+}
+
+#[test]
+fn truncated_bundle() {
+    let headers = mine(10);
+    let mut bundle = headers.iter().flat_map(|header| serialize(header)).collect::<Vec<u8>>();
+    bundle.extend_from_slice(&serialize(&mine(11)[10])[..40]);
+    let chaindb = Constructor::open_db(None, Network::Regtest, 0).unwrap();
+    let mut chaindb = chaindb.write().unwrap();
+    assert!(chaindb.import_headers(bundle.as_slice()).is_err());
+    assert_eq!(chaindb.header_tip().unwrap().stored.height, 10);
+}
+
+#[test]
+fn corrupted_genesis_recovered() {
+    let blob = {
+        let chaindb = Constructor::open_db(None, Network::Regtest, 0).unwrap();
+        let mut chaindb = chaindb.write().unwrap();
+        for header in &mine(10) {
+            chaindb.add_header(header).unwrap();
+        }
+        chaindb.batch().unwrap();
+        chaindb.export().unwrap()
+    };
+    // the genesis stored is not that of the network
+    let mut chaindb = ChainDB::mem_from(Network::Testnet, blob.as_slice()).unwrap();
+    chaindb.init().unwrap();
+    assert!(!chaindb.take_recovered().is_empty());
+    let tip = chaindb.header_tip().unwrap();
+    assert_eq!(tip.stored.height, 0);
+    assert_eq!(tip.bitcoin_hash(), genesis_block(Network::Testnet).bitcoin_hash());
+}
@@ -0,0 +1,172 @@
+//
+// Copyright 2019 Tamas Blummer
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+// http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+//
+//!
+//! # Helpers shared by the tests
+//!
+//! Regtest headers mined on genesis, fixtures of scripts and transactions, and a peer speaking
+//! to murmel over a socket.
+//!
+
+#![allow(dead_code)]
+
+use bitcoin::{
+    BitcoinHash,
+    blockdata::{
+        block::BlockHeader,
+        constants::genesis_block,
+        script::Script,
+        transaction::{OutPoint, Transaction, TxIn, TxOut}
+    },
+    consensus::serialize,
+    network::{
+        address::Address,
+        constants::Network,
+        message::{NetworkMessage, RawNetworkMessage},
+        message_network::VersionMessage
+    }
+};
+use murmel::p2p::{Buffer, decode_message};
+use std::{
+    io::{self, Read, Write},
+    net::{SocketAddr, TcpListener, TcpStream},
+    thread,
+    time::{Duration, SystemTime, UNIX_EPOCH}
+};
+
+// longest wait for a reply
+pub const PATIENCE: Duration = Duration::from_secs(30);
+
+/// find a nonce for the header to meet the regtest target
+pub fn solve(header: &mut BlockHeader) {
+    // regtest target, the most significant byte of the hash below 0x7f is enough
+    while header.bitcoin_hash()[..][31] >= 0x7f {
+        header.nonce += 1;
+    }
+}
+
+/// regtest headers on genesis, ten minutes apart
+pub fn mine(count: u32) -> Vec<BlockHeader> {
+    let genesis = genesis_block(Network::Regtest).header;
+    let mut headers = Vec::new();
+    let mut header = genesis;
+    for height in 1..=count {
+        header = BlockHeader { prev_blockhash: header.bitcoin_hash(), time: genesis.time + 600 * height, nonce: 0, ..header };
+        solve(&mut header);
+        headers.push(header);
+    }
+    headers
+}
+
+/// a P2WPKH script of a key hash of n only
+pub fn script(n: u8) -> Script {
+    Script::from(vec!(0x00, 0x14, n, n, n, n, n, n, n, n, n, n, n, n, n, n, n, n, n, n, n, n))
+}
+
+/// a transaction spending the outpoints and paying the scripts
+pub fn tx(spends: Vec<OutPoint>, pays: Vec<(Script, u64)>) -> Transaction {
+    Transaction {
+        version: 2,
+        lock_time: 0,
+        input: spends.into_iter().map(|previous_output| TxIn { previous_output, script_sig: Script::new(), sequence: 0xffff_fffd, witness: vec!() }).collect(),
+        output: pays.into_iter().map(|(script_pubkey, value)| TxOut { value, script_pubkey }).collect()
+    }
+}
+
+/// a port no one listens at
+pub fn free_port() -> u16 {
+    TcpListener::bind("127.0.0.1:0").unwrap().local_addr().unwrap().port()
+}
+
+/// a peer connected to murmel
+pub struct Client {
+    pub stream: TcpStream,
+    buffer: Buffer,
+    /// services murmel announced
+    pub services: u64
+}
+
+impl Client {
+    /// connect murmel and complete the handshake, murmel might still be starting
+    pub fn connect(addr: SocketAddr, user_agent: &str, relay: bool) -> Client {
+        let mut tries = 0;
+        let stream = loop {
+            match TcpStream::connect(addr) {
+                Ok(stream) => break stream,
+                Err(e) => {
+                    tries += 1;
+                    if tries > 50 {
+                        panic!("can not connect murmel: {}", e);
+                    }
+                    thread::sleep(Duration::from_millis(100));
+                }
+            }
+        };
+        stream.set_read_timeout(Some(PATIENCE)).unwrap();
+        let mut client = Client { stream, buffer: Buffer::new(), services: 0 };
+        let timestamp = SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_secs() as i64;
+        client.send(NetworkMessage::Version(VersionMessage {
+            version: 70013,
+            services: 0,
+            timestamp,
+            receiver: Address::new(&addr, 0),
+            sender: Address::new(&SocketAddr::from(([0, 0, 0, 0], 0)), 0),
+            nonce: 4711,
+            user_agent: user_agent.to_string(),
+            start_height: 0,
+            relay
+        }));
+        let mut version = false;
+        let mut verack = false;
+        while !(version && verack) {
+            match client.receive().expect("murmel closed the connection during handshake") {
+                NetworkMessage::Version(v) => {
+                    client.services = v.services;
+                    client.send(NetworkMessage::Verack);
+                    version = true;
+                },
+                NetworkMessage::Verack => verack = true,
+                _ => {}
+            }
+        }
+        client
+    }
+
+    pub fn send(&mut self, message: NetworkMessage) {
+        self.stream.write_all(serialize(&RawNetworkMessage { magic: Network::Regtest.magic(), payload: message }).as_slice()).unwrap();
+    }
+
+    /// next message, None if murmel closed the connection, an error if it was silent for the
+    /// read timeout
+    pub fn read(&mut self) -> Result<Option<NetworkMessage>, io::Error> {
+        loop {
+            if let Some(message) = decode_message(&mut self.buffer)? {
+                return Ok(Some(message.payload));
+            }
+            let mut data = [0u8; 65536];
+            match self.stream.read(&mut data) {
+                Ok(0) => return Ok(None),
+                Ok(len) => self.buffer.write_all(&data[..len])?,
+                Err(ref e) if e.kind() == io::ErrorKind::ConnectionReset => return Ok(None),
+                Err(e) => return Err(e)
+            }
+        }
+    }
+
+    /// next message, None if murmel closed the connection
+    pub fn receive(&mut self) -> Option<NetworkMessage> {
+        self.read().unwrap_or_else(|e| panic!("no reply from murmel: {}", e))
+    }
+}
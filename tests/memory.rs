@@ -0,0 +1,90 @@
+//
+// Copyright 2019 Tamas Blummer
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+// http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+//
+//!
+//! # In-memory stack
+//!
+//! A stack constructed in memory from an exported state rescans its wallet from the filters and
+//! blocks stored, and exports a state to resume from again.
+//!
+
+extern crate bitcoin;
+extern crate bitcoin_hashes;
+extern crate murmel;
+
+mod common;
+
+use bitcoin::{
+    BitcoinHash,
+    blockdata::{
+        block::{Block, BlockHeader},
+        constants::genesis_block,
+        script::Script,
+        transaction::OutPoint
+    },
+    consensus::serialize,
+    network::constants::Network
+};
+use bitcoin_hashes::sha256d::Hash as Sha256dHash;
+use common::{script, solve, tx};
+use murmel::{
+    chaindb::ChainDB,
+    configdb::ConfigDB,
+    constructor::Constructor,
+    syncconfig::SyncConfig
+};
+
+// a regtest block on genesis paying the script
+fn block(pays: &Script) -> Block {
+    let genesis = genesis_block(Network::Regtest).header;
+    let coinbase = tx(vec!(OutPoint::null()), vec!((script(0xee), 50)));
+    let payment = tx(vec!(OutPoint { txid: Sha256dHash::default(), vout: 0 }), vec!((pays.clone(), 100_000)));
+    let mut block = Block {
+        header: BlockHeader { version: 1, prev_blockhash: genesis.bitcoin_hash(), merkle_root: Sha256dHash::default(), time: genesis.time + 600, bits: genesis.bits, nonce: 0 },
+        txdata: vec!(coinbase, payment)
+    };
+    block.header.merkle_root = block.merkle_root();
+    solve(&mut block.header);
+    block
+}
+
+#[test]
+fn rescan_from_stored_filters() {
+    let wallet_script = script(1);
+    let block = block(&wallet_script);
+    let id = block.bitcoin_hash();
+    let state = {
+        let mut chaindb = ChainDB::mem(Network::Regtest).unwrap();
+        chaindb.init().unwrap();
+        chaindb.add_header(&block.header).unwrap();
+        chaindb.store_filter(&id, vec!(1, 2, 3), true).unwrap();
+        chaindb.store_block(&block).unwrap();
+        chaindb.store_block_tip(&id).unwrap();
+        let mut configdb = ConfigDB::mem().unwrap();
+        configdb.store_wallet_scripts("", vec!(wallet_script.clone())).unwrap();
+        serialize(&(chaindb.export().unwrap(), configdb.export().unwrap()))
+    };
+
+    let payment = OutPoint { txid: block.txdata[1].txid(), vout: 0 };
+    let constructor = Constructor::new_in_memory(Network::Regtest, vec!(), Some(state.as_slice()), SyncConfig::default()).unwrap();
+    assert_eq!(constructor.chain_view().tip(), Some((1, id)));
+    assert_eq!(constructor.wallet().lock().unwrap().utxos().into_iter().map(|(u, _)| u.outpoint).collect::<Vec<_>>(), vec!(payment));
+
+    // resumed again from the state the stack exports
+    let state = constructor.export_state().unwrap();
+    let resumed = Constructor::new_in_memory(Network::Regtest, vec!(), Some(state.as_slice()), SyncConfig::default()).unwrap();
+    assert_eq!(resumed.chain_view().tip(), Some((1, id)));
+    assert_eq!(resumed.wallet().lock().unwrap().utxos().into_iter().map(|(u, _)| u.outpoint).collect::<Vec<_>>(), vec!(payment));
+}
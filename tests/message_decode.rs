@@ -0,0 +1,100 @@
+//
+// Copyright 2019 Tamas Blummer
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+// http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+//
+//!
+//! # Regression corpus of message decoding
+//!
+//! Malformed input found by fuzzing or seen from peers, decoding must fail without panic.
+//!
+
+extern crate bitcoin;
+extern crate murmel;
+
+use bitcoin::{
+    consensus::serialize,
+    network::{
+        constants::Network,
+        message::{NetworkMessage, RawNetworkMessage}
+    }
+};
+use murmel::p2p::{Buffer, decode_message, MAX_MESSAGE_SIZE};
+use std::io::Write;
+
+fn ping() -> Vec<u8> {
+    serialize(&RawNetworkMessage { magic: Network::Bitcoin.magic(), payload: NetworkMessage::Ping(42) })
+}
+
+// decode all messages, stop at the first error
+fn decode_all(data: &[u8]) -> Result<Vec<RawNetworkMessage>, ()> {
+    let mut buffer = Buffer::new();
+    buffer.write_all(data).unwrap();
+    let mut messages = Vec::new();
+    loop {
+        match decode_message(&mut buffer) {
+            Ok(Some(message)) => messages.push(message),
+            Ok(None) => return Ok(messages),
+            Err(_) => return Err(())
+        }
+    }
+}
+
+#[test]
+fn decodes_messages_in_pieces() {
+    let mut data = ping();
+    data.extend(ping());
+    let mut buffer = Buffer::new();
+    let mut messages = Vec::new();
+    for byte in &data {
+        buffer.write_all(&[*byte]).unwrap();
+        while let Some(message) = decode_message(&mut buffer).unwrap() {
+            messages.push(message);
+        }
+    }
+    assert_eq!(messages.len(), 2);
+    assert_eq!(messages[0].payload, NetworkMessage::Ping(42));
+}
+
+#[test]
+fn truncated_message_needs_more_data() {
+    let data = ping();
+    assert_eq!(decode_all(&data[..data.len() - 1]).unwrap().len(), 0);
+    assert_eq!(decode_all(&data[..10]).unwrap().len(), 0);
+}
+
+#[test]
+fn bad_checksum_is_error() {
+    let mut data = ping();
+    data[20] ^= 0xff;
+    assert!(decode_all(&data).is_err());
+}
+
+#[test]
+fn oversized_payload_is_error() {
+    let mut data = ping();
+    let len = (MAX_MESSAGE_SIZE as u32 + 1).to_le_bytes();
+    data[16..20].copy_from_slice(&len);
+    assert!(decode_all(&data[..24]).is_err());
+}
+
+#[test]
+fn garbage_is_error() {
+    // empty command and payload with a zero checksum
+    assert!(decode_all(&[0u8; 24]).is_err());
+    // ping with a payload too short for its nonce
+    let mut data = ping();
+    data[16] = 4;
+    data.truncate(data.len() - 4);
+    assert!(decode_all(&data).is_err());
+}
@@ -0,0 +1,174 @@
+//
+// Copyright 2019 Tamas Blummer
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+// http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+//
+//!
+//! # Serving filters to Neutrino clients
+//!
+//! A client speaking to murmel as Neutrino does, e.g. within lnd, checks the replies to its
+//! BIP157 requests the way Neutrino relies on them: service bits, checkpoints, ranges of filter
+//! headers and filters in block order, the largest ranges allowed and disconnect on requests
+//! that can not be served.
+//!
+
+extern crate bitcoin;
+extern crate bitcoin_hashes;
+extern crate murmel;
+
+mod common;
+
+use bitcoin::{
+    BitcoinHash,
+    blockdata::{block::BlockHeader, constants::genesis_block},
+    consensus::serialize,
+    network::{
+        constants::Network,
+        message::NetworkMessage,
+        message_filter::{GetCFCheckpt, GetCFHeaders, GetCFilters}
+    }
+};
+use bitcoin_hashes::{Hash, sha256d::Hash as Sha256dHash};
+use common::{Client, free_port, solve};
+use murmel::{
+    chaindb::SharedChainDB,
+    constructor::Constructor,
+    filterheaderdownload::filter_header,
+    filterserver::{FilterServePolicy, MAX_FILTERS, MAX_FILTER_HEADERS},
+    p2p::SERVICE_FILTERS,
+    syncconfig::SyncConfig
+};
+use std::net::SocketAddr;
+
+// blocks above genesis, below the first difficulty adjustment
+const BLOCKS: u32 = 2010;
+
+// a block of the test chain with its made up filter
+struct Block {
+    id: Sha256dHash,
+    filter: Vec<u8>,
+    filter_header: Sha256dHash
+}
+
+// mine regtest headers on genesis and store a filter with its filter header for every block
+fn chain(chaindb: &SharedChainDB) -> Vec<Block> {
+    let mut chaindb = chaindb.write().unwrap();
+    let genesis = genesis_block(Network::Regtest).header;
+    let mut blocks = Vec::new();
+    let mut header = genesis;
+    let mut previous = Sha256dHash::default();
+    for height in 0..=BLOCKS {
+        if height > 0 {
+            header = BlockHeader { prev_blockhash: header.bitcoin_hash(), time: genesis.time + 600 * height, nonce: 0, ..header };
+            solve(&mut header);
+            chaindb.add_header(&header).unwrap();
+        }
+        let id = header.bitcoin_hash();
+        let filter = serialize(&height);
+        let filter_header = filter_header(&Sha256dHash::hash(filter.as_slice()), &previous);
+        chaindb.store_filter(&id, filter.clone(), false).unwrap();
+        chaindb.store_filter_header(&id, &filter_header).unwrap();
+        previous = filter_header;
+        blocks.push(Block { id, filter, filter_header });
+    }
+    chaindb.batch().unwrap();
+    blocks
+}
+
+// a peer as Neutrino
+impl Client {
+    // next message of interest, answering what murmel asks on the way
+    fn next(&mut self) -> Option<NetworkMessage> {
+        loop {
+            match self.receive()? {
+                NetworkMessage::Ping(nonce) => self.send(NetworkMessage::Pong(nonce)),
+                NetworkMessage::GetHeaders(_) => self.send(NetworkMessage::Headers(vec!())),
+                NetworkMessage::CFilter(filter) => return Some(NetworkMessage::CFilter(filter)),
+                NetworkMessage::CFHeaders(headers) => return Some(NetworkMessage::CFHeaders(headers)),
+                NetworkMessage::CFCheckpt(checkpoints) => return Some(NetworkMessage::CFCheckpt(checkpoints)),
+                _ => {}
+            }
+        }
+    }
+}
+
+#[test]
+fn serves_filters_as_neutrino_expects() {
+    let listen = SocketAddr::from(([127, 0, 0, 1], free_port()));
+    let chaindb = Constructor::open_db(None, Network::Regtest, 0).unwrap();
+    let blocks = chain(&chaindb);
+    let configdb = Constructor::open_config_db(None).unwrap();
+    let sync = SyncConfig { headers_only: true, ..SyncConfig::default() };
+    let constructor = Constructor::new(Network::Regtest, vec!(listen), chaindb, configdb, sync).unwrap();
+    constructor.set_filter_serve_policy(FilterServePolicy::neutrino());
+    let _node = constructor.start(Network::Regtest, vec!(), 0).unwrap();
+
+    let mut client = Client::connect(listen, "/neutrino:0.11.0/", false);
+    assert_ne!(client.services & SERVICE_FILTERS, 0, "Neutrino only asks peers announcing compact filters");
+
+    // checkpoints at every 1000th block up to the stop hash
+    let tip = blocks.last().unwrap().id;
+    client.send(NetworkMessage::GetCFCheckpt(GetCFCheckpt { filter_type: 0, stop_hash: tip }));
+    match client.next() {
+        Some(NetworkMessage::CFCheckpt(checkpoints)) => {
+            assert_eq!(checkpoints.filter_type, 0);
+            assert_eq!(checkpoints.stop_hash, tip);
+            assert_eq!(checkpoints.filter_headers, vec!(blocks[1000].filter_header, blocks[2000].filter_header));
+        },
+        other => panic!("expected cfcheckpt, got {:?}", other)
+    }
+
+    // the largest range of filter headers, connected to the filter header before the range
+    let stop = MAX_FILTER_HEADERS as usize;
+    client.send(NetworkMessage::GetCFHeaders(GetCFHeaders { filter_type: 0, start_height: 1, stop_hash: blocks[stop].id }));
+    match client.next() {
+        Some(NetworkMessage::CFHeaders(headers)) => {
+            assert_eq!(headers.stop_hash, blocks[stop].id);
+            assert_eq!(headers.previous_filter, blocks[0].filter_header);
+            assert_eq!(headers.filter_hashes.len(), MAX_FILTER_HEADERS as usize);
+            for (i, hash) in headers.filter_hashes.iter().enumerate() {
+                assert_eq!(*hash, Sha256dHash::hash(blocks[1 + i].filter.as_slice()));
+            }
+        },
+        other => panic!("expected cfheaders, got {:?}", other)
+    }
+
+    // the largest range of filters, in the order of the blocks
+    let start = 1000;
+    let stop = start + MAX_FILTERS as usize - 1;
+    client.send(NetworkMessage::GetCFilters(GetCFilters { filter_type: 0, start_height: start as u32, stop_hash: blocks[stop].id }));
+    for block in &blocks[start ..= stop] {
+        match client.next() {
+            Some(NetworkMessage::CFilter(filter)) => {
+                assert_eq!(filter.filter_type, 0);
+                assert_eq!(filter.block_hash, block.id);
+                assert_eq!(filter.filter, block.filter);
+            },
+            other => panic!("expected cfilter, got {:?}", other)
+        }
+    }
+
+    // a range too large is not served, the peer is disconnected
+    let mut client = Client::connect(listen, "/neutrino:0.11.0/", false);
+    client.send(NetworkMessage::GetCFilters(GetCFilters { filter_type: 0, start_height: 0, stop_hash: blocks[MAX_FILTERS as usize].id }));
+    assert!(client.next().is_none(), "a range of more than {} filters must not be served", MAX_FILTERS);
+
+    let mut client = Client::connect(listen, "/neutrino:0.11.0/", false);
+    client.send(NetworkMessage::GetCFHeaders(GetCFHeaders { filter_type: 0, start_height: 0, stop_hash: blocks[MAX_FILTER_HEADERS as usize].id }));
+    assert!(client.next().is_none(), "a range of more than {} filter headers must not be served", MAX_FILTER_HEADERS);
+
+    // as is a stop hash not on the trunk
+    let mut client = Client::connect(listen, "/neutrino:0.11.0/", false);
+    client.send(NetworkMessage::GetCFCheckpt(GetCFCheckpt { filter_type: 0, stop_hash: Sha256dHash::hash(b"unknown") }));
+    assert!(client.next().is_none(), "an unknown stop hash must not be served");
+}
@@ -0,0 +1,103 @@
+//
+// Copyright 2019 Tamas Blummer
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+// http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+//
+//!
+//! # Awaited payments
+//!
+//! A payment announced but replaced by an other one paying the request is reported with the
+//! transaction confirmed. Awaited requests survive a restart.
+//!
+
+extern crate bitcoin;
+extern crate bitcoin_hashes;
+extern crate futures;
+extern crate murmel;
+
+use bitcoin::blockdata::{
+    block::{Block, BlockHeader},
+    script::Script,
+    transaction::{OutPoint, Transaction, TxIn, TxOut}
+};
+use bitcoin_hashes::sha256d::Hash as Sha256dHash;
+use murmel::{
+    constructor::Constructor,
+    downstream::{Downstream, Subscribers},
+    event::Event,
+    payment::{PaymentRequest, PaymentWatch}
+};
+use std::sync::{Arc, atomic::{AtomicBool, Ordering}};
+
+fn request() -> PaymentRequest {
+    let script = Script::from(vec!(0x00, 0x14, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1));
+    PaymentRequest { uri: "bitcoin:order42?amount=0.001".to_string(), script, amount: Some(100_000), depth: 2 }
+}
+
+fn payment(request: &PaymentRequest, value: u64) -> Transaction {
+    Transaction {
+        version: 2,
+        lock_time: 0,
+        input: vec!(TxIn { previous_output: OutPoint { txid: Sha256dHash::default(), vout: 0 }, script_sig: Script::new(), sequence: 0xffff_fffd, witness: vec!() }),
+        output: vec!(TxOut { value, script_pubkey: request.script.clone() })
+    }
+}
+
+fn block(txdata: Vec<Transaction>) -> Block {
+    Block { header: BlockHeader { version: 1, prev_blockhash: Sha256dHash::default(), merkle_root: Sha256dHash::default(), time: 1_500_000_000, bits: 0x207fffff, nonce: 0 }, txdata }
+}
+
+#[test]
+fn replaced_payment_confirms() {
+    let configdb = Constructor::open_config_db(None).unwrap();
+    let events = Subscribers::new();
+    let mut receiver = events.subscribe();
+    let interest = Arc::new(AtomicBool::new(false));
+    let mut watch = PaymentWatch::new(configdb.clone(), events.clone(), interest.clone()).unwrap();
+    let request = request();
+    watch.watch(request.clone()).unwrap();
+    assert!(interest.load(Ordering::Relaxed));
+
+    let announced = payment(&request, 100_000);
+    watch.transaction(&announced);
+    assert!(!interest.load(Ordering::Relaxed));
+
+    // a replacement, e.g. a fee bump, confirms instead of the announced payment
+    let bump = Transaction { lock_time: 1, ..announced.clone() };
+    watch.block_connected(&block(vec!(bump.clone())), 1);
+
+    let mut reported = Vec::new();
+    while let Ok(Some(event)) = receiver.try_next() {
+        if let Event::PaymentReceived { txid, confirmations, .. } = event {
+            reported.push((txid, confirmations));
+        }
+    }
+    assert_eq!(reported, vec!((announced.txid(), 0), (bump.txid(), 1)));
+}
+
+#[test]
+fn awaited_after_restart() {
+    let configdb = Constructor::open_config_db(None).unwrap();
+    let request = request();
+    {
+        let mut watch = PaymentWatch::new(configdb.clone(), Subscribers::new(), Arc::new(AtomicBool::new(false))).unwrap();
+        watch.watch(request.clone()).unwrap();
+    }
+    let interest = Arc::new(AtomicBool::new(false));
+    let mut watch = PaymentWatch::new(configdb.clone(), Subscribers::new(), interest.clone()).unwrap();
+    assert_eq!(watch.awaited(), vec!(request.clone()));
+    assert!(interest.load(Ordering::Relaxed));
+
+    watch.unwatch(request.uri.as_str()).unwrap();
+    assert!(configdb.read().unwrap().fetch_payment_requests().unwrap().is_empty());
+}
@@ -0,0 +1,214 @@
+//
+// Copyright 2019 Tamas Blummer
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+// http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+//
+//!
+//! # Timeouts under simulated time
+//!
+//! Timeouts of expected replies, the check for enough connections and the rebroadcast of a
+//! transaction not seen propagated only happen as the simulated clock moves.
+//!
+
+extern crate bitcoin;
+extern crate bitcoin_hashes;
+extern crate mio;
+extern crate murmel;
+extern crate rand;
+
+mod common;
+
+use bitcoin::{
+    blockdata::transaction::OutPoint,
+    network::{address::Address, constants::Network, message::NetworkMessage}
+};
+use bitcoin_hashes::sha256d::Hash as Sha256dHash;
+use common::{Client, PATIENCE, free_port, script, tx};
+use mio::Token;
+use murmel::{
+    addressbook::KnownAddress,
+    bandwidth::Bandwidth,
+    clock::{Clock, Random, SeededRandom, SimulatedClock},
+    configdb::SharedConfigDB,
+    constructor::Constructor,
+    p2p::{BitcoinP2PConfig, LocalAddress, P2P, P2PControlSender, PeerId, PeerMessageSender},
+    syncconfig::SyncConfig,
+    timeout::{ExpectedReply, Timeout}
+};
+use rand::RngCore;
+use std::{
+    io,
+    net::{SocketAddr, TcpListener},
+    sync::{Arc, atomic::AtomicUsize},
+    thread,
+    time::{Duration, Instant}
+};
+
+// wait to be sure murmel does not act while the clock stands still
+const STILL: Duration = Duration::from_secs(3);
+
+fn control() -> P2PControlSender<NetworkMessage> {
+    let clock = Arc::new(SimulatedClock::new(1_500_000_000));
+    let bandwidth = Arc::new(Bandwidth::new(clock.clone()));
+    let config = BitcoinP2PConfig {
+        network: Network::Regtest,
+        nonce: 1,
+        height: AtomicUsize::new(0),
+        user_agent: "murmel: test".to_owned(),
+        max_protocol_version: 70001,
+        local: Arc::new(LocalAddress::new(vec!(), bandwidth.clone())),
+        clock: clock.clone()
+    };
+    let (_, control) = P2P::new(config, PeerMessageSender::dummy(), 10, bandwidth, clock);
+    control
+}
+
+// a node on in-memory databases following the simulated clock
+fn node(listen: Vec<SocketAddr>, configdb: SharedConfigDB, clock: Arc<SimulatedClock>) -> Constructor {
+    let chaindb = Constructor::open_db(None, Network::Regtest, 0).unwrap();
+    let sync = SyncConfig { headers_only: true, ..SyncConfig::default() };
+    Constructor::new_with_clock(Network::Regtest, listen, chaindb, configdb, sync, clock, Arc::new(SeededRandom::new(42))).unwrap()
+}
+
+// whether the listener is connected within the time
+fn accepts(listener: &TcpListener, within: Duration) -> bool {
+    let start = Instant::now();
+    while start.elapsed() < within {
+        if listener.accept().is_ok() {
+            return true;
+        }
+        thread::sleep(Duration::from_millis(100));
+    }
+    false
+}
+
+// a peer connected to murmel
+impl Client {
+    // id of the next transaction murmel sends within the time, answering what it asks on the way
+    fn next_tx(&mut self, within: Duration) -> Option<Sha256dHash> {
+        let start = Instant::now();
+        loop {
+            let elapsed = start.elapsed();
+            if elapsed >= within {
+                return None;
+            }
+            self.stream.set_read_timeout(Some(within - elapsed)).unwrap();
+            let message = match self.read() {
+                Ok(message) => message?,
+                Err(ref e) if e.kind() == io::ErrorKind::WouldBlock || e.kind() == io::ErrorKind::TimedOut => return None,
+                Err(e) => panic!("no reply from murmel: {}", e)
+            };
+            match message {
+                NetworkMessage::Ping(nonce) => self.send(NetworkMessage::Pong(nonce)),
+                NetworkMessage::GetHeaders(_) => self.send(NetworkMessage::Headers(vec!())),
+                NetworkMessage::Tx(tx) => return Some(tx.txid()),
+                _ => {}
+            }
+        }
+    }
+}
+
+#[test]
+fn stalled_peer_times_out() {
+    let clock = Arc::new(SimulatedClock::new(1_500_000_000));
+    let mut timeout = Timeout::new(control(), clock.clone());
+    let peer = PeerId::new("bitcoin", Token(1));
+
+    timeout.expect(peer, 2, ExpectedReply::Block);
+    clock.advance(Duration::from_secs(50));
+    timeout.check(vec!(ExpectedReply::Block));
+    assert!(timeout.is_busy_with(peer, ExpectedReply::Block));
+
+    // a reply restarts the timeout
+    timeout.received(peer, 1, ExpectedReply::Block);
+    clock.advance(Duration::from_secs(50));
+    timeout.check(vec!(ExpectedReply::Block));
+    assert!(timeout.is_busy_with(peer, ExpectedReply::Block));
+
+    // only expected replies count
+    clock.advance(Duration::from_secs(20));
+    timeout.check(vec!(ExpectedReply::Headers));
+    assert!(timeout.is_busy(peer));
+    timeout.check(vec!(ExpectedReply::Block));
+    assert!(!timeout.is_busy(peer));
+}
+
+#[test]
+fn answered_peer_is_not_busy() {
+    let clock = Arc::new(SimulatedClock::new(1_500_000_000));
+    let mut timeout = Timeout::new(control(), clock.clone());
+    let peer = PeerId::new("bitcoin", Token(1));
+
+    timeout.expect(peer, 1, ExpectedReply::Headers);
+    timeout.received(peer, 1, ExpectedReply::Headers);
+    clock.advance(Duration::from_secs(3600));
+    timeout.check(vec!(ExpectedReply::Headers));
+    assert!(!timeout.is_busy(peer));
+}
+
+#[test]
+fn simulated_clock_moves_when_advanced() {
+    let clock = SimulatedClock::new(1_500_000_000);
+    let start = clock.now();
+    assert_eq!(clock.now(), start);
+    clock.advance(Duration::from_secs(90));
+    assert_eq!(clock.now() - start, Duration::from_secs(90));
+    assert_eq!(clock.unix_time(), 1_500_000_090);
+}
+
+#[test]
+fn seeded_random_repeats() {
+    let a = SeededRandom::new(42);
+    let b = SeededRandom::new(42);
+    for _ in 0..3 {
+        assert_eq!(a.rng().next_u64(), b.rng().next_u64());
+    }
+    assert_ne!(SeededRandom::new(1).rng().next_u64(), SeededRandom::new(2).rng().next_u64());
+}
+
+#[test]
+fn connections_checked_as_clock_moves() {
+    let clock = Arc::new(SimulatedClock::new(1_500_000_000));
+    let peer = TcpListener::bind("127.0.0.1:0").unwrap();
+    peer.set_nonblocking(true).unwrap();
+    let configdb = Constructor::open_config_db(None).unwrap();
+    {
+        let mut configdb = configdb.write().unwrap();
+        configdb.store_addresses(vec!(KnownAddress { last_seen: 1_500_000_000, address: Address::new(&peer.local_addr().unwrap(), 0) })).unwrap();
+        configdb.batch().unwrap();
+    }
+    let constructor = node(vec!(), configdb, clock.clone());
+    let _node = constructor.start(Network::Regtest, vec!(), 1).unwrap();
+
+    assert!(!accepts(&peer, STILL), "connections should not be checked while the clock stands still");
+    clock.advance(Duration::from_secs(10));
+    assert!(accepts(&peer, PATIENCE), "a known address should be connected once the check is due");
+}
+
+#[test]
+fn rebroadcast_as_clock_moves() {
+    let clock = Arc::new(SimulatedClock::new(1_500_000_000));
+    let listen = SocketAddr::from(([127, 0, 0, 1], free_port()));
+    let constructor = node(vec!(listen), Constructor::open_config_db(None).unwrap(), clock.clone());
+    let _node = constructor.start(Network::Regtest, vec!(), 0).unwrap();
+    let mut client = Client::connect(listen, "/test:0.1/", true);
+
+    let tx = tx(vec!(OutPoint { txid: Sha256dHash::default(), vout: 0 }), vec!((script(2), 10_000)));
+    constructor.broadcast(tx.clone());
+    assert_eq!(client.next_tx(PATIENCE), Some(tx.txid()));
+
+    // not announced back by an other peer, sent again once the clock passed the time to propagate
+    assert_eq!(client.next_tx(STILL), None);
+    clock.advance(Duration::from_secs(31));
+    assert_eq!(client.next_tx(PATIENCE), Some(tx.txid()));
+}
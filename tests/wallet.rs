@@ -0,0 +1,200 @@
+//
+// Copyright 2019 Tamas Blummer
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+// http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+//
+//!
+//! # Unconfirmed transactions of the wallet
+//!
+//! Transactions added unconfirmed are undone with their descendants once a block double spends
+//! them, and no longer persisted. Transactions of a block disconnected are unconfirmed again,
+//! unconfirmed transactions expire if not confirmed in time. Changes of the status of a script
+//! hash are told while any of its subscribers is left.
+//!
+
+extern crate bitcoin;
+extern crate bitcoin_hashes;
+extern crate futures;
+extern crate murmel;
+
+mod common;
+
+use bitcoin::{
+    BitcoinHash,
+    blockdata::{
+        block::{Block, BlockHeader},
+        transaction::{OutPoint, Transaction}
+    }
+};
+use bitcoin_hashes::sha256d::Hash as Sha256dHash;
+use common::{script, tx};
+use murmel::{
+    configdb::SharedConfigDB,
+    constructor::Constructor,
+    downstream::{Downstream, Subscribers},
+    event::Event,
+    wallet::{Wallet, script_hash}
+};
+
+fn block(prev: Sha256dHash, nonce: u32, txdata: Vec<Transaction>) -> Block {
+    let coinbase = tx(vec!(OutPoint::null()), vec!((script(0xee), 50)));
+    let mut all = vec!(Transaction { lock_time: nonce, ..coinbase });
+    all.extend(txdata);
+    Block { header: BlockHeader { version: 1, prev_blockhash: prev, merkle_root: Sha256dHash::default(), time: 1_500_000_000 + nonce, bits: 0x207fffff, nonce }, txdata: all }
+}
+
+fn replaced(events: &mut futures::channel::mpsc::UnboundedReceiver<Event>) -> Vec<Sha256dHash> {
+    let mut replaced = Vec::new();
+    while let Ok(Some(event)) = events.try_next() {
+        if let Event::TransactionReplaced { replaced: txid, .. } = event {
+            replaced.push(txid);
+        }
+    }
+    replaced
+}
+
+fn wallet(configdb: &SharedConfigDB, events: &Subscribers<Event>) -> Wallet {
+    let mut wallet = Wallet::new(configdb.clone(), events.clone()).unwrap();
+    wallet.add_script(script(1)).unwrap();
+    wallet
+}
+
+#[test]
+fn double_spent_by_block_with_descendants() {
+    let configdb = Constructor::open_config_db(None).unwrap();
+    let events = Subscribers::new();
+    let mut receiver = events.subscribe();
+    let mut wallet = wallet(&configdb, &events);
+
+    let funding = tx(vec!(OutPoint { txid: Sha256dHash::default(), vout: 0 }), vec!((script(1), 100_000)));
+    let first = block(Sha256dHash::default(), 1, vec!(funding.clone()));
+    wallet.block_connected(&first, 1);
+    let funded = OutPoint { txid: funding.txid(), vout: 0 };
+
+    // a spend with change to the wallet and a child spending the change
+    let parent = tx(vec!(funded), vec!((script(2), 50_000), (script(1), 49_000)));
+    let child = tx(vec!(OutPoint { txid: parent.txid(), vout: 1 }), vec!((script(1), 48_000)));
+    wallet.add_unconfirmed(&parent);
+    wallet.add_unconfirmed(&child);
+    assert_eq!(wallet.utxos().into_iter().map(|(u, _)| u.outpoint).collect::<Vec<_>>(), vec!(OutPoint { txid: child.txid(), vout: 0 }));
+    assert_eq!(configdb.read().unwrap().fetch_unconfirmed("").unwrap().len(), 2);
+
+    // a fee bump confirms instead
+    let bump = tx(vec!(funded), vec!((script(2), 50_000), (script(1), 40_000)));
+    wallet.block_connected(&block(first.bitcoin_hash(), 2, vec!(bump.clone())), 2);
+
+    let mut evicted = replaced(&mut receiver);
+    evicted.sort();
+    let mut expected = vec!(parent.txid(), child.txid());
+    expected.sort();
+    assert_eq!(evicted, expected);
+    assert_eq!(wallet.utxos().into_iter().map(|(u, _)| u.outpoint).collect::<Vec<_>>(), vec!(OutPoint { txid: bump.txid(), vout: 1 }));
+    assert!(wallet.transactions().iter().all(|t| t.txid != parent.txid() && t.txid != child.txid()));
+    assert!(configdb.read().unwrap().fetch_unconfirmed("").unwrap().is_empty());
+}
+
+#[test]
+fn kept_unless_double_spent() {
+    let configdb = Constructor::open_config_db(None).unwrap();
+    let events = Subscribers::new();
+    let mut wallet = wallet(&configdb, &events);
+
+    let funding = tx(vec!(OutPoint { txid: Sha256dHash::default(), vout: 0 }), vec!((script(1), 100_000)));
+    wallet.block_connected(&block(Sha256dHash::default(), 1, vec!(funding.clone())), 1);
+    let spend = tx(vec!(OutPoint { txid: funding.txid(), vout: 0 }), vec!((script(2), 90_000)));
+    wallet.add_unconfirmed(&spend);
+
+    // a block without a conflict keeps the unconfirmed spend
+    wallet.block_connected(&block(Sha256dHash::default(), 2, vec!()), 2);
+    assert_eq!(configdb.read().unwrap().fetch_unconfirmed("").unwrap().into_iter().map(|u| u.tx).collect::<Vec<_>>(), vec!(spend.clone()));
+    assert!(wallet.transactions().iter().any(|t| t.txid == spend.txid() && t.height.is_none()));
+}
+
+#[test]
+fn unconfirmed_again_after_disconnect() {
+    let configdb = Constructor::open_config_db(None).unwrap();
+    let events = Subscribers::new();
+    let mut wallet = wallet(&configdb, &events);
+
+    let funding = tx(vec!(OutPoint { txid: Sha256dHash::default(), vout: 0 }), vec!((script(1), 100_000)));
+    let first = block(Sha256dHash::default(), 1, vec!(funding.clone()));
+    wallet.block_connected(&first, 1);
+    let spend = tx(vec!(OutPoint { txid: funding.txid(), vout: 0 }), vec!((script(2), 90_000)));
+    let second = block(first.bitcoin_hash(), 2, vec!(spend.clone()));
+    wallet.block_connected(&second, 2);
+    assert!(configdb.read().unwrap().fetch_unconfirmed("").unwrap().is_empty());
+
+    wallet.block_disconnected(&second.header);
+    assert_eq!(wallet.unconfirmed_transaction(&spend.txid()), Some(spend.clone()));
+    assert_eq!(configdb.read().unwrap().fetch_unconfirmed("").unwrap().into_iter().map(|u| u.tx).collect::<Vec<_>>(), vec!(spend.clone()));
+    // the output spent stays spent
+    assert!(wallet.utxos().is_empty());
+    assert!(wallet.transactions().iter().any(|t| t.txid == spend.txid() && t.height.is_none()));
+}
+
+#[test]
+fn unconfirmed_expires() {
+    let configdb = Constructor::open_config_db(None).unwrap();
+    let events = Subscribers::new();
+    let mut wallet = wallet(&configdb, &events);
+    wallet.set_unconfirmed_expiry(10);
+
+    let funding = tx(vec!(OutPoint { txid: Sha256dHash::default(), vout: 0 }), vec!((script(1), 100_000)));
+    let first = block(Sha256dHash::default(), 1, vec!(funding.clone()));
+    wallet.block_connected(&first, 1);
+    let spend = tx(vec!(OutPoint { txid: funding.txid(), vout: 0 }), vec!((script(2), 90_000)));
+    wallet.add_unconfirmed(&spend);
+
+    wallet.header_connected(&first.header, 10);
+    assert!(wallet.unconfirmed_transaction(&spend.txid()).is_some());
+    wallet.header_connected(&first.header, 11);
+    assert!(wallet.unconfirmed_transaction(&spend.txid()).is_none());
+    assert!(configdb.read().unwrap().fetch_unconfirmed("").unwrap().is_empty());
+    assert_eq!(wallet.utxos().into_iter().map(|(u, _)| u.outpoint).collect::<Vec<_>>(), vec!(OutPoint { txid: funding.txid(), vout: 0 }));
+}
+
+#[test]
+fn script_hash_subscriptions_are_counted() {
+    let configdb = Constructor::open_config_db(None).unwrap();
+    let events = Subscribers::new();
+    let mut receiver = events.subscribe();
+    let mut wallet = wallet(&configdb, &events);
+    let hash = script_hash(&script(1));
+    let status_changes = |receiver: &mut futures::channel::mpsc::UnboundedReceiver<Event>| {
+        let mut changes = 0;
+        while let Ok(Some(event)) = receiver.try_next() {
+            if let Event::ScriptHashStatus { hash: h, .. } = event {
+                assert_eq!(h, hash);
+                changes += 1;
+            }
+        }
+        changes
+    };
+
+    assert_eq!(wallet.subscribe(hash), None);
+    assert_eq!(wallet.subscribe(hash), None);
+    let funding = tx(vec!(OutPoint { txid: Sha256dHash::default(), vout: 0 }), vec!((script(1), 100_000)));
+    let first = block(Sha256dHash::default(), 1, vec!(funding.clone()));
+    wallet.block_connected(&first, 1);
+    assert_eq!(status_changes(&mut receiver), 1);
+
+    // one subscriber is left
+    wallet.unsubscribe(&hash);
+    let spend = tx(vec!(OutPoint { txid: funding.txid(), vout: 0 }), vec!((script(1), 90_000)));
+    wallet.add_unconfirmed(&spend);
+    assert_eq!(status_changes(&mut receiver), 1);
+
+    wallet.unsubscribe(&hash);
+    wallet.block_connected(&block(first.bitcoin_hash(), 2, vec!(spend)), 2);
+    assert_eq!(status_changes(&mut receiver), 0);
+}